@@ -0,0 +1,122 @@
+/* cancel.rs
+ *
+ * Stopping an evaluation from outside: a cancellation token that can be
+ * set from any thread, and a deadline.  The evaluator polls both every
+ * POLL_INTERVAL steps
+ */
+use error::Error;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+pub const POLL_INTERVAL: u64 = 256;
+
+/* Clones share the flag, so one can be kept to cancel an evaluation using another */
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        return CancelToken{cancelled: Arc::new(AtomicBool::new(false))};
+    }
+    pub fn cancel(&self) -> () {
+        (*self).cancelled.store(true, Ordering::SeqCst);
+    }
+    /* Clear the flag so evaluations using the token can run again */
+    pub fn reset(&self) -> () {
+        (*self).cancelled.store(false, Ordering::SeqCst);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        return (*self).cancelled.load(Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Interrupts {
+    pub token: Option<CancelToken>,
+    pub deadline: Option<Instant>,
+}
+
+impl Interrupts {
+    pub fn poll(&self) -> Result<(), Error> {
+        if let Some(ref token) = (*self).token {
+            if token.is_cancelled() {
+                return Err(Error::Cancelled(String::from("evaluation was cancelled")));
+            }
+        }
+        if let Some(deadline) = (*self).deadline {
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout(String::from("evaluation passed its deadline")));
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cancel::CancelToken;
+    use error::Error;
+    use interpreter::Interpreter;
+    use limits::Limits;
+    use parser::AstNode;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const LOOP: &str = "(define loop (lambda (n) (loop (+ n 1))))";
+
+    #[test]
+    fn cancelled_before_evaluation() {
+        let mut interpreter = Interpreter::new();
+        let token = CancelToken::new();
+        interpreter.set_cancel_token(Some(token.clone()));
+        token.cancel();
+        match interpreter.eval_str("(+ 1 2)") {
+            Err(Error::Cancelled(_)) => {},
+            other => panic!("Expected cancellation: {:?}", other)
+        }
+        token.reset();
+        assert_eq!(interpreter.eval_str("(+ 1 2)"), Ok(AstNode::Number(3.0)));
+    }
+
+    #[test]
+    fn cancelled_from_another_thread() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_limits(Limits::unlimited());
+        let token = CancelToken::new();
+        interpreter.set_cancel_token(Some(token.clone()));
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            token.cancel();
+        });
+        interpreter.eval_str(LOOP).unwrap();
+        match interpreter.eval_str("(loop 0)") {
+            Err(Error::Cancelled(_)) => {},
+            other => panic!("Expected cancellation: {:?}", other)
+        }
+        canceller.join().unwrap();
+        // Nothing is left behind by the abandoned calls
+        assert_eq!(interpreter.usage().depth, 0);
+        interpreter.set_cancel_token(None);
+        assert_eq!(interpreter.eval_str("(+ 1 2)"), Ok(AstNode::Number(3.0)));
+    }
+
+    #[test]
+    fn deadline() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_limits(Limits::unlimited());
+        interpreter.eval_str(LOOP).unwrap();
+        let start = Instant::now();
+        interpreter.set_deadline(Some(start + Duration::from_millis(50)));
+        match interpreter.eval_str("(loop 0)") {
+            Err(Error::Timeout(_)) => {},
+            other => panic!("Expected timeout: {:?}", other)
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        interpreter.set_deadline(Some(Instant::now() + Duration::from_secs(60)));
+        assert_eq!(interpreter.eval_str("(+ 1 2)"), Ok(AstNode::Number(3.0)));
+    }
+}
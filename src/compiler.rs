@@ -0,0 +1,265 @@
+/* compiler.rs
+ *
+ * Compiles an AST into bytecode for the vm.  Calls, ifs, defines and
+ * identifiers become instructions; forms with no instructions of their
+ * own, such as guard and quasiquote, are kept as AST for the tree walker.
+ * Parameters are loaded from their slot, as addressed by the resolver.
+ * Instructions that stand for an AST node count a step, as eval does.
+ * Chunks display as a listing of their instructions
+ */
+use eval::Context;
+use lexer::Span;
+use parser::AstNode;
+use resolver;
+use resolver::{Address, Scope};
+use symbols::Symbol;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Constant(usize), // Constant(constant), push a literal, quote or lambda
+    Load(usize), // Load(name), push the value of an identifier
+    LoadLocal(usize, usize, usize), // LoadLocal(depth, index, name), push a variable by its address, or by name if unset
+    Define(usize), // Define(name), check the name can be defined before its value is evaluated
+    Bind(usize, usize), // Bind(name, constant), pop the value of a define and push the define itself
+    JumpUnless(usize), // JumpUnless(address), pop the predicate of an if and jump when it is false
+    Jump(usize), // Jump(address)
+    Expand(usize, usize, usize), // Expand(name, constant, address), if name is a macro evaluate the call and jump
+    Call(usize, usize), // Call(argument count, name), call the operator below the arguments
+    Eval(usize), // Eval(constant), evaluate an AST with the tree walker
+    EnterSpan(usize), // EnterSpan(span), note where the following code is in the source
+    ExitSpan,
+    Return,
+}
+
+/* Constants, names and spans are referred to by their index */
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    pub constants: Vec<AstNode>,
+    pub names: Vec<Symbol>,
+    pub spans: Vec<Span>,
+}
+
+impl Chunk {
+    fn constant(&mut self, value: AstNode) -> usize {
+        (*self).constants.push(value);
+        return (*self).constants.len() - 1;
+    }
+    fn name(&mut self, name: Symbol) -> usize {
+        if let Some(i) = (*self).names.iter().position(|x| *x == name) {
+            return i;
+        }
+        (*self).names.push(name);
+        return (*self).names.len() - 1;
+    }
+    fn emit(&mut self, op: Op) -> usize {
+        (*self).code.push(op);
+        return (*self).code.len() - 1;
+    }
+    /* Point the jump at the next instruction */
+    fn patch(&mut self, jump: usize) -> () {
+        let address = (*self).code.len();
+        match (*self).code[jump] {
+            Op::JumpUnless(ref mut target) | Op::Jump(ref mut target) | Op::Expand(_, _, ref mut target) => {
+                *target = address;
+            },
+            ref other => panic!("Can't patch {:?}", other)
+        }
+    }
+}
+
+/* The chunk evaluating the AST and returning its value */
+pub fn compile(ast: &AstNode, context: &Context) -> Chunk {
+    return compile_in(ast, &[], context);
+}
+
+/* The chunk of a lambda body, run in the namespace of the call */
+pub fn compile_lambda(parameters: &[Symbol], body: &AstNode, context: &Context) -> Chunk {
+    return compile_in(body, &[Scope::lambda(parameters, body, context)], context);
+}
+
+fn compile_in(ast: &AstNode, scopes: &[Scope], context: &Context) -> Chunk {
+    let mut chunk = Chunk::default();
+    compile_node(ast, &mut chunk, scopes, context);
+    chunk.emit(Op::Return);
+    return chunk;
+}
+
+/* The listing of a chunk followed by those of the lambdas it creates, which
+ * are compiled as they would be when first called
+ */
+pub fn disassemble(chunk: &Chunk, context: &Context) -> String {
+    let mut listing = format!("{}", chunk);
+    for op in chunk.code.iter() {
+        if let Op::Constant(i) = *op {
+            if let AstNode::Lambda(ref parameters, ref body) = chunk.constants[i] {
+                let body = disassemble(&compile_lambda(parameters, body, context), context);
+                listing.push_str(&format!("\n(lambda ({})) from constant {}:\n{}", join(parameters), i, body));
+            }
+        }
+    }
+    return listing;
+}
+
+fn join(names: &[Symbol]) -> String {
+    return names.iter().map(|name| name.as_str()).collect::<Vec<_>>().join(" ");
+}
+
+/* Constants are abbreviated so lambdas don't repeat their bodies */
+fn describe(value: &AstNode) -> String {
+    match *value {
+        AstNode::Lambda(ref parameters, _) => format!("(lambda ({}) ...)", join(parameters)),
+        AstNode::Define(ref name, _) => format!("(define {} ...)", name),
+        ref other => format!("{:?}", other)
+    }
+}
+
+/* One instruction per line: its address, the instruction, what its operands
+ * refer to, and the span of the source it was compiled from
+ */
+impl fmt::Display for Chunk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut spans: Vec<&Span> = Vec::new();
+        for (address, op) in (*self).code.iter().enumerate() {
+            let name = |i: usize| &(*self).names[i];
+            let text = match *op {
+                Op::Constant(i) => format!("Constant {} {}", i, describe(&(*self).constants[i])),
+                Op::Load(i) => format!("Load {}", name(i)),
+                Op::LoadLocal(depth, index, i) => format!("LoadLocal {} {} {}", depth, index, name(i)),
+                Op::Define(i) => format!("Define {}", name(i)),
+                Op::Bind(i, _) => format!("Bind {}", name(i)),
+                Op::JumpUnless(target) => format!("JumpUnless {:04}", target),
+                Op::Jump(target) => format!("Jump {:04}", target),
+                Op::Expand(i, _, target) => format!("Expand {} {:04}", name(i), target),
+                Op::Call(count, i) => format!("Call {} {}", count, name(i)),
+                Op::Eval(i) => format!("Eval {} {}", i, describe(&(*self).constants[i])),
+                Op::EnterSpan(i) => {
+                    spans.push(&(*self).spans[i]);
+                    String::from("EnterSpan")
+                },
+                Op::ExitSpan => String::from("ExitSpan"),
+                Op::Return => String::from("Return")
+            };
+            if address > 0 {
+                writeln!(f)?;
+            }
+            match spans.last() {
+                Some(span) => write!(f, "{:04}  {:<40} ; {}", address, text, span)?,
+                None => write!(f, "{:04}  {}", address, text)?
+            }
+            if let Op::ExitSpan = *op {
+                spans.pop();
+            }
+        }
+        return Ok(());
+    }
+}
+
+fn compile_node(ast: &AstNode, chunk: &mut Chunk, scopes: &[Scope], context: &Context) -> () {
+    match *ast {
+        AstNode::Located(ref span, ref expr) => {
+            (*chunk).spans.push(span.clone());
+            let span = (*chunk).spans.len() - 1;
+            chunk.emit(Op::EnterSpan(span));
+            compile_node(expr, chunk, scopes, context);
+            chunk.emit(Op::ExitSpan);
+        },
+        AstNode::Identifier(ref ident) => {
+            let name = chunk.name(ident.clone());
+            match resolver::resolve(ident.clone(), scopes, context) {
+                Address::Local(depth, index) => chunk.emit(Op::LoadLocal(depth, index, name)),
+                Address::Builtin | Address::Dynamic => chunk.emit(Op::Load(name))
+            };
+        },
+        AstNode::Define(ref name, ref value) => {
+            let name = chunk.name(name.clone());
+            chunk.emit(Op::Define(name));
+            compile_node(value, chunk, scopes, context);
+            let define = chunk.constant(ast.clone());
+            chunk.emit(Op::Bind(name, define));
+        },
+        AstNode::If(ref pred, ref true_expr, ref false_expr) => {
+            compile_node(pred, chunk, scopes, context);
+            let unless = chunk.emit(Op::JumpUnless(0));
+            compile_node(true_expr, chunk, scopes, context);
+            let end = chunk.emit(Op::Jump(0));
+            chunk.patch(unless);
+            compile_node(false_expr, chunk, scopes, context);
+            chunk.patch(end);
+        },
+        AstNode::Quote(ref datum) => {
+            let datum = chunk.constant((**datum).clone());
+            chunk.emit(Op::Constant(datum));
+        },
+        AstNode::Expression(ref items) if !items.is_empty() => {
+            let (function, expand) = match *items[0] {
+                // Builtins can't be redefined as macros, so only other names need checking
+                AstNode::Identifier(ref name) => (chunk.name(name.clone()), !context.is_builtin(name.clone())),
+                _ => (chunk.name(Symbol::keyword("lambda")), false)
+            };
+            let expand = if expand {
+                let call = chunk.constant(ast.clone());
+                Some(chunk.emit(Op::Expand(function, call, 0)))
+            }
+            else {
+                None
+            };
+            for item in items.iter() {
+                compile_node(item, chunk, scopes, context);
+            }
+            chunk.emit(Op::Call(items.len() - 1, function));
+            if let Some(expand) = expand {
+                chunk.patch(expand);
+            }
+        },
+        AstNode::Defmacro(..) | AstNode::Quasiquote(_) | AstNode::Guard(..) => {
+            let node = chunk.constant(ast.clone());
+            chunk.emit(Op::Eval(node));
+        },
+        // Everything else, including the empty list, evaluates to itself
+        ref other => {
+            let value = chunk.constant(other.clone());
+            chunk.emit(Op::Constant(value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use compiler::{compile, disassemble, Op};
+    use eval::Context;
+    use lexer;
+    use parser;
+
+    #[test]
+    fn compile_if_and_call() {
+        let context = Context::new();
+        let ast = parser::parse(&mut lexer::parse("(if (f x) 1 (+ 2 3))").into_iter().peekable());
+        let chunk = compile(&ast, &context);
+        assert_eq!(chunk.code, vec![Op::Expand(0, 0, 4), Op::Load(0), Op::Load(1), Op::Call(1, 0),
+                                    Op::JumpUnless(7), Op::Constant(1), Op::Jump(11),
+                                    Op::Load(2), Op::Constant(2), Op::Constant(3), Op::Call(2, 2),
+                                    Op::Return]);
+        assert_eq!(chunk.names, vec!["f", "x", "+"]);
+    }
+
+    #[test]
+    fn disassemble_with_spans() {
+        let context = Context::new();
+        let mut tokens = lexer::try_parse_spanned("(define f (lambda (x)\n  (car x)))", Some("f.wsp")).unwrap();
+        let ast = parser::try_parse(&mut tokens.drain(..).peekable()).unwrap();
+        assert_eq!(disassemble(&compile(&ast, &context), &context), "\
+0000  Define f
+0001  Constant 0 (lambda (x) ...)
+0002  Bind f
+0003  Return
+(lambda (x)) from constant 0:
+0000  EnterSpan                                ; f.wsp:2:3
+0001  Load car                                 ; f.wsp:2:3
+0002  LoadLocal 0 0 x                          ; f.wsp:2:3
+0003  Call 1 car                               ; f.wsp:2:3
+0004  ExitSpan                                 ; f.wsp:2:3
+0005  Return");
+    }
+}
@@ -0,0 +1,434 @@
+/* conditions.rs
+ *
+ * Raising and handling conditions, in the style of R7RS.
+ * Errors returned by builtins can be caught too: handlers and guard
+ * clauses receive them as error objects describing the failure.
+ *
+ * raise calls the innermost handler where it is raised, before anything
+ * unwinds, so dynamic-wind's after thunks run once the handler has.
+ * Builtins fail by returning errors, so their handler is called where
+ * the error reaches the innermost dynamic-wind or with-exception-handler.
+ * Guards catch what reaches them as it unwinds, and raise it to the
+ * handlers outside if no clause applies
+ */
+use error::Error;
+use eval;
+use eval::Context;
+use lexer::Span;
+use parser::AstNode;
+use symbols::Symbol;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::iter;
+use std::rc::Rc;
+
+#[derive(Debug, PartialEq, PartialOrd)]
+pub struct ErrorObject {
+    pub kind: String,
+    pub message: String,
+    pub irritants: Vec<AstNode>,
+    pub span: Option<Span>,
+}
+
+impl fmt::Display for ErrorObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", (*self).message)?;
+        for irritant in (*self).irritants.iter() {
+            write!(f, " {:?}", irritant)?;
+        }
+        if let Some(ref span) = (*self).span {
+            write!(f, " at {}", span)?;
+        }
+        return Ok(());
+    }
+}
+
+pub fn is_builtin(ident: &str) -> bool {
+    lazy_static! {
+        static ref BUILTINS: BTreeSet<&'static str> = ["error", "raise", "raise-continuable",
+            "with-exception-handler", "dynamic-wind", "error-object?", "error-object-message",
+            "error-object-irritants", "error-object-kind", "error-object-span"].iter().cloned().collect();
+    }
+    return BUILTINS.contains(ident);
+}
+
+/* Cancellation, timeouts and exits must stop the evaluation, so scripts can't catch them.
+ * Nor can they catch a continuation on its way to its call/cc
+ */
+pub fn is_catchable(error: &Error) -> bool {
+    return !matches!(*error, Error::Cancelled(_) | Error::Timeout(_) | Error::Escape(..) | Error::Exit(_));
+}
+
+/* What raising a condition reaches, innermost last on the context's stack */
+#[derive(Debug, Clone)]
+pub enum Handler {
+    Procedure(AstNode), // Procedure(handler), installed by with-exception-handler
+    Guard, // a guard, which the error unwinds to before its clauses are tried
+}
+
+fn error_object(kind: &str, message: String, irritants: Vec<AstNode>, span: Option<Span>) -> AstNode {
+    return AstNode::ErrorObject(Rc::new(ErrorObject{kind: String::from(kind), message, irritants, span}));
+}
+
+/* The value handlers receive for an error: raised values as they are,
+ * anything else as an error object whose kind names the error
+ */
+pub fn condition(error: Error, span: Option<Span>) -> AstNode {
+    let (kind, message, irritants) = match error {
+        Error::Raised(value) => return value,
+        // The name was read as a symbol, unless a host made the error
+        Error::Undefined(ident) => ("undefined-error", String::from("Undefined Identifier"),
+                                    vec![Symbol::find(&ident).map_or(AstNode::String(ident), AstNode::Identifier)]),
+        Error::Type(message) => ("type-error", message, vec![]),
+        Error::Arity(message) => ("arity-error", message, vec![]),
+        Error::OutOfBounds(message) => ("out-of-bounds-error", message, vec![]),
+        Error::Arithmetic(message) => ("arithmetic-error", message, vec![]),
+        Error::Syntax(message) => ("syntax-error", message, vec![]),
+        Error::Io(message) => ("io-error", message, vec![]),
+        Error::ResourceExhausted(message) => ("resource-error", message, vec![]),
+        Error::Cancelled(message) => ("cancelled", message, vec![]),
+        Error::Timeout(message) => ("timeout", message, vec![]),
+        Error::NotPermitted(message) => ("permission-error", message, vec![]),
+        Error::Escape(_, value) => ("escape", String::from("Continuation called outside of its call/cc"), vec![value]),
+        Error::Exit(status) => ("exit", String::from("Exit"), vec![AstNode::Number(status as f64)]),
+    };
+    return error_object(kind, message, irritants, span);
+}
+
+fn check_arity(name: &str, args: &[Box<AstNode>], count: usize) -> Result<(), Error> {
+    if args.len() != count {
+        return Err(Error::Arity(format!("{} expects {} argument(s) but got {}", name, count, args.len())));
+    }
+
+    return Ok(());
+}
+
+fn error_object_arg<'a>(name: &str, arg: &'a AstNode) -> Result<&'a ErrorObject, Error> {
+    match *arg {
+        AstNode::ErrorObject(ref object) => Ok(object),
+        ref other => Err(Error::Type(format!("{} expects an error object: {:?}", name, other)))
+    }
+}
+
+/* Calls the handler with the condition.  Handlers run with the handler stack as it
+ * was outside of their with-exception-handler.  An error from a builtin the handler
+ * calls is raised to the handlers outside, as it leaves the handler
+ */
+fn call_handler(handler: &AstNode, condition: AstNode, context: &mut Context) -> Result<AstNode, Error> {
+    return match eval::apply(handler, &[Box::new(condition)], context) {
+        Err(error) if is_catchable(&error) && !matches!(error, Error::Raised(_)) => {
+            let span = context.take_error_span();
+            context.take_backtrace();
+            Err(raise(Error::Raised(self::condition(error, span)), context))
+        },
+        other => other
+    };
+}
+
+/* Raises the error to the handlers, innermost first, until one escapes or it reaches
+ * a guard.  Returns the error to pass on: a raised condition has been through the
+ * handlers when it is passed on, so is never raised to them again
+ */
+fn raise(error: Error, context: &mut Context) -> Error {
+    let mut error = error;
+    let mut outer = Vec::new();
+    while is_catchable(&error) {
+        let handler = match context.pop_handler() {
+            Some(Handler::Procedure(handler)) => handler,
+            Some(Handler::Guard) => {
+                context.push_handler(Handler::Guard);
+                break;
+            },
+            None => break
+        };
+        let span = context.take_error_span();
+        context.take_backtrace();
+        let condition = condition(error, span);
+        let result = call_handler(&handler, condition.clone(), context);
+        outer.push(handler);
+        match result {
+            // Returning from a handler is only allowed for raise-continuable, so that is raised in turn
+            Ok(_) => error = Error::Raised(error_object("error", String::from("exception handler returned from non-continuable raise"),
+                                                        vec![condition], context.current_span())),
+            // The handler escaped, or raised an error of its own to the handlers outside
+            Err(escaped) => {
+                error = escaped;
+                break;
+            }
+        }
+    }
+    while let Some(handler) = outer.pop() {
+        context.push_handler(Handler::Procedure(handler));
+    }
+    return error;
+}
+
+/* A raise that may return: the value the innermost handler returns is the value of the raise */
+fn raise_continuable(condition: AstNode, context: &mut Context) -> Result<AstNode, Error> {
+    match context.pop_handler() {
+        Some(Handler::Procedure(handler)) => {
+            let result = call_handler(&handler, condition, context);
+            context.push_handler(Handler::Procedure(handler));
+            result
+        },
+        Some(Handler::Guard) => {
+            context.push_handler(Handler::Guard);
+            Err(Error::Raised(condition))
+        },
+        None => Err(Error::Raised(condition))
+    }
+}
+
+/* An error from a builtin that hasn't been raised to the handlers yet is raised here */
+fn raise_unraised(result: Result<AstNode, Error>, context: &mut Context) -> Result<AstNode, Error> {
+    match result {
+        Err(error) if is_catchable(&error) && !matches!(error, Error::Raised(_)) => Err(raise(error, context)),
+        other => other
+    }
+}
+
+fn with_exception_handler(handler: &AstNode, thunk: &AstNode, context: &mut Context) -> Result<AstNode, Error> {
+    context.push_handler(Handler::Procedure(handler.clone()));
+    let result = eval::apply(thunk, &[], context);
+    let result = raise_unraised(result, context);
+    context.pop_handler();
+    return result;
+}
+
+fn dynamic_wind(before: &AstNode, thunk: &AstNode, after: &AstNode, context: &mut Context) -> Result<AstNode, Error> {
+    eval::apply(before, &[], context)?;
+    let result = eval::apply(thunk, &[], context);
+    let result = raise_unraised(result, context);
+    eval::apply(after, &[], context)?;
+    return result;
+}
+
+/* Evaluates each expression in turn, returning the value of the last */
+fn eval_body(body: &[Box<AstNode>], context: &mut Context) -> Result<AstNode, Error> {
+    let mut value = AstNode::Expression(vec![]);
+    for expr in body.iter() {
+        value = (**expr).clone();
+        eval::eval(&mut value, context)?;
+    }
+
+    return Ok(value);
+}
+
+/* Returns the value of the first clause whose test is true, if there is one */
+#[allow(clippy::vec_box)] // the clauses of an AstNode::Guard
+fn eval_clauses(clauses: &[Vec<Box<AstNode>>], context: &mut Context) -> Result<Option<AstNode>, Error> {
+    for clause in clauses.iter() {
+        if *clause[0] == AstNode::Identifier(Symbol::keyword("else")) {
+            return eval_body(&clause[1..], context).map(Some);
+        }
+        let mut test = (*clause[0]).clone();
+        eval::eval(&mut test, context)?;
+        if test == AstNode::Bool(false) {
+            continue;
+        }
+        if clause.len() == 3 && *clause[1] == AstNode::Identifier(Symbol::keyword("=>")) {
+            let mut receiver = (*clause[2]).clone();
+            eval::eval(&mut receiver, context)?;
+            return eval::apply(&receiver, &[Box::new(test)], context).map(Some);
+        }
+        if clause.len() == 1 {
+            return Ok(Some(test));
+        }
+        return eval_body(&clause[1..], context).map(Some);
+    }
+
+    return Ok(None);
+}
+
+/* Evaluates the body, and if it fails binds the condition to variable and
+ * evaluates the clauses.  The error is passed on if no clause applies
+ */
+pub fn guard(variable: Symbol, clauses: &[Vec<Box<AstNode>>], body: &[Box<AstNode>], context: &mut Context)
+    -> Result<AstNode, Error>
+{
+    context.push_handler(Handler::Guard);
+    let result = eval_body(body, context);
+    context.pop_handler();
+    let error = match result {
+        Err(error) if is_catchable(&error) => error,
+        other => return other
+    };
+    let span = context.take_error_span();
+    let backtrace = context.take_backtrace();
+    let value = Box::new(condition(error.clone(), span.clone()));
+    context.add_parameters(&[variable], iter::once(value));
+    let result = eval_clauses(clauses, context);
+    (*context).remove_namespace();
+    match result? {
+        Some(value) => Ok(value),
+        None => {
+            context.set_error_span(span);
+            context.set_backtrace(backtrace);
+            Err(raise(error, context))
+        }
+    }
+}
+
+pub fn apply(ident: &str, args: &[Box<AstNode>], context: &mut Context) -> Result<AstNode, Error> {
+    match ident {
+        "error" => {
+            match args.split_first() {
+                Some((message, irritants)) => match **message {
+                    AstNode::String(ref message) => {
+                        let irritants = irritants.iter().map(|x| (**x).clone()).collect();
+                        let object = error_object("error", message.clone(), irritants, context.current_span());
+                        Err(raise(Error::Raised(object), context))
+                    },
+                    ref other => Err(Error::Type(format!("error message must be a string: {:?}", other)))
+                },
+                None => Err(Error::Arity(String::from("error expects at least 1 argument")))
+            }
+        },
+        "raise" => {
+            check_arity(ident, args, 1)?;
+            Err(raise(Error::Raised((*args[0]).clone()), context))
+        },
+        "raise-continuable" => {
+            check_arity(ident, args, 1)?;
+            raise_continuable((*args[0]).clone(), context)
+        },
+        "with-exception-handler" => {
+            check_arity(ident, args, 2)?;
+            with_exception_handler(&args[0], &args[1], context)
+        },
+        "dynamic-wind" => {
+            check_arity(ident, args, 3)?;
+            dynamic_wind(&args[0], &args[1], &args[2], context)
+        },
+        "error-object?" => {
+            check_arity(ident, args, 1)?;
+            match *args[0] {
+                AstNode::ErrorObject(_) => Ok(AstNode::Bool(true)),
+                _ => Ok(AstNode::Bool(false))
+            }
+        },
+        "error-object-message" => {
+            check_arity(ident, args, 1)?;
+            Ok(AstNode::String(error_object_arg(ident, &args[0])?.message.clone()))
+        },
+        "error-object-irritants" => {
+            check_arity(ident, args, 1)?;
+            let irritants = error_object_arg(ident, &args[0])?.irritants.iter().cloned().map(Box::new).collect();
+            Ok(AstNode::Expression(irritants))
+        },
+        "error-object-kind" => {
+            check_arity(ident, args, 1)?;
+            Ok(AstNode::Identifier(Symbol::intern(&error_object_arg(ident, &args[0])?.kind)?))
+        },
+        // The line and column of the expression that raised the error, or #f if it isn't known
+        "error-object-span" => {
+            check_arity(ident, args, 1)?;
+            match error_object_arg(ident, &args[0])?.span {
+                Some(ref span) => Ok(AstNode::Expression(vec![Box::new(AstNode::Number(span.line as f64)),
+                                                              Box::new(AstNode::Number(span.column as f64))])),
+                None => Ok(AstNode::Bool(false))
+            }
+        },
+        _ => Err(Error::Type(format!("Invalid operator: {:?}", ident)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use error::Error;
+    use interpreter::Interpreter;
+    use parser::AstNode;
+
+    fn eval(source: &str) -> Result<AstNode, Error> {
+        return Interpreter::new().eval_str(source);
+    }
+
+    fn string(s: &str) -> AstNode {
+        return AstNode::String(String::from(s));
+    }
+
+    #[test]
+    fn guard_catches_raised_values() {
+        assert_eq!(eval("(guard (e ((equal? e 'oops) \"caught\")) (raise 'oops))"), Ok(string("caught")));
+        assert_eq!(eval("(guard (e (else (+ e 1))) (raise 41))"), Ok(AstNode::Number(42.0)));
+        assert_eq!(eval("(guard (e ((error-object-message e) => string-length)) (error \"bad\"))"),
+                   Ok(AstNode::Number(3.0)));
+        // Without an error the body's value is returned
+        assert_eq!(eval("(guard (e (else 0)) 1 2)"), Ok(AstNode::Number(2.0)));
+        // No clause applies, so the raise continues outward
+        assert_eq!(eval("(guard (e ((equal? e 'other) e)) (raise 5))"), Err(Error::Raised(AstNode::Number(5.0))));
+        assert_eq!(eval("(guard (outer (else (list 'outer outer))) (guard (e ((equal? e 'other) e)) (raise 5)))"),
+                   eval("'(outer 5)"));
+    }
+
+    #[test]
+    fn error_objects() {
+        let source = "(guard (e (else (list (error-object-message e) (error-object-irritants e) (error-object-kind e)
+                                       (error-object-span e))))
+                        (error \"out of range\" 5 'x))";
+        assert_eq!(eval(source), eval("'(\"out of range\" (5 x) error (3 25))"));
+        match eval("(error \"out of range\" 5)") {
+            Err(e) => assert_eq!(format!("{}", e), "out of range Number(5.0) at 1:1"),
+            other => panic!("Expected error: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn builtin_errors_are_catchable() {
+        let kind = |body: &str| eval(&format!("(guard (e (else (error-object-kind e))) {})", body));
+        assert_eq!(kind("(car 5)"), eval("'type-error"));
+        assert_eq!(kind("(/ 1 0)"), eval("'arithmetic-error"));
+        assert_eq!(kind("(+ 1 (car '()))"), eval("'out-of-bounds-error"));
+        assert_eq!(eval("(guard (e (else (error-object-irritants e))) (+ 1 undefined-thing))"), eval("'(undefined-thing)"));
+        // The span is that of the innermost call that failed
+        assert_eq!(eval("(guard (e (else (error-object-span e)))\n  (list 1\n    (/ 1 0)))"), eval("'(3 5)"));
+    }
+
+    #[test]
+    fn exception_handlers() {
+        assert_eq!(eval("(with-exception-handler (lambda (e) (+ e 1)) (lambda () (* 2 (raise-continuable 20))))"),
+                   Ok(AstNode::Number(42.0)));
+        // Handlers run with the outer handler installed
+        let source = "(with-exception-handler (lambda (e) (* e 10))
+                        (lambda () (with-exception-handler (lambda (e) (+ (raise-continuable e) 1))
+                                     (lambda () (raise-continuable 4)))))";
+        assert_eq!(eval(source), Ok(AstNode::Number(41.0)));
+        // Escaping from a handler by raising is the usual way to recover
+        assert_eq!(eval("(guard (e (else e)) (with-exception-handler (lambda (e) (raise 'handled)) (lambda () (car 1))))"),
+                   eval("'handled"));
+        // Returning from a non-continuable raise is itself an error
+        let message = eval("(guard (e (else (error-object-message e))) (with-exception-handler (lambda (e) 0) (lambda () (raise 1))))");
+        assert_eq!(message, Ok(string("exception handler returned from non-continuable raise")));
+        assert_eq!(eval("(raise-continuable 3)"), Err(Error::Raised(AstNode::Number(3.0))));
+    }
+
+    #[test]
+    fn dynamic_wind_runs_after_on_errors() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str("(define log (make-vector 2 false))").unwrap();
+        let source = "(guard (e (else (list e (vector->list log))))
+                        (dynamic-wind (lambda () (vector-set! log 0 'before))
+                                      (lambda () (raise 'oops))
+                                      (lambda () (vector-set! log 1 'after))))";
+        assert_eq!(interpreter.eval_str(source), interpreter.eval_str("'(oops (before after))"));
+        assert_eq!(interpreter.eval_str("(dynamic-wind (lambda () 1) (lambda () 2) (lambda () 3))"), Ok(AstNode::Number(2.0)));
+    }
+
+    #[test]
+    fn handlers_run_before_unwinding() {
+        // The handler raises whether the after thunk had run when it was called
+        let order = |body: &str| eval(&format!("(define log (vector 'not-yet))
+          (guard (e (else (list e (vector-ref log 0))))
+            (with-exception-handler (lambda (e) (raise (vector-ref log 0)))
+              (lambda () (dynamic-wind (lambda () 0) (lambda () {}) (lambda () (vector-set! log 0 'after))))))", body));
+        assert_eq!(order("(raise 'oops)"), eval("'(not-yet after)"));
+        assert_eq!(order("(error \"oops\")"), eval("'(not-yet after)"));
+        assert_eq!(order("(car 5)"), eval("'(not-yet after)"));
+        // A guard inside the handler catches first, and passes on what no clause applies to
+        assert_eq!(eval("(with-exception-handler (lambda (e) 'handler) (lambda () (guard (e (else 'guard)) (raise 1))))"),
+                   eval("'guard"));
+        assert_eq!(eval("(guard (outer (else outer))
+                           (with-exception-handler (lambda (e) (raise (list 'handled e)))
+                             (lambda () (guard (e ((equal? e 2) 0)) (raise 1)))))"), eval("'(handled 1)"));
+    }
+}
@@ -0,0 +1,155 @@
+/* continuations.rs
+ *
+ * Escaping continuations, captured by call-with-current-continuation.
+ * Calling a continuation returns its value from the call/cc that made it,
+ * abandoning whatever was being evaluated in between, as an error would.
+ * Guards and exception handlers let it pass, and dynamic-wind's after
+ * thunks still run.  A continuation can only be called while its call/cc
+ * is being evaluated: once that has returned, there is nowhere to return to
+ */
+use error::Error;
+use eval;
+use eval::Context;
+use parser::AstNode;
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ptr;
+use std::rc::Rc;
+
+pub struct Continuation {
+    active: Cell<bool>, // whether its call/cc is still being evaluated
+}
+
+impl fmt::Debug for Continuation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#<continuation>")
+    }
+}
+
+/* Each continuation is only equal to itself */
+impl PartialEq for Continuation {
+    fn eq(&self, other: &Continuation) -> bool {
+        return ptr::eq(self, other);
+    }
+}
+
+/* Continuations have no natural order, they are only comparable when equal */
+impl PartialOrd for Continuation {
+    fn partial_cmp(&self, other: &Continuation) -> Option<Ordering> {
+        if *self == *other {
+            return Some(Ordering::Equal);
+        }
+
+        return None;
+    }
+}
+
+pub fn is_builtin(ident: &str) -> bool {
+    lazy_static! {
+        static ref BUILTINS: BTreeSet<&'static str> = ["call-with-current-continuation", "call/cc"]
+            .iter().cloned().collect();
+    }
+    return BUILTINS.contains(ident);
+}
+
+/* Calls receiver with the continuation of this call, returning the receiver's
+ * value or the value the continuation is called with
+ */
+fn call_with_current_continuation(receiver: &AstNode, context: &mut Context) -> Result<AstNode, Error> {
+    let continuation = Rc::new(Continuation{active: Cell::new(true)});
+    let result = eval::apply(receiver, &[Box::new(AstNode::Continuation(continuation.clone()))], context);
+    continuation.active.set(false);
+    match result {
+        Err(Error::Escape(target, value)) if Rc::ptr_eq(&target, &continuation) => {
+            // Escaping isn't a failure, so there is no error location or backtrace to keep
+            context.take_error_span();
+            context.take_backtrace();
+            Ok(value)
+        },
+        other => other
+    }
+}
+
+/* Calling a continuation unwinds to its call/cc, like an error no one can catch */
+pub fn resume(continuation: &Rc<Continuation>, args: &[Box<AstNode>]) -> Result<AstNode, Error> {
+    if !continuation.active.get() {
+        return Err(Error::Type(String::from("Continuation called after its call/cc returned")));
+    }
+    let value = match args.len() {
+        0 => AstNode::Expression(vec![]),
+        1 => (*args[0]).clone(),
+        count => return Err(Error::Arity(format!("continuation expects 0 to 1 arguments but got {}", count)))
+    };
+
+    return Err(Error::Escape(continuation.clone(), value));
+}
+
+pub fn apply(ident: &str, args: &[Box<AstNode>], context: &mut Context) -> Result<AstNode, Error> {
+    match ident {
+        "call-with-current-continuation" | "call/cc" => {
+            if args.len() != 1 {
+                return Err(Error::Arity(format!("{} expects 1 argument(s) but got {}", ident, args.len())));
+            }
+            call_with_current_continuation(&args[0], context)
+        },
+        _ => Err(Error::Type(format!("Invalid operator: {:?}", ident)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use error::Error;
+    use interpreter::Interpreter;
+    use parser::AstNode;
+    use symbols::Symbol;
+    use vm::Backend;
+
+    fn eval(source: &str) -> Result<AstNode, Error> {
+        let results: Vec<_> = Backend::all().iter().map(|&backend| {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_backend(backend);
+            interpreter.eval_str(source)
+        }).collect();
+        assert_eq!(results[0], results[1], "backends disagree on {}", source);
+        return results[0].clone();
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(eval("(call/cc (lambda (k) 1))"), Ok(AstNode::Number(1.0)));
+        assert_eq!(eval("(+ 1 (call/cc (lambda (k) (* 10 (k 2)))))"), Ok(AstNode::Number(3.0)));
+        assert_eq!(eval("(call-with-current-continuation (lambda (k) (k)))"), Ok(AstNode::Expression(vec![])));
+        // return from the middle of a for-each, which otherwise returns the empty list
+        let find = "(define find (lambda (p xs)
+          (call/cc (lambda (return) (for-each (lambda (x) (if (p x) (return x) false)) xs)))))";
+        assert_eq!(eval(&format!("{} (find (lambda (x) (> x 2)) '(1 2 3 4))", find)), Ok(AstNode::Number(3.0)));
+        assert_eq!(eval(&format!("{} (find (lambda (x) (> x 9)) '(1 2 3 4))", find)), Ok(AstNode::Expression(vec![])));
+    }
+
+    #[test]
+    fn nested_and_uncatchable() {
+        // The outer continuation leaves the inner call/cc too
+        assert_eq!(eval("(call/cc (lambda (outer) (+ 1 (call/cc (lambda (inner) (outer 5))))))"), Ok(AstNode::Number(5.0)));
+        assert_eq!(eval("(call/cc (lambda (k) (guard (e (else 'caught)) (k 'escaped))))"),
+                   Ok(AstNode::Identifier(Symbol::intern("escaped").unwrap())));
+        assert_eq!(eval("(call/cc (lambda (k) (with-exception-handler (lambda (e) 0) (lambda () (k 7)))))"),
+                   Ok(AstNode::Number(7.0)));
+        // The after thunk runs as the continuation leaves
+        assert_eq!(eval("(define v (vector 0))
+          (call/cc (lambda (k) (dynamic-wind (lambda () 0) (lambda () (k 1)) (lambda () (vector-set! v 0 'after)))))
+          (vector-ref v 0)"), Ok(AstNode::Identifier(Symbol::intern("after").unwrap())));
+    }
+
+    #[test]
+    fn only_escaping() {
+        match eval("(define saved (vector 0)) (call/cc (lambda (k) (vector-set! saved 0 k))) ((vector-ref saved 0) 2)") {
+            Err(Error::Type(message)) => assert!(message.contains("after its call/cc returned")),
+            other => panic!("Expected a type error: {:?}", other)
+        }
+        assert!(eval("(call/cc (lambda (k) (k 1 2)))").is_err());
+        // Errors still propagate through call/cc
+        assert_eq!(eval("(call/cc (lambda (k) (raise 'oops)))"), Err(Error::Raised(AstNode::Identifier(Symbol::intern("oops").unwrap()))));
+    }
+}
@@ -0,0 +1,617 @@
+/* convert.rs
+ *
+ * Conversions between Rust and Wisp values, used to pass arguments
+ * to registered native functions and to return their results
+ */
+use error::Error;
+use hashtables::{new_hash_table, HashTable};
+use parser::AstNode;
+use symbols::Symbol;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::mem;
+
+pub trait FromWisp: Sized {
+    fn from_wisp(value: &AstNode) -> Result<Self, Error>;
+
+    /* The value of a record field that is absent, if absence is allowed */
+    fn from_missing() -> Option<Self> {
+        return None;
+    }
+}
+
+pub trait ToWisp {
+    fn to_wisp(self) -> AstNode;
+}
+
+/* The result of a native function: either a plain value or a Result of one */
+pub trait IntoWispResult {
+    fn into_wisp_result(self) -> Result<AstNode, Error>;
+}
+
+impl<T: ToWisp> IntoWispResult for T {
+    fn into_wisp_result(self) -> Result<AstNode, Error> {
+        return Ok(self.to_wisp());
+    }
+}
+
+impl<T: ToWisp> IntoWispResult for Result<T, Error> {
+    fn into_wisp_result(self) -> Result<AstNode, Error> {
+        return self.map(ToWisp::to_wisp);
+    }
+}
+
+/* Describes the kind of a value for error messages */
+pub fn type_name(value: &AstNode) -> &'static str {
+    match *value {
+        AstNode::Expression(_) => "list",
+        AstNode::Define(..) | AstNode::Defmacro(..) | AstNode::If(..) | AstNode::Quote(_) |
+            AstNode::Quasiquote(_) | AstNode::Guard(..) | AstNode::Located(..) | AstNode::Local(..) => "expression",
+        AstNode::Lambda(..) => "lambda",
+        AstNode::Macro(..) => "macro",
+        AstNode::Vector(_) => "vector",
+        AstNode::HashTable(_) => "hash table",
+        AstNode::Host(_) => "host object",
+        AstNode::ErrorObject(_) => "error object",
+        AstNode::Continuation(_) => "continuation",
+        AstNode::Generator(_) => "generator",
+        AstNode::Promise(_) => "promise",
+        AstNode::Bool(_) => "boolean",
+        AstNode::Char(_) => "character",
+        AstNode::Number(_) => "number",
+        AstNode::String(_) => "string",
+        AstNode::Identifier(_) => "symbol",
+    }
+}
+
+pub fn mismatch(kind: &str, value: &AstNode) -> Error {
+    return Error::Type(format!("expected {} but got {} {:?}", kind, type_name(value), value));
+}
+
+pub fn expected<T>(kind: &str, value: &AstNode) -> Result<T, Error> {
+    return Err(mismatch(kind, value));
+}
+
+/* Say where in a larger value a conversion failed, innermost location first */
+pub fn within(error: Error, location: &str) -> Error {
+    match error {
+        Error::Type(message) => Error::Type(format!("{} in {}", message, location)),
+        other => other
+    }
+}
+
+/* Lists and vectors both convert to sequences */
+fn sequence(value: &AstNode, kind: &str) -> Result<Vec<AstNode>, Error> {
+    match *value {
+        AstNode::Expression(ref items) => Ok(items.iter().map(|item| (**item).clone()).collect()),
+        AstNode::Vector(ref elements) => Ok(elements.borrow().clone()),
+        ref other => expected(kind, other)
+    }
+}
+
+impl FromWisp for AstNode {
+    fn from_wisp(value: &AstNode) -> Result<AstNode, Error> {
+        return Ok(value.clone());
+    }
+}
+
+impl FromWisp for f64 {
+    fn from_wisp(value: &AstNode) -> Result<f64, Error> {
+        match *value {
+            AstNode::Number(x) => Ok(x),
+            ref other => expected("a number", other)
+        }
+    }
+}
+
+impl FromWisp for f32 {
+    fn from_wisp(value: &AstNode) -> Result<f32, Error> {
+        return f64::from_wisp(value).map(|x| x as f32);
+    }
+}
+
+macro_rules! impl_integer {
+    ($($t:ident),*) => {
+        $(
+            impl FromWisp for $t {
+                fn from_wisp(value: &AstNode) -> Result<$t, Error> {
+                    match *value {
+                        // Numbers are floats, so only whole numbers within range convert
+                        AstNode::Number(x) if x.fract() == 0.0 && x >= $t::MIN as f64 && x < $t::MAX as f64 + 1.0 =>
+                            Ok(x as $t),
+                        ref other => expected(concat!("an integer within ", stringify!($t)), other)
+                    }
+                }
+            }
+
+            impl ToWisp for $t {
+                fn to_wisp(self) -> AstNode {
+                    return AstNode::Number(self as f64);
+                }
+            }
+        )*
+    }
+}
+
+impl_integer!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl FromWisp for bool {
+    fn from_wisp(value: &AstNode) -> Result<bool, Error> {
+        match *value {
+            AstNode::Bool(b) => Ok(b),
+            ref other => expected("a boolean", other)
+        }
+    }
+}
+
+impl FromWisp for char {
+    fn from_wisp(value: &AstNode) -> Result<char, Error> {
+        match *value {
+            AstNode::Char(c) => Ok(c),
+            ref other => expected("a character", other)
+        }
+    }
+}
+
+impl FromWisp for String {
+    fn from_wisp(value: &AstNode) -> Result<String, Error> {
+        match *value {
+            AstNode::String(ref s) => Ok(s.clone()),
+            ref other => expected("a string", other)
+        }
+    }
+}
+
+impl<T: FromWisp> FromWisp for Box<T> {
+    fn from_wisp(value: &AstNode) -> Result<Box<T>, Error> {
+        return T::from_wisp(value).map(Box::new);
+    }
+}
+
+/* None is false, like the result of assoc or member when nothing is found.  So an
+ * Option of a type that can itself be false doesn't convert back to what it was:
+ * Some(false) and Some(None) are both false, which converts to None
+ */
+impl<T: FromWisp> FromWisp for Option<T> {
+    fn from_wisp(value: &AstNode) -> Result<Option<T>, Error> {
+        match *value {
+            AstNode::Bool(false) => Ok(None),
+            ref other => T::from_wisp(other).map(Some)
+        }
+    }
+    fn from_missing() -> Option<Option<T>> {
+        return Some(None);
+    }
+}
+
+impl<T: FromWisp> FromWisp for Vec<T> {
+    fn from_wisp(value: &AstNode) -> Result<Vec<T>, Error> {
+        let items = sequence(value, "a list")?;
+        return items.iter().enumerate()
+                    .map(|(i, item)| T::from_wisp(item).map_err(|e| within(e, &format!("element {}", i))))
+                    .collect();
+    }
+}
+
+/* Maps convert from hash tables or association lists of (key value) entries */
+fn map_entries<K: FromWisp, V: FromWisp>(value: &AstNode) -> Result<Vec<(K, V)>, Error> {
+    let entries = match *value {
+        AstNode::HashTable(ref table) => table.borrow().entries().to_vec(),
+        AstNode::Expression(_) => {
+            let mut entries = Vec::new();
+            for (i, entry) in sequence(value, "a hash table")?.iter().enumerate() {
+                match *entry {
+                    AstNode::Expression(ref pair) if pair.len() == 2 => {
+                        entries.push(((*pair[0]).clone(), (*pair[1]).clone()));
+                    },
+                    ref other => return Err(within(mismatch("a (key value) entry", other), &format!("entry {}", i)))
+                }
+            }
+            entries
+        },
+        ref other => return expected("a hash table", other)
+    };
+    let mut result = Vec::with_capacity(entries.len());
+    for (key, value) in entries.iter() {
+        let k = K::from_wisp(key).map_err(|e| within(e, "hash table key"))?;
+        let v = V::from_wisp(value).map_err(|e| within(e, &format!("hash table value for key {:?}", key)))?;
+        result.push((k, v));
+    }
+
+    return Ok(result);
+}
+
+impl<K: FromWisp + Eq + Hash, V: FromWisp> FromWisp for HashMap<K, V> {
+    fn from_wisp(value: &AstNode) -> Result<HashMap<K, V>, Error> {
+        return map_entries(value).map(|entries| entries.into_iter().collect());
+    }
+}
+
+impl<K: FromWisp + Ord, V: FromWisp> FromWisp for BTreeMap<K, V> {
+    fn from_wisp(value: &AstNode) -> Result<BTreeMap<K, V>, Error> {
+        return map_entries(value).map(|entries| entries.into_iter().collect());
+    }
+}
+
+impl ToWisp for AstNode {
+    fn to_wisp(self) -> AstNode {
+        return self;
+    }
+}
+
+impl ToWisp for f64 {
+    fn to_wisp(self) -> AstNode {
+        return AstNode::Number(self);
+    }
+}
+
+impl ToWisp for f32 {
+    fn to_wisp(self) -> AstNode {
+        return AstNode::Number(self as f64);
+    }
+}
+
+impl ToWisp for bool {
+    fn to_wisp(self) -> AstNode {
+        return AstNode::Bool(self);
+    }
+}
+
+impl ToWisp for char {
+    fn to_wisp(self) -> AstNode {
+        return AstNode::Char(self);
+    }
+}
+
+impl ToWisp for String {
+    fn to_wisp(self) -> AstNode {
+        return AstNode::String(self);
+    }
+}
+
+impl ToWisp for &str {
+    fn to_wisp(self) -> AstNode {
+        return AstNode::String(String::from(self));
+    }
+}
+
+/* Functions called only for their effect return the empty list */
+impl ToWisp for () {
+    fn to_wisp(self) -> AstNode {
+        return AstNode::Expression(vec![]);
+    }
+}
+
+impl<T: ToWisp> ToWisp for Box<T> {
+    fn to_wisp(self) -> AstNode {
+        return (*self).to_wisp();
+    }
+}
+
+/* Some(false) and None are both false, see FromWisp for Option */
+impl<T: ToWisp> ToWisp for Option<T> {
+    fn to_wisp(self) -> AstNode {
+        match self {
+            Some(x) => x.to_wisp(),
+            None => AstNode::Bool(false)
+        }
+    }
+}
+
+impl<T: ToWisp> ToWisp for Vec<T> {
+    fn to_wisp(self) -> AstNode {
+        return AstNode::Expression(self.into_iter().map(|x| Box::new(x.to_wisp())).collect());
+    }
+}
+
+impl<K: ToWisp, V: ToWisp, S> ToWisp for HashMap<K, V, S> {
+    fn to_wisp(self) -> AstNode {
+        let mut table = HashTable::new();
+        for (k, v) in self.into_iter() {
+            table.insert(k.to_wisp(), v.to_wisp());
+        }
+        return new_hash_table(table);
+    }
+}
+
+impl<K: ToWisp, V: ToWisp> ToWisp for BTreeMap<K, V> {
+    fn to_wisp(self) -> AstNode {
+        let mut table = HashTable::new();
+        for (k, v) in self.into_iter() {
+            table.insert(k.to_wisp(), v.to_wisp());
+        }
+        return new_hash_table(table);
+    }
+}
+
+/* Tuples are lists of exactly their length */
+macro_rules! impl_tuple {
+    ($len:expr, $($t:ident $i:tt),*) => {
+        impl<$($t: FromWisp),*> FromWisp for ($($t,)*) {
+            fn from_wisp(value: &AstNode) -> Result<($($t,)*), Error> {
+                let items = sequence(value, concat!("a list of ", $len))?;
+                if items.len() != $len {
+                    return expected(concat!("a list of ", $len), value);
+                }
+                return Ok(($($t::from_wisp(&items[$i]).map_err(|e| within(e, concat!("element ", $i)))?,)*));
+            }
+        }
+
+        impl<$($t: ToWisp),*> ToWisp for ($($t,)*) {
+            fn to_wisp(self) -> AstNode {
+                return AstNode::Expression(vec![$(Box::new(self.$i.to_wisp())),*]);
+            }
+        }
+    }
+}
+
+impl_tuple!(1, A 0);
+impl_tuple!(2, A 0, B 1);
+impl_tuple!(3, A 0, B 1, C 2);
+impl_tuple!(4, A 0, B 1, C 2, D 3);
+impl_tuple!(5, A 0, B 1, C 2, D 3, E 4);
+impl_tuple!(6, A 0, B 1, C 2, D 3, E 4, F 5);
+
+/* Support for #[derive(ToWisp, FromWisp)].  Structs with named fields are
+ * association lists of (field value) entries keyed by symbols, tuple structs
+ * are lists and unit structs are their name as a symbol.  Enum variants are
+ * the variant name as a symbol, or a list headed by it and followed by the
+ * fields as a struct of the same shape would be
+ */
+pub fn record(fields: Vec<(&'static str, AstNode)>) -> AstNode {
+    let entries = fields.into_iter().map(|(name, value)| {
+        Box::new(AstNode::Expression(vec![Box::new(AstNode::Identifier(Symbol::keyword(name))), Box::new(value)]))
+    });
+    return AstNode::Expression(entries.collect());
+}
+
+pub fn tag(tag: &'static str) -> AstNode {
+    return AstNode::Identifier(Symbol::keyword(tag));
+}
+
+pub fn tagged(tag: &'static str, mut fields: AstNode) -> AstNode {
+    let mut items = vec![Box::new(AstNode::Identifier(Symbol::keyword(tag)))];
+    if let AstNode::Expression(ref mut fields) = fields {
+        items.extend(mem::take(fields));
+    }
+    return AstNode::Expression(items);
+}
+
+fn is_field_key(key: &AstNode, field: &str) -> bool {
+    match *key {
+        AstNode::Identifier(ref name) => *name == field,
+        AstNode::String(ref name) => name == field,
+        _ => false
+    }
+}
+
+/* Finds the field by name in an association list or hash table */
+pub fn record_field<T: FromWisp>(value: &AstNode, owner: &str, field: &str) -> Result<T, Error> {
+    let location = format!("field {} of {}", field, owner);
+    let found = match *value {
+        AstNode::Expression(ref entries) => {
+            let mut found = None;
+            for entry in entries.iter() {
+                match **entry {
+                    AstNode::Expression(ref pair) if pair.len() == 2 => {
+                        if is_field_key(&pair[0], field) {
+                            found = Some((*pair[1]).clone());
+                            break;
+                        }
+                    },
+                    ref other => return Err(within(mismatch("a (field value) entry", other), owner))
+                }
+            }
+            found
+        },
+        AstNode::HashTable(ref table) => {
+            table.borrow().entries().iter().find(|entry| is_field_key(&entry.0, field)).map(|entry| entry.1.clone())
+        },
+        ref other => return Err(within(mismatch("an association list", other), owner))
+    };
+    match found {
+        Some(value) => T::from_wisp(&value).map_err(|e| within(e, &location)),
+        None => T::from_missing().ok_or_else(|| Error::Type(format!("missing {}", location)))
+    }
+}
+
+/* The elements of a tuple struct or variant, which must number exactly count */
+pub fn tuple_items(value: &AstNode, owner: &str, count: usize) -> Result<Vec<AstNode>, Error> {
+    let kind = format!("a list of {}", count);
+    let items = sequence(value, &kind).map_err(|e| within(e, owner))?;
+    if items.len() != count {
+        return Err(within(mismatch(&kind, value), owner));
+    }
+
+    return Ok(items);
+}
+
+pub fn tuple_item<T: FromWisp>(items: &[AstNode], owner: &str, index: usize) -> Result<T, Error> {
+    return T::from_wisp(&items[index]).map_err(|e| within(e, &format!("field {} of {}", index, owner)));
+}
+
+pub fn unit(value: &AstNode, owner: &str, name: &str) -> Result<(), Error> {
+    if !matches!(*value, AstNode::Identifier(ref symbol) if *symbol == name) {
+        return Err(within(mismatch(&format!("the symbol {}", name), value), owner));
+    }
+
+    return Ok(());
+}
+
+/* Splits an enum value into its variant name and the list of its fields */
+pub fn variant(value: &AstNode, owner: &str) -> Result<(String, AstNode), Error> {
+    match *value {
+        AstNode::Identifier(ref name) => Ok((name.to_string(), AstNode::Expression(vec![]))),
+        AstNode::Expression(ref items) => {
+            match items.split_first() {
+                Some((head, fields)) => match **head {
+                    AstNode::Identifier(ref name) => Ok((name.to_string(), AstNode::Expression(fields.to_vec()))),
+                    ref other => Err(within(mismatch("a variant name", other), owner))
+                },
+                None => Err(within(mismatch("a variant", value), owner))
+            }
+        },
+        ref other => Err(within(mismatch("a variant", other), owner))
+    }
+}
+
+pub fn unknown_variant<T>(name: &str, owner: &str) -> Result<T, Error> {
+    return Err(Error::Type(format!("unknown variant {} of {}", name, owner)));
+}
+
+#[cfg(test)]
+mod test {
+    use {FromWisp, ToWisp};
+    use error::Error;
+    use interpreter::Interpreter;
+    use parser::AstNode;
+    use symbols::Symbol;
+    use std::collections::HashMap;
+    use vectors::new_vector;
+
+    #[derive(Debug, PartialEq, Clone, ToWisp, FromWisp)]
+    struct Player {
+        name: String,
+        scores: Vec<i64>,
+        team: Option<String>,
+    }
+
+    #[derive(Debug, PartialEq, ToWisp, FromWisp)]
+    struct Pair<T>(T, T);
+
+    #[derive(Debug, PartialEq, ToWisp, FromWisp)]
+    struct Marker;
+
+    #[derive(Debug, PartialEq, ToWisp, FromWisp)]
+    enum Shape {
+        Empty,
+        Circle(f64),
+        Rect { width: f64, height: f64 },
+        Group(Vec<Shape>),
+    }
+
+    // Derived code doesn't depend on what the names of the prelude mean where it is derived
+    #[allow(dead_code, non_upper_case_globals, unused_macros)]
+    mod shadowed {
+        use {FromWisp, ToWisp};
+
+        type Result = ();
+        struct Box;
+        const Ok: () = ();
+        macro_rules! vec { ($($x:tt)*) => { () } }
+
+        #[derive(ToWisp, FromWisp)]
+        struct Point { x: f64, y: f64 }
+
+        #[derive(ToWisp, FromWisp)]
+        enum Token { Word(String), Pair(f64, f64), End }
+    }
+
+    fn type_message<T: ::std::fmt::Debug>(result: Result<T, Error>) -> String {
+        match result {
+            Err(Error::Type(message)) => message,
+            other => panic!("Expected type error: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn primitives_round_trip() {
+        assert_eq!(f64::from_wisp(&2.5.to_wisp()), Ok(2.5));
+        assert_eq!(i64::from_wisp(&(-7i64).to_wisp()), Ok(-7));
+        assert_eq!(bool::from_wisp(&true.to_wisp()), Ok(true));
+        assert_eq!(char::from_wisp(&'x'.to_wisp()), Ok('x'));
+        assert_eq!(String::from_wisp(&"wisp".to_wisp()), Ok(String::from("wisp")));
+    }
+
+    #[test]
+    fn integers_must_be_whole() {
+        match i64::from_wisp(&AstNode::Number(1.5)) {
+            Err(Error::Type(_)) => {},
+            other => panic!("Expected type error: {:?}", other)
+        }
+        assert!(i64::from_wisp(&AstNode::Number(1e300)).is_err());
+    }
+
+    #[test]
+    fn vectors_from_lists_and_vectors() {
+        let list = vec![1i64, 2, 3].to_wisp();
+        assert_eq!(list, AstNode::Expression(vec![Box::new(AstNode::Number(1.0)), Box::new(AstNode::Number(2.0)),
+                                                  Box::new(AstNode::Number(3.0))]));
+        assert_eq!(Vec::<i64>::from_wisp(&list), Ok(vec![1, 2, 3]));
+        let vector = new_vector(vec![AstNode::Bool(true), AstNode::Bool(false)]);
+        assert_eq!(Vec::<bool>::from_wisp(&vector), Ok(vec![true, false]));
+        assert!(Vec::<bool>::from_wisp(&list).is_err());
+        assert!(Vec::<f64>::from_wisp(&AstNode::Number(1.0)).is_err());
+    }
+
+    #[test]
+    fn options_and_tuples() {
+        assert_eq!(Option::<f64>::from_wisp(&AstNode::Bool(false)), Ok(None));
+        assert_eq!(Option::<f64>::from_wisp(&AstNode::Number(1.0)), Ok(Some(1.0)));
+        assert_eq!(None::<f64>.to_wisp(), AstNode::Bool(false));
+        // False can't stand for both None and Some(false)
+        assert_eq!(Option::<bool>::from_wisp(&Some(false).to_wisp()), Ok(None));
+        let pair = (1u8, String::from("one")).to_wisp();
+        assert_eq!(<(u8, String)>::from_wisp(&pair), Ok((1, String::from("one"))));
+        assert!(<(u8, String, bool)>::from_wisp(&pair).is_err());
+        assert!(u8::from_wisp(&AstNode::Number(256.0)).is_err());
+    }
+
+    #[test]
+    fn hash_maps() {
+        let mut map = HashMap::new();
+        map.insert(String::from("a"), vec![1.0, 2.0]);
+        map.insert(String::from("b"), vec![]);
+        let table = map.clone().to_wisp();
+        assert_eq!(HashMap::<String, Vec<f64>>::from_wisp(&table), Ok(map));
+        let mut interpreter = Interpreter::new();
+        let alist = interpreter.eval_str("'((1 \"x\") (2 \"y\"))").unwrap();
+        let map = HashMap::<i32, String>::from_wisp(&alist).unwrap();
+        assert_eq!(map[&2], "y");
+        let message = type_message(HashMap::<i32, i32>::from_wisp(&alist));
+        assert!(message.contains("hash table value for key Number(1.0)"), "{}", message);
+    }
+
+    #[test]
+    fn derived_structs() {
+        let player = Player{name: String::from("ada"), scores: vec![3, 5], team: None};
+        let mut interpreter = Interpreter::new();
+        interpreter.set_global("player", player.clone().to_wisp()).unwrap();
+        assert_eq!(interpreter.eval_str("(car (cdr (assoc 'name player)))"), Ok(AstNode::String(String::from("ada"))));
+        assert_eq!(Player::from_wisp(&interpreter.get_global("player").unwrap()), Ok(player));
+        // Field order doesn't matter and optional fields may be left out
+        let value = interpreter.eval_str("'((scores (1)) (name \"bob\"))").unwrap();
+        assert_eq!(Player::from_wisp(&value), Ok(Player{name: String::from("bob"), scores: vec![1], team: None}));
+        let pair = Pair(1.5, 2.5).to_wisp();
+        assert_eq!(pair, AstNode::Expression(vec![Box::new(AstNode::Number(1.5)), Box::new(AstNode::Number(2.5))]));
+        assert_eq!(Pair::from_wisp(&pair), Ok(Pair(1.5, 2.5)));
+        assert_eq!(Marker.to_wisp(), AstNode::Identifier(Symbol::intern("Marker").unwrap()));
+        assert_eq!(Marker::from_wisp(&Marker.to_wisp()), Ok(Marker));
+    }
+
+    #[test]
+    fn derived_enums() {
+        let shape = Shape::Group(vec![Shape::Empty, Shape::Circle(1.0), Shape::Rect{width: 2.0, height: 3.0}]);
+        let mut interpreter = Interpreter::new();
+        let expected = interpreter.eval_str("'(Group (Empty (Circle 1) (Rect (width 2) (height 3))))").unwrap();
+        let value = shape.to_wisp();
+        assert_eq!(value, expected);
+        assert_eq!(Shape::from_wisp(&value), Ok(Shape::Group(vec![Shape::Empty, Shape::Circle(1.0),
+                                                                 Shape::Rect{width: 2.0, height: 3.0}])));
+        let message = type_message(Shape::from_wisp(&interpreter.eval_str("'Triangle").unwrap()));
+        assert_eq!(message, "unknown variant Triangle of Shape");
+    }
+
+    #[test]
+    fn conversion_errors_locate_the_problem() {
+        let mut interpreter = Interpreter::new();
+        let value = interpreter.eval_str("'((name \"ada\") (scores (1 \"two\")))").unwrap();
+        assert_eq!(type_message(Player::from_wisp(&value)),
+                   "expected an integer within i64 but got string String(\"two\") in element 1 in field scores of Player");
+        let value = interpreter.eval_str("'((scores ()))").unwrap();
+        assert_eq!(type_message(Player::from_wisp(&value)), "missing field name of Player");
+        let value = interpreter.eval_str("'(Rect (width 1) (height #\\h))").unwrap();
+        assert_eq!(type_message(Shape::from_wisp(&value)),
+                   "expected a number but got character Char('h') in field height of Shape::Rect");
+    }
+}
@@ -0,0 +1,48 @@
+/* error.rs
+ *
+ * Errors returned when evaluation fails
+ */
+use continuations::Continuation;
+use parser::AstNode;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Undefined(String), // Undefined(identifier)
+    Type(String), // Type(message), an argument or operator of the wrong type
+    Arity(String), // Arity(message), the wrong number of arguments
+    OutOfBounds(String), // OutOfBounds(message), an index outside of a string, list or vector
+    Arithmetic(String), // Arithmetic(message), such as division by zero
+    Syntax(String), // Syntax(message), malformed source or code produced at runtime such as by a macro
+    Io(String), // Io(message), a source file couldn't be read
+    ResourceExhausted(String), // ResourceExhausted(message), a step, depth or allocation limit was exceeded
+    Cancelled(String), // Cancelled(message), the evaluation's cancel token was set
+    Timeout(String), // Timeout(message), the evaluation ran past its deadline
+    NotPermitted(String), // NotPermitted(message), the sandbox policy denies a builtin
+    Raised(AstNode), // Raised(condition), a value raised by a script and not handled
+    Escape(Rc<Continuation>, AstNode), // Escape(continuation, value), unwinding to the call/cc the continuation came from
+    Exit(i32), // Exit(status), the script called exit, which it is up to the host to act on
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Undefined(ref ident) => write!(f, "Undefined Identifier: {}", ident),
+            Error::Type(ref message) => write!(f, "Type error: {}", message),
+            Error::Arity(ref message) => write!(f, "Arity error: {}", message),
+            Error::OutOfBounds(ref message) => write!(f, "Out of bounds: {}", message),
+            Error::Arithmetic(ref message) => write!(f, "Arithmetic error: {}", message),
+            Error::Syntax(ref message) => write!(f, "Syntax error: {}", message),
+            Error::Io(ref message) => write!(f, "IO error: {}", message),
+            Error::ResourceExhausted(ref message) => write!(f, "Resource exhausted: {}", message),
+            Error::Cancelled(ref message) => write!(f, "Cancelled: {}", message),
+            Error::Timeout(ref message) => write!(f, "Timeout: {}", message),
+            Error::NotPermitted(ref message) => write!(f, "Not permitted: {}", message),
+            Error::Raised(AstNode::ErrorObject(ref object)) => write!(f, "{}", object),
+            Error::Raised(ref value) => write!(f, "Uncaught raise: {:?}", value),
+            Error::Escape(_, ref value) => write!(f, "Continuation called outside of its call/cc: {:?}", value),
+            Error::Exit(status) => write!(f, "Exit with status {}", status),
+        }
+    }
+}
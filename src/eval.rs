@@ -3,155 +3,779 @@
  * Takes an AST and returns a result
  *
  */
+use cancel::{CancelToken, Interrupts, POLL_INTERVAL};
+use conditions;
+use conditions::Handler;
+use continuations;
+use error::Error;
+use frames::{Backtrace, Frame};
+use generators;
+use generators::Owner;
+use hashtables;
+use heap;
+use lexer::Span;
+use limits;
+use limits::{Limits, Usage};
+use lists;
+use natives::{NativeFn, Natives};
+use parser;
 use parser::AstNode;
-use std::collections::BTreeMap;
+use promises;
+use resolver;
+use sandbox::Policy;
+use streams;
+use strings;
+use symbols::Symbol;
+use system;
+use vectors;
+use vm;
+use vm::{Backend, BodyCache, CodeCache};
+use std::any::TypeId;
 use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::mem;
+use std::rc::Rc;
+use std::time::Instant;
+
+const STACK_RED_ZONE: usize = 128 * 1024;
+const STACK_SEGMENT_SIZE: usize = 2 * 1024 * 1024;
+
+/* The variables of a call, by slot: its parameters in order, then the names it defines.
+ * A parameter left without an argument keeps its slot but has no value, so lookups
+ * pass over it to the namespaces outside
+ */
+#[derive(Debug, Clone, Default)]
+struct Namespace {
+    names: Vec<Symbol>,
+    values: Vec<Option<Box<AstNode>>>,
+}
+
+impl Namespace {
+    /* A name bound twice, as by (lambda (x x) ...), refers to the later binding */
+    fn slot(&self, name: Symbol) -> Option<usize> {
+        return (*self).names.iter().rposition(|x| *x == name);
+    }
+    fn get(&self, name: Symbol) -> Option<&AstNode> {
+        return (*self).slot(name).and_then(|i| (*self).values[i].as_deref());
+    }
+}
+
+/* Where the context's stacks stood when a generator was resumed.  What it adds above them is its own */
+#[derive(Debug, Clone)]
+pub(crate) struct Marks {
+    namespaces: usize,
+    handlers: usize,
+    frames: usize,
+    depth: usize,
+    span: Option<Span>,
+}
+
+/* The calls of a generator that has yielded, kept out of the context until it is resumed */
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Suspended {
+    namespaces: Vec<Namespace>,
+    handlers: Vec<Handler>,
+    frames: Vec<Frame>,
+    depth: usize,
+    span: Option<Span>,
+}
 
 #[derive(Debug, Clone)]
 pub struct Context {
-    defines: Vec<BTreeMap<String, Box<AstNode>>>,
+    globals: HashMap<Symbol, Box<AstNode>>,
+    namespaces: Vec<Namespace>,
+    gensym_counter: usize,
+    natives: Natives,
+    limits: Limits,
+    usage: Usage,
+    interrupts: Interrupts,
+    policy: Policy,
+    handlers: Vec<Handler>,
+    current_span: Option<Span>,
+    error_span: Option<Span>,
+    frames: Vec<Frame>,
+    backtrace: Option<Backtrace>,
+    backend: Backend,
+    code: CodeCache,
+    bodies: BodyCache<Rc<AstNode>>, // lambda bodies with their variables resolved, for the tree walker
+    generators: Owner,
 }
 
 impl Context {
     /* Add a definition to the given state */
     pub fn new() -> Context {
-        return Context{defines: vec![BTreeMap::new()]};
+        return Context{globals: HashMap::new(), namespaces: vec![], gensym_counter: 0, natives: Natives::with_defaults(),
+                       limits: Limits::default(), usage: Usage::default(),
+                       interrupts: Interrupts::default(), policy: Policy::default(), handlers: vec![],
+                       current_span: None, error_span: None, frames: vec![], backtrace: None,
+                       backend: Backend::default(), code: CodeCache::new(), bodies: BodyCache::new(),
+                       generators: Owner::new()};
     }
     pub fn add_namespace(&mut self) -> () {
-        (*self).defines.push(BTreeMap::new())
+        (*self).namespaces.push(Namespace::default())
+    }
+    /* A namespace for a call, with a slot for each parameter in order, so the
+     * resolver's addresses find them by index
+     */
+    pub(crate) fn add_parameters<I: Iterator<Item = Box<AstNode>>>(&mut self, parameters: &[Symbol], args: I) -> () {
+        let mut values: Vec<Option<Box<AstNode>>> = args.take(parameters.len()).map(Some).collect();
+        values.resize(parameters.len(), None);
+        (*self).namespaces.push(Namespace{names: parameters.to_vec(), values});
     }
     pub fn remove_namespace(&mut self) -> () {
-        if (*self).defines.len() == 0 {
+        if (*self).namespaces.pop().is_none() {
             panic!("Can't remove only namespace!");
         }
-        (*self).defines.pop();
     }
-    pub fn add_define(&mut self, name: String, value: Box<AstNode>) -> Option<Box<AstNode>> {
-        let end = (*self).defines.len() - 1;
-        return (*self).defines[end].insert(name, value);
+    pub fn add_define(&mut self, name: Symbol, value: Box<AstNode>) -> Option<Box<AstNode>> {
+        let namespace = match (*self).namespaces.last_mut() {
+            Some(namespace) => namespace,
+            None => return (*self).globals.insert(name, value)
+        };
+        match namespace.slot(name.clone()) {
+            Some(i) => return namespace.values[i].replace(value),
+            None => {
+                namespace.names.push(name);
+                namespace.values.push(Some(value));
+                return None;
+            }
+        }
     }
-    fn get_define(&self, name: &String) -> Option<&Box<AstNode>> {
-        for defines in (*self).defines.iter().rev() {
-            if let Some(value) = defines.get(name) {
+    fn get_define(&self, name: Symbol) -> Option<&AstNode> {
+        for namespace in (*self).namespaces.iter().rev() {
+            if let Some(value) = namespace.get(name.clone()) {
                 return Some(value);
             }
         }
 
-        return None;
+        return (*self).globals.get(&name).map(|value| &**value);
+    }
+    /* The body of a lambda with its variables resolved, resolved once for each body as the vm compiles it once */
+    fn lambda_body(&mut self, parameters: &[Symbol], body: &Rc<AstNode>) -> Rc<AstNode> {
+        if let Some(resolved) = (*self).bodies.get(body) {
+            return resolved;
+        }
+        let resolved = Rc::new(resolver::resolve_lambda(parameters, body, self));
+        (*self).bodies.insert(body, resolved.clone());
+        return resolved;
+    }
+    /* The innermost definition of the name */
+    pub(crate) fn lookup(&self, name: Symbol) -> Option<&AstNode> {
+        return (*self).get_define(name);
+    }
+    /* The innermost definition of the name made by the calls being evaluated, passing over the globals */
+    pub(crate) fn lookup_in_calls(&self, name: Symbol) -> Option<&AstNode> {
+        return (*self).namespaces.iter().rev().find_map(|namespace| namespace.get(name.clone()));
+    }
+    /* The value in a slot of the namespace depth levels out from the innermost, as
+     * addressed by the resolver.  None if the slot's parameter was given no argument,
+     * and an error if there is no such slot, which only malformed compiled code refers to
+     */
+    pub(crate) fn lookup_local(&self, depth: usize, index: usize) -> Result<Option<&AstNode>, Error> {
+        let namespace = (*self).namespaces.len().checked_sub(1 + depth).map(|i| &(*self).namespaces[i]);
+        match namespace.and_then(|namespace| namespace.values.get(index)) {
+            Some(value) => Ok(value.as_deref()),
+            None => Err(vm::malformed())
+        }
+    }
+    /* The value of a variable the resolver addressed, looked up by name if its parameter was given no argument */
+    fn lookup_resolved(&self, name: Symbol, depth: usize, index: usize) -> Result<Option<&AstNode>, Error> {
+        return match (*self).lookup_local(depth, index)? {
+            Some(value) => Ok(Some(value)),
+            None => Ok((*self).lookup(name))
+        };
+    }
+    pub(crate) fn is_macro(&self, name: Symbol) -> bool {
+        return matches!(self.lookup(name), Some(&AstNode::Macro(..)));
+    }
+    /* Globals are defined outside of any call */
+    pub fn get_global(&self, name: &str) -> Option<&AstNode> {
+        return Symbol::find(name).and_then(|name| (*self).globals.get(&name)).map(|value| &**value);
+    }
+    pub fn set_global(&mut self, name: Symbol, value: AstNode) -> Option<Box<AstNode>> {
+        return (*self).globals.insert(name, Box::new(value));
+    }
+    /* Returns a symbol that can't collide with any identifier in the source */
+    pub fn gensym(&mut self, prefix: &str) -> Result<AstNode, Error> {
+        (*self).usage.new_symbol(&(*self).limits)?;
+        (*self).gensym_counter += 1;
+        return Ok(AstNode::Identifier(Symbol::uninterned(&format!("#:{}{}", prefix, (*self).gensym_counter))?));
+    }
+    /* Counts a symbol a script makes, failing if it would exceed the limit */
+    pub(crate) fn new_symbol(&mut self) -> Result<(), Error> {
+        return (*self).usage.new_symbol(&(*self).limits);
+    }
+    /* Natives are called like builtins and take precedence over them */
+    /* Compiled code and resolved bodies depend on which names are builtins, so are made again */
+    pub fn register_native(&mut self, name: &str, function: NativeFn) -> Result<(), Error> {
+        (*self).natives.insert(Symbol::intern(name)?, function);
+        (*self).code = CodeCache::new();
+        (*self).bodies = BodyCache::new();
+        return Ok(());
+    }
+    pub fn register_method(&mut self, name: &str, type_id: TypeId, method: NativeFn) -> Result<(), Error> {
+        (*self).natives.insert_method(Symbol::intern(name)?, type_id, method);
+        (*self).code = CodeCache::new();
+        (*self).bodies = BodyCache::new();
+        return Ok(());
+    }
+    pub fn method(&self, name: &str, type_id: TypeId) -> Option<NativeFn> {
+        return (*self).natives.method(name, type_id);
+    }
+    pub fn set_limits(&mut self, limits: Limits) -> () {
+        (*self).limits = limits;
+    }
+    pub fn limits(&self) -> Limits {
+        return (*self).limits;
+    }
+    pub fn usage(&self) -> Usage {
+        return (*self).usage;
+    }
+    /* Start counting steps and allocations afresh for a new top level evaluation */
+    pub fn reset_usage(&mut self) -> () {
+        (*self).usage = Usage{depth: (*self).usage.depth, symbols: (*self).usage.symbols, ..Usage::default()};
+    }
+    pub fn set_backend(&mut self, backend: Backend) -> () {
+        (*self).backend = backend;
+    }
+    pub fn backend(&self) -> Backend {
+        return (*self).backend;
+    }
+    pub(crate) fn code_cache(&mut self) -> &mut CodeCache {
+        return &mut (*self).code;
+    }
+    /* Count a step of the evaluation, polling for interrupts on the first and then periodically */
+    pub(crate) fn step(&mut self) -> Result<(), Error> {
+        (*self).usage.step(&(*self).limits)?;
+        if (*self).usage.steps % POLL_INTERVAL == 1 {
+            (*self).interrupts.poll()?;
+        }
+        return Ok(());
+    }
+    /* Fails if the evaluation has been cancelled or passed its deadline */
+    pub(crate) fn poll_interrupts(&self) -> Result<(), Error> {
+        return (*self).interrupts.poll();
+    }
+    pub(crate) fn enter_call(&mut self) -> Result<(), Error> {
+        return (*self).usage.enter_call(&(*self).limits);
+    }
+    pub(crate) fn exit_call(&mut self) -> () {
+        (*self).usage.exit_call();
+    }
+    /* Called after each call, when every value in use is held by the interpreter, so cycles can be collected */
+    pub(crate) fn allocate(&mut self, value: &AstNode) -> Result<(), Error> {
+        heap::maybe_collect();
+        return (*self).usage.allocate(value, &(*self).limits);
+    }
+    /* Called before making a value whose size comes from the arguments, so a huge one is refused rather than made */
+    pub(crate) fn reserve(&self, size: u64) -> Result<(), Error> {
+        return (*self).usage.reserve(size, &(*self).limits);
+    }
+    /* Called before a value is put in a vector or table, which may then nest deeper than the limit */
+    pub(crate) fn check_element(&self, value: &AstNode) -> Result<(), Error> {
+        return limits::check_nesting(value, 2, &(*self).limits);
+    }
+    /* Returns the span that was current, to be restored by exit_span */
+    pub(crate) fn enter_span(&mut self, span: Span) -> Option<Span> {
+        return (*self).current_span.replace(span);
+    }
+    pub(crate) fn exit_span(&mut self, outer: Option<Span>) -> () {
+        (*self).current_span = outer;
+    }
+    /* Called as an error leaves each located expression, so the innermost is kept */
+    pub(crate) fn note_error_span(&mut self, span: &Span) -> () {
+        if (*self).error_span.is_none() {
+            (*self).error_span = Some(span.clone());
+        }
+    }
+    pub(crate) fn push_frame(&mut self, frame: Frame) -> () {
+        (*self).frames.push(frame);
+    }
+    pub(crate) fn pop_frame(&mut self) -> () {
+        (*self).frames.pop();
+    }
+    /* Called as an error leaves each call.  The innermost call sees the whole stack */
+    pub(crate) fn note_backtrace(&mut self) -> () {
+        if (*self).backtrace.is_none() && !(*self).frames.is_empty() {
+            (*self).backtrace = Some(Backtrace::capture(&(*self).frames));
+        }
+    }
+    /* Forget where the previous top level evaluation was, where it failed and the calls that led there */
+    pub fn reset_trace(&mut self) -> () {
+        (*self).current_span = None;
+        (*self).error_span = None;
+        (*self).frames.clear();
+        (*self).backtrace = None;
+    }
+    /* Where the innermost call being evaluated starts, if it was parsed with spans */
+    pub fn current_span(&self) -> Option<Span> {
+        return (*self).current_span.clone();
+    }
+    /* Where the error being propagated was raised.  Taken when the error is caught */
+    pub fn take_error_span(&mut self) -> Option<Span> {
+        return (*self).error_span.take();
+    }
+    pub fn set_error_span(&mut self, span: Option<Span>) -> () {
+        (*self).error_span = span;
+    }
+    /* The calls being evaluated, outermost first */
+    pub fn frames(&self) -> &[Frame] {
+        return &(*self).frames;
+    }
+    /* The calls that led to the error being propagated, taken like its span when it is caught */
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        return (*self).backtrace.as_ref();
+    }
+    pub fn take_backtrace(&mut self) -> Option<Backtrace> {
+        return (*self).backtrace.take();
+    }
+    pub fn set_backtrace(&mut self, backtrace: Option<Backtrace>) -> () {
+        (*self).backtrace = backtrace;
+    }
+    /* Handlers installed by with-exception-handler, and the guards between them, innermost last */
+    pub fn push_handler(&mut self, handler: Handler) -> () {
+        (*self).handlers.push(handler);
+    }
+    pub fn pop_handler(&mut self) -> Option<Handler> {
+        return (*self).handlers.pop();
+    }
+    pub fn set_cancel_token(&mut self, token: Option<CancelToken>) -> () {
+        (*self).interrupts.token = token;
+    }
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) -> () {
+        (*self).interrupts.deadline = deadline;
+    }
+    pub fn set_policy(&mut self, policy: Policy) -> () {
+        (*self).policy = policy;
+    }
+    /* Fails if the builtin belongs to a group the sandbox policy denies */
+    pub fn check_permitted(&self, name: &str) -> Result<(), Error> {
+        match system::group(name) {
+            Some(group) if !(*self).policy.permits(group) => {
+                Err(Error::NotPermitted(format!("{} is in the {} group", name, group)))
+            },
+            _ => Ok(())
+        }
+    }
+    pub fn is_builtin(&self, name: Symbol) -> bool {
+        return name.is_standard_builtin() || (*self).natives.contains(name.clone());
+    }
+    /* A builtin that still has its own implementation, rather than a native registered in its place */
+    pub(crate) fn is_standard(&self, name: Symbol) -> bool {
+        return (*self).natives.is_default(name.clone()) || (!(*self).natives.contains(name.clone()) && name.is_standard_builtin());
+    }
+    pub(crate) fn generator_owner(&mut self) -> &mut Owner {
+        return &mut (*self).generators;
+    }
+    pub(crate) fn marks(&self) -> Marks {
+        return Marks{namespaces: (*self).namespaces.len(), handlers: (*self).handlers.len(), frames: (*self).frames.len(),
+                     depth: (*self).usage.depth, span: (*self).current_span.clone()};
+    }
+    /* Take out what a generator's calls added since it was resumed, leaving the context as its caller had it */
+    pub(crate) fn suspend(&mut self, marks: Marks) -> Suspended {
+        let depth = (*self).usage.depth - marks.depth;
+        (*self).usage.depth = marks.depth;
+        let span = mem::replace(&mut (*self).current_span, marks.span);
+        return Suspended{namespaces: (*self).namespaces.split_off(marks.namespaces),
+                         handlers: (*self).handlers.split_off(marks.handlers),
+                         frames: (*self).frames.split_off(marks.frames), depth, span};
+    }
+    /* Put a suspended generator's calls back, returning the marks to take them out at again */
+    pub(crate) fn resume(&mut self, suspended: Suspended) -> Marks {
+        let marks = (*self).marks();
+        (*self).namespaces.extend(suspended.namespaces);
+        (*self).handlers.extend(suspended.handlers);
+        (*self).frames.extend(suspended.frames);
+        (*self).usage.depth += suspended.depth;
+        (*self).current_span = suspended.span;
+        return marks;
+    }
+}
+
+impl Default for Context {
+    fn default() -> Context {
+        return Context::new();
     }
 }
 
-fn is_builtin(ident: &String) -> bool {
+/* Whether the name is one of the builtins implemented here or in the modules for each kind of value.
+ * Symbols note this when they are interned, so it is checked once per name
+ */
+pub(crate) fn is_builtin(ident: &str) -> bool {
     lazy_static! {
-        static ref BUILTINS: BTreeSet<&'static str> = ["+", "-", "*", "/"].iter().cloned().collect();
+        static ref BUILTINS: BTreeSet<&'static str> = ["list", "cons", "car", "cdr",
+            "gensym", "macroexpand-1", "macroexpand", "eq?", "equal?"].iter().cloned().collect();
+    }
+    return BUILTINS.contains(ident) || strings::is_builtin(ident) || vectors::is_builtin(ident) ||
+        hashtables::is_builtin(ident) || lists::is_builtin(ident) || system::is_builtin(ident) ||
+        conditions::is_builtin(ident) || heap::is_builtin(ident) || continuations::is_builtin(ident) ||
+        generators::is_builtin(ident) || promises::is_builtin(ident) || streams::is_builtin(ident);
+}
+
+fn list_arg<'a>(name: &str, arg: &'a AstNode) -> Result<&'a [Box<AstNode>], Error> {
+    match *arg {
+        AstNode::Expression(ref list) => Ok(list),
+        ref other => Err(Error::Type(format!("{} expects a list: {:?}", name, other)))
     }
-    return BUILTINS.contains(&ident.as_str());
 }
 
-fn reduce<F>(args: &[Box<AstNode>], f: F) -> AstNode
-    where F: Fn(f64, f64) -> f64
+fn check_arity(name: &str, args: &[Box<AstNode>], count: usize) -> Result<(), Error> {
+    if args.len() != count {
+        return Err(Error::Arity(format!("{} expects {} argument(s) but got {}", name, count, args.len())));
+    }
+
+    return Ok(());
+}
+
+/* Whether two values are the same object.  Symbols are the same when they are the same
+ * interned name, and numbers, booleans and characters when they are equal.  Shared values
+ * are the same when they share their contents, lists only when both are empty
+ */
+fn is_eq(a: &AstNode, b: &AstNode) -> bool {
+    match (a, b) {
+        (&AstNode::Identifier(ref a), &AstNode::Identifier(ref b)) => a == b,
+        (&AstNode::Number(a), &AstNode::Number(b)) => a == b,
+        (&AstNode::Bool(a), &AstNode::Bool(b)) => a == b,
+        (&AstNode::Char(a), &AstNode::Char(b)) => a == b,
+        (&AstNode::Vector(ref a), &AstNode::Vector(ref b)) => Rc::ptr_eq(a, b),
+        (&AstNode::HashTable(ref a), &AstNode::HashTable(ref b)) => Rc::ptr_eq(a, b),
+        (&AstNode::ErrorObject(ref a), &AstNode::ErrorObject(ref b)) => Rc::ptr_eq(a, b),
+        (&AstNode::Host(ref a), &AstNode::Host(ref b)) => a == b,
+        (&AstNode::Continuation(ref a), &AstNode::Continuation(ref b)) => Rc::ptr_eq(a, b),
+        (&AstNode::Generator(ref a), &AstNode::Generator(ref b)) => Rc::ptr_eq(a, b),
+        (&AstNode::Promise(ref a), &AstNode::Promise(ref b)) => Rc::ptr_eq(a, b),
+        (&AstNode::Lambda(ref a_params, ref a), &AstNode::Lambda(ref b_params, ref b)) => {
+            Rc::ptr_eq(a, b) && a_params == b_params
+        },
+        (&AstNode::Expression(ref a), &AstNode::Expression(ref b)) => a.is_empty() && b.is_empty(),
+        _ => false
+    }
+}
+
+/* Returns the macro a datum invokes, if its head names one */
+fn macro_call(datum: &AstNode, context: &Context) -> Option<(Vec<Symbol>, AstNode)> {
+    if let AstNode::Expression(ref items) = *datum {
+        let value = match items.first().map(|x| &**x) {
+            Some(&AstNode::Identifier(ref name)) => context.get_define(name.clone()),
+            Some(&AstNode::Local(ref name, depth, index)) => context.lookup_resolved(name.clone(), depth, index).ok()?,
+            _ => None
+        };
+        if let Some(&AstNode::Macro(ref params, ref body)) = value {
+            return Some((params.clone(), (**body).clone()));
+        }
+    }
+
+    return None;
+}
+
+fn bind_macro_args(params: &[Symbol], args: &[Box<AstNode>], context: &mut Context) -> Result<(), Error> {
+    let mut remaining = args.iter();
+    let mut params_iter = params.iter();
+    while let Some(param) = params_iter.next() {
+        if param == "&rest" {
+            match params_iter.next() {
+                Some(rest) => {
+                    let rest_args = AstNode::Expression(remaining.by_ref().cloned().collect());
+                    context.add_define(rest.clone(), Box::new(rest_args));
+                },
+                None => return Err(Error::Syntax(String::from("&rest must be followed by a parameter name")))
+            }
+            break;
+        }
+        match remaining.next() {
+            Some(arg) => context.add_define(param.clone(), arg.clone()),
+            None => return Err(Error::Arity(format!("Too few arguments for macro, missing: {:?}", param)))
+        };
+    }
+    if remaining.next().is_some() {
+        return Err(Error::Arity(String::from("Too many arguments for macro")));
+    }
+
+    return Ok(());
+}
+
+/* Run a macro body with its parameters bound to the unevaluated argument datums */
+fn expand_macro(params: &[Symbol], body: &AstNode, args: &[Box<AstNode>], context: &mut Context)
+    -> Result<AstNode, Error>
 {
-    let mut sum: f64;
-    match *args[0] {
-        AstNode::Number(x) => sum = x,
-        _ => panic!("Invalid number arg: {:?}", args[0]),
-    }
-    for arg in args[1..].iter() {
-        match **arg {
-            AstNode::Number(x) => sum = f(x, sum),
-            _ => panic!("Invalid number arg: {:?}", arg),
+    (*context).add_namespace();
+    let mut expansion = body.clone();
+    let result = bind_macro_args(params, args, context).and_then(|_| eval(&mut expansion, context));
+    (*context).remove_namespace();
+    result?;
+    return Ok(expansion);
+}
+
+/* Expand the datum once if it is a macro call.  Returns None if it isn't */
+fn macroexpand_1(datum: &AstNode, context: &mut Context) -> Result<Option<AstNode>, Error> {
+    if let Some((params, body)) = macro_call(datum, context) {
+        if let AstNode::Expression(ref items) = *datum {
+            return expand_macro(&params, &body, &items[1..], context).map(Some);
+        }
+    }
+
+    return Ok(None);
+}
+
+/* Build the value of a quasiquote template, evaluating unquoted parts */
+fn quasiquote(template: &AstNode, context: &mut Context) -> Result<AstNode, Error> {
+    if let AstNode::Expression(ref items) = *template {
+        if items.len() == 2 && *items[0] == AstNode::Identifier(Symbol::keyword("unquote")) {
+            let mut value = parser::from_datum(&items[1])?;
+            eval(&mut value, context)?;
+            return Ok(value);
+        }
+        let mut list: Vec<Box<AstNode>> = Vec::new();
+        for item in items.iter() {
+            if let AstNode::Expression(ref splice) = **item {
+                if splice.len() == 2 && *splice[0] == AstNode::Identifier(Symbol::keyword("unquote-splicing")) {
+                    let mut value = parser::from_datum(&splice[1])?;
+                    eval(&mut value, context)?;
+                    list.extend(list_arg("unquote-splicing", &value)?.iter().cloned());
+                    continue;
+                }
+            }
+            list.push(Box::new(quasiquote(item, context)?));
         }
+        return Ok(AstNode::Expression(list));
     }
 
-    return AstNode::Number((sum));
+    return Ok(template.clone());
 }
 
 /* Apply the given evaluated arguments to the given operand */
-fn apply(op: &AstNode, args: &[Box<AstNode>], context: &mut Context) -> AstNode {
+pub(crate) fn apply(op: &AstNode, args: &[Box<AstNode>], context: &mut Context) -> Result<AstNode, Error> {
     match *op {
         AstNode::Identifier(ref ident) => {
-            match ident.as_str() {
-                "+" => reduce(args, |x, sum| sum + x),
-                "*" => reduce(args, |x, prod| prod * x),
-                "-" => reduce(args, |x, sum| sum - x),
-                "/" => reduce(args, |x, prod| prod / x),
-                _ => {
-                    /* TODO: implement functions
-                       match context.get_define(&String::from(name)) {
-                       Some(value) => *(value.clone()),
-                       None => panic!("Undefined Identifier: {:?}", name)
-                       }
-                       */
-                    panic!("TODO: implement functions")
-                }
+            context.check_permitted(ident)?;
+            if let Some(native) = context.natives.get(ident.clone()) {
+                return native(args, context);
+            }
+            let ident = ident.as_str();
+            match ident {
+                "list" => Ok(AstNode::Expression(args.to_vec())),
+                "cons" => {
+                    check_arity(ident, args, 2)?;
+                    let mut list = vec![args[0].clone()];
+                    list.extend(list_arg(ident, &args[1])?.iter().cloned());
+                    Ok(AstNode::Expression(list))
+                },
+                "car" => {
+                    check_arity(ident, args, 1)?;
+                    match list_arg(ident, &args[0])?.first() {
+                        Some(first) => Ok((**first).clone()),
+                        None => Err(Error::OutOfBounds(String::from("car of empty list")))
+                    }
+                },
+                "cdr" => {
+                    check_arity(ident, args, 1)?;
+                    match list_arg(ident, &args[0])?.split_first() {
+                        Some((_, rest)) => Ok(AstNode::Expression(rest.to_vec())),
+                        None => Err(Error::OutOfBounds(String::from("cdr of empty list")))
+                    }
+                },
+                "gensym" => {
+                    match args.first().map(|x| &**x) {
+                        Some(&AstNode::String(ref prefix)) => context.gensym(prefix),
+                        Some(other) => Err(Error::Type(format!("gensym prefix must be a string: {:?}", other))),
+                        None => context.gensym("G")
+                    }
+                },
+                "macroexpand-1" => {
+                    check_arity(ident, args, 1)?;
+                    Ok(macroexpand_1(&args[0], context)?.unwrap_or_else(|| (*args[0]).clone()))
+                },
+                "macroexpand" => {
+                    check_arity(ident, args, 1)?;
+                    let mut datum = (*args[0]).clone();
+                    while let Some(expansion) = macroexpand_1(&datum, context)? {
+                        datum = expansion;
+                    }
+                    Ok(datum)
+                },
+                "eq?" => {
+                    check_arity(ident, args, 2)?;
+                    Ok(AstNode::Bool(is_eq(&args[0], &args[1])))
+                },
+                "equal?" => {
+                    check_arity(ident, args, 2)?;
+                    Ok(AstNode::Bool(hashtables::equal(&args[0], &args[1])))
+                },
+                _ if strings::is_builtin(ident) => strings::apply(ident, args, context),
+                _ if hashtables::is_builtin(ident) => hashtables::apply(ident, args, context),
+                _ if lists::is_builtin(ident) => lists::apply(ident, args, context),
+                _ if vectors::is_builtin(ident) => vectors::apply(ident, args, context),
+                _ if system::is_builtin(ident) => system::apply(ident, args, context),
+                _ if heap::is_builtin(ident) => heap::apply(ident, args),
+                _ if conditions::is_builtin(ident) => conditions::apply(ident, args, context),
+                _ if continuations::is_builtin(ident) => continuations::apply(ident, args, context),
+                _ if generators::is_builtin(ident) => generators::apply(ident, args, context),
+                _ if promises::is_builtin(ident) => promises::apply(ident, args, context),
+                _ if streams::is_builtin(ident) => streams::apply(ident, args, context),
+                _ => Err(Error::Type(format!("Invalid operator: {:?}", ident)))
             }
         },
         // TODO: avoid copying when creating sub context
+        AstNode::Lambda(ref parameters, ref expr) if context.backend == Backend::Bytecode => {
+            return vm::call_lambda(parameters, expr, args, context);
+        },
         AstNode::Lambda(ref parameters, ref expr) => {
+            context.enter_call()?;
             // Add arg values to context
-            (*context).add_namespace();
-            for (param, arg) in parameters.iter().zip(args.iter()) {
-                context.add_define(param.clone(), arg.clone());
-            }
+            context.add_parameters(parameters, args.iter().cloned());
             // Eval expression
-            let mut lambda_body = (**expr).clone();
-            eval(&mut lambda_body, context);
+            let mut lambda_body = (*context.lambda_body(parameters, expr)).clone();
+            let result = eval(&mut lambda_body, context);
             (*context).remove_namespace();
-            return lambda_body;
+            context.exit_call();
+            result?;
+            return Ok(lambda_body);
         },
-        ref op => panic!("Invalid operator: {:?}", op)
+        AstNode::Continuation(ref continuation) => continuations::resume(continuation, args),
+        ref op => Err(Error::Type(format!("Invalid operator: {:?}", op)))
+    }
+}
+
+/* Run a level of recursion over code or values, making sure there is room on the stack for it.
+ * Recursion depth is bounded by the depth limit rather than the native stack, which is
+ * grown on the heap as needed
+ */
+pub(crate) fn with_stack<R, F: FnOnce() -> R>(f: F) -> Result<R, Error> {
+    // Generators run on stacks of their own, which can't be grown
+    if let Some(remaining) = generators::remaining_stack() {
+        if remaining < STACK_RED_ZONE {
+            return Err(Error::ResourceExhausted(String::from("generator stack exhausted")));
+        }
+        return Ok(f());
     }
+    return Ok(stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT_SIZE, f));
 }
 
 /* Evaluate the given AST in place
  */
-pub fn eval(ast: &mut AstNode, context: &mut Context) -> () {
+pub fn eval(ast: &mut AstNode, context: &mut Context) -> Result<(), Error> {
+    return with_stack(|| eval_node(ast, context))?;
+}
+
+/* Evaluate a located expression, remembering where it is while it runs
+ * and, if it fails, where the error was raised
+ */
+fn eval_located(span: &Span, expr: &mut AstNode, context: &mut Context) -> Result<AstNode, Error> {
+    let outer = context.enter_span(span.clone());
+    let result = eval(expr, context);
+    context.exit_span(outer);
+    if result.is_err() {
+        context.note_error_span(span);
+    }
+    result?;
+    return Ok(mem::replace(expr, AstNode::Bool(false)));
+}
+
+fn eval_node(ast: &mut AstNode, context: &mut Context) -> Result<(), Error> {
     let mut result: Option<AstNode> = None;
+    // Locations aren't steps of their own
+    if let AstNode::Located(ref span, ref mut expr) = *ast {
+        let value = eval_located(span, expr, context)?;
+        *ast = value;
+        return Ok(());
+    }
+    context.step()?;
 
     match *ast {
-        AstNode::Define(ref name, ref value) => {
-            if !is_builtin(name) {
-                context.add_define(name.clone(), value.clone());
+        AstNode::Define(ref name, ref written) => {
+            if !context.is_builtin(name.clone()) {
+                let mut value = written.clone();
+                eval(&mut value, context)?;
+                context.add_define(name.clone(), value);
+                // A define gives itself as it was written, without the addresses of a resolved body
+                result = Some(AstNode::Define(name.clone(), Box::new(resolver::unresolved(written))));
+            }
+            else {
+                return Err(Error::Syntax(format!("Can't override buildin: {:?}", name)));
+            }
+        },
+        AstNode::Defmacro(ref name, ref params, ref body) => {
+            if !context.is_builtin(name.clone()) {
+                context.add_define(name.clone(), Box::new(AstNode::Macro(params.clone(), body.clone())));
             }
             else {
-                panic!("Can't override buildin: {:?}", name);
+                return Err(Error::Syntax(format!("Can't override buildin: {:?}", name)));
             }
         },
+        AstNode::Quote(ref datum) => {
+            result = Some((**datum).clone());
+        },
+        AstNode::Guard(ref variable, ref clauses, ref body) => {
+            result = Some(conditions::guard(variable.clone(), clauses, body, context)?);
+        },
+        AstNode::Quasiquote(ref template) => {
+            result = Some(quasiquote(template, context)?);
+        },
+        AstNode::Expression(_) if macro_call(ast, context).is_some() => {
+            // Macros receive their arguments as data and return the code to evaluate
+            let datum = parser::to_datum(ast);
+            let expansion = macroexpand_1(&datum, context)?.unwrap();
+            let mut expanded = parser::from_datum(&expansion)?;
+            eval(&mut expanded, context)?;
+            result = Some(expanded);
+        },
         AstNode::Expression(ref mut expr) => {
             if let Some((p_op, args)) = (*expr).split_first_mut() {
+                let function = match **p_op {
+                    AstNode::Identifier(ref name) | AstNode::Local(ref name, _, _) => name.clone(),
+                    _ => Symbol::keyword("lambda")
+                };
                 // Evaluate operator
-                eval(&mut **p_op, context);
+                eval(&mut **p_op, context)?;
                 // Evaluate all arguments
                 for e in args.iter_mut() {
-                    eval(e, context);
+                    eval(e, context)?;
+                }
+                let frame = Frame{function, span: context.current_span(), args: args.iter().map(|x| (**x).clone()).collect()};
+                context.push_frame(frame);
+                let value = apply(p_op, args, context);
+                if value.is_err() {
+                    context.note_backtrace();
                 }
-                result = Some(apply(p_op, args, context));
+                context.pop_frame();
+                let value = value?;
+                context.allocate(&value)?;
+                result = Some(value);
             }
 
         },
         AstNode::Identifier(ref ident) => {
             // substitute defines
-            if !is_builtin(ident) {
-                match context.get_define(ident) {
-                    Some(value) => result = Some((**value).clone()),
-                    None => panic!("Undefined Identifier: {:?}", ident)
+            if context.is_builtin(ident.clone()) {
+                context.check_permitted(ident)?;
+            }
+            else {
+                match context.get_define(ident.clone()) {
+                    Some(value) => result = Some((*value).clone()),
+                    None => return Err(Error::Undefined(ident.to_string()))
                 }
             }
         },
+        AstNode::Local(ref name, depth, index) => {
+            match context.lookup_resolved(name.clone(), depth, index)? {
+                Some(value) => result = Some(value.clone()),
+                None => return Err(Error::Undefined(name.to_string()))
+            }
+        },
         AstNode::If(ref mut pred, ref mut true_expr, ref mut false_expr) => {
-            eval(pred, context);
+            eval(pred, context)?;
             match **pred {
                 AstNode::Bool(b) => {
                     if b {
-                        eval(true_expr, context);
+                        eval(true_expr, context)?;
                         result = Some((**true_expr).clone());
                     }
                     else {
-                        eval(false_expr, context);
+                        eval(false_expr, context)?;
                         result = Some((**false_expr).clone());
                     }
                 },
-                ref pred => panic!("Unexpected predicate for if statement: {:?}", pred)
+                ref pred => return Err(Error::Type(format!("Unexpected predicate for if statement: {:?}", pred)))
             }
         },
         _ => {}
@@ -160,60 +784,80 @@ pub fn eval(ast: &mut AstNode, context: &mut Context) -> () {
         *ast = x;
     }
 
-    return ();
+    return Ok(());
 }
 
 #[cfg(test)]
 mod test {
+    use parser;
     use parser::AstNode;
+    use lexer;
+    use error::Error;
     use eval::eval;
     use eval::Context;
+    use symbols::Symbol;
+    use std::rc::Rc;
+
+    // Evaluate every expression in the source, returning the last result
+    fn eval_source(source: &str, c: &mut Context) -> AstNode {
+        let mut tokens = lexer::parse(source).into_iter().peekable();
+        let mut ast = AstNode::Bool(false);
+        while tokens.peek().is_some() {
+            ast = parser::parse(&mut tokens);
+            eval(&mut ast, c).unwrap();
+        }
+        return ast;
+    }
+
+    fn number_list(numbers: &[f64]) -> AstNode {
+        AstNode::Expression(numbers.iter().map(|x| Box::new(AstNode::Number(*x))).collect())
+    }
 
     #[test]
     fn simple_context() {
         let mut c = Context::new();
-        let name = String::from("A");
+        let name = Symbol::intern("A").unwrap();
         let value = Box::new(AstNode::Number(10.0));
         c.add_define(name.clone(), value.clone());
-        assert_eq!(value, *c.get_define(&name).unwrap());
+        assert_eq!(*value, *c.get_define(name).unwrap());
     }
 
     #[test]
     fn multiple_namespace_context() {
         let mut c = Context::new();
-        let name = String::from("A");
+        let name = Symbol::intern("A").unwrap();
         let old_value = Box::new(AstNode::Number(5.0));
         let value = Box::new(AstNode::Number(10.0));
         c.add_define(name.clone(), old_value.clone());
         c.add_namespace();
         c.add_define(name.clone(), value.clone());
-        assert_eq!(value, *c.get_define(&name).unwrap());
+        assert_eq!(*value, *c.get_define(name.clone()).unwrap());
         c.remove_namespace();
-        assert_eq!(old_value, *c.get_define(&name).unwrap());
+        assert_eq!(*old_value, *c.get_define(name).unwrap());
     }
 
     #[test]
     fn context_get_non_existent_define() {
         let mut c = Context::new();
-        let name = String::from("A");
-        let undefined = String::from("BLAH");
+        let name = Symbol::intern("A").unwrap();
+        let undefined = Symbol::intern("BLAH").unwrap();
         let value = Box::new(AstNode::Number(10.0));
         c.add_define(name.clone(), value.clone());
-        assert_eq!(None, c.get_define(&undefined));
+        assert_eq!(None, c.get_define(undefined));
     }
 
     #[test]
     fn eval_simple_define() {
         let mut c = Context::new();
-        let name = String::from("A");
+        let name = Symbol::intern("A").unwrap();
         let value = Box::new(AstNode::Number(10.0));
         c.add_define(name.clone(), value.clone());
-        assert_eq!(value, *c.get_define(&name).unwrap());
+        assert_eq!(*value, *c.get_define(name).unwrap());
 
-        let mut ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(String::from("+"))),
+        let mut ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(Symbol::intern("+").unwrap())),
                                                     Box::new(AstNode::Number(3.0)),
-                                                    Box::new(AstNode::Identifier(String::from("A")))]);
-        eval(&mut ast, &mut c);
+                                                    Box::new(AstNode::Identifier(Symbol::intern("A").unwrap()))]);
+        eval(&mut ast, &mut c).unwrap();
         let expected_result = AstNode::Number(13.0);
         assert_eq!(ast, expected_result);
     }
@@ -221,15 +865,15 @@ mod test {
     #[test]
     fn eval_operator_define() {
         let mut c = Context::new();
-        let name = String::from("ADD");
-        let value = Box::new(AstNode::Identifier(String::from("+")));
+        let name = Symbol::intern("ADD").unwrap();
+        let value = Box::new(AstNode::Identifier(Symbol::intern("+").unwrap()));
         c.add_define(name.clone(), value.clone());
-        assert_eq!(value, *c.get_define(&name).unwrap());
+        assert_eq!(*value, *c.get_define(name).unwrap());
 
-        let mut ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(String::from("ADD"))),
+        let mut ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(Symbol::intern("ADD").unwrap())),
                                                     Box::new(AstNode::Number(3.0)),
                                                     Box::new(AstNode::Number(4.0))]);
-        eval(&mut ast, &mut c);
+        eval(&mut ast, &mut c).unwrap();
         let expected_result = AstNode::Number(7.0);
         assert_eq!(ast, expected_result);
     }
@@ -239,13 +883,13 @@ mod test {
         // ((lambda (x) (* x x)) 4)
         let mut c = Context::new();
         let mut ast = AstNode::Expression(vec![
-                            Box::new(AstNode::Lambda(vec![String::from("x")],
-                                            Box::new(AstNode::Expression(vec![
-                                                 Box::new(AstNode::Identifier(String::from("*"))),
-                                                 Box::new(AstNode::Identifier(String::from("x"))),
-                                                 Box::new(AstNode::Identifier(String::from("x")))])))),
+                            Box::new(AstNode::Lambda(vec![Symbol::intern("x").unwrap()],
+                                            Rc::new(AstNode::Expression(vec![
+                                                 Box::new(AstNode::Identifier(Symbol::intern("*").unwrap())),
+                                                 Box::new(AstNode::Identifier(Symbol::intern("x").unwrap())),
+                                                 Box::new(AstNode::Identifier(Symbol::intern("x").unwrap()))])))),
                             Box::new(AstNode::Number(4.0))]);
-        eval(&mut ast, &mut c);
+        eval(&mut ast, &mut c).unwrap();
         let expected_result = AstNode::Number(16.0);
         assert_eq!(ast, expected_result);
     }
@@ -253,10 +897,10 @@ mod test {
     #[test]
     fn simple_eval() {
         let mut c = Context::new();
-        let mut ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(String::from("+"))),
+        let mut ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(Symbol::intern("+").unwrap())),
                                                     Box::new(AstNode::Number(3.0)),
                                                     Box::new(AstNode::Number(4.0))]);
-        eval(&mut ast, &mut c);
+        eval(&mut ast, &mut c).unwrap();
         let expected_result = AstNode::Number(7.0);
         assert_eq!(ast, expected_result);
     }
@@ -264,21 +908,21 @@ mod test {
     #[test]
     fn simple_sub() {
         let mut c = Context::new();
-        let mut ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(String::from("-"))),
+        let mut ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(Symbol::intern("-").unwrap())),
                                                     Box::new(AstNode::Number(3.0)),
                                                     Box::new(AstNode::Number(4.0))]);
-        eval(&mut ast, &mut c);
-        let expected_result = AstNode::Number((-1.0));
+        eval(&mut ast, &mut c).unwrap();
+        let expected_result = AstNode::Number(-1.0);
         assert_eq!(ast, expected_result);
     }
 
     #[test]
     fn simple_mult() {
         let mut c = Context::new();
-        let mut ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(String::from("*"))),
+        let mut ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(Symbol::intern("*").unwrap())),
                                                     Box::new(AstNode::Number(3.0)),
                                                     Box::new(AstNode::Number(4.0))]);
-        eval(&mut ast, &mut c);
+        eval(&mut ast, &mut c).unwrap();
         let expected_result = AstNode::Number(12.0);
         assert_eq!(ast, expected_result);
     }
@@ -286,24 +930,24 @@ mod test {
     #[test]
     fn simple_div() {
         let mut c = Context::new();
-        let mut ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(String::from("/"))),
+        let mut ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(Symbol::intern("/").unwrap())),
                                                     Box::new(AstNode::Number(3.0)),
                                                     Box::new(AstNode::Number(4.0))]);
-        eval(&mut ast, &mut c);
-        let expected_result = AstNode::Number((0.75));
+        eval(&mut ast, &mut c).unwrap();
+        let expected_result = AstNode::Number(0.75);
         assert_eq!(ast, expected_result);
     }
 
     #[test]
     fn nested_eval() {
         let mut c = Context::new();
-        let mut ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(String::from("+"))),
+        let mut ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(Symbol::intern("+").unwrap())),
                                                  Box::new(AstNode::Number(3.0)),
                                                  Box::new(AstNode::Expression(vec![
-                                                    Box::new(AstNode::Identifier(String::from("+"))),
+                                                    Box::new(AstNode::Identifier(Symbol::intern("+").unwrap())),
                                                     Box::new(AstNode::Number(3.0)),
                                                     Box::new(AstNode::Number(4.0))]))]);
-        eval(&mut ast, &mut c);
+        eval(&mut ast, &mut c).unwrap();
         let expected_result = AstNode::Number(10.0);
         assert_eq!(ast, expected_result);
     }
@@ -314,8 +958,8 @@ mod test {
         let mut ast = AstNode::If(Box::new(AstNode::Bool(true)),
                                                     Box::new(AstNode::Number(3.0)),
                                                     Box::new(AstNode::Number(4.0)));
-        eval(&mut ast, &mut c);
-        let expected_result = AstNode::Number((3.0));
+        eval(&mut ast, &mut c).unwrap();
+        let expected_result = AstNode::Number(3.0);
         assert_eq!(ast, expected_result);
     }
 
@@ -325,8 +969,210 @@ mod test {
         let mut ast = AstNode::If(Box::new(AstNode::Bool(false)),
                                                     Box::new(AstNode::Number(3.0)),
                                                     Box::new(AstNode::Number(4.0)));
-        eval(&mut ast, &mut c);
-        let expected_result = AstNode::Number((4.0));
+        eval(&mut ast, &mut c).unwrap();
+        let expected_result = AstNode::Number(4.0);
         assert_eq!(ast, expected_result);
     }
+
+    #[test]
+    fn define_evaluates_value() {
+        let mut c = Context::new();
+        let result = eval_source("(define A (+ 1 2)) (* A 2)", &mut c);
+        assert_eq!(result, AstNode::Number(6.0));
+    }
+
+    #[test]
+    fn quote_and_list_ops() {
+        let mut c = Context::new();
+        assert_eq!(eval_source("'(1 2 3)", &mut c), number_list(&[1.0, 2.0, 3.0]));
+        assert_eq!(eval_source("(cons 1 (list 2 3))", &mut c), number_list(&[1.0, 2.0, 3.0]));
+        assert_eq!(eval_source("(car '(1 2 3))", &mut c), AstNode::Number(1.0));
+        assert_eq!(eval_source("(cdr '(1 2 3))", &mut c), number_list(&[2.0, 3.0]));
+        assert_eq!(eval_source("'if", &mut c), AstNode::Identifier(Symbol::intern("if").unwrap()));
+    }
+
+    #[test]
+    fn quasiquote_unquote() {
+        let mut c = Context::new();
+        let result = eval_source("(define A 2) `(1 ,A ,@(list 3 4))", &mut c);
+        assert_eq!(result, number_list(&[1.0, 2.0, 3.0, 4.0]));
+    }
+
+    #[test]
+    fn simple_macro() {
+        let mut c = Context::new();
+        let result = eval_source("(defmacro unless (pred a b) `(if ,pred ,b ,a)) (unless false 1 2)", &mut c);
+        assert_eq!(result, AstNode::Number(1.0));
+    }
+
+    #[test]
+    fn macro_args_unevaluated() {
+        let mut c = Context::new();
+        // The undefined identifier is never evaluated because the macro discards it
+        let result = eval_source("(defmacro first (a b) a) (first (+ 1 2) undefined)", &mut c);
+        assert_eq!(result, AstNode::Number(3.0));
+    }
+
+    #[test]
+    fn macro_rest_args() {
+        let mut c = Context::new();
+        let result = eval_source("(defmacro sum (&rest xs) `(+ 0 ,@xs)) (sum 1 2 3)", &mut c);
+        assert_eq!(result, AstNode::Number(6.0));
+    }
+
+    #[test]
+    fn macro_built_with_list() {
+        let mut c = Context::new();
+        let result = eval_source("(defmacro my-if (p a b) (list 'if p a b)) (my-if true 1 2)", &mut c);
+        assert_eq!(result, AstNode::Number(1.0));
+    }
+
+    #[test]
+    fn gensym_is_fresh() {
+        let mut c = Context::new();
+        let a = eval_source("(gensym)", &mut c);
+        let b = eval_source("(gensym)", &mut c);
+        assert!(a != b);
+        let result = eval_source("(defmacro square (x) ((lambda (g) `((lambda (,g) (* ,g ,g)) ,x)) (gensym)))
+                                  (square 5)", &mut c);
+        assert_eq!(result, AstNode::Number(25.0));
+    }
+
+    #[test]
+    fn macroexpand() {
+        let mut c = Context::new();
+        eval_source("(defmacro inc (x) `(+ ,x 1)) (defmacro inc2 (x) `(inc (inc ,x)))", &mut c);
+        let once = eval_source("(macroexpand-1 '(inc2 y))", &mut c);
+        assert_eq!(once, eval_source("'(inc (inc y))", &mut c));
+        let full = eval_source("(macroexpand '(inc2 y))", &mut c);
+        assert_eq!(full, eval_source("'(+ (inc y) 1)", &mut c));
+        let plain = eval_source("(macroexpand '(+ y 1))", &mut c);
+        assert_eq!(plain, eval_source("'(+ y 1)", &mut c));
+    }
+
+    #[test]
+    fn string_builtins() {
+        let mut c = Context::new();
+        let result = eval_source("(string-append (string-upcase \"hello\") \", \" (number->string (+ 1 2)))", &mut c);
+        assert_eq!(result, AstNode::String(String::from("HELLO, 3")));
+    }
+
+    #[test]
+    fn char_literals() {
+        let mut c = Context::new();
+        let result = eval_source("(list->string (list (string-ref \"abc\" 2) #\\space #\\x41))", &mut c);
+        assert_eq!(result, AstNode::String(String::from("c A")));
+    }
+
+    #[test]
+    fn vector_literal() {
+        let mut c = Context::new();
+        let result = eval_source("(define v #(1 2 3)) (vector-set! v 0 (+ (vector-ref v 1) (vector-ref v 2))) (vector->list v)", &mut c);
+        assert_eq!(result, number_list(&[5.0, 2.0, 3.0]));
+    }
+
+    #[test]
+    fn vector_map_lambda() {
+        let mut c = Context::new();
+        let result = eval_source("(vector->list (vector-map (lambda (x) (* x x)) (list->vector '(1 2 3))))", &mut c);
+        assert_eq!(result, number_list(&[1.0, 4.0, 9.0]));
+    }
+
+    #[test]
+    fn errors_restore_namespace() {
+        let mut c = Context::new();
+        let mut tokens = lexer::parse("((lambda (x) (vector-ref #(1) x)) 4)").into_iter().peekable();
+        let mut ast = parser::parse(&mut tokens);
+        match eval(&mut ast, &mut c) {
+            Err(Error::OutOfBounds(_)) => {},
+            other => panic!("Expected out of bounds error: {:?}", other)
+        }
+        assert_eq!(None, c.get_define(Symbol::intern("x").unwrap()));
+    }
+
+    #[test]
+    fn undefined_identifier_error() {
+        let mut c = Context::new();
+        let mut ast = AstNode::Identifier(Symbol::intern("nope").unwrap());
+        assert_eq!(eval(&mut ast, &mut c), Err(Error::Undefined(String::from("nope"))));
+    }
+
+    #[test]
+    fn hash_table_literal() {
+        let mut c = Context::new();
+        let result = eval_source("(define h {\"a\" 1 (x y) 2}) (hash-set! h #\\c 3)
+                                  (+ (hash-ref h \"a\") (hash-ref h '(x y)) (hash-ref h #\\c) (hash-count h))", &mut c);
+        assert_eq!(result, AstNode::Number(9.0));
+    }
+
+    #[test]
+    fn equal() {
+        let mut c = Context::new();
+        assert_eq!(eval_source("(equal? '(1 #(2 \"a\")) (list 1 (vector 2 \"a\")))", &mut c), AstNode::Bool(true));
+        assert_eq!(eval_source("(equal? {1 2} {1 3})", &mut c), AstNode::Bool(false));
+        // Values holding themselves
+        assert_eq!(eval_source("(define v (vector 0)) (define w (vector 0)) (vector-set! v 0 v) (vector-set! w 0 w)
+          (equal? v w)", &mut c), AstNode::Bool(true));
+        assert_eq!(eval_source("(define h (make-hash-table)) (hash-set! h h 1) (hash-set! h v 2)
+          (list (hash-ref h h) (hash-ref h w) (hash-count h))", &mut c),
+                   AstNode::Expression(vec![Box::new(AstNode::Number(1.0)), Box::new(AstNode::Number(2.0)),
+                                            Box::new(AstNode::Number(2.0))]));
+    }
+
+    #[test]
+    fn eq() {
+        let mut c = Context::new();
+        assert_eq!(eval_source("(eq? 'abc (string->symbol \"abc\"))", &mut c), AstNode::Bool(true));
+        assert_eq!(eval_source("(eq? 'abc 'abd)", &mut c), AstNode::Bool(false));
+        assert_eq!(eval_source("(define v #(1)) (eq? v v)", &mut c), AstNode::Bool(true));
+        assert_eq!(eval_source("(eq? v #(1))", &mut c), AstNode::Bool(false));
+        assert_eq!(eval_source("(define f (lambda (x) x)) (eq? f f)", &mut c), AstNode::Bool(true));
+        assert_eq!(eval_source("(eq? '() (list))", &mut c), AstNode::Bool(true));
+        assert_eq!(eval_source("(eq? '(1) '(1))", &mut c), AstNode::Bool(false));
+        assert_eq!(eval_source("(eq? 2 (+ 1 1))", &mut c), AstNode::Bool(true));
+    }
+
+    #[test]
+    fn comparisons() {
+        let mut c = Context::new();
+        assert_eq!(eval_source("(< 1 2 3)", &mut c), AstNode::Bool(true));
+        assert_eq!(eval_source("(< 1 3 2)", &mut c), AstNode::Bool(false));
+        assert_eq!(eval_source("(>= 3 3 1)", &mut c), AstNode::Bool(true));
+        assert_eq!(eval_source("(= 2 2)", &mut c), AstNode::Bool(true));
+        assert_eq!(eval_source("(< \"abc\" \"abd\")", &mut c), AstNode::Bool(true));
+        assert_eq!(eval_source("(> #\\b #\\a)", &mut c), AstNode::Bool(true));
+    }
+
+    #[test]
+    fn higher_order_with_lambdas() {
+        let mut c = Context::new();
+        let result = eval_source("(define n 10)
+                                  (map (lambda (x y) (+ x y n)) '(1 2 3) '(4 5 6))", &mut c);
+        assert_eq!(result, number_list(&[15.0, 17.0, 19.0]));
+        let result = eval_source("(filter (lambda (x) (> x 1)) '(3 1 2))", &mut c);
+        assert_eq!(result, number_list(&[3.0, 2.0]));
+        let result = eval_source("(sort '(\"pear\" \"fig\" \"apple\")
+                                        (lambda (a b) (< (string-length a) (string-length b))))", &mut c);
+        assert_eq!(result, eval_source("'(\"fig\" \"pear\" \"apple\")", &mut c));
+        let result = eval_source("(fold-left (lambda (acc x) (cons x acc)) '() '(1 2 3))", &mut c);
+        assert_eq!(result, number_list(&[3.0, 2.0, 1.0]));
+    }
+
+    #[test]
+    fn resolved_bodies_of_freed_lambdas_are_forgotten() {
+        let mut c = Context::new();
+        // Each expansion is a new lambda, freed once it has been called
+        eval_source("(defmacro fresh () '((lambda (x) x) 1))", &mut c);
+        for _ in 0..1000 {
+            assert_eq!(eval_source("(fresh)", &mut c), AstNode::Number(1.0));
+        }
+        assert!(c.bodies.len() < 100);
+        // Bodies still in use are resolved once
+        eval_source("(define id (lambda (x) x)) (id 1) (id 2)", &mut c);
+        let body = match c.lookup(Symbol::intern("id").unwrap()) {
+            Some(&AstNode::Lambda(_, ref body)) => body.clone(),
+            other => panic!("Expected a lambda: {:?}", other)
+        };
+        assert!(c.bodies.get(&body).is_some());
+    }
 }
@@ -0,0 +1,97 @@
+/* frames.rs
+ *
+ * The stack of calls being evaluated.  When an error unwinds through a
+ * call, the stack at that point is kept as the error's backtrace
+ */
+use lexer::Span;
+use parser::AstNode;
+use symbols::Symbol;
+use std::fmt;
+
+/* A call of function, from span, on the evaluated arguments.
+ * Calls of anonymous lambdas are named lambda
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub function: Symbol,
+    pub span: Option<Span>,
+    pub args: Vec<AstNode>,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (*self).span {
+            Some(ref span) => write!(f, "at {} ({})", (*self).function, span),
+            None => write!(f, "at {}", (*self).function)
+        }
+    }
+}
+
+/* The frames innermost first, one per line */
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Backtrace {
+    pub frames: Vec<Frame>,
+}
+
+impl Backtrace {
+    /* Takes a stack ordered outermost first, as it is built */
+    pub fn capture(stack: &[Frame]) -> Backtrace {
+        return Backtrace{frames: stack.iter().rev().cloned().collect()};
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, frame) in (*self).frames.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", frame)?;
+        }
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use error::Error;
+    use interpreter::Interpreter;
+    use parser::AstNode;
+
+    const FACT: &str = "(define fact (lambda (n)
+  (if (= n 0)
+    (car n)
+    (* n (fact (- n 1))))))";
+
+    #[test]
+    fn backtrace_of_nested_calls() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_source(FACT, "fact.wsp").unwrap();
+        assert!(interpreter.eval_source("\n(fact 2)", "main.wsp").is_err());
+        let backtrace = interpreter.backtrace().unwrap();
+        assert_eq!(format!("{}", backtrace), "at car (fact.wsp:3:5)
+at fact (fact.wsp:4:10)
+at fact (fact.wsp:4:10)
+at fact (main.wsp:2:1)");
+        let args: Vec<Vec<AstNode>> = backtrace.frames.iter().map(|frame| frame.args.clone()).collect();
+        assert_eq!(args, vec![vec![AstNode::Number(0.0)], vec![AstNode::Number(0.0)], vec![AstNode::Number(1.0)],
+                              vec![AstNode::Number(2.0)]]);
+    }
+
+    #[test]
+    fn backtraces_are_per_evaluation() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str(FACT).unwrap();
+        assert_eq!(interpreter.eval_str("(fact 3)"), Err(Error::Type(String::from("car expects a list: Number(0.0)"))));
+        assert_eq!(interpreter.backtrace().map(|b| b.frames.len()), Some(5));
+        assert_eq!(interpreter.eval_str("(+ 1 2)"), Ok(AstNode::Number(3.0)));
+        assert_eq!(interpreter.backtrace(), None);
+        // Caught errors leave no backtrace behind
+        interpreter.eval_str("(guard (e (else 0)) (fact 1))").unwrap();
+        assert_eq!(interpreter.backtrace(), None);
+        assert!(interpreter.eval_str("((lambda (x) (car x)) 1)").is_err());
+        let backtrace = interpreter.backtrace().unwrap();
+        assert_eq!(backtrace.frames.len(), 2);
+        assert_eq!(format!("{}", backtrace.frames[1]), "at lambda (1:1)");
+    }
+}
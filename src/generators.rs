@@ -0,0 +1,455 @@
+/* generators.rs
+ *
+ * Generators produce their values one at a time, as they are asked for.
+ * (define-generator (name params...) body) defines a function returning a
+ * new generator.  Each call to next runs the generator's body until it
+ * yields a value, and the following call carries on from the yield.
+ * yield can be called anywhere within the body's calls, such as from a
+ * function the body passes to for-each.
+ *
+ * Each generator is a coroutine with a stack of its own, so yielding
+ * suspends all of the calls between the yield and the body.  While it is
+ * suspended, the namespaces, handlers and frames of those calls are kept
+ * out of the context, and the caller of next carries on as if they had
+ * returned.
+ *
+ * The suspended calls refer to the context the generator was started in,
+ * so only that context can resume it.  Dropping the context finishes the
+ * generators it started, which can't be resumed after that
+ */
+use corosensei::stack::{DefaultStack, Stack};
+use corosensei::{Coroutine, CoroutineResult, Yielder};
+use error::Error;
+use eval;
+use eval::{Context, Marks, Suspended};
+use parser::AstNode;
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::mem;
+use std::ptr;
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+
+/* Room for the call depth limit's worth of calls.  Pages are only allocated once they are used */
+const STACK_SIZE: usize = 64 * 1024 * 1024;
+
+/* Resumed with nothing, yields a value along with the calls it suspended, returns the body's result */
+type Body = Coroutine<(), (AstNode, Suspended), Result<AstNode, Error>, DefaultStack>;
+type BodyYielder = Yielder<(), (AstNode, Suspended)>;
+
+/* A generator waiting at a yield */
+struct Paused {
+    body: Body,
+    calls: Suspended,
+    yielder: *const BodyYielder, // on the body's stack, so it stays put
+    limit: usize, // the lowest address of the body's stack
+}
+
+enum State {
+    Ready(AstNode, Vec<AstNode>), // Ready(function, arguments), yet to be started
+    Paused(Paused),
+    Running,
+    Finished,
+}
+
+pub struct Generator {
+    state: RefCell<State>,
+    owner: Cell<u64>, // the id of the context it was started in, or 0 before it starts
+    context: Cell<*const Context>, // the context it was started in, which its suspended calls refer to
+}
+
+impl fmt::Debug for Generator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#<generator>")
+    }
+}
+
+/* Each generator is only equal to itself */
+impl PartialEq for Generator {
+    fn eq(&self, other: &Generator) -> bool {
+        return ptr::eq(self, other);
+    }
+}
+
+/* Generators have no natural order, they are only comparable when equal */
+impl PartialOrd for Generator {
+    fn partial_cmp(&self, other: &Generator) -> Option<Ordering> {
+        if *self == *other {
+            return Some(Ordering::Equal);
+        }
+
+        return None;
+    }
+}
+
+static NEXT_OWNER: AtomicU64 = AtomicU64::new(1);
+
+/* The generators a context has started.  Each context has an id of its own, never
+ * reused even once the context is freed, which its generators check when resumed
+ */
+pub(crate) struct Owner {
+    id: u64,
+    started: Vec<Weak<Generator>>,
+}
+
+impl Owner {
+    pub(crate) fn new() -> Owner {
+        return Owner{id: NEXT_OWNER.fetch_add(1, AtomicOrdering::Relaxed), started: Vec::new()};
+    }
+    /* Note a generator started in the context, returning the context's id */
+    fn adopt(&mut self, generator: &Rc<Generator>) -> u64 {
+        // Forget freed generators rather than grow
+        if (*self).started.len() == (*self).started.capacity() {
+            (*self).started.retain(|started| started.strong_count() > 0);
+        }
+        (*self).started.push(Rc::downgrade(generator));
+        return (*self).id;
+    }
+}
+
+/* A copy of a context is a context of its own, which hasn't started any generators */
+impl Clone for Owner {
+    fn clone(&self) -> Owner {
+        return Owner::new();
+    }
+}
+
+impl fmt::Debug for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Owner({})", (*self).id)
+    }
+}
+
+/* The generators' suspended calls refer to the context being dropped, so they are finished */
+impl Drop for Owner {
+    fn drop(&mut self) -> () {
+        for generator in (*self).started.iter().filter_map(|started| started.upgrade()) {
+            if let Ok(mut state) = generator.state.try_borrow_mut() {
+                let finished = mem::replace(&mut *state, State::Finished);
+                drop(state);
+                drop(finished);
+            }
+        }
+    }
+}
+
+/* A generator being resumed, innermost last */
+struct Running {
+    yielder: *const BodyYielder,
+    marks: Marks,
+    limit: usize,
+}
+
+thread_local! {
+    static RUNNING: RefCell<Vec<Running>> = const { RefCell::new(Vec::new()) };
+    // The stack limit of the innermost running generator, or 0 outside of generators
+    static STACK_LIMIT: Cell<usize> = const { Cell::new(0) };
+}
+
+fn push_running(running: Running) -> () {
+    STACK_LIMIT.with(|limit| limit.set(running.limit));
+    RUNNING.with(|stack| stack.borrow_mut().push(running));
+}
+
+fn pop_running() -> Running {
+    let running = RUNNING.with(|stack| stack.borrow_mut().pop()).expect("no generator running");
+    let limit = RUNNING.with(|stack| stack.borrow().last().map_or(0, |outer| outer.limit));
+    STACK_LIMIT.with(|cell| cell.set(limit));
+    return running;
+}
+
+/* How much of its stack the running generator has left, if one is running */
+pub fn remaining_stack() -> Option<usize> {
+    let limit = STACK_LIMIT.with(|limit| limit.get());
+    if limit == 0 {
+        return None;
+    }
+    let marker = 0u8;
+    return Some((&marker as *const u8 as usize).saturating_sub(limit));
+}
+
+pub fn new_generator(function: AstNode, args: Vec<AstNode>) -> AstNode {
+    return AstNode::Generator(Rc::new(Generator{state: RefCell::new(State::Ready(function, args)),
+                                                owner: Cell::new(0), context: Cell::new(ptr::null())}));
+}
+
+fn start(function: AstNode, args: Vec<AstNode>, context: &mut Context) -> Result<Paused, Error> {
+    let args: Vec<_> = args.into_iter().map(Box::new).collect();
+    let stack = DefaultStack::new(STACK_SIZE)
+        .map_err(|error| Error::ResourceExhausted(format!("Couldn't allocate a generator stack: {}", error)))?;
+    let limit = stack.limit().get();
+    let context: *mut Context = context;
+    let body = Coroutine::with_stack(stack, move |yielder: &BodyYielder, ()| {
+        RUNNING.with(|stack| stack.borrow_mut().last_mut().expect("no generator running").yielder = yielder);
+        // The body only runs while next is resuming it, and next checks it has the
+        // context the body started with and leaves the context to the body until it
+        // yields or returns.  The body is finished when that context is dropped, so
+        // never outlives it.  So this is the only reference to it in use
+        let context = unsafe { &mut *context };
+        eval::apply(&function, &args, context)
+    });
+    return Ok(Paused{body, calls: Suspended::default(), yielder: ptr::null(), limit});
+}
+
+/* Run the generator to its next yield, returning the value yielded, or None once its body has returned */
+pub fn next(generator: &Rc<Generator>, context: &mut Context) -> Result<Option<AstNode>, Error> {
+    let state = mem::replace(&mut *generator.state.borrow_mut(), State::Running);
+    let (mut paused, marks) = match state {
+        State::Ready(function, args) => {
+            generator.owner.set(context.generator_owner().adopt(generator));
+            generator.context.set(context);
+            let marks = context.marks();
+            match start(function, args, context) {
+                Ok(paused) => (paused, marks),
+                Err(error) => {
+                    *generator.state.borrow_mut() = State::Finished;
+                    return Err(error);
+                }
+            }
+        },
+        State::Paused(mut paused) => {
+            if generator.owner.get() != context.generator_owner().id {
+                *generator.state.borrow_mut() = State::Paused(paused);
+                return Err(Error::Type(String::from("Generator resumed by a different interpreter than started it")));
+            }
+            if !ptr::eq(generator.context.get(), context) {
+                *generator.state.borrow_mut() = State::Paused(paused);
+                return Err(Error::Type(String::from("Generator resumed after its context moved")));
+            }
+            let marks = context.resume(mem::take(&mut paused.calls));
+            (paused, marks)
+        },
+        State::Running => return Err(Error::Type(String::from("Generator is already running"))),
+        State::Finished => {
+            *generator.state.borrow_mut() = State::Finished;
+            return Ok(None);
+        }
+    };
+    push_running(Running{yielder: paused.yielder, marks, limit: paused.limit});
+    let result = paused.body.resume(());
+    let running = pop_running();
+    match result {
+        CoroutineResult::Yield((value, calls)) => {
+            *generator.state.borrow_mut() = State::Paused(Paused{calls, yielder: running.yielder, ..paused});
+            return Ok(Some(value));
+        },
+        CoroutineResult::Return(result) => {
+            *generator.state.borrow_mut() = State::Finished;
+            return result.map(|_| None);
+        }
+    }
+}
+
+/* The rest of the generator's values */
+pub fn drain(generator: &Rc<Generator>, context: &mut Context) -> Result<Vec<Box<AstNode>>, Error> {
+    let mut values = Vec::new();
+    while let Some(value) = next(generator, context)? {
+        values.push(Box::new(value));
+    }
+
+    return Ok(values);
+}
+
+/* Suspend the running generator, giving the value to its caller.  Returns the value once resumed */
+fn yield_value(value: AstNode, context: &mut Context) -> Result<AstNode, Error> {
+    let running = RUNNING.with(|stack| stack.borrow().last().map(|running| (running.yielder, running.marks.clone())));
+    let (yielder, marks) = match running {
+        Some(running) => running,
+        None => return Err(Error::Syntax(String::from("yield outside of a generator")))
+    };
+    let calls = context.suspend(marks);
+    // The yielder is on the stack of the running generator, which this is called from
+    unsafe { &*yielder }.suspend((value.clone(), calls));
+    return Ok(value);
+}
+
+pub fn is_builtin(ident: &str) -> bool {
+    lazy_static! {
+        static ref BUILTINS: BTreeSet<&'static str> = ["make-generator", "yield", "next", "generator?"]
+            .iter().cloned().collect();
+    }
+    return BUILTINS.contains(ident);
+}
+
+fn check_arity(name: &str, args: &[Box<AstNode>], min: usize, max: usize) -> Result<(), Error> {
+    if args.len() < min || args.len() > max {
+        return Err(Error::Arity(format!("{} expects {} to {} arguments but got {}", name, min, max, args.len())));
+    }
+
+    return Ok(());
+}
+
+fn generator_arg<'a>(name: &str, arg: &'a AstNode) -> Result<&'a Rc<Generator>, Error> {
+    match *arg {
+        AstNode::Generator(ref generator) => Ok(generator),
+        ref other => Err(Error::Type(format!("{} expects a generator: {:?}", name, other)))
+    }
+}
+
+pub fn apply(ident: &str, args: &[Box<AstNode>], context: &mut Context) -> Result<AstNode, Error> {
+    match ident {
+        // (make-generator f args...) runs (f args...) as the generator's body
+        "make-generator" => match args.split_first() {
+            Some((function, args)) => {
+                Ok(new_generator((**function).clone(), args.iter().map(|arg| (**arg).clone()).collect()))
+            },
+            None => Err(Error::Arity(format!("{} expects a function", ident)))
+        },
+        "yield" => {
+            check_arity(ident, args, 0, 1)?;
+            yield_value(args.first().map_or(AstNode::Expression(vec![]), |value| (**value).clone()), context)
+        },
+        // (next g default) returns default once g is finished, rather than failing
+        "next" => {
+            check_arity(ident, args, 1, 2)?;
+            match next(generator_arg(ident, &args[0])?, context)? {
+                Some(value) => Ok(value),
+                None => match args.get(1) {
+                    Some(default) => Ok((**default).clone()),
+                    None => Err(Error::OutOfBounds(String::from("next of finished generator")))
+                }
+            }
+        },
+        "generator?" => {
+            check_arity(ident, args, 1, 1)?;
+            Ok(AstNode::Bool(matches!(*args[0], AstNode::Generator(_))))
+        },
+        _ => Err(Error::Type(format!("Invalid operator: {:?}", ident)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use error::Error;
+    use interpreter::Interpreter;
+    use limits::Limits;
+    use parser::AstNode;
+    use symbols::Symbol;
+    use vm::Backend;
+
+    const RANGE: &str = "(define count-up (lambda (i n) (if (< i n) (count-up (+ (yield i) 1) n) 'done)))
+(define-generator (range n) (count-up 0 n))
+(define count-from (lambda (i) (count-from (+ (yield i) 1))))
+(define-generator (naturals) (count-from 0))";
+
+    fn numbers(xs: &[f64]) -> AstNode {
+        return AstNode::Expression(xs.iter().map(|&x| Box::new(AstNode::Number(x))).collect());
+    }
+
+    fn eval(source: &str) -> Result<AstNode, Error> {
+        let results: Vec<_> = Backend::all().iter().map(|&backend| {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_backend(backend);
+            interpreter.eval_str(RANGE).unwrap();
+            interpreter.eval_str(source)
+        }).collect();
+        assert_eq!(results[0], results[1], "backends disagree on {}", source);
+        return results[0].clone();
+    }
+
+    #[test]
+    fn yields_in_turn() {
+        assert_eq!(eval("(define g (range 2)) (list (next g) (next g) (next g 'end) (next g 'end))"),
+                   Ok(AstNode::Expression(vec![Box::new(AstNode::Number(0.0)), Box::new(AstNode::Number(1.0)),
+                                               Box::new(AstNode::Identifier(Symbol::intern("end").unwrap())),
+                                               Box::new(AstNode::Identifier(Symbol::intern("end").unwrap()))])));
+        assert!(eval("(define g (range 0)) (next g)").is_err());
+        assert_eq!(eval("(generator? (range 1))"), Ok(AstNode::Bool(true)));
+        // Each call makes a generator of its own
+        assert_eq!(eval("(define a (range 3)) (define b (range 3)) (next a) (list (next a) (next b))"),
+                   Ok(numbers(&[1.0, 0.0])));
+    }
+
+    #[test]
+    fn list_builtins() {
+        assert_eq!(eval("(map (lambda (x) (* x x)) (range 4))"), Ok(numbers(&[0.0, 1.0, 4.0, 9.0])));
+        assert_eq!(eval("(filter (lambda (x) (> x 1)) (range 4))"), Ok(numbers(&[2.0, 3.0])));
+        assert_eq!(eval("(fold-left + 0 (range 5))"), Ok(AstNode::Number(10.0)));
+        // An infinite generator zipped with a list stops at the end of the list
+        assert_eq!(eval("(map list '(a b) (naturals))").map(|x| format!("{:?}", x)),
+                   Ok(String::from("Expression([Expression([Identifier(\"a\"), Number(0.0)]), Expression([Identifier(\"b\"), Number(1.0)])])")));
+        assert_eq!(eval("(call/cc (lambda (k) (for-each (lambda (x) (if (> x 100) (k x) x)) (naturals))))"), Ok(AstNode::Number(101.0)));
+    }
+
+    #[test]
+    fn yield_from_nested_calls() {
+        // The yield is in a function for-each calls, within the generator's body
+        assert_eq!(eval("(define-generator (each xs) (for-each (lambda (x) (yield (* 10 x))) xs))
+          (map (lambda (x) x) (each '(1 2 3)))"), Ok(numbers(&[10.0, 20.0, 30.0])));
+        // A generator consuming another
+        assert_eq!(eval("(define-generator (doubled g) (for-each (lambda (x) (yield (* 2 x))) g))
+          (fold-left + 0 (doubled (range 4)))"), Ok(AstNode::Number(12.0)));
+        assert!(eval("(yield 1)").is_err());
+    }
+
+    #[test]
+    fn scopes_are_kept_apart() {
+        // The generator's parameter n isn't visible to the caller between yields
+        assert_eq!(eval("(define n 'outer) (define g (range 5)) (next g) (next g) n"),
+                   Ok(AstNode::Identifier(Symbol::intern("outer").unwrap())));
+        // Errors in the body reach the caller of next, after which the generator is finished
+        assert_eq!(eval("(define-generator (bad) (car (list (yield 1) (car 5)))) (define g (bad)) (next g)"),
+                   Ok(AstNode::Number(1.0)));
+        assert!(eval("(define-generator (bad) (car (list (yield 1) (car 5)))) (define g (bad)) (next g) (next g)").is_err());
+        assert_eq!(eval("(define-generator (bad) (car (list (yield 1) (car 5)))) (define g (bad)) (next g)
+          (guard (e (else 'caught)) (next g)) (next g 'finished)"), Ok(AstNode::Identifier(Symbol::intern("finished").unwrap())));
+    }
+
+    #[test]
+    fn deep_calls() {
+        // Close to the call depth limit, on the generator's own stack
+        assert_eq!(eval("(define deep (lambda (n) (if (= n 0) (yield 'bottom) (deep (- n 1)))))
+          (define-generator (dig n) (deep n)) (next (dig 990))"), Ok(AstNode::Identifier(Symbol::intern("bottom").unwrap())));
+        // Without a depth limit, running out of the generator's stack is an error rather than a crash
+        let mut interpreter = Interpreter::new();
+        interpreter.set_limits(Limits::unlimited());
+        interpreter.eval_str("(define forever (lambda (n) (+ 1 (forever n)))) (define-generator (dig) (forever 0))").unwrap();
+        match interpreter.eval_str("(next (dig))") {
+            Err(Error::ResourceExhausted(_)) => {},
+            other => panic!("Expected the stack to run out: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn interpreter_can_move() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str(RANGE).unwrap();
+        interpreter.eval_str("(define g (range 3)) (next g)").unwrap();
+        let mut moved = Box::new(interpreter);
+        assert_eq!(moved.eval_str("(next g)").unwrap(), AstNode::Number(1.0));
+        // Another interpreter can't resume calls made in this one's context
+        let mut other = Interpreter::new();
+        let g = moved.get_global("g").unwrap();
+        other.set_global("g", g).unwrap();
+        assert!(other.eval_str("(next g)").is_err());
+        assert_eq!(moved.eval_str("(next g)").unwrap(), AstNode::Number(2.0));
+    }
+
+    #[test]
+    fn dropped_interpreters_finish_their_generators() {
+        let mut first = Interpreter::new();
+        first.eval_str(RANGE).unwrap();
+        first.eval_str("(define g (range 3)) (next g)").unwrap();
+        let g = first.get_global("g").unwrap();
+        drop(first);
+        // However the second interpreter's context is allocated, g never runs again
+        let mut second = Interpreter::new();
+        second.set_global("g", g).unwrap();
+        match second.eval_str("(next g)") {
+            Err(Error::OutOfBounds(message)) => assert_eq!(message, "next of finished generator"),
+            other => panic!("Expected a finished generator: {:?}", other)
+        }
+        assert_eq!(second.eval_str("(next g 'done)").unwrap(), AstNode::Identifier(Symbol::intern("done").unwrap()));
+    }
+
+    #[test]
+    fn abandoned_generators_are_freed() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str(RANGE).unwrap();
+        for _ in 0..200 {
+            assert_eq!(interpreter.eval_str("(define g (range 10)) (next g) (next g)").unwrap(), AstNode::Number(1.0));
+        }
+    }
+}
@@ -0,0 +1,422 @@
+/* hashtables.rs
+ *
+ * Hash tables keyed by any value, where keys match if they are equal? to each other.
+ * Like vectors, hash tables are mutable and shared between copies
+ */
+use error::Error;
+use eval::Context;
+use heap;
+use parser::AstNode;
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::rc::Rc;
+
+/* Entries are kept in a Vec so iteration order is deterministic (insertion order, until a removal) */
+#[derive(Clone, Default)]
+pub struct HashTable {
+    entries: Vec<(AstNode, AstNode)>,
+    hashes: Vec<u64>, // the hash of each entry's key when it was inserted, which indexes it
+    index: HashMap<u64, Vec<usize>>,
+}
+
+/* Hash a value consistently with equal.  Only the lengths of the vectors inside it are
+ * hashed, as they may hold themselves, or each other many times over
+ */
+fn hash_value<H: Hasher>(value: &AstNode, state: &mut H, nested: bool) {
+    mem::discriminant(value).hash(state);
+    match *value {
+        AstNode::Expression(ref items) => {
+            for item in items.iter() {
+                hash_value(item, state, nested);
+            }
+        },
+        AstNode::Vector(ref elements) if nested => elements.borrow().len().hash(state),
+        AstNode::Vector(ref elements) => {
+            for element in elements.borrow().iter() {
+                hash_value(element, state, true);
+            }
+        },
+        // 0.0 and -0.0 are equal so must hash the same
+        AstNode::Number(x) => (if x == 0.0 { 0.0f64 } else { x }).to_bits().hash(state),
+        AstNode::Bool(b) => b.hash(state),
+        AstNode::Char(c) => c.hash(state),
+        AstNode::String(ref s) => s.hash(state),
+        AstNode::Identifier(ref s) => s.hash(state),
+        // Equal tables may have different entry orders, and a table may be a key in itself,
+        // changing its size, so nothing more than that it is a table is hashed
+        AstNode::HashTable(_) => {},
+        _ => {}
+    }
+}
+
+fn hash_key(key: &AstNode) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hash_value(key, &mut hasher, false);
+    return hasher.finish();
+}
+
+/* Whether two values are equal?, as AstNode's PartialEq finds, but ending for vectors
+ * and tables that hold themselves.  A shared value is equal to itself, and a pair of
+ * them already being compared further out is taken to be equal
+ */
+pub fn equal(a: &AstNode, b: &AstNode) -> bool {
+    return equal_in(a, b, &mut Vec::new());
+}
+
+fn equal_in(a: &AstNode, b: &AstNode, comparing: &mut Vec<(usize, usize)>) -> bool {
+    let pair = match (a, b) {
+        (&AstNode::Vector(ref a), &AstNode::Vector(ref b)) if Rc::ptr_eq(a, b) => return true,
+        (&AstNode::HashTable(ref a), &AstNode::HashTable(ref b)) if Rc::ptr_eq(a, b) => return true,
+        (&AstNode::Vector(ref a), &AstNode::Vector(ref b)) => (Rc::as_ptr(a) as usize, Rc::as_ptr(b) as usize),
+        (&AstNode::HashTable(ref a), &AstNode::HashTable(ref b)) => (Rc::as_ptr(a) as usize, Rc::as_ptr(b) as usize),
+        (&AstNode::Expression(ref a), &AstNode::Expression(ref b)) => {
+            return a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| equal_in(a, b, comparing));
+        },
+        _ => return *a == *b
+    };
+    if comparing.contains(&pair) {
+        return true;
+    }
+    comparing.push(pair);
+    let result = match (a, b) {
+        (&AstNode::Vector(ref a), &AstNode::Vector(ref b)) => {
+            let (a, b) = (a.borrow(), b.borrow());
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| equal_in(a, b, comparing))
+        },
+        (&AstNode::HashTable(ref a), &AstNode::HashTable(ref b)) => {
+            let (a, b) = (a.borrow(), b.borrow());
+            a.len() == b.len() && a.entries.iter().all(|&(ref key, ref value)| {
+                match b.position_by(key, hash_key(key), |a, b| equal_in(a, b, comparing)) {
+                    Some(i) => equal_in(value, &b.entries[i].1, comparing),
+                    None => false
+                }
+            })
+        },
+        _ => unreachable!()
+    };
+    comparing.pop();
+    return result;
+}
+
+impl HashTable {
+    pub fn new() -> HashTable {
+        return HashTable{entries: Vec::new(), hashes: Vec::new(), index: HashMap::new()};
+    }
+    fn position_by<F>(&self, key: &AstNode, hash: u64, mut equal: F) -> Option<usize>
+        where F: FnMut(&AstNode, &AstNode) -> bool {
+        if let Some(bucket) = (*self).index.get(&hash) {
+            return bucket.iter().cloned().find(|&i| equal(&(*self).entries[i].0, key));
+        }
+
+        return None;
+    }
+    fn position(&self, key: &AstNode, hash: u64) -> Option<usize> {
+        return (*self).position_by(key, hash, equal);
+    }
+    pub fn get(&self, key: &AstNode) -> Option<&AstNode> {
+        return (*self).position(key, hash_key(key)).map(|i| &(*self).entries[i].1);
+    }
+    pub fn insert(&mut self, key: AstNode, value: AstNode) -> Option<AstNode> {
+        let hash = hash_key(&key);
+        let i = (*self).position(&key, hash);
+        return (*self).insert_at(i, hash, key, value);
+    }
+    /* Insert at the position already found for the key, if it has one */
+    fn insert_at(&mut self, i: Option<usize>, hash: u64, key: AstNode, value: AstNode) -> Option<AstNode> {
+        if let Some(i) = i {
+            return Some(mem::replace(&mut (*self).entries[i].1, value));
+        }
+        let i = (*self).entries.len();
+        (*self).index.entry(hash).or_default().push(i);
+        (*self).entries.push((key, value));
+        (*self).hashes.push(hash);
+        return None;
+    }
+    pub fn remove(&mut self, key: &AstNode) -> Option<AstNode> {
+        let i = (*self).position(key, hash_key(key))?;
+        return Some((*self).remove_at(i));
+    }
+    /* Keys may have changed since they were inserted, so entries are unindexed by the hashes they were indexed by */
+    fn remove_at(&mut self, i: usize) -> AstNode {
+        let hash = (*self).hashes[i];
+        (*self).index.get_mut(&hash).unwrap().retain(|&x| x != i);
+        if (*self).index[&hash].is_empty() {
+            (*self).index.remove(&hash);
+        }
+        let last = (*self).entries.len() - 1;
+        let (_, value) = (*self).entries.swap_remove(i);
+        (*self).hashes.swap_remove(i);
+        // The last entry moved into the hole, so repoint its index
+        if i != last {
+            let moved = (*self).hashes[i];
+            for x in (*self).index.get_mut(&moved).unwrap().iter_mut() {
+                if *x == last {
+                    *x = i;
+                }
+            }
+        }
+        return value;
+    }
+    pub fn len(&self) -> usize {
+        return (*self).entries.len();
+    }
+    pub fn is_empty(&self) -> bool {
+        return (*self).entries.is_empty();
+    }
+    pub fn entries(&self) -> &[(AstNode, AstNode)] {
+        return &(*self).entries;
+    }
+    pub fn into_entries(self) -> Vec<(AstNode, AstNode)> {
+        return self.entries;
+    }
+}
+
+impl fmt::Debug for HashTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries((*self).entries.iter().map(|&(ref k, ref v)| (k, v))).finish()
+    }
+}
+
+/* Tables are equal if they hold equal keys mapped to equal values, regardless of order */
+impl PartialEq for HashTable {
+    fn eq(&self, other: &HashTable) -> bool {
+        return (*self).len() == other.len() &&
+            (*self).entries.iter().all(|&(ref k, ref v)| other.get(k).is_some_and(|other| equal(v, other)));
+    }
+}
+
+/* Tables have no natural order, they are only comparable when equal */
+impl PartialOrd for HashTable {
+    fn partial_cmp(&self, other: &HashTable) -> Option<Ordering> {
+        if *self == *other {
+            return Some(Ordering::Equal);
+        }
+
+        return None;
+    }
+}
+
+pub fn new_hash_table(table: HashTable) -> AstNode {
+    let table = Rc::new(RefCell::new(table));
+    heap::track_table(&table);
+    return AstNode::HashTable(table);
+}
+
+pub fn is_builtin(ident: &str) -> bool {
+    lazy_static! {
+        static ref BUILTINS: BTreeSet<&'static str> = ["make-hash-table", "hash-ref", "hash-set!", "hash-remove!",
+            "hash-keys", "hash-values", "hash-count", "hash->alist"].iter().cloned().collect();
+    }
+    return BUILTINS.contains(ident);
+}
+
+fn check_arity(name: &str, args: &[Box<AstNode>], min: usize, max: usize) -> Result<(), Error> {
+    if args.len() < min || args.len() > max {
+        return Err(Error::Arity(format!("{} expects {} to {} arguments but got {}", name, min, max, args.len())));
+    }
+
+    return Ok(());
+}
+
+fn table_arg<'a>(name: &str, arg: &'a AstNode) -> Result<&'a Rc<RefCell<HashTable>>, Error> {
+    match *arg {
+        AstNode::HashTable(ref table) => Ok(table),
+        ref other => Err(Error::Type(format!("{} expects a hash table: {:?}", name, other)))
+    }
+}
+
+fn list(items: Vec<AstNode>) -> AstNode {
+    return AstNode::Expression(items.into_iter().map(Box::new).collect());
+}
+
+/* Apply the hash table builtin with the given name to the evaluated arguments */
+pub fn apply(ident: &str, args: &[Box<AstNode>], context: &mut Context) -> Result<AstNode, Error> {
+    match ident {
+        "make-hash-table" => {
+            check_arity(ident, args, 0, 0)?;
+            Ok(new_hash_table(HashTable::new()))
+        },
+        "hash-ref" => {
+            // Missing keys are an error unless a default is given
+            check_arity(ident, args, 2, 3)?;
+            match (table_arg(ident, &args[0])?.borrow().get(&args[1]), args.get(2)) {
+                (Some(value), _) => Ok(value.clone()),
+                (None, Some(default)) => Ok((**default).clone()),
+                (None, None) => Err(Error::OutOfBounds(format!("{} key not found: {:?}", ident, args[1])))
+            }
+        },
+        // The key may hold the table itself, so is found before the table is borrowed to change it
+        "hash-set!" => {
+            check_arity(ident, args, 3, 3)?;
+            context.check_element(&args[1])?;
+            context.check_element(&args[2])?;
+            let table = table_arg(ident, &args[0])?;
+            let hash = hash_key(&args[1]);
+            let i = table.borrow().position(&args[1], hash);
+            table.borrow_mut().insert_at(i, hash, (*args[1]).clone(), (*args[2]).clone());
+            Ok((*args[0]).clone())
+        },
+        "hash-remove!" => {
+            check_arity(ident, args, 2, 2)?;
+            let table = table_arg(ident, &args[0])?;
+            let i = table.borrow().position(&args[1], hash_key(&args[1]));
+            if let Some(i) = i {
+                table.borrow_mut().remove_at(i);
+            }
+            Ok((*args[0]).clone())
+        },
+        "hash-keys" => {
+            check_arity(ident, args, 1, 1)?;
+            Ok(list(table_arg(ident, &args[0])?.borrow().entries().iter().map(|e| e.0.clone()).collect()))
+        },
+        "hash-values" => {
+            check_arity(ident, args, 1, 1)?;
+            Ok(list(table_arg(ident, &args[0])?.borrow().entries().iter().map(|e| e.1.clone()).collect()))
+        },
+        "hash-count" => {
+            check_arity(ident, args, 1, 1)?;
+            Ok(AstNode::Number(table_arg(ident, &args[0])?.borrow().len() as f64))
+        },
+        "hash->alist" => {
+            // Each association is a (key value) list
+            check_arity(ident, args, 1, 1)?;
+            let table = table_arg(ident, &args[0])?.borrow();
+            Ok(list(table.entries().iter().map(|e| list(vec![e.0.clone(), e.1.clone()])).collect()))
+        },
+        _ => Err(Error::Undefined(String::from(ident)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use eval::Context;
+    use hashtables::{apply, equal, new_hash_table, HashTable};
+    use parser::AstNode;
+    use vectors::new_vector;
+
+    fn string(s: &str) -> AstNode {
+        AstNode::String(String::from(s))
+    }
+
+    #[test]
+    fn insert_get_remove() {
+        let mut table = HashTable::new();
+        assert_eq!(table.insert(string("a"), AstNode::Number(1.0)), None);
+        assert_eq!(table.insert(string("b"), AstNode::Number(2.0)), None);
+        assert_eq!(table.insert(string("c"), AstNode::Number(3.0)), None);
+        assert_eq!(table.insert(string("a"), AstNode::Number(4.0)), Some(AstNode::Number(1.0)));
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.remove(&string("a")), Some(AstNode::Number(4.0)));
+        assert_eq!(table.remove(&string("a")), None);
+        assert_eq!(table.get(&string("c")), Some(&AstNode::Number(3.0)));
+        assert_eq!(table.get(&string("b")), Some(&AstNode::Number(2.0)));
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn structural_keys() {
+        let mut table = HashTable::new();
+        let key = AstNode::Expression(vec![Box::new(AstNode::Number(1.0)), Box::new(string("x"))]);
+        table.insert(key.clone(), AstNode::Bool(true));
+        table.insert(new_vector(vec![AstNode::Char('a')]), AstNode::Bool(false));
+        table.insert(AstNode::Number(0.0), string("zero"));
+        assert_eq!(table.get(&key.clone()), Some(&AstNode::Bool(true)));
+        assert_eq!(table.get(&new_vector(vec![AstNode::Char('a')])), Some(&AstNode::Bool(false)));
+        assert_eq!(table.get(&AstNode::Number(-0.0)), Some(&string("zero")));
+        assert_eq!(table.get(&string("1")), None);
+    }
+
+    #[test]
+    fn equality_ignores_order() {
+        let mut a = HashTable::new();
+        let mut b = HashTable::new();
+        a.insert(AstNode::Number(1.0), string("x"));
+        a.insert(AstNode::Number(2.0), string("y"));
+        b.insert(AstNode::Number(2.0), string("y"));
+        b.insert(AstNode::Number(1.0), string("x"));
+        assert_eq!(a, b);
+        b.insert(AstNode::Number(1.0), string("z"));
+        assert!(a != b);
+    }
+
+    #[test]
+    fn builtins() {
+        let mut c = Context::new();
+        let table = Box::new(new_hash_table(HashTable::new()));
+        let key = Box::new(string("k"));
+        apply("hash-set!", &[table.clone(), key.clone(), Box::new(AstNode::Number(5.0))], &mut c).unwrap();
+        assert_eq!(apply("hash-ref", &[table.clone(), key.clone()], &mut c).unwrap(), AstNode::Number(5.0));
+        assert_eq!(apply("hash-count", ::std::slice::from_ref(&table), &mut c).unwrap(), AstNode::Number(1.0));
+        assert_eq!(apply("hash-keys", ::std::slice::from_ref(&table), &mut c).unwrap(), AstNode::Expression(vec![key.clone()]));
+        let alist = apply("hash->alist", ::std::slice::from_ref(&table), &mut c).unwrap();
+        assert_eq!(alist, AstNode::Expression(vec![Box::new(AstNode::Expression(vec![key.clone(),
+                                                                                    Box::new(AstNode::Number(5.0))]))]));
+        apply("hash-remove!", &[table.clone(), key.clone()], &mut c).unwrap();
+        assert!(apply("hash-ref", &[table.clone(), key.clone()], &mut c).is_err());
+        assert_eq!(apply("hash-ref", &[table, key, Box::new(AstNode::Bool(false))], &mut c).unwrap(), AstNode::Bool(false));
+    }
+
+    #[test]
+    fn tables_as_their_own_keys() {
+        let mut c = Context::new();
+        let table = Box::new(new_hash_table(HashTable::new()));
+        apply("hash-set!", &[table.clone(), table.clone(), Box::new(AstNode::Number(1.0))], &mut c).unwrap();
+        apply("hash-set!", &[table.clone(), table.clone(), Box::new(AstNode::Number(2.0))], &mut c).unwrap();
+        assert_eq!(apply("hash-ref", &[table.clone(), table.clone()], &mut c).unwrap(), AstNode::Number(2.0));
+        assert_eq!(apply("hash-count", ::std::slice::from_ref(&table), &mut c).unwrap(), AstNode::Number(1.0));
+        apply("hash-remove!", &[table.clone(), table.clone()], &mut c).unwrap();
+        assert_eq!(apply("hash-count", ::std::slice::from_ref(&table), &mut c).unwrap(), AstNode::Number(0.0));
+    }
+
+    #[test]
+    fn cyclic_values() {
+        let v = new_vector(vec![AstNode::Number(0.0)]);
+        let w = new_vector(vec![AstNode::Number(0.0), AstNode::Number(0.0)]);
+        if let (&AstNode::Vector(ref v_elements), &AstNode::Vector(ref w_elements)) = (&v, &w) {
+            v_elements.borrow_mut()[0] = v.clone();
+            w_elements.borrow_mut()[0] = w.clone();
+            w_elements.borrow_mut()[1] = v.clone();
+        }
+        assert!(equal(&v, &v.clone()));
+        assert!(!equal(&v, &w));
+        // Each holds itself, so they unfold the same however far they are followed
+        let u = new_vector(vec![AstNode::Number(0.0)]);
+        if let AstNode::Vector(ref u_elements) = u {
+            u_elements.borrow_mut()[0] = new_vector(vec![u.clone()]);
+        }
+        assert!(equal(&v, &u));
+        let mut table = HashTable::new();
+        table.insert(v.clone(), string("v"));
+        table.insert(w.clone(), string("w"));
+        assert_eq!(table.get(&v), Some(&string("v")));
+        assert_eq!(table.get(&u), Some(&string("v")));
+        assert_eq!(table.get(&w), Some(&string("w")));
+        // Cycles are broken so the vectors are freed
+        for value in [v, w, u].iter() {
+            if let AstNode::Vector(ref elements) = *value {
+                elements.borrow_mut().clear();
+            }
+        }
+    }
+
+    #[test]
+    fn changed_keys_can_be_removed() {
+        let mut table = HashTable::new();
+        let key = new_vector(vec![AstNode::Number(1.0)]);
+        table.insert(string("b"), string("b"));
+        table.insert(key.clone(), string("a"));
+        if let AstNode::Vector(ref elements) = key {
+            elements.borrow_mut().push(AstNode::Number(2.0));
+        }
+        // The key moves into the removed entry's place, under the hash it had
+        assert_eq!(table.remove(&string("b")), Some(string("b")));
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.remove(&new_vector(vec![AstNode::Number(1.0)])), None);
+    }
+}
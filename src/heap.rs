@@ -0,0 +1,300 @@
+/* heap.rs
+ *
+ * Vectors and hash tables are shared by reference counting, which can't
+ * free values that refer to each other in a cycle, such as a vector that
+ * holds itself.  Every vector and table is tracked here, and the cycle
+ * collector finds those only kept alive by references from other tracked
+ * values.  Nothing else can reach them, so they are emptied, which breaks
+ * the cycles and lets reference counting free them.
+ *
+ * Values are shared with Rc, so never leave the thread that made them,
+ * and each thread has a heap of its own.  Collection runs when (gc) is
+ * called, and whenever enough values have been made since the last one
+ */
+use convert::record;
+use error::Error;
+use hashtables::HashTable;
+use parser::AstNode;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::mem;
+use std::rc::{Rc, Weak};
+
+/* Collection runs once this many values, or as many as survived the last collection, have been made */
+const MIN_THRESHOLD: usize = 1024;
+
+/* A tracked value, without keeping it alive */
+enum Tracked {
+    Vector(Weak<RefCell<Vec<AstNode>>>),
+    Table(Weak<RefCell<HashTable>>),
+}
+
+impl Tracked {
+    fn upgrade(&self) -> Option<Object> {
+        match *self {
+            Tracked::Vector(ref weak) => weak.upgrade().map(Object::Vector),
+            Tracked::Table(ref weak) => weak.upgrade().map(Object::Table),
+        }
+    }
+    fn is_live(&self) -> bool {
+        match *self {
+            Tracked::Vector(ref weak) => weak.strong_count() > 0,
+            Tracked::Table(ref weak) => weak.strong_count() > 0,
+        }
+    }
+}
+
+/* A tracked value held while it is collected */
+enum Object {
+    Vector(Rc<RefCell<Vec<AstNode>>>),
+    Table(Rc<RefCell<HashTable>>),
+}
+
+impl Object {
+    fn address(&self) -> usize {
+        match *self {
+            Object::Vector(ref vector) => Rc::as_ptr(vector) as *const u8 as usize,
+            Object::Table(ref table) => Rc::as_ptr(table) as *const u8 as usize,
+        }
+    }
+    fn strong_count(&self) -> usize {
+        match *self {
+            Object::Vector(ref vector) => Rc::strong_count(vector),
+            Object::Table(ref table) => Rc::strong_count(table),
+        }
+    }
+    /* Calls found with the address of each tracked value this one refers to.
+     * False if the value is being changed, so can't be looked at
+     */
+    fn references<F: FnMut(usize)>(&self, found: &mut F) -> bool {
+        match *self {
+            Object::Vector(ref vector) => match vector.try_borrow() {
+                Ok(elements) => elements.iter().for_each(|element| references(element, found)),
+                Err(_) => return false
+            },
+            Object::Table(ref table) => match table.try_borrow() {
+                Ok(table) => table.entries().iter().for_each(|&(ref key, ref value)| {
+                    references(key, found);
+                    references(value, found);
+                }),
+                Err(_) => return false
+            }
+        }
+        return true;
+    }
+    /* Take what the value holds, so it no longer refers to anything */
+    fn empty(&self) -> Vec<AstNode> {
+        match *self {
+            Object::Vector(ref vector) => mem::take(&mut *vector.borrow_mut()),
+            Object::Table(ref table) => {
+                let table = mem::take(&mut *table.borrow_mut());
+                table.entries().iter().flat_map(|&(ref key, ref value)| vec![key.clone(), value.clone()]).collect()
+            }
+        }
+    }
+}
+
+/* The tracked values a value holds directly or in the lists and code within it.
+ * Lambda bodies and error objects are shared but never changed, so can't be part
+ * of a cycle made by changing a value.  They are left to their reference counts
+ */
+fn references<F: FnMut(usize)>(value: &AstNode, found: &mut F) -> () {
+    match *value {
+        AstNode::Vector(ref vector) => found(Rc::as_ptr(vector) as *const u8 as usize),
+        AstNode::HashTable(ref table) => found(Rc::as_ptr(table) as *const u8 as usize),
+        AstNode::Expression(ref items) => items.iter().for_each(|item| references(item, found)),
+        AstNode::Define(_, ref value) | AstNode::Located(_, ref value) | AstNode::Quote(ref value) |
+        AstNode::Quasiquote(ref value) | AstNode::Macro(_, ref value) | AstNode::Defmacro(_, _, ref value) => {
+            references(value, found)
+        },
+        AstNode::If(ref pred, ref true_expr, ref false_expr) => {
+            references(pred, found);
+            references(true_expr, found);
+            references(false_expr, found);
+        },
+        AstNode::Guard(_, ref clauses, ref body) => {
+            clauses.iter().flat_map(|clause| clause.iter()).chain(body.iter()).for_each(|item| references(item, found))
+        },
+        _ => {}
+    }
+}
+
+/* What the heap holds and has done, since the thread started */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct HeapStats {
+    pub live: usize, // vectors and tables not yet freed
+    pub allocated: u64, // vectors and tables made
+    pub freed: u64, // values in cycles the collector has freed
+    pub collections: u64,
+}
+
+struct Heap {
+    tracked: Vec<Tracked>,
+    since: usize, // values made since the last collection
+    threshold: usize,
+    stats: HeapStats,
+}
+
+impl Heap {
+    fn track(&mut self, tracked: Tracked) -> () {
+        // Forget freed values rather than grow
+        if (*self).tracked.len() == (*self).tracked.capacity() {
+            (*self).tracked.retain(|tracked| tracked.is_live());
+        }
+        (*self).tracked.push(tracked);
+        (*self).since += 1;
+        (*self).stats.allocated += 1;
+    }
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = RefCell::new(Heap{tracked: Vec::new(), since: 0, threshold: MIN_THRESHOLD,
+                                                   stats: HeapStats::default()});
+}
+
+/* Track a new vector, called by vectors::new_vector which makes every vector */
+pub fn track_vector(vector: &Rc<RefCell<Vec<AstNode>>>) -> () {
+    HEAP.with(|heap| heap.borrow_mut().track(Tracked::Vector(Rc::downgrade(vector))));
+}
+
+/* Track a new hash table, called by hashtables::new_hash_table which makes every table */
+pub fn track_table(table: &Rc<RefCell<HashTable>>) -> () {
+    HEAP.with(|heap| heap.borrow_mut().track(Tracked::Table(Rc::downgrade(table))));
+}
+
+/* Free the values only reachable from cycles, returning how many there were.
+ * Each value's references from other tracked values are discounted from its
+ * reference count.  Those with references left are held from outside, by the
+ * program's variables or the interpreter, and everything they reach is live
+ */
+pub fn collect() -> usize {
+    let objects: Vec<Object> = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.tracked.retain(|tracked| tracked.is_live());
+        heap.tracked.iter().filter_map(|tracked| tracked.upgrade()).collect()
+    });
+    let index: HashMap<usize, usize> = objects.iter().enumerate().map(|(i, object)| (object.address(), i)).collect();
+    // Less the reference held here
+    let mut outside: Vec<usize> = objects.iter().map(|object| object.strong_count() - 1).collect();
+    for object in objects.iter() {
+        let readable = object.references(&mut |address| {
+            if let Some(&i) = index.get(&address) {
+                outside[i] -= 1;
+            }
+        });
+        // A value being changed may be about to refer to anything, so nothing is freed
+        if !readable {
+            return 0;
+        }
+    }
+    let mut live = vec![false; objects.len()];
+    let mut pending: Vec<usize> = (0..objects.len()).filter(|&i| outside[i] > 0).collect();
+    while let Some(i) = pending.pop() {
+        if !live[i] {
+            live[i] = true;
+            objects[i].references(&mut |address| pending.extend(index.get(&address)));
+        }
+    }
+    // Emptied values are only dropped once all are emptied, so none is freed while being emptied
+    let garbage: Vec<&Object> = objects.iter().zip(live.iter()).filter(|&(_, &live)| !live).map(|(object, _)| object).collect();
+    let contents: Vec<Vec<AstNode>> = garbage.iter().map(|object| object.empty()).collect();
+    let freed = garbage.len();
+    drop(contents);
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.since = 0;
+        heap.threshold = MIN_THRESHOLD.max(objects.len() - freed);
+        heap.stats.freed += freed as u64;
+        heap.stats.collections += 1;
+    });
+    return freed;
+}
+
+/* Collect if enough values have been made since the last collection.  Called
+ * where the interpreter holds everything it is using, between calls
+ */
+pub fn maybe_collect() -> () {
+    if HEAP.with(|heap| heap.borrow().since >= heap.borrow().threshold) {
+        collect();
+    }
+}
+
+pub fn stats() -> HeapStats {
+    return HEAP.with(|heap| {
+        let heap = heap.borrow();
+        HeapStats{live: heap.tracked.iter().filter(|tracked| tracked.is_live()).count(), ..heap.stats}
+    });
+}
+
+pub fn is_builtin(ident: &str) -> bool {
+    lazy_static! {
+        static ref BUILTINS: BTreeSet<&'static str> = ["gc", "heap-stats"].iter().cloned().collect();
+    }
+    return BUILTINS.contains(ident);
+}
+
+fn check_arity(name: &str, args: &[Box<AstNode>]) -> Result<(), Error> {
+    if !args.is_empty() {
+        return Err(Error::Arity(format!("{} expects 0 argument(s) but got {}", name, args.len())));
+    }
+
+    return Ok(());
+}
+
+pub fn apply(ident: &str, args: &[Box<AstNode>]) -> Result<AstNode, Error> {
+    match ident {
+        "gc" => {
+            check_arity(ident, args)?;
+            Ok(AstNode::Number(collect() as f64))
+        },
+        "heap-stats" => {
+            check_arity(ident, args)?;
+            let stats = stats();
+            Ok(record(vec![("live", AstNode::Number(stats.live as f64)),
+                           ("allocated", AstNode::Number(stats.allocated as f64)),
+                           ("freed", AstNode::Number(stats.freed as f64)),
+                           ("collections", AstNode::Number(stats.collections as f64))]))
+        },
+        _ => Err(Error::Type(format!("Invalid operator: {:?}", ident)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use convert::record_field;
+    use heap;
+    use interpreter::Interpreter;
+    use parser::AstNode;
+
+    const PROGRAM: &str = "(define make-cycles (lambda (n)
+  (if (= n 0) 0
+    ((lambda (v t) (car (list (make-cycles (- n 1)) (vector-set! v 0 v) (hash-set! t 'self t) (hash-set! t 'v v))))
+     (vector 0) (make-hash-table)))))";
+
+    #[test]
+    fn cycles_are_freed() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str(PROGRAM).unwrap();
+        interpreter.eval_str("(make-cycles 10)").unwrap();
+        interpreter.eval_str("(define kept (vector 0)) (vector-set! kept 0 kept)").unwrap();
+        assert!(interpreter.eval_str("(gc)").unwrap() >= AstNode::Number(20.0));
+        // The cycle still reachable from a variable is kept
+        assert_eq!(interpreter.eval_str("(eq? kept (vector-ref kept 0))").unwrap(), AstNode::Bool(true));
+        assert_eq!(interpreter.eval_str("(gc)").unwrap(), AstNode::Number(0.0));
+        let stats = interpreter.eval_str("(heap-stats)").unwrap();
+        assert_eq!(record_field::<f64>(&stats, "heap-stats", "live").unwrap(), heap::stats().live as f64);
+        assert!(record_field::<f64>(&stats, "heap-stats", "freed").unwrap() >= 20.0);
+    }
+
+    #[test]
+    fn memory_stays_bounded() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str(PROGRAM).unwrap();
+        // 10000 values in cycles, never collected explicitly
+        for _ in 0..25 {
+            interpreter.eval_str("(make-cycles 200)").unwrap();
+            assert!(heap::stats().live < 4 * heap::MIN_THRESHOLD, "{:?}", heap::stats());
+        }
+        assert!(heap::stats().freed >= 8000);
+    }
+}
@@ -0,0 +1,216 @@
+/* hosts.rs
+ *
+ * Host objects: opaque Rust values handed to scripts, such as database
+ * handles or sockets.  Scripts can only pass them around and call the
+ * methods registered for their type; natives downcast them to operate on them
+ */
+use convert::{within, FromWisp, IntoWispResult, ToWisp};
+use error::Error;
+use eval::Context;
+use natives::NativeFn;
+use parser::AstNode;
+use std::any::{Any, TypeId};
+use std::cmp::Ordering;
+use std::fmt;
+use std::rc::Rc;
+
+/* Copies share the value, and are equal only to other copies of it */
+#[derive(Clone)]
+pub struct HostObject {
+    type_name: String,
+    value: Rc<dyn Any>,
+}
+
+/* The type's name without its module path, e.g. Connection for db::Connection */
+fn short_type_name<T: Any>() -> String {
+    let full = ::std::any::type_name::<T>();
+    let base = full.split('<').next().unwrap_or(full);
+    let short = base.rsplit("::").next().unwrap_or(base);
+    return format!("{}{}", short, &full[base.len()..]);
+}
+
+impl HostObject {
+    pub fn new<T: Any>(value: T) -> HostObject {
+        return HostObject::named(&short_type_name::<T>(), value);
+    }
+    pub fn named<T: Any>(type_name: &str, value: T) -> HostObject {
+        return HostObject{type_name: String::from(type_name), value: Rc::new(value)};
+    }
+    pub fn type_name(&self) -> &str {
+        return &(*self).type_name;
+    }
+    /* The id of the wrapped value's type, which methods are dispatched on */
+    pub fn type_id(&self) -> TypeId {
+        return (*(*self).value).type_id();
+    }
+    pub fn is<T: Any>(&self) -> bool {
+        return (*self).value.is::<T>();
+    }
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        return (*self).value.downcast_ref::<T>();
+    }
+}
+
+impl fmt::Debug for HostObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#<host:{}>", (*self).type_name)
+    }
+}
+
+impl PartialEq for HostObject {
+    fn eq(&self, other: &HostObject) -> bool {
+        return Rc::ptr_eq(&(*self).value, &other.value);
+    }
+}
+
+/* Host objects have no natural order, they are only comparable when equal */
+impl PartialOrd for HostObject {
+    fn partial_cmp(&self, other: &HostObject) -> Option<Ordering> {
+        if *self == *other {
+            return Some(Ordering::Equal);
+        }
+
+        return None;
+    }
+}
+
+pub fn new_host<T: Any>(value: T) -> AstNode {
+    return AstNode::Host(HostObject::new(value));
+}
+
+impl FromWisp for HostObject {
+    fn from_wisp(value: &AstNode) -> Result<HostObject, Error> {
+        match *value {
+            AstNode::Host(ref host) => Ok(host.clone()),
+            ref other => Err(::convert::mismatch("a host object", other))
+        }
+    }
+}
+
+impl ToWisp for HostObject {
+    fn to_wisp(self) -> AstNode {
+        return AstNode::Host(self);
+    }
+}
+
+/* Calls the implementation of a method registered for the receiver's type */
+pub fn dispatch(name: &str, args: &[Box<AstNode>], context: &mut Context) -> Result<AstNode, Error> {
+    let receiver = match args.first().map(|x| &**x) {
+        Some(&AstNode::Host(ref host)) => host.clone(),
+        Some(other) => return Err(Error::Type(format!("{} expects a host object receiver: {:?}", name, other))),
+        None => return Err(Error::Arity(format!("{} expects a receiver", name)))
+    };
+    match context.method(name, receiver.type_id()) {
+        Some(method) => method(args, context),
+        None => Err(Error::Type(format!("{} is not a method of {:?}", name, receiver)))
+    }
+}
+
+/* Rust closures taking a &T receiver that can be registered as methods of T.
+ * The remaining arguments and the result are converted as for natives
+ */
+pub trait IntoMethod<T, Args> {
+    fn into_method(self, name: &str) -> NativeFn;
+}
+
+macro_rules! impl_into_method {
+    ($($arg:ident),*) => {
+        impl<T, F, R, $($arg),*> IntoMethod<T, ($($arg,)*)> for F
+            where T: Any, F: Fn(&T, $($arg),*) -> R + 'static, R: IntoWispResult, $($arg: FromWisp),*
+        {
+            fn into_method(self, name: &str) -> NativeFn {
+                let name = String::from(name);
+                let params: &[&str] = &[$(stringify!($arg)),*];
+                let count = params.len() + 1;
+                return Rc::new(move |args: &[Box<AstNode>], _: &mut Context| {
+                    if args.len() != count {
+                        return Err(Error::Arity(format!("{} expects {} argument(s) but got {}",
+                                                        name, count, args.len())));
+                    }
+                    let receiver = match *args[0] {
+                        AstNode::Host(ref host) => host.clone(),
+                        ref other => return Err(Error::Type(format!("{} expects a host object receiver: {:?}",
+                                                                    name, other)))
+                    };
+                    let this = match receiver.downcast_ref::<T>() {
+                        Some(this) => this,
+                        None => return Err(Error::Type(format!("{} is not a method of {:?}", name, receiver)))
+                    };
+                    #[allow(unused_variables, unused_mut)]
+                    let mut args = args.iter().enumerate().skip(1);
+                    return (self)(this, $({
+                        let (i, arg) = args.next().unwrap();
+                        $arg::from_wisp(arg).map_err(|e| within(e, &format!("argument {} of {}", i + 1, name)))?
+                    }),*).into_wisp_result();
+                });
+            }
+        }
+    }
+}
+
+impl_into_method!();
+impl_into_method!(A);
+impl_into_method!(A, B);
+impl_into_method!(A, B, C);
+impl_into_method!(A, B, C, D);
+
+#[cfg(test)]
+mod test {
+    use error::Error;
+    use hosts::{new_host, HostObject};
+    use interpreter::Interpreter;
+    use parser::AstNode;
+    use std::cell::RefCell;
+
+    struct Counter {
+        count: RefCell<i64>,
+    }
+
+    struct Socket {
+        sent: RefCell<Vec<String>>,
+    }
+
+    #[test]
+    fn printing_and_identity() {
+        let counter = HostObject::new(Counter{count: RefCell::new(0)});
+        assert_eq!(format!("{:?}", counter), "#<host:Counter>");
+        assert_eq!(format!("{:?}", HostObject::named("Database", 5)), "#<host:Database>");
+        assert_eq!(counter, counter.clone());
+        assert!(counter != HostObject::new(Counter{count: RefCell::new(0)}));
+        assert!(counter.is::<Counter>());
+        assert!(counter.downcast_ref::<Socket>().is_none());
+    }
+
+    #[test]
+    fn methods_dispatch_on_receiver_type() {
+        let mut interpreter = Interpreter::new();
+        interpreter.register_method("add!", |c: &Counter, n: i64| { *c.count.borrow_mut() += n; *c.count.borrow() }).unwrap();
+        interpreter.register_method("count", |c: &Counter| *c.count.borrow()).unwrap();
+        interpreter.register_method("add!", |s: &Socket, message: String| s.sent.borrow_mut().push(message)).unwrap();
+        interpreter.set_global("counter", new_host(Counter{count: RefCell::new(0)})).unwrap();
+        let socket = new_host(Socket{sent: RefCell::new(vec![])});
+        interpreter.set_global("socket", socket.clone()).unwrap();
+
+        assert_eq!(interpreter.eval_str("(add! counter 2) (add! counter 3) (count counter)"), Ok(AstNode::Number(5.0)));
+        interpreter.eval_str("(add! socket \"hello\")").unwrap();
+        match socket {
+            AstNode::Host(ref host) => assert_eq!(*host.downcast_ref::<Socket>().unwrap().sent.borrow(), vec!["hello"]),
+            _ => unreachable!()
+        }
+        // Host objects are first class values
+        assert_eq!(interpreter.eval_str("(equal? (car (list counter)) counter)"), Ok(AstNode::Bool(true)));
+    }
+
+    #[test]
+    fn method_errors() {
+        let mut interpreter = Interpreter::new();
+        interpreter.register_method("count", |c: &Counter| *c.count.borrow()).unwrap();
+        interpreter.set_global("socket", new_host(Socket{sent: RefCell::new(vec![])})).unwrap();
+        match interpreter.eval_str("(count socket)") {
+            Err(Error::Type(message)) => assert_eq!(message, "count is not a method of #<host:Socket>"),
+            other => panic!("Expected type error: {:?}", other)
+        }
+        assert!(interpreter.eval_str("(count 1)").is_err());
+        assert!(interpreter.eval_str("(count)").is_err());
+    }
+}
@@ -0,0 +1,346 @@
+/* interpreter.rs
+ *
+ * Embedding interface: owns the evaluation context and
+ * takes source text rather than tokens or ASTs
+ */
+use cancel::CancelToken;
+use compiler;
+use compiler::Chunk;
+use error::Error;
+use eval;
+use eval::Context;
+use frames::Backtrace;
+use hosts::IntoMethod;
+use lexer;
+use limits::{Limits, Usage};
+use natives::IntoNative;
+use optimizer::{Optimizer, Pass};
+use parser;
+use parser::AstNode;
+use resolver;
+use resolver::Unbound;
+use sandbox::Policy;
+use symbols::Symbol;
+use std::any::{Any, TypeId};
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Instant;
+use vm;
+use vm::Backend;
+
+pub struct Interpreter {
+    context: Box<Context>, // boxed so it stays put when the interpreter moves, as suspended generators refer to it
+    optimizer: Optimizer,
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        return Interpreter{context: Box::new(Context::new()), optimizer: Optimizer::new()};
+    }
+
+    /* Parse every expression in the source without evaluating them */
+    pub fn parse_str(&self, source: &str) -> Result<Vec<AstNode>, Error> {
+        return (*self).parse_named(source, None);
+    }
+
+    /* As parse_str, with errors and backtraces locating code by the source's name, e.g. a file name */
+    pub fn parse_source(&self, source: &str, name: &str) -> Result<Vec<AstNode>, Error> {
+        return (*self).parse_named(source, Some(name));
+    }
+
+    fn parse_named(&self, source: &str, name: Option<&str>) -> Result<Vec<AstNode>, Error> {
+        let mut tokens = lexer::try_parse_spanned(source, name)?.into_iter().peekable();
+        let mut program = Vec::new();
+        while tokens.peek().is_some() {
+            program.push(parser::try_parse_limited(&mut tokens, (*self).context.limits().max_depth)?);
+        }
+
+        return Ok(program);
+    }
+
+    pub fn eval_ast(&mut self, mut ast: AstNode) -> Result<AstNode, Error> {
+        if (*self).optimizer.is_enabled() {
+            ast = (*self).optimizer.optimize(&ast, &(*self).context)?;
+        }
+        (*self).context.reset_usage();
+        (*self).context.reset_trace();
+        match (*self).context.backend() {
+            Backend::TreeWalker => {
+                eval::eval(&mut ast, &mut (*self).context)?;
+                return Ok(ast);
+            },
+            Backend::Bytecode => return vm::eval(&ast, &mut (*self).context)
+        }
+    }
+
+    /* Compile each expression in the source for the vm, e.g. to be written
+     * to a compiled file with wspc::write_file
+     */
+    pub fn compile_source(&mut self, source: &str, name: &str) -> Result<Vec<Chunk>, Error> {
+        let program = (*self).parse_source(source, name)?;
+        (*self).optimizer.note_program(&program);
+        return program.iter().map(|ast| {
+            let ast = (*self).optimizer.optimize(ast, &(*self).context)?;
+            Ok(compiler::compile(&ast, &(*self).context))
+        }).collect();
+    }
+
+    /* The variables used by the program that no definition or parameter in it, nor any
+     * global, binds.  Evaluating them would fail, so they can be reported beforehand
+     */
+    pub fn unbound_variables(&self, program: &[AstNode]) -> Vec<Unbound> {
+        return resolver::unbound(program, &(*self).context);
+    }
+
+    /* Optimization passes rewrite each expression before it is evaluated or compiled.
+     * None are enabled by default, and they should be set before any code is evaluated
+     */
+    pub fn set_passes(&mut self, passes: &[Pass]) -> () {
+        (*self).optimizer.set_passes(passes);
+    }
+
+    /* Let the optimizer see every binding in the program before its expressions are
+     * evaluated in turn with eval_ast, so it doesn't inline a function the program
+     * defines again
+     */
+    pub fn note_program(&mut self, program: &[AstNode]) -> () {
+        if (*self).optimizer.is_enabled() {
+            (*self).optimizer.note_program(program);
+        }
+    }
+
+    /* Optimize the AST with the passes set, returning it as it is after each pass that
+     * changed it, for inspecting what the optimizer does
+     */
+    pub fn trace_passes(&mut self, ast: &AstNode) -> Result<Vec<(Pass, AstNode)>, Error> {
+        return (*self).optimizer.trace(ast, &(*self).context);
+    }
+
+    /* A listing of the chunk's instructions and those of the lambdas it creates */
+    pub fn disassemble(&self, chunk: &Chunk) -> String {
+        return compiler::disassemble(chunk, &(*self).context);
+    }
+
+    /* Evaluate a compiled expression with the vm, whichever backend is set */
+    pub fn eval_chunk(&mut self, chunk: Chunk) -> Result<AstNode, Error> {
+        (*self).context.reset_usage();
+        (*self).context.reset_trace();
+        return vm::eval_chunk(chunk, &mut (*self).context);
+    }
+
+    /* Evaluate with the tree walker or compile to bytecode for the vm.  Both give the same results */
+    pub fn set_backend(&mut self, backend: Backend) -> () {
+        (*self).context.set_backend(backend);
+    }
+
+    /* Evaluate each expression in the source, returning the value of the last.
+     * Nothing is evaluated if the source doesn't parse
+     */
+    pub fn eval_str(&mut self, source: &str) -> Result<AstNode, Error> {
+        let program = (*self).parse_str(source)?;
+        return (*self).eval_program(program);
+    }
+
+    pub fn eval_source(&mut self, source: &str, name: &str) -> Result<AstNode, Error> {
+        let program = (*self).parse_source(source, name)?;
+        return (*self).eval_program(program);
+    }
+
+    fn eval_program(&mut self, program: Vec<AstNode>) -> Result<AstNode, Error> {
+        (*self).note_program(&program);
+        let mut result = AstNode::Expression(vec![]);
+        for ast in program {
+            result = (*self).eval_ast(ast)?;
+        }
+
+        return Ok(result);
+    }
+
+    /* The calls that led to the error of the last evaluation, innermost first.
+     * None if it succeeded
+     */
+    pub fn backtrace(&self) -> Option<Backtrace> {
+        return (*self).context.backtrace().cloned();
+    }
+
+    pub fn eval_file<P: AsRef<Path>>(&mut self, path: P) -> Result<AstNode, Error> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).map_err(|e| Error::Io(format!("{}: {}", path.display(), e)))?;
+        return (*self).eval_source(&source, &path.display().to_string());
+    }
+
+    /* Limits apply to each later evaluation or function call */
+    pub fn set_limits(&mut self, limits: Limits) -> () {
+        (*self).context.set_limits(limits);
+    }
+
+    /* Evaluations stop with a Cancelled error soon after the token is cancelled */
+    pub fn set_cancel_token(&mut self, token: Option<CancelToken>) -> () {
+        (*self).context.set_cancel_token(token);
+    }
+
+    /* Evaluations stop with a Timeout error soon after the deadline passes */
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) -> () {
+        (*self).context.set_deadline(deadline);
+    }
+
+    /* Builtins in groups the policy denies fail with a NotPermitted error */
+    pub fn set_policy(&mut self, policy: Policy) -> () {
+        (*self).context.set_policy(policy);
+    }
+
+    /* Resources used by the last evaluation or function call */
+    pub fn usage(&self) -> Usage {
+        return (*self).context.usage();
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<AstNode> {
+        return (*self).context.get_global(name).cloned();
+    }
+
+    /* Define a global, as (define name value) would, without evaluating the value */
+    pub fn set_global(&mut self, name: &str, value: AstNode) -> Result<(), Error> {
+        let name = Symbol::intern(name)?;
+        if (*self).context.is_builtin(name.clone()) {
+            return Err(Error::Syntax(format!("Can't override buildin: {:?}", name)));
+        }
+        (*self).context.set_global(name, value);
+        return Ok(());
+    }
+
+    /* Register a Rust closure as a builtin.  Its arguments and result are
+     * converted automatically, e.g. |x: f64, n: i64| x.powi(n as i32)
+     */
+    pub fn register_fn<Args, F: IntoNative<Args>>(&mut self, name: &str, function: F) -> Result<(), Error> {
+        return (*self).context.register_native(name, function.into_native(name));
+    }
+
+    /* Register a builtin that takes its arguments unconverted, for variadic functions */
+    pub fn register_native<F>(&mut self, name: &str, function: F) -> Result<(), Error>
+        where F: Fn(&[Box<AstNode>], &mut Context) -> Result<AstNode, Error> + 'static
+    {
+        return (*self).context.register_native(name, Rc::new(function));
+    }
+
+    /* Register a method of host objects of type T, e.g. |db: &Database, sql: String| db.query(&sql).
+     * Methods of different types can share a name, the receiver's type picks which is called
+     */
+    pub fn register_method<T: Any, Args, F: IntoMethod<T, Args>>(&mut self, name: &str, method: F) -> Result<(), Error> {
+        return (*self).context.register_method(name, TypeId::of::<T>(), method.into_method(name));
+    }
+
+    /* Call the builtin or global function with the given name on already evaluated arguments */
+    pub fn call_function(&mut self, name: &str, args: Vec<AstNode>) -> Result<AstNode, Error> {
+        let symbol = Symbol::intern(name)?;
+        let function = if (*self).context.is_builtin(symbol.clone()) {
+            AstNode::Identifier(symbol)
+        }
+        else {
+            match (*self).context.get_global(name) {
+                Some(function) => function.clone(),
+                None => return Err(Error::Undefined(String::from(name)))
+            }
+        };
+        let args: Vec<Box<AstNode>> = args.into_iter().map(Box::new).collect();
+        (*self).context.reset_usage();
+        (*self).context.reset_trace();
+        return eval::apply(&function, &args, &mut (*self).context);
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        return Interpreter::new();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use error::Error;
+    use interpreter::Interpreter;
+    use parser::AstNode;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn eval_str_returns_last_value() {
+        let mut interpreter = Interpreter::new();
+        assert_eq!(interpreter.eval_str("(define x 2) (* x 21)"), Ok(AstNode::Number(42.0)));
+        assert_eq!(interpreter.eval_str(""), Ok(AstNode::Expression(vec![])));
+    }
+
+    #[test]
+    fn syntax_errors_are_returned() {
+        let mut interpreter = Interpreter::new();
+        match interpreter.eval_str("(define y 1) (+ 1") {
+            Err(Error::Syntax(_)) => {},
+            other => panic!("Expected syntax error: {:?}", other)
+        }
+        // Nothing runs when the source doesn't parse
+        assert_eq!(interpreter.get_global("y"), None);
+        assert!(interpreter.eval_str("(+ 1 ???)").is_err());
+    }
+
+    #[test]
+    fn globals() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_global("limit", AstNode::Number(10.0)).unwrap();
+        assert_eq!(interpreter.eval_str("(* limit 2)"), Ok(AstNode::Number(20.0)));
+        interpreter.eval_str("(define name \"wisp\")").unwrap();
+        assert_eq!(interpreter.get_global("name"), Some(AstNode::String(String::from("wisp"))));
+        assert!(interpreter.set_global("+", AstNode::Number(1.0)).is_err());
+    }
+
+    #[test]
+    fn call_function() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str("(define square (lambda (x) (* x x)))").unwrap();
+        assert_eq!(interpreter.call_function("square", vec![AstNode::Number(5.0)]), Ok(AstNode::Number(25.0)));
+        let sum = interpreter.call_function("+", vec![AstNode::Number(1.0), AstNode::Number(2.0)]);
+        assert_eq!(sum, Ok(AstNode::Number(3.0)));
+        assert_eq!(interpreter.call_function("missing", vec![]), Err(Error::Undefined(String::from("missing"))));
+    }
+
+    #[test]
+    fn register_fn() {
+        let mut interpreter = Interpreter::new();
+        interpreter.register_fn("hypot", |x: f64, y: f64| x.hypot(y)).unwrap();
+        interpreter.register_fn("shout", |s: String, times: i64| format!("{}{}", s.to_uppercase(), "!".repeat(times as usize))).unwrap();
+        interpreter.register_fn("evens", |xs: Vec<i64>| xs.into_iter().filter(|x| x % 2 == 0).collect::<Vec<i64>>()).unwrap();
+        assert_eq!(interpreter.eval_str("(hypot 3 4)"), Ok(AstNode::Number(5.0)));
+        assert_eq!(interpreter.eval_str("(shout \"hi\" 2)"), Ok(AstNode::String(String::from("HI!!"))));
+        assert_eq!(interpreter.eval_str("(evens '(1 2 3 4))"), interpreter.eval_str("'(2 4)"));
+        // Natives are first class like any other builtin
+        assert_eq!(interpreter.eval_str("(map hypot '(3 5) '(4 12))"), interpreter.eval_str("'(5 13)"));
+        assert!(interpreter.eval_str("(define hypot 1)").is_err());
+        match interpreter.eval_str("(hypot \"3\" 4)") {
+            Err(Error::Type(_)) => {},
+            other => panic!("Expected type error: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn register_native() {
+        let mut interpreter = Interpreter::new();
+        interpreter.register_native("count", |args, _| Ok(AstNode::Number(args.len() as f64))).unwrap();
+        assert_eq!(interpreter.eval_str("(count 1 2 3)"), Ok(AstNode::Number(3.0)));
+        // Defaults can be replaced
+        interpreter.register_fn("+", |a: String, b: String| a + &b).unwrap();
+        assert_eq!(interpreter.eval_str("(+ \"a\" \"b\")"), Ok(AstNode::String(String::from("ab"))));
+    }
+
+    #[test]
+    fn eval_file() {
+        let path = env::temp_dir().join(format!("wisp_eval_file_{}.wsp", ::std::process::id()));
+        fs::write(&path, "(define f (lambda (x) (+ x 1)))\n(f 41)\n").unwrap();
+        let mut interpreter = Interpreter::new();
+        let result = interpreter.eval_file(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result, Ok(AstNode::Number(42.0)));
+        match interpreter.eval_file("/nonexistent/file.wsp") {
+            Err(Error::Io(_)) => {},
+            other => panic!("Expected IO error: {:?}", other)
+        }
+    }
+}
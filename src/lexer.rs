@@ -1,94 +1,323 @@
 /* lexer.rs
  *
  * Takes str as an input and returns a Vec of tokens.
- * Panics if an invalid token is encountered
+ * try_parse returns an error if an invalid token is encountered, parse panics.
+ * try_parse_spanned also returns where in the source each token starts
  */
+use error::Error;
 use regex::Regex;
+use symbols::Symbol;
+use std::fmt;
+use std::iter::Peekable;
+use std::rc::Rc;
+use std::str::Chars;
 
 #[derive(Debug, PartialEq)]
 pub enum Token {
     OpenParen,
+    VectorOpen,
+    OpenBrace,
+    CloseBrace,
     Define,
+    Defmacro,
     Lambda,
     If,
+    Guard,
+    DefineGenerator,
+    Delay,
+    DelayForce,
+    StreamCons,
+    Quote,
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
     Bool(bool),
+    Char(char),
     Number(f64),
     String(String),
-    Identifier(String),
+    Identifier(Symbol),
     CloseParen,
 }
 
+/* The position of a token in the source, counting from line 1 column 1.
+ * The source is the name of the file it was read from, if there is one
+ */
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub struct Span {
+    pub source: Option<Rc<str>>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (*self).source {
+            Some(ref source) => write!(f, "{}:{}:{}", source, (*self).line, (*self).column),
+            None => write!(f, "{}:{}", (*self).line, (*self).column)
+        }
+    }
+}
+
+/* Characters of the source along with the position of the next one */
+struct SourceChars<'a> {
+    chars: Peekable<Chars<'a>>,
+    source: Option<Rc<str>>,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> SourceChars<'a> {
+    fn new(buff: &'a str, source: Option<&str>) -> SourceChars<'a> {
+        return SourceChars{chars: buff.chars().peekable(), source: source.map(Rc::from), line: 1, column: 1};
+    }
+    fn peek(&mut self) -> Option<&char> {
+        return (*self).chars.peek();
+    }
+    fn position(&self) -> Span {
+        return Span{source: (*self).source.clone(), line: (*self).line, column: (*self).column};
+    }
+}
+
+impl<'a> Iterator for SourceChars<'a> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = (*self).chars.next()?;
+        if c == '\n' {
+            (*self).line += 1;
+            (*self).column = 1;
+        }
+        else {
+            (*self).column += 1;
+        }
+        return Some(c);
+    }
+}
+
+/* Returns the keyword token for the given word, if it is one */
+pub fn keyword(token: &str) -> Option<Token> {
+    lazy_static! {
+        static ref DEFINE: Regex = Regex::new(r"^define$").unwrap();
+        static ref DEFMACRO: Regex = Regex::new(r"^defmacro$").unwrap();
+        static ref LAMBDA: Regex = Regex::new(r"^lambda$").unwrap();
+        static ref IF: Regex = Regex::new(r"^if$").unwrap();
+        static ref GUARD: Regex = Regex::new(r"^guard$").unwrap();
+        static ref DEFINE_GENERATOR: Regex = Regex::new(r"^define-generator$").unwrap();
+        static ref DELAY: Regex = Regex::new(r"^delay$").unwrap();
+        static ref DELAY_FORCE: Regex = Regex::new(r"^delay-force$").unwrap();
+        static ref STREAM_CONS: Regex = Regex::new(r"^stream-cons$").unwrap();
+    }
+    if DEFINE.is_match(token) {
+        return Some(Token::Define);
+    }
+    else if DEFMACRO.is_match(token) {
+        return Some(Token::Defmacro);
+    }
+    else if LAMBDA.is_match(token) {
+        return Some(Token::Lambda);
+    }
+    else if IF.is_match(token) {
+        return Some(Token::If);
+    }
+    else if GUARD.is_match(token) {
+        return Some(Token::Guard);
+    }
+    else if DEFINE_GENERATOR.is_match(token) {
+        return Some(Token::DefineGenerator);
+    }
+    else if DELAY.is_match(token) {
+        return Some(Token::Delay);
+    }
+    else if DELAY_FORCE.is_match(token) {
+        return Some(Token::DelayForce);
+    }
+    else if STREAM_CONS.is_match(token) {
+        return Some(Token::StreamCons);
+    }
+
+    return None;
+}
+
+/* Reads the remainder of a string literal after its opening quote */
+fn parse_string<I>(chars: &mut I) -> Result<Token, Error>
+    where I: Iterator<Item=char>
+{
+    let mut string = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => break,
+            Some('\\') => match chars.next() {
+                Some('n') => string.push('\n'),
+                Some('t') => string.push('\t'),
+                Some('"') => string.push('"'),
+                Some('\\') => string.push('\\'),
+                Some(other) => return Err(Error::Syntax(format!("Invalid escape in string: \\{}", other))),
+                None => return Err(Error::Syntax(format!("Unterminated string: \"{}", string)))
+            },
+            Some(c) => string.push(c),
+            None => return Err(Error::Syntax(format!("Unterminated string: \"{}", string)))
+        }
+    }
+
+    return Ok(Token::String(string));
+}
+
+/* Reads the remainder of a character literal after its #\\ prefix */
+fn parse_char(chars: &mut SourceChars) -> Result<Token, Error> {
+    let mut name = String::new();
+    // The first character is always part of the literal so #\( and #\  are valid
+    match chars.next() {
+        Some(c) => name.push(c),
+        None => return Err(Error::Syntax(String::from("Unexpected end of character literal")))
+    }
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() || c == '(' || c == ')' || c == '{' || c == '}' || c == '"' {
+            break;
+        }
+        name.push(c);
+        chars.next();
+    }
+
+    if name.chars().count() == 1 {
+        return Ok(Token::Char(name.chars().next().unwrap()));
+    }
+    match name.as_str() {
+        "space" => Ok(Token::Char(' ')),
+        "newline" => Ok(Token::Char('\n')),
+        "tab" => Ok(Token::Char('\t')),
+        _ if name.starts_with('x') => {
+            let code = u32::from_str_radix(&name[1..], 16).ok().and_then(::std::char::from_u32);
+            match code {
+                Some(c) => Ok(Token::Char(c)),
+                None => Err(Error::Syntax(format!("Invalid character literal: #\\{}", name)))
+            }
+        },
+        _ => Err(Error::Syntax(format!("Invalid character literal: #\\{}", name)))
+    }
+}
+
+/* Converts a complete word into a token */
+fn classify(token: &str) -> Result<Token, Error> {
+    lazy_static! {
+        static ref BOOL: Regex = Regex::new(r"^((true)|(false))$").unwrap();
+        static ref IDENT: Regex = Regex::new(r"^([A-Za-z_&]|[/*\+<>=!-])([0-9A-Za-z_]|[/*\+<>=!?-])*$").unwrap();
+        static ref NUMBER: Regex = Regex::new(r"^-?[0-9]+(\.[0-9]+)?$").unwrap();
+    }
+    if let Some(keyword) = keyword(token) {
+        return Ok(keyword);
+    }
+    else if BOOL.is_match(token) {
+        let boolean = token.parse::<bool>().expect("Invalid boolean!");
+        return Ok(Token::Bool(boolean));
+    }
+    else if NUMBER.is_match(token) {
+        let num = token.parse::<f64>().expect("Invalid number!");
+        return Ok(Token::Number(num));
+    }
+    else if IDENT.is_match(token) {
+        return Ok(Token::Identifier(Symbol::intern(token)?));
+    }
+    return Err(Error::Syntax(format!("Invalid token: {}", token)));
+}
+
+/* Panics if an invalid token is encountered */
 pub fn parse(buff: &str) -> Vec<Token> {
+    return try_parse(buff).unwrap_or_else(|e| panic!("{}", e));
+}
+
+pub fn try_parse(buff: &str) -> Result<Vec<Token>, Error> {
+    return Ok(try_parse_spanned(buff, None)?.into_iter().map(|(token, _)| token).collect());
+}
+
+/* Spans name the source, such as a file name, when one is given */
+pub fn try_parse_spanned(buff: &str, source: Option<&str>) -> Result<Vec<(Token, Span)>, Error> {
     let mut tokens = Vec::new();
     let mut token = String::new();
+    let mut chars = SourceChars::new(buff, source);
+    let mut token_start = chars.position();
 
-    lazy_static! {
-        static ref DEFINE: Regex = Regex::new(r"define").unwrap();
-        static ref LAMBDA: Regex = Regex::new(r"lambda").unwrap();
-        static ref IF: Regex = Regex::new(r"if").unwrap();
-        static ref BOOL: Regex = Regex::new(r"(true)|(false)").unwrap();
-        static ref IDENT: Regex = Regex::new(r"([A-Za-z_]|[/*\+-])([0-9A-Za-z_]|[/*\+-])*").unwrap();
-        static ref NUMBER: Regex = Regex::new(r"\d+(\.\d+)?").unwrap();
-        static ref STRING: Regex = Regex::new(r#"".*""#).unwrap();
-        static ref WHITESPACE: Regex = Regex::new(r"[:space:]").unwrap();
-    }
-    for c in buff.chars() {
-        // White space and parentheses trigger the completion of the previous token
-        if WHITESPACE.is_match(c.to_string().as_str()) || c == '(' || c == ')' {
-            if DEFINE.is_match(token.as_str()) {
-                tokens.push(Token::Define);
-            }
-            else if LAMBDA.is_match(token.as_str()) {
-                tokens.push(Token::Lambda);
-            }
-            else if IF.is_match(token.as_str()) {
-                tokens.push(Token::If);
-            }
-            else if BOOL.is_match(token.as_str()) {
-                let boolean = token.parse::<bool>().expect("Invalid boolean!");
-                tokens.push(Token::Bool(boolean));
-            }
-            else if NUMBER.is_match(token.as_str()) {
-                let num = token.parse::<f64>().expect("Invalid number!");
-                tokens.push(Token::Number(num));
-            }
-            else if STRING.is_match(token.as_str()) {
-                tokens.push(Token::String(String::from(token.trim_matches('\"'))));
-            }
-            else if IDENT.is_match(token.as_str()) {
-                tokens.push(Token::Identifier(token.clone()));
-            }
-            else if !token.is_empty() {
-                panic!("Invalid token: {}", token);
-            }
+    loop {
+        let start = chars.position();
+        let c = match chars.next() {
+            Some(c) => c,
+            None => break
+        };
+        let is_whitespace = c.is_whitespace();
+        let is_delimiter = c == '(' || c == ')' || c == '{' || c == '}' || c == '\'' || c == '`' || c == ','
+            || c == '"';
+        // White space and delimiters trigger the completion of the previous token
+        if (is_whitespace || is_delimiter) && !token.is_empty() {
+            tokens.push((classify(token.as_str())?, token_start.clone()));
             token.clear();
         }
-        if WHITESPACE.is_match(c.to_string().as_str()) {
-            // WHITESPACE is ignored
+        let mut push = |t: Token| tokens.push((t, start.clone()));
+        if is_whitespace {
+            // White space is ignored
         }
         else if c == '(' {
-            tokens.push(Token::OpenParen);
+            push(Token::OpenParen);
         }
         else if c == ')' {
-            tokens.push(Token::CloseParen);
+            push(Token::CloseParen);
+        }
+        else if c == '{' {
+            push(Token::OpenBrace);
+        }
+        else if c == '}' {
+            push(Token::CloseBrace);
+        }
+        else if c == '"' {
+            push(parse_string(&mut chars)?);
+        }
+        else if c == '#' && token.is_empty() && chars.peek() == Some(&'(') {
+            chars.next();
+            push(Token::VectorOpen);
+        }
+        else if c == '#' && token.is_empty() && chars.peek() == Some(&'\\') {
+            chars.next();
+            push(parse_char(&mut chars)?);
+        }
+        else if c == '\'' {
+            push(Token::Quote);
+        }
+        else if c == '`' {
+            push(Token::Quasiquote);
+        }
+        else if c == ',' {
+            if let Some(&'@') = chars.peek() {
+                chars.next();
+                push(Token::UnquoteSplicing);
+            }
+            else {
+                push(Token::Unquote);
+            }
         }
         else {
+            if token.is_empty() {
+                token_start = start.clone();
+            }
             token.push(c);
         }
     }
+    if !token.is_empty() {
+        tokens.push((classify(token.as_str())?, token_start));
+    }
 
-    return tokens;
+    return Ok(tokens);
 }
 
 #[cfg(test)]
 mod test {
     use lexer::parse;
+    use lexer::try_parse;
+    use lexer::try_parse_spanned;
     use lexer::Token;
+    use symbols::Symbol;
 
     #[test]
     fn simple_parse() {
         let tokens = parse("(+ 3 4)");
-        let expected_tokens = vec![Token::OpenParen, Token::Identifier(String::from("+")),
+        let expected_tokens = vec![Token::OpenParen, Token::Identifier(Symbol::intern("+").unwrap()),
             Token::Number(3.0), Token::Number(4.0), Token::CloseParen];
         assert_eq!(tokens, expected_tokens);
     }
@@ -96,8 +325,8 @@ mod test {
     #[test]
     fn nested_parse() {
         let tokens = parse("(+ (* 3 5) 4)");
-        let expected_tokens = vec![Token::OpenParen, Token::Identifier(String::from("+")),
-            Token::OpenParen, Token::Identifier(String::from("*")), Token::Number(3.0), Token::Number(5.0),
+        let expected_tokens = vec![Token::OpenParen, Token::Identifier(Symbol::intern("+").unwrap()),
+            Token::OpenParen, Token::Identifier(Symbol::intern("*").unwrap()), Token::Number(3.0), Token::Number(5.0),
             Token::CloseParen,  Token::Number(4.0), Token::CloseParen];
         assert_eq!(tokens, expected_tokens);
     }
@@ -110,15 +339,60 @@ mod test {
     #[test]
     fn string_test() {
         let tokens = parse("(cat \"new\" \"wow\")");
-        let expected_tokens = vec![Token::OpenParen, Token::Identifier(String::from("cat")),
+        let expected_tokens = vec![Token::OpenParen, Token::Identifier(Symbol::intern("cat").unwrap()),
             Token::String(String::from("new")), Token::String(String::from("wow")), Token::CloseParen];
         assert_eq!(tokens, expected_tokens);
     }
+    #[test]
+    fn string_with_spaces() {
+        let tokens = parse("(f \"a (b)\" \"say \\\"hi\\\"\\n\")");
+        let expected_tokens = vec![Token::OpenParen, Token::Identifier(Symbol::intern("f").unwrap()),
+            Token::String(String::from("a (b)")), Token::String(String::from("say \"hi\"\n")), Token::CloseParen];
+        assert_eq!(tokens, expected_tokens);
+    }
+
+    #[test]
+    #[should_panic]
+    fn unterminated_string() {
+        parse("(f \"abc)");
+    }
+
+    #[test]
+    fn char_test() {
+        let tokens = parse("(f #\\a #\\space #\\newline #\\x41 #\\( #\\λ)");
+        let expected_tokens = vec![Token::OpenParen, Token::Identifier(Symbol::intern("f").unwrap()),
+            Token::Char('a'), Token::Char(' '), Token::Char('\n'), Token::Char('A'), Token::Char('('),
+            Token::Char('λ'), Token::CloseParen];
+        assert_eq!(tokens, expected_tokens);
+    }
+
+    #[test]
+    fn vector_test() {
+        let tokens = parse("#(1 #(2))");
+        let expected_tokens = vec![Token::VectorOpen, Token::Number(1.0), Token::VectorOpen, Token::Number(2.0),
+            Token::CloseParen, Token::CloseParen];
+        assert_eq!(tokens, expected_tokens);
+    }
+
+    #[test]
+    fn hash_table_test() {
+        let tokens = parse("{\"a\" 1}");
+        let expected_tokens = vec![Token::OpenBrace, Token::String(String::from("a")), Token::Number(1.0),
+            Token::CloseBrace];
+        assert_eq!(tokens, expected_tokens);
+    }
+
+    #[test]
+    #[should_panic]
+    fn invalid_char_name() {
+        parse("#\\bogus");
+    }
+
     #[test]
     fn define_test() {
         let tokens = parse("(define A 10.0)");
         let expected_tokens = vec![Token::OpenParen, Token::Define,
-            Token::Identifier(String::from("A")), Token::Number(10.0), Token::CloseParen];
+            Token::Identifier(Symbol::intern("A").unwrap()), Token::Number(10.0), Token::CloseParen];
         assert_eq!(tokens, expected_tokens);
     }
 
@@ -126,9 +400,9 @@ mod test {
     fn lambda_test() {
         let tokens = parse("(lambda (x) (* x x))");
         let expected_tokens = vec![Token::OpenParen, Token::Lambda,
-            Token::OpenParen, Token::Identifier(String::from("x")), Token::CloseParen,
-            Token::OpenParen, Token::Identifier(String::from("*")), Token::Identifier(String::from("x")),
-            Token::Identifier(String::from("x")), Token::CloseParen, Token::CloseParen];
+            Token::OpenParen, Token::Identifier(Symbol::intern("x").unwrap()), Token::CloseParen,
+            Token::OpenParen, Token::Identifier(Symbol::intern("*").unwrap()), Token::Identifier(Symbol::intern("x").unwrap()),
+            Token::Identifier(Symbol::intern("x").unwrap()), Token::CloseParen, Token::CloseParen];
         assert_eq!(tokens, expected_tokens);
     }
 
@@ -140,6 +414,17 @@ mod test {
         assert_eq!(tokens, expected_tokens);
     }
 
+    #[test]
+    fn spans() {
+        let tokens = try_parse_spanned("(car\n  \"a b\" #\\c)", None).unwrap();
+        let spans: Vec<(usize, usize)> = tokens.iter().map(|&(_, ref span)| (span.line, span.column)).collect();
+        assert_eq!(spans, vec![(1, 1), (1, 2), (2, 3), (2, 9), (2, 12)]);
+        let tokens = try_parse_spanned("\n (f)", Some("input.wsp")).unwrap();
+        assert_eq!(format!("{}", tokens[1].1), "input.wsp:2:3");
+        assert_eq!(parse("(guard)")[1], Token::Guard);
+        assert_eq!(parse("(delay-force (delay x))")[1..4], [Token::DelayForce, Token::OpenParen, Token::Delay]);
+    }
+
     #[test]
     fn valid_ops() {
         parse("(+ 3 4.0)");
@@ -154,10 +439,51 @@ mod test {
         parse("(+ ??? 4)");
     }
 
+    #[test]
+    fn invalid_token_error() {
+        assert!(try_parse("(+ ??? 4)").is_err());
+        assert!(try_parse("\"abc").is_err());
+        assert!(try_parse("(+ 3 4)").is_ok());
+    }
+
     #[test]
     #[should_panic]
     fn leading_digit() {
         parse("(+ 4add 4)");
     }
 
+    #[test]
+    fn defmacro_test() {
+        let tokens = parse("(defmacro m (x) x)");
+        let expected_tokens = vec![Token::OpenParen, Token::Defmacro, Token::Identifier(Symbol::intern("m").unwrap()),
+            Token::OpenParen, Token::Identifier(Symbol::intern("x").unwrap()), Token::CloseParen,
+            Token::Identifier(Symbol::intern("x").unwrap()), Token::CloseParen];
+        assert_eq!(tokens, expected_tokens);
+    }
+
+    #[test]
+    fn quote_test() {
+        let tokens = parse("'a `(b ,c ,@d)");
+        let expected_tokens = vec![Token::Quote, Token::Identifier(Symbol::intern("a").unwrap()),
+            Token::Quasiquote, Token::OpenParen, Token::Identifier(Symbol::intern("b").unwrap()),
+            Token::Unquote, Token::Identifier(Symbol::intern("c").unwrap()),
+            Token::UnquoteSplicing, Token::Identifier(Symbol::intern("d").unwrap()), Token::CloseParen];
+        assert_eq!(tokens, expected_tokens);
+    }
+
+    #[test]
+    fn keyword_prefix_is_identifier() {
+        let tokens = parse("(undefined diff macroexpand-1)");
+        let expected_tokens = vec![Token::OpenParen, Token::Identifier(Symbol::intern("undefined").unwrap()),
+            Token::Identifier(Symbol::intern("diff").unwrap()), Token::Identifier(Symbol::intern("macroexpand-1").unwrap()),
+            Token::CloseParen];
+        assert_eq!(tokens, expected_tokens);
+    }
+
+    #[test]
+    fn trailing_token() {
+        let tokens = parse("-42");
+        assert_eq!(tokens, vec![Token::Number(-42.0)]);
+    }
+
 }
@@ -0,0 +1,59 @@
+/* lib.rs
+ *
+ * Wisp as a library.  Interpreter is the embedding interface, the
+ * lexer, parser and evaluator are exposed for lower level use
+ */
+// The codebase favours explicit returns, derefs, unit return types and ref patterns
+#![allow(clippy::needless_return, clippy::explicit_auto_deref, clippy::unused_unit,
+         clippy::needless_borrowed_reference)]
+extern crate corosensei;
+#[macro_use] extern crate lazy_static;
+extern crate regex;
+extern crate stacker;
+extern crate wisp_derive;
+// Code generated by the derives refers to ::wisp, which must resolve within this crate too
+extern crate self as wisp;
+
+pub mod cancel;
+pub mod compiler;
+pub mod conditions;
+pub mod continuations;
+pub mod convert;
+pub mod error;
+pub mod frames;
+pub mod generators;
+pub mod hashtables;
+pub mod heap;
+pub mod hosts;
+pub mod interpreter;
+pub mod lexer;
+pub mod limits;
+pub mod lists;
+pub mod natives;
+pub mod optimizer;
+pub mod parser;
+pub mod promises;
+pub mod resolver;
+pub mod eval;
+pub mod sandbox;
+pub mod streams;
+pub mod strings;
+pub mod symbols;
+pub mod system;
+pub mod vectors;
+pub mod vm;
+pub mod wspc;
+
+pub use cancel::CancelToken;
+pub use convert::{FromWisp, ToWisp};
+pub use error::Error;
+pub use frames::{Backtrace, Frame};
+pub use hosts::HostObject;
+pub use interpreter::Interpreter;
+pub use limits::Limits;
+pub use optimizer::Pass;
+pub use parser::AstNode;
+pub use sandbox::{Group, Policy};
+pub use symbols::Symbol;
+pub use vm::Backend;
+pub use wisp_derive::{FromWisp, ToWisp};
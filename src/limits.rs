@@ -0,0 +1,312 @@
+/* limits.rs
+ *
+ * Limits on the resources a script may use, so untrusted scripts
+ * can't run forever, exhaust memory or overflow the Rust stack
+ */
+use error::Error;
+use eval;
+use parser::AstNode;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/* Evaluation grows its stack on the heap, so the default only guards against runaway recursion */
+pub const DEFAULT_MAX_DEPTH: usize = 1000;
+
+/* None means unlimited.  Steps and allocations are counted
+ * per top level evaluation, depth is the number of nested calls,
+ * and also limits how deeply values and source may nest.
+ * Interned symbols are never freed, so the symbol limit is on how
+ * many new symbols the interpreter's scripts make with gensym or
+ * string->symbol over the life of the interpreter
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    pub max_steps: Option<u64>,
+    pub max_depth: Option<usize>,
+    pub max_allocations: Option<u64>,
+    pub max_symbols: Option<u64>,
+}
+
+impl Limits {
+    /* No limits at all, not even on depth, so recursion is only bounded by memory */
+    pub fn unlimited() -> Limits {
+        return Limits{max_steps: None, max_depth: None, max_allocations: None, max_symbols: None};
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        return Limits{max_steps: None, max_depth: Some(DEFAULT_MAX_DEPTH), max_allocations: None, max_symbols: None};
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Usage {
+    pub steps: u64,
+    pub depth: usize,
+    pub allocations: u64,
+    pub symbols: u64, // made by scripts, kept across top level evaluations
+}
+
+impl Usage {
+    pub fn step(&mut self, limits: &Limits) -> Result<(), Error> {
+        (*self).steps += 1;
+        return check("step", (*self).steps, limits.max_steps);
+    }
+    pub fn enter_call(&mut self, limits: &Limits) -> Result<(), Error> {
+        check("call depth", (*self).depth as u64 + 1, limits.max_depth.map(|x| x as u64))?;
+        (*self).depth += 1;
+        return Ok(());
+    }
+    pub fn exit_call(&mut self) -> () {
+        (*self).depth -= 1;
+    }
+    /* Values nothing else shares yet are new, so may be nested deeper than any before them */
+    pub fn allocate(&mut self, value: &AstNode, limits: &Limits) -> Result<(), Error> {
+        (*self).allocations += allocation_size(value);
+        check("allocation", (*self).allocations, limits.max_allocations)?;
+        let unshared = match *value {
+            AstNode::Expression(_) => true,
+            AstNode::Vector(ref elements) => Rc::strong_count(elements) == 1,
+            AstNode::HashTable(ref table) => Rc::strong_count(table) == 1,
+            _ => false
+        };
+        if unshared {
+            check_nesting(value, 1, limits)?;
+        }
+        return Ok(());
+    }
+    /* Counts another symbol if it fits in the limit, before it is made */
+    pub fn new_symbol(&mut self, limits: &Limits) -> Result<(), Error> {
+        check("symbol", (*self).symbols + 1, limits.max_symbols)?;
+        (*self).symbols += 1;
+        return Ok(());
+    }
+    /* Checks a value of the given allocation size fits in the limit, before it is made */
+    pub fn reserve(&self, size: u64, limits: &Limits) -> Result<(), Error> {
+        return check("allocation", (*self).allocations.saturating_add(size), limits.max_allocations);
+    }
+}
+
+/* Fails if lists, vectors and hash tables nest deeper than the depth limit in the value,
+ * counting from the level it is at, which is 1 for a value on its own and 2 for one
+ * being put in a vector or table.  Recursing over values nested deeper than that
+ * could overflow the stack
+ */
+pub fn check_nesting(value: &AstNode, level: usize, limits: &Limits) -> Result<(), Error> {
+    if let Some(limit) = limits.max_depth {
+        nesting(value, level, limit, &mut HashMap::new())?;
+    }
+    return Ok(());
+}
+
+/* How many levels of lists, vectors and tables the value holds, itself included.  Each
+ * vector and table is looked at once, and counts for nothing when found inside itself
+ */
+fn nesting(value: &AstNode, level: usize, limit: usize, seen: &mut HashMap<usize, usize>) -> Result<usize, Error> {
+    let address = match *value {
+        AstNode::Expression(_) => None,
+        AstNode::Vector(ref elements) => Some(Rc::as_ptr(elements) as *const u8 as usize),
+        AstNode::HashTable(ref table) => Some(Rc::as_ptr(table) as *const u8 as usize),
+        _ => return Ok(0)
+    };
+    if let Some(&levels) = address.and_then(|address| seen.get(&address)) {
+        check_nesting_depth((level + levels).saturating_sub(1), Some(limit))?;
+        return Ok(levels);
+    }
+    check_nesting_depth(level, Some(limit))?;
+    if let Some(address) = address {
+        seen.insert(address, 0);
+    }
+    let mut inner = 0;
+    let mut visit = |value: &AstNode| -> Result<(), Error> {
+        inner = inner.max(eval::with_stack(|| nesting(value, level + 1, limit, seen))??);
+        Ok(())
+    };
+    match *value {
+        AstNode::Expression(ref items) => items.iter().try_for_each(|item| visit(item))?,
+        // A value being changed can't be looked at, but is checked as it is changed
+        AstNode::Vector(ref elements) => {
+            if let Ok(elements) = elements.try_borrow() {
+                elements.iter().try_for_each(&mut visit)?;
+            }
+        },
+        AstNode::HashTable(ref table) => {
+            if let Ok(table) = table.try_borrow() {
+                table.entries().iter().try_for_each(|&(ref key, ref value)| {
+                    visit(key)?;
+                    visit(value)
+                })?;
+            }
+        },
+        _ => {}
+    }
+    if let Some(address) = address {
+        seen.insert(address, inner + 1);
+    }
+    return Ok(inner + 1);
+}
+
+/* Fails if source or a value nested depth levels deep is deeper than the limit */
+pub fn check_nesting_depth(depth: usize, limit: Option<usize>) -> Result<(), Error> {
+    return check("nesting depth", depth as u64, limit.map(|x| x as u64));
+}
+
+fn check(resource: &str, used: u64, limit: Option<u64>) -> Result<(), Error> {
+    match limit {
+        Some(limit) if used > limit => Err(Error::ResourceExhausted(format!("{} limit of {} exceeded", resource, limit))),
+        _ => Ok(())
+    }
+}
+
+/* A new value counts as one allocation, plus one for each element it holds */
+pub fn allocation_size(value: &AstNode) -> u64 {
+    let elements = match *value {
+        AstNode::Expression(ref items) => items.len(),
+        AstNode::Vector(ref elements) => elements.borrow().len(),
+        AstNode::HashTable(ref table) => table.borrow().len(),
+        AstNode::String(ref s) => s.len(),
+        _ => 0
+    };
+    return 1 + elements as u64;
+}
+
+#[cfg(test)]
+mod test {
+    use error::Error;
+    use interpreter::Interpreter;
+    use limits::{Limits, Usage};
+    use parser::AstNode;
+    use symbols::Symbol;
+
+    fn exhausted(result: Result<AstNode, Error>) -> String {
+        match result {
+            Err(Error::ResourceExhausted(message)) => message,
+            other => panic!("Expected resource exhausted error: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn usage_counts() {
+        let limits = Limits{max_steps: Some(2), max_depth: Some(1), max_allocations: Some(4), max_symbols: None};
+        let mut usage = Usage::default();
+        assert!(usage.step(&limits).is_ok());
+        assert!(usage.step(&limits).is_ok());
+        assert!(usage.step(&limits).is_err());
+        assert!(usage.enter_call(&limits).is_ok());
+        assert!(usage.enter_call(&limits).is_err());
+        usage.exit_call();
+        assert_eq!(usage.depth, 0);
+        assert!(usage.allocate(&AstNode::String(String::from("abc")), &limits).is_ok());
+        assert!(usage.allocate(&AstNode::Number(1.0), &limits).is_err());
+        let usage = Usage::default();
+        assert!(usage.reserve(4, &limits).is_ok());
+        assert!(usage.reserve(u64::MAX, &limits).is_err());
+        assert_eq!(usage.allocations, 0);
+    }
+
+    #[test]
+    fn infinite_recursion_is_stopped() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str("(define loop (lambda (n) (+ 1 (loop n))))").unwrap();
+        assert_eq!(exhausted(interpreter.eval_str("(loop 0)")), "call depth limit of 1000 exceeded");
+        // The interpreter is still usable afterwards
+        assert_eq!(interpreter.eval_str("(+ 1 2)"), Ok(AstNode::Number(3.0)));
+    }
+
+    #[test]
+    fn step_limit() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_limits(Limits{max_steps: Some(100), ..Limits::default()});
+        interpreter.eval_str("(define count (lambda (n) (if (= n 0) 0 (count (- n 1)))))").unwrap();
+        assert_eq!(interpreter.eval_str("(count 5)"), Ok(AstNode::Number(0.0)));
+        assert_eq!(exhausted(interpreter.eval_str("(count 50)")), "step limit of 100 exceeded");
+        // Steps are counted per evaluation
+        assert_eq!(interpreter.eval_str("(count 5)"), Ok(AstNode::Number(0.0)));
+    }
+
+    #[test]
+    fn allocation_limit() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_limits(Limits{max_allocations: Some(1000), ..Limits::default()});
+        interpreter.eval_str("(define grow (lambda (l) (grow (cons 1 l))))").unwrap();
+        assert_eq!(exhausted(interpreter.eval_str("(grow '())")), "allocation limit of 1000 exceeded");
+        assert!(interpreter.eval_str("(make-vector 5000)").is_err());
+        assert_eq!(interpreter.usage().depth, 0);
+    }
+
+    #[test]
+    fn allocations_are_checked_before_they_are_made() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_limits(Limits{max_allocations: Some(1000), ..Limits::default()});
+        // Far more than memory could hold, so only refusing before allocating gets here
+        assert_eq!(exhausted(interpreter.eval_str("(make-vector 4000000000 0)")), "allocation limit of 1000 exceeded");
+        interpreter.eval_str("(define s \"abcdefghijklmnopqrstuvwxyz\")").unwrap();
+        assert!(interpreter.eval_str("(string-replace s \"\" s)").is_ok());
+        assert_eq!(exhausted(interpreter.eval_str("(string-replace (string-append s s s s s s s s) \"\" s)")),
+                   "allocation limit of 1000 exceeded");
+        let words = vec!["s"; 40].join(" ");
+        assert_eq!(exhausted(interpreter.eval_str(&format!("(string-join (list {}))", words))),
+                   "allocation limit of 1000 exceeded");
+        assert_eq!(interpreter.eval_str("(vector-length (make-vector 10 0))"), Ok(AstNode::Number(10.0)));
+    }
+
+    #[test]
+    fn deep_recursion_within_the_limit() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_limits(Limits{max_depth: Some(3000), ..Limits::default()});
+        interpreter.eval_str("(define sum (lambda (n) (if (= n 0) 0 (+ n (sum (- n 1))))))").unwrap();
+        // Far deeper than the native stack of a test thread would allow
+        assert_eq!(interpreter.eval_str("(sum 2500)"), Ok(AstNode::Number(3126250.0)));
+    }
+
+    #[test]
+    fn symbol_limit() {
+        let make = "(define make (lambda (n) (if (= n 0) 'done (car (list (make (- n 1)) (gensym))))))";
+        let limited = || {
+            let mut interpreter = Interpreter::new();
+            interpreter.eval_str(make).unwrap();
+            interpreter.set_limits(Limits{max_symbols: Some(10), ..Limits::default()});
+            interpreter
+        };
+        assert_eq!(exhausted(limited().eval_str("(make 100)")), "symbol limit of 10 exceeded");
+        // Symbols made by other interpreters, or interned by parsing, don't count
+        let mut interpreter = limited();
+        let mut other = Interpreter::new();
+        other.eval_str(make).unwrap();
+        other.eval_str("(make 100) (string->symbol \"made-by-another-interpreter\") '(names never seen before)").unwrap();
+        assert_eq!(interpreter.eval_str("(make 10)"), Ok(AstNode::Identifier(Symbol::intern("done").unwrap())));
+        // Names already interned don't make new symbols, and what was made counts in later evaluations
+        assert!(interpreter.eval_str("(string->symbol \"make\")").is_ok());
+        assert_eq!(exhausted(interpreter.eval_str("(string->symbol \"a-name-never-seen-before\")")),
+                   "symbol limit of 10 exceeded");
+        assert_eq!(exhausted(interpreter.eval_str("(gensym)")), "symbol limit of 10 exceeded");
+        assert_eq!(interpreter.usage().symbols, 10);
+    }
+
+    #[test]
+    fn nesting_limit() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str("(define ones (lambda (n) (stream-cons n (ones n))))
+                              (define nest (lambda (wrap n) (fold-left (lambda (acc x) (wrap acc)) 0 (stream-take n (ones 1)))))").unwrap();
+        // Only a few calls deep, but more deeply nested than the limit
+        assert_eq!(exhausted(interpreter.eval_str("(nest vector 5000)")), "nesting depth limit of 1000 exceeded");
+        assert_eq!(exhausted(interpreter.eval_str("(nest list 5000)")), "nesting depth limit of 1000 exceeded");
+        assert_eq!(exhausted(interpreter.eval_str("(define v (vector 0)) (vector-set! v 0 (nest vector 1000))")),
+                   "nesting depth limit of 1000 exceeded");
+        assert!(format!("{:?}", interpreter.eval_str("(nest vector 1000)").unwrap()).starts_with("Vector"));
+        // Values inside themselves are as deep as the values they hold
+        assert!(interpreter.eval_str("(define t (make-hash-table)) (hash-set! t 'self t) (vector t t)").is_ok());
+        assert_eq!(interpreter.eval_str("(define v (vector 0)) (vector-set! v 0 (nest vector 998)) 1"), Ok(AstNode::Number(1.0)));
+    }
+
+    #[test]
+    fn deeply_nested_values_are_dropped() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_limits(Limits::unlimited());
+        interpreter.eval_str("(define ones (lambda (n) (stream-cons n (ones n))))").unwrap();
+        let vectors = interpreter.eval_str("(fold-left (lambda (acc x) (vector acc)) 0 (stream-take 50000 (ones 1)))");
+        // Far more deeply nested than the stack could drop one level at a time
+        assert!(vectors.is_ok());
+    }
+}
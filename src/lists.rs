@@ -0,0 +1,333 @@
+/* lists.rs
+ *
+ * Builtin higher-order list library.
+ * Function arguments may be lambdas or builtins, and are called through eval::apply.
+ * Generators can be given in place of lists: map, for-each and fold-left take their
+ * values one at a time, the others take all that remain
+ */
+use error::Error;
+use eval;
+use eval::Context;
+use generators;
+use hashtables;
+use parser::AstNode;
+use symbols::Symbol;
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+
+pub fn is_builtin(ident: &str) -> bool {
+    lazy_static! {
+        static ref BUILTINS: BTreeSet<&'static str> = ["map", "filter", "fold-left", "fold-right", "reduce",
+            "for-each", "apply", "assoc", "member", "sort"].iter().cloned().collect();
+    }
+    return BUILTINS.contains(ident);
+}
+
+fn check_arity(name: &str, args: &[Box<AstNode>], min: usize, max: usize) -> Result<(), Error> {
+    if args.len() < min || args.len() > max {
+        return Err(Error::Arity(format!("{} expects {} to {} arguments but got {}", name, min, max, args.len())));
+    }
+
+    return Ok(());
+}
+
+fn list_arg<'a>(name: &str, arg: &'a AstNode) -> Result<&'a [Box<AstNode>], Error> {
+    match *arg {
+        AstNode::Expression(ref list) => Ok(list),
+        ref other => Err(Error::Type(format!("{} expects a list: {:?}", name, other)))
+    }
+}
+
+/* A list argument, or the rest of a generator's values as a list */
+fn sequence_arg<'a>(name: &str, arg: &'a AstNode, context: &mut Context) -> Result<Cow<'a, [Box<AstNode>]>, Error> {
+    match *arg {
+        AstNode::Generator(ref generator) => Ok(Cow::Owned(generators::drain(generator, context)?)),
+        ref other => list_arg(name, other).map(Cow::Borrowed)
+    }
+}
+
+fn list(items: Vec<AstNode>) -> AstNode {
+    return AstNode::Expression(items.into_iter().map(Box::new).collect());
+}
+
+/* Anything other than false counts as true */
+fn is_true(value: &AstNode) -> bool {
+    return *value != AstNode::Bool(false);
+}
+
+/* Returns the elements at the position of each list, or None past the end of the shortest.
+ * Generators give their next value instead, so an infinite one can be zipped with a list.
+ * Rows are boxed as apply takes its arguments
+ */
+#[allow(clippy::vec_box)]
+fn row(name: &str, lists: &[Box<AstNode>], position: usize, context: &mut Context)
+    -> Result<Option<Vec<Box<AstNode>>>, Error>
+{
+    // Lists are checked first, so no generator is resumed for a row that won't be used
+    for l in lists.iter().filter(|l| !matches!(***l, AstNode::Generator(_))) {
+        if list_arg(name, l)?.len() <= position {
+            return Ok(None);
+        }
+    }
+    let mut row = Vec::with_capacity(lists.len());
+    for l in lists.iter() {
+        match **l {
+            AstNode::Generator(ref generator) => match generators::next(generator, context)? {
+                Some(value) => row.push(Box::new(value)),
+                None => return Ok(None)
+            },
+            _ => row.push(list_arg(name, l)?[position].clone())
+        }
+    }
+
+    return Ok(Some(row));
+}
+
+/* Returns the rows of elements at each position of the lists, stopping at the shortest list */
+#[allow(clippy::vec_box)]
+fn zip_lists(name: &str, lists: &[Box<AstNode>], context: &mut Context) -> Result<Vec<Vec<Box<AstNode>>>, Error> {
+    let mut rows = Vec::new();
+    while let Some(row) = row(name, lists, rows.len(), context)? {
+        rows.push(row);
+    }
+
+    return Ok(rows);
+}
+
+/* Stable merge sort that stops at the first comparator error.  Items are boxed as a list's elements are */
+#[allow(clippy::vec_box)]
+fn merge_sort(items: Vec<Box<AstNode>>, less: &AstNode, context: &mut Context) -> Result<Vec<Box<AstNode>>, Error> {
+    if items.len() <= 1 {
+        return Ok(items);
+    }
+    let mut left = items;
+    let right = left.split_off(left.len() / 2);
+    let left = merge_sort(left, less, context)?;
+    let right = merge_sort(right, less, context)?;
+
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    loop {
+        let take_right = match (left.peek(), right.peek()) {
+            // Only take from the right when it is strictly less, keeping equal elements in order
+            (Some(l), Some(r)) => is_true(&eval::apply(less, &[r.clone(), l.clone()], context)?),
+            (Some(_), None) => false,
+            (None, Some(_)) => true,
+            (None, None) => break
+        };
+        if take_right {
+            merged.push(right.next().unwrap());
+        }
+        else {
+            merged.push(left.next().unwrap());
+        }
+    }
+    return Ok(merged);
+}
+
+/* Apply the list builtin with the given name to the evaluated arguments */
+pub fn apply(ident: &str, args: &[Box<AstNode>], context: &mut Context) -> Result<AstNode, Error> {
+    match ident {
+        "map" => {
+            // (map f l1 l2 ...) stops at the end of the shortest list
+            if args.len() < 2 {
+                return Err(Error::Arity(format!("{} expects a function and at least 1 list", ident)));
+            }
+            let mut result = Vec::new();
+            while let Some(row) = row(ident, &args[1..], result.len(), context)? {
+                result.push(eval::apply(&args[0], &row, context)?);
+            }
+            Ok(list(result))
+        },
+        "for-each" => {
+            if args.len() < 2 {
+                return Err(Error::Arity(format!("{} expects a function and at least 1 list", ident)));
+            }
+            let mut position = 0;
+            while let Some(row) = row(ident, &args[1..], position, context)? {
+                eval::apply(&args[0], &row, context)?;
+                position += 1;
+            }
+            Ok(list(vec![]))
+        },
+        "filter" => {
+            check_arity(ident, args, 2, 2)?;
+            let mut result = Vec::new();
+            for item in sequence_arg(ident, &args[1], context)?.iter() {
+                if is_true(&eval::apply(&args[0], ::std::slice::from_ref(item), context)?) {
+                    result.push(item.clone());
+                }
+            }
+            Ok(AstNode::Expression(result))
+        },
+        "fold-left" => {
+            // (fold-left f init l1 ...) calls (f acc x1 ...) from the front
+            if args.len() < 3 {
+                return Err(Error::Arity(format!("{} expects a function, an initial value and at least 1 list", ident)));
+            }
+            let mut acc = (*args[1]).clone();
+            let mut position = 0;
+            while let Some(row) = row(ident, &args[2..], position, context)? {
+                let mut call_args = vec![Box::new(acc)];
+                call_args.extend(row);
+                acc = eval::apply(&args[0], &call_args, context)?;
+                position += 1;
+            }
+            Ok(acc)
+        },
+        "fold-right" => {
+            // (fold-right f init l1 ...) calls (f x1 ... acc) from the back
+            if args.len() < 3 {
+                return Err(Error::Arity(format!("{} expects a function, an initial value and at least 1 list", ident)));
+            }
+            let mut acc = (*args[1]).clone();
+            for mut row in zip_lists(ident, &args[2..], context)?.into_iter().rev() {
+                row.push(Box::new(acc));
+                acc = eval::apply(&args[0], &row, context)?;
+            }
+            Ok(acc)
+        },
+        "reduce" => {
+            // (reduce f default l) folds (f x acc) over l starting from its first element
+            check_arity(ident, args, 3, 3)?;
+            let items = sequence_arg(ident, &args[2], context)?;
+            match items.split_first() {
+                Some((first, rest)) => {
+                    let mut acc = (**first).clone();
+                    for item in rest.iter() {
+                        acc = eval::apply(&args[0], &[item.clone(), Box::new(acc)], context)?;
+                    }
+                    Ok(acc)
+                },
+                None => Ok((*args[1]).clone())
+            }
+        },
+        "apply" => {
+            // (apply f a b '(c d)) calls (f a b c d)
+            if args.len() < 2 {
+                return Err(Error::Arity(format!("{} expects a function and a list of arguments", ident)));
+            }
+            let (last, leading) = args[1..].split_last().unwrap();
+            let mut call_args = leading.to_vec();
+            call_args.extend(sequence_arg(ident, last, context)?.iter().cloned());
+            eval::apply(&args[0], &call_args, context)
+        },
+        "assoc" => {
+            // Returns the first (key value ...) entry whose key is equal? to the given key, or false
+            check_arity(ident, args, 2, 2)?;
+            for entry in sequence_arg(ident, &args[1], context)?.iter() {
+                if list_arg(ident, entry)?.first().is_some_and(|key| hashtables::equal(key, &args[0])) {
+                    return Ok((**entry).clone());
+                }
+            }
+            Ok(AstNode::Bool(false))
+        },
+        "member" => {
+            // Returns the tail of the list starting at the first equal? element, or false
+            check_arity(ident, args, 2, 2)?;
+            let items = sequence_arg(ident, &args[1], context)?;
+            match items.iter().position(|item| hashtables::equal(item, &args[0])) {
+                Some(i) => Ok(AstNode::Expression(items[i..].to_vec())),
+                None => Ok(AstNode::Bool(false))
+            }
+        },
+        "sort" => {
+            // (sort l [less?]) sorts stably, with < as the default comparison
+            check_arity(ident, args, 1, 2)?;
+            let less = match args.get(1) {
+                Some(less) => (**less).clone(),
+                None => AstNode::Identifier(Symbol::keyword("<"))
+            };
+            let items = sequence_arg(ident, &args[0], context)?.into_owned();
+            Ok(AstNode::Expression(merge_sort(items, &less, context)?))
+        },
+        _ => Err(Error::Undefined(String::from(ident)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use error::Error;
+    use eval::Context;
+    use lists::apply;
+    use parser::AstNode;
+    use symbols::Symbol;
+
+    fn ident(name: &str) -> Box<AstNode> {
+        Box::new(AstNode::Identifier(Symbol::intern(name).unwrap()))
+    }
+
+    fn numbers(xs: &[f64]) -> Box<AstNode> {
+        Box::new(AstNode::Expression(xs.iter().map(|x| Box::new(AstNode::Number(*x))).collect()))
+    }
+
+    #[test]
+    fn map_multiple_lists() {
+        let mut c = Context::new();
+        let result = apply("map", &[ident("+"), numbers(&[1.0, 2.0, 3.0]), numbers(&[10.0, 20.0])], &mut c);
+        assert_eq!(result.unwrap(), *numbers(&[11.0, 22.0]));
+    }
+
+    #[test]
+    fn folds() {
+        let mut c = Context::new();
+        let left = apply("fold-left", &[ident("-"), Box::new(AstNode::Number(0.0)), numbers(&[1.0, 2.0, 3.0])], &mut c);
+        assert_eq!(left.unwrap(), AstNode::Number(-6.0));
+        let right = apply("fold-right", &[ident("-"), Box::new(AstNode::Number(0.0)), numbers(&[1.0, 2.0, 3.0])], &mut c);
+        assert_eq!(right.unwrap(), AstNode::Number(2.0));
+        let cons = apply("fold-right", &[ident("cons"), numbers(&[]), numbers(&[1.0, 2.0])], &mut c);
+        assert_eq!(cons.unwrap(), *numbers(&[1.0, 2.0]));
+    }
+
+    #[test]
+    fn reduce() {
+        let mut c = Context::new();
+        let sum = apply("reduce", &[ident("+"), Box::new(AstNode::Number(0.0)), numbers(&[1.0, 2.0, 3.0])], &mut c);
+        assert_eq!(sum.unwrap(), AstNode::Number(6.0));
+        let empty = apply("reduce", &[ident("+"), Box::new(AstNode::Number(0.0)), numbers(&[])], &mut c);
+        assert_eq!(empty.unwrap(), AstNode::Number(0.0));
+    }
+
+    #[test]
+    fn apply_spreads_last_list() {
+        let mut c = Context::new();
+        let result = apply("apply", &[ident("*"), Box::new(AstNode::Number(2.0)), numbers(&[3.0, 4.0])], &mut c);
+        assert_eq!(result.unwrap(), AstNode::Number(24.0));
+        assert!(apply("apply", &[ident("*"), Box::new(AstNode::Number(2.0))], &mut c).is_err());
+    }
+
+    #[test]
+    fn member_and_assoc() {
+        let mut c = Context::new();
+        let member = apply("member", &[Box::new(AstNode::Number(2.0)), numbers(&[1.0, 2.0, 3.0])], &mut c);
+        assert_eq!(member.unwrap(), *numbers(&[2.0, 3.0]));
+        let missing = apply("member", &[Box::new(AstNode::Number(5.0)), numbers(&[1.0])], &mut c);
+        assert_eq!(missing.unwrap(), AstNode::Bool(false));
+        let alist = Box::new(AstNode::Expression(vec![numbers(&[1.0, 10.0]), numbers(&[2.0, 20.0])]));
+        let entry = apply("assoc", &[Box::new(AstNode::Number(2.0)), alist.clone()], &mut c);
+        assert_eq!(entry.unwrap(), *numbers(&[2.0, 20.0]));
+        let missing = apply("assoc", &[Box::new(AstNode::Number(3.0)), alist], &mut c);
+        assert_eq!(missing.unwrap(), AstNode::Bool(false));
+    }
+
+    #[test]
+    fn sort_default_and_custom() {
+        let mut c = Context::new();
+        let sorted = apply("sort", &[numbers(&[3.0, 1.0, 2.0, 1.0])], &mut c);
+        assert_eq!(sorted.unwrap(), *numbers(&[1.0, 1.0, 2.0, 3.0]));
+        let reversed = apply("sort", &[numbers(&[3.0, 1.0, 2.0]), ident(">")], &mut c);
+        assert_eq!(reversed.unwrap(), *numbers(&[3.0, 2.0, 1.0]));
+    }
+
+    #[test]
+    fn sort_comparator_error() {
+        let mut c = Context::new();
+        let mixed = Box::new(AstNode::Expression(vec![Box::new(AstNode::Number(1.0)),
+                                                      Box::new(AstNode::String(String::from("a")))]));
+        match apply("sort", &[mixed], &mut c) {
+            Err(Error::Type(_)) => {},
+            other => panic!("Expected type error: {:?}", other)
+        }
+    }
+}
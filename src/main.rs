@@ -1,34 +1,122 @@
-#[macro_use] extern crate lazy_static;
-extern crate regex;
+// Explicit returns, as in the wisp crate
+#![allow(clippy::needless_return, clippy::unused_unit)]
+extern crate wisp;
 
-use std::io::prelude::*;
-use std::fs::File;
-use std::io::Error;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+use wisp::parser::unparse;
+use wisp::{wspc, AstNode, Error, Interpreter, Pass, Policy};
 
-pub mod lexer;
-pub mod parser;
-pub mod eval;
+const USAGE: &str = "usage: wisp [-O] [file.wsp | file.wspc]
+       wisp disasm [-O] file.wsp
+       wisp compile [-O] file.wsp [out.wspc]
+       wisp optimize file.wsp";
 
-fn read_file(filename: &str) -> Result<String, Error> {
-	let mut f = try!(File::open(filename));
-	let mut s = String::new();
-	try!(f.read_to_string(&mut s));
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut args: Vec<&str> = args.iter().map(|x| x.as_str()).collect();
+    // -O enables every optimization pass
+    let optimize = args.contains(&"-O");
+    args.retain(|&arg| arg != "-O");
+    // Scripts run from the command line are the user's own, so may use the whole system
+    let mut interpreter = Interpreter::new();
+    interpreter.set_policy(Policy::allow_all());
+    if optimize {
+        interpreter.set_passes(Pass::all());
+    }
+    let result = match args[..] {
+        [] => run_source(interpreter, "input.wsp"),
+        ["disasm", filename] => disasm(interpreter, filename),
+        ["compile", filename] => {
+            compile(interpreter, filename, &Path::new(filename).with_extension("wspc").display().to_string())
+        },
+        ["compile", filename, out] => compile(interpreter, filename, out),
+        ["optimize", filename] => dump_passes(filename),
+        [filename] if filename.ends_with(".wspc") => run_compiled(interpreter, filename),
+        [filename] => run_source(interpreter, filename),
+        _ => {
+            println!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = result {
+        println!("Error: {}", e);
+        process::exit(1);
+    }
+}
 
-	return Ok(s);
+fn read_source(filename: &str) -> Result<String, Error> {
+    return fs::read_to_string(filename).map_err(|e| Error::Io(format!("{}: {}", filename, e)));
 }
 
-fn main() {
-	let result = read_file("input.wsp");
-	let s = result.expect("Unable to read file");
-	println!("{}", s);
-	let tokens = lexer::parse(s.as_str());
-    let mut token_iter = tokens.into_iter().peekable();
+fn report(interpreter: &Interpreter, result: Result<AstNode, Error>) -> () {
+    match result {
+        Ok(value) => println!("{:?}", value),
+        Err(Error::Exit(status)) => process::exit(status),
+        Err(e) => {
+            println!("Error: {}", e);
+            for frame in interpreter.backtrace().unwrap_or_default().frames {
+                println!("    {}", frame);
+            }
+        }
+    }
+}
+
+fn run_source(mut interpreter: Interpreter, filename: &str) -> Result<(), Error> {
+    let s = read_source(filename)?;
+    println!("{}", s);
 
     // Parse and evaluate each expression until end of file
-    let mut c = eval::Context::new();
-    while let Some(_) = token_iter.peek() {
-        let mut ast = parser::parse(&mut token_iter);
-        eval::eval(&mut ast, &mut c);
-        println!("{:?}", ast);
+    let program = interpreter.parse_source(s.as_str(), filename)?;
+    for unbound in interpreter.unbound_variables(&program) {
+        println!("Warning: {}", unbound);
+    }
+    for ast in program {
+        let result = interpreter.eval_ast(ast);
+        report(&interpreter, result);
+    }
+    return Ok(());
+}
+
+/* Compiled files are run by the vm without lexing or parsing */
+fn run_compiled(mut interpreter: Interpreter, filename: &str) -> Result<(), Error> {
+    for chunk in wspc::read_file(filename)? {
+        let result = interpreter.eval_chunk(chunk);
+        report(&interpreter, result);
+    }
+    return Ok(());
+}
+
+fn disasm(mut interpreter: Interpreter, filename: &str) -> Result<(), Error> {
+    let chunks = interpreter.compile_source(&read_source(filename)?, filename)?;
+    for (i, chunk) in chunks.iter().enumerate() {
+        println!("expression {}:", i + 1);
+        println!("{}", interpreter.disassemble(chunk));
+    }
+    return Ok(());
+}
+
+fn compile(mut interpreter: Interpreter, filename: &str, out: &str) -> Result<(), Error> {
+    let chunks = interpreter.compile_source(&read_source(filename)?, filename)?;
+    wspc::write_file(out, &chunks)?;
+    println!("Wrote {} expressions to {}", chunks.len(), out);
+    return Ok(());
+}
+
+/* Each expression as written and after every pass that changed it */
+fn dump_passes(filename: &str) -> Result<(), Error> {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_passes(Pass::all());
+    let program = interpreter.parse_source(&read_source(filename)?, filename)?;
+    for (i, ast) in program.iter().enumerate() {
+        println!("expression {}:", i + 1);
+        println!("    {}", unparse(ast));
+        for (pass, optimized) in interpreter.trace_passes(ast)? {
+            println!("after {}:", pass.name());
+            println!("    {}", unparse(&optimized));
+        }
     }
+    return Ok(());
 }
@@ -0,0 +1,256 @@
+/* natives.rs
+ *
+ * Builtins implemented as Rust closures and registered by name.
+ * The arithmetic and comparison operators are the default natives,
+ * embedders register their own through Interpreter::register_fn
+ */
+use convert::{within, FromWisp, IntoWispResult};
+use error::Error;
+use eval::Context;
+use parser::AstNode;
+use std::cmp::Ordering;
+use hosts;
+use symbols::Symbol;
+use std::any::TypeId;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
+
+/* A native receives its evaluated arguments along with the calling context */
+pub type NativeFn = Rc<dyn Fn(&[Box<AstNode>], &mut Context) -> Result<AstNode, Error>>;
+
+#[derive(Clone)]
+pub struct Natives {
+    functions: HashMap<Symbol, NativeFn>,
+    methods: BTreeMap<String, Vec<(TypeId, NativeFn)>>,
+    defaults: HashSet<Symbol>, // names still bound to their default native
+}
+
+impl Natives {
+    pub fn new() -> Natives {
+        return Natives{functions: HashMap::new(), methods: BTreeMap::new(), defaults: HashSet::new()};
+    }
+    pub fn with_defaults() -> Natives {
+        let mut natives = Natives::new();
+        register_defaults(&mut natives);
+        natives.defaults = natives.functions.keys().cloned().collect();
+        return natives;
+    }
+    /* Replaces any native already registered with the name */
+    pub fn insert(&mut self, name: Symbol, function: NativeFn) -> () {
+        (*self).defaults.remove(&name);
+        (*self).functions.insert(name, function);
+    }
+    /* Whether the name is a default native that hasn't been replaced */
+    pub fn is_default(&self, name: Symbol) -> bool {
+        return (*self).defaults.contains(&name);
+    }
+    pub fn get(&self, name: Symbol) -> Option<NativeFn> {
+        return (*self).functions.get(&name).cloned();
+    }
+    pub fn contains(&self, name: Symbol) -> bool {
+        return (*self).functions.contains_key(&name);
+    }
+    /* Methods of host object types share a native that dispatches on the
+     * type of the first argument.  It replaces any native with the same name
+     */
+    pub fn insert_method(&mut self, name: Symbol, type_id: TypeId, method: NativeFn) -> () {
+        let methods = (*self).methods.entry(String::from(name.as_str())).or_default();
+        methods.retain(|&(id, _)| id != type_id);
+        methods.push((type_id, method));
+        (*self).defaults.remove(&name);
+        let dispatch_name = String::from(name.as_str());
+        (*self).functions.insert(name, Rc::new(move |args: &[Box<AstNode>], context: &mut Context| {
+            hosts::dispatch(&dispatch_name, args, context)
+        }));
+    }
+    pub fn method(&self, name: &str, type_id: TypeId) -> Option<NativeFn> {
+        let methods = (*self).methods.get(name)?;
+        return methods.iter().find(|&&(id, _)| id == type_id).map(|&(_, ref method)| method.clone());
+    }
+}
+
+impl Default for Natives {
+    fn default() -> Natives {
+        return Natives::new();
+    }
+}
+
+impl fmt::Debug for Natives {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names: Vec<&Symbol> = (*self).functions.keys().collect();
+        names.sort();
+        f.debug_set().entries(names).finish()
+    }
+}
+
+/* Rust closures that can be registered as natives, with their
+ * arguments converted by FromWisp and their result by IntoWispResult
+ */
+pub trait IntoNative<Args> {
+    fn into_native(self, name: &str) -> NativeFn;
+}
+
+macro_rules! impl_into_native {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg),*> IntoNative<($($arg,)*)> for F
+            where F: Fn($($arg),*) -> R + 'static, R: IntoWispResult, $($arg: FromWisp),*
+        {
+            fn into_native(self, name: &str) -> NativeFn {
+                let name = String::from(name);
+                let params: &[&str] = &[$(stringify!($arg)),*];
+                let count = params.len();
+                return Rc::new(move |args: &[Box<AstNode>], _: &mut Context| {
+                    if args.len() != count {
+                        return Err(Error::Arity(format!("{} expects {} argument(s) but got {}",
+                                                        name, count, args.len())));
+                    }
+                    #[allow(unused_variables, unused_mut)]
+                    let mut args = args.iter().enumerate();
+                    return (self)($({
+                        let (i, arg) = args.next().unwrap();
+                        $arg::from_wisp(arg).map_err(|e| within(e, &format!("argument {} of {}", i + 1, name)))?
+                    }),*).into_wisp_result();
+                });
+            }
+        }
+    }
+}
+
+impl_into_native!();
+impl_into_native!(A);
+impl_into_native!(A, B);
+impl_into_native!(A, B, C);
+impl_into_native!(A, B, C, D);
+impl_into_native!(A, B, C, D, E);
+
+
+fn reduce<F>(name: &str, args: &[Box<AstNode>], f: F) -> Result<AstNode, Error>
+    where F: Fn(f64, f64) -> f64
+{
+    let mut sum: f64;
+    match args.first().map(|x| &**x) {
+        Some(&AstNode::Number(x)) => sum = x,
+        Some(other) => return Err(Error::Type(format!("Invalid number arg: {:?}", other))),
+        None => return Err(Error::Arity(format!("{} expects at least 1 argument", name))),
+    }
+    for arg in args[1..].iter() {
+        match **arg {
+            AstNode::Number(x) => sum = f(x, sum),
+            _ => return Err(Error::Type(format!("Invalid number arg: {:?}", arg))),
+        }
+    }
+
+    return Ok(AstNode::Number(sum));
+}
+
+/* Check that each adjacent pair of numbers, strings or characters is ordered as f expects */
+fn compare<F>(name: &str, args: &[Box<AstNode>], f: F) -> Result<AstNode, Error>
+    where F: Fn(Ordering) -> bool
+{
+    if args.is_empty() {
+        return Err(Error::Arity(format!("{} expects at least 1 argument", name)));
+    }
+    let mut result = true;
+    for pair in args.windows(2) {
+        let ordering = match (&*pair[0], &*pair[1]) {
+            (&AstNode::Number(a), &AstNode::Number(b)) => a.partial_cmp(&b),
+            (&AstNode::String(ref a), &AstNode::String(ref b)) => Some(a.cmp(b)),
+            (&AstNode::Char(a), &AstNode::Char(b)) => Some(a.cmp(&b)),
+            (a, b) => return Err(Error::Type(format!("{} can't compare {:?} and {:?}", name, a, b)))
+        };
+        // NaN is unordered, so every comparison with it is false
+        result = result && ordering.is_some_and(&f);
+    }
+
+    return Ok(AstNode::Bool(result));
+}
+
+fn register_arithmetic(natives: &mut Natives, name: &'static str, f: fn(f64, f64) -> f64) -> () {
+    natives.insert(Symbol::keyword(name), Rc::new(move |args: &[Box<AstNode>], _: &mut Context| reduce(name, args, f)));
+}
+
+/* Division by zero is an error rather than infinity */
+fn divide(args: &[Box<AstNode>]) -> Result<AstNode, Error> {
+    if args.iter().skip(1).any(|x| **x == AstNode::Number(0.0)) {
+        return Err(Error::Arithmetic(String::from("division by zero")));
+    }
+
+    return reduce("/", args, |x, prod| prod / x);
+}
+
+fn register_comparison(natives: &mut Natives, name: &'static str, f: fn(Ordering) -> bool) -> () {
+    natives.insert(Symbol::keyword(name), Rc::new(move |args: &[Box<AstNode>], _: &mut Context| compare(name, args, f)));
+}
+
+/* The variadic arithmetic and comparison operators */
+fn register_defaults(natives: &mut Natives) -> () {
+    register_arithmetic(natives, "+", |x, sum| sum + x);
+    register_arithmetic(natives, "*", |x, prod| prod * x);
+    register_arithmetic(natives, "-", |x, sum| sum - x);
+    natives.insert(Symbol::keyword("/"), Rc::new(|args: &[Box<AstNode>], _: &mut Context| divide(args)));
+    register_comparison(natives, "=", |o| o == Ordering::Equal);
+    register_comparison(natives, "<", |o| o == Ordering::Less);
+    register_comparison(natives, ">", |o| o == Ordering::Greater);
+    register_comparison(natives, "<=", |o| o != Ordering::Greater);
+    register_comparison(natives, ">=", |o| o != Ordering::Less);
+}
+
+#[cfg(test)]
+mod test {
+    use error::Error;
+    use eval::Context;
+    use natives::{IntoNative, Natives};
+    use parser::AstNode;
+    use symbols::Symbol;
+
+    fn call(natives: &Natives, name: &str, args: Vec<AstNode>) -> Result<AstNode, Error> {
+        let args: Vec<Box<AstNode>> = args.into_iter().map(Box::new).collect();
+        return natives.get(Symbol::intern(name).unwrap()).unwrap()(&args, &mut Context::new());
+    }
+
+    #[test]
+    fn defaults() {
+        let natives = Natives::with_defaults();
+        assert_eq!(call(&natives, "-", vec![AstNode::Number(10.0), AstNode::Number(3.0)]), Ok(AstNode::Number(7.0)));
+        assert_eq!(call(&natives, "<", vec![AstNode::Number(1.0), AstNode::Number(2.0)]), Ok(AstNode::Bool(true)));
+        let plus = Symbol::intern("+").unwrap();
+        assert!(!Natives::new().contains(plus.clone()));
+        assert_eq!(call(&natives, "/", vec![AstNode::Number(1.0), AstNode::Number(0.0)]),
+                   Err(Error::Arithmetic(String::from("division by zero"))));
+        let mut natives = natives.clone();
+        assert!(natives.is_default(plus.clone()) && !natives.is_default(Symbol::intern("car").unwrap()));
+        natives.insert(Symbol::intern("+").unwrap(), (|a: String, b: String| a + &b).into_native("+"));
+        assert!(!natives.is_default(plus));
+    }
+
+    #[test]
+    fn closures_convert_arguments() {
+        let mut natives = Natives::new();
+        natives.insert(Symbol::intern("repeat").unwrap(), (|s: String, n: i64| s.repeat(n as usize)).into_native("repeat"));
+        natives.insert(Symbol::intern("sum").unwrap(), (|xs: Vec<f64>| xs.iter().sum::<f64>()).into_native("sum"));
+        natives.insert(Symbol::intern("answer").unwrap(), (|| 42i64).into_native("answer"));
+        let result = call(&natives, "repeat", vec![AstNode::String(String::from("ab")), AstNode::Number(2.0)]);
+        assert_eq!(result, Ok(AstNode::String(String::from("abab"))));
+        let list = AstNode::Expression(vec![Box::new(AstNode::Number(1.5)), Box::new(AstNode::Number(2.0))]);
+        assert_eq!(call(&natives, "sum", vec![list]), Ok(AstNode::Number(3.5)));
+        assert_eq!(call(&natives, "answer", vec![]), Ok(AstNode::Number(42.0)));
+    }
+
+    #[test]
+    fn closure_errors() {
+        let mut natives = Natives::new();
+        natives.insert(Symbol::intern("not").unwrap(), (|b: bool| !b).into_native("not"));
+        natives.insert(Symbol::intern("checked").unwrap(), (|x: f64| if x < 0.0 { Err(Error::Type(String::from("negative"))) } else { Ok(x) })
+                       .into_native("checked"));
+        match call(&natives, "not", vec![AstNode::Number(1.0)]) {
+            Err(Error::Type(message)) => assert!(message.ends_with("in argument 1 of not"), "{}", message),
+            other => panic!("Expected type error: {:?}", other)
+        }
+        match call(&natives, "not", vec![]) {
+            Err(Error::Arity(_)) => {},
+            other => panic!("Expected arity error: {:?}", other)
+        }
+        assert_eq!(call(&natives, "checked", vec![AstNode::Number(-1.0)]), Err(Error::Type(String::from("negative"))));
+    }
+}
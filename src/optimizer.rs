@@ -0,0 +1,541 @@
+/* optimizer.rs
+ *
+ * Rewrites ASTs before they are evaluated or compiled.  Each pass can be
+ * enabled on its own, and the enabled passes run in order, repeatedly,
+ * until none of them changes anything.  Programs give the same values and
+ * errors, but not the same step counts or backtraces: folded calls take no
+ * steps and inlined functions are called as lambdas.
+ *
+ * Scoping is dynamic, and macros or the host can bind a function again, so
+ * an inlined call first checks the name is still bound to the lambda that
+ * was inlined, and otherwise calls whatever it is bound to.  The bindings
+ * of a whole program are noted before any of it is optimized, so a
+ * function it defines again isn't inlined
+ */
+use error::Error;
+use eval;
+use eval::Context;
+use parser::AstNode;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::rc::Rc;
+use strings;
+use symbols::Symbol;
+
+const INLINE_LIMIT: usize = 32; // the most nodes the body of an inlined function may have
+const MAX_ROUNDS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Pass {
+    Inlining, // Inlining, replace the names of small top level functions by their lambdas where called
+    BetaReduction, // BetaReduction, substitute literal or variable arguments into the body of a called lambda
+    ConstantFolding, // ConstantFolding, evaluate calls of pure builtins on literals
+    DeadBranches, // DeadBranches, replace an if with a literal predicate by the branch it takes
+}
+
+impl Pass {
+    /* Every pass, in the order they run */
+    pub fn all() -> &'static [Pass] {
+        return &[Pass::Inlining, Pass::BetaReduction, Pass::ConstantFolding, Pass::DeadBranches];
+    }
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Pass::Inlining => "inlining",
+            Pass::BetaReduction => "beta-reduction",
+            Pass::ConstantFolding => "constant-folding",
+            Pass::DeadBranches => "dead-branches",
+        }
+    }
+}
+
+/* Builtins whose result depends only on their arguments, and which take and give atoms */
+fn is_pure(name: &str) -> bool {
+    lazy_static! {
+        static ref PURE: BTreeSet<&'static str> = ["+", "-", "*", "/", "=", "<", ">", "<=", ">=", "equal?",
+            "string-length", "string-append", "substring", "string-ref", "string-upcase", "string-downcase",
+            "string-contains", "string-replace", "string->number", "number->string", "char->integer",
+            "integer->char", "char-alphabetic?", "char-numeric?", "char-whitespace?"].iter().cloned().collect();
+    }
+    return PURE.contains(name);
+}
+
+/* Builtins that can't call back into the program or look up its variables */
+fn is_inert(name: Symbol, context: &Context) -> bool {
+    return context.is_standard(name.clone()) &&
+        (is_pure(&name) || strings::is_builtin(&name) || ["list", "cons", "car", "cdr"].contains(&name.as_str()));
+}
+
+/* Values that evaluate to themselves and can be written in source */
+fn is_literal(ast: &AstNode) -> bool {
+    return matches!(*ast, AstNode::Number(_) | AstNode::Bool(_) | AstNode::String(_) | AstNode::Char(_));
+}
+
+/* The passes to run, and what is known of the program from the code optimized so far */
+#[derive(Debug, Clone, Default)]
+pub struct Optimizer {
+    passes: BTreeSet<Pass>,
+    bindings: HashMap<Symbol, usize>, // how often each name is defined or bound as a parameter
+    functions: HashMap<Symbol, AstNode>, // the lambdas of functions defined at the top level
+    macros: HashSet<Symbol>,
+    noted: VecDeque<AstNode>, // the expressions to be optimized next, whose bindings are noted already
+}
+
+impl Optimizer {
+    pub fn new() -> Optimizer {
+        return Optimizer::default();
+    }
+    /* Inlining only knows of functions defined after it is enabled */
+    pub fn set_passes(&mut self, passes: &[Pass]) -> () {
+        (*self).passes = passes.iter().cloned().collect();
+    }
+    pub fn is_enabled(&self) -> bool {
+        return !(*self).passes.is_empty();
+    }
+
+    /* Note the bindings of the program, whose expressions are to be optimized in turn */
+    pub fn note_program(&mut self, program: &[AstNode]) -> () {
+        (*self).noted.clear();
+        for ast in program.iter() {
+            (*self).note(ast);
+        }
+        (*self).noted = program.iter().cloned().collect();
+    }
+
+    pub fn optimize(&mut self, ast: &AstNode, context: &Context) -> Result<AstNode, Error> {
+        return match (*self).trace(ast, context)?.pop() {
+            Some((_, optimized)) => Ok(optimized),
+            None => Ok(ast.clone())
+        };
+    }
+
+    /* The AST after each pass that changed it, in the order they ran */
+    pub fn trace(&mut self, ast: &AstNode, context: &Context) -> Result<Vec<(Pass, AstNode)>, Error> {
+        // Unless the AST is the next of a noted program, the rest of that program won't be optimized
+        if (*self).noted.pop_front().as_ref() != Some(ast) {
+            (*self).noted.clear();
+            (*self).note(ast);
+        }
+        let mut steps: Vec<(Pass, AstNode)> = Vec::new();
+        let mut current = ast.clone();
+        let passes: Vec<Pass> = Pass::all().iter().cloned().filter(|pass| (*self).passes.contains(pass)).collect();
+        for _ in 0..MAX_ROUNDS {
+            let mut changed = false;
+            for &pass in passes.iter() {
+                let mut rewriter = Rewriter{optimizer: self, context, scratch: None, pass, changed: false};
+                let rewritten = rewriter.rewrite(&current, &mut Vec::new());
+                if rewriter.changed {
+                    current = rewritten;
+                    steps.push((pass, current.clone()));
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        if let AstNode::Define(ref name, ref value) = current {
+            if let AstNode::Lambda(..) = **value {
+                (*self).functions.insert(name.clone(), (**value).clone());
+            }
+        }
+        return Ok(steps);
+    }
+
+    /* Count the names the AST binds */
+    fn note(&mut self, ast: &AstNode) -> () {
+        let mut names = Vec::new();
+        note_bindings(ast, &mut names, &mut (*self).macros);
+        for name in names.into_iter() {
+            *(*self).bindings.entry(name).or_insert(0) += 1;
+        }
+    }
+
+    /* The lambda of a function that can be inlined: defined only once and not recursive */
+    fn inlinable(&self, name: Symbol) -> Option<&AstNode> {
+        if (*self).bindings.get(&name) != Some(&1) {
+            return None;
+        }
+        match (*self).functions.get(&name) {
+            Some(lambda @ &AstNode::Lambda(_, ref body)) if size(body) <= INLINE_LIMIT && !mentions(body, &name) =>
+                Some(lambda),
+            _ => None
+        }
+    }
+}
+
+/* Every name the AST binds, once for each time it binds it */
+fn note_bindings(ast: &AstNode, names: &mut Vec<Symbol>, macros: &mut HashSet<Symbol>) -> () {
+    match *ast {
+        AstNode::Define(ref name, _) => names.push(name.clone()),
+        AstNode::Lambda(ref params, _) => names.extend(params.iter().cloned()),
+        AstNode::Defmacro(ref name, ref params, _) => {
+            names.push(name.clone());
+            names.extend(params.iter().cloned());
+            macros.insert(name.clone());
+        },
+        AstNode::Guard(ref variable, _, _) => names.push(variable.clone()),
+        _ => {}
+    }
+    match *ast {
+        AstNode::Expression(ref items) => items.iter().for_each(|item| note_bindings(item, names, macros)),
+        AstNode::Define(_, ref value) | AstNode::Located(_, ref value) => note_bindings(value, names, macros),
+        AstNode::Lambda(_, ref body) => note_bindings(body, names, macros),
+        AstNode::Defmacro(_, _, ref body) => note_bindings(body, names, macros),
+        AstNode::If(ref pred, ref true_expr, ref false_expr) => {
+            note_bindings(pred, names, macros);
+            note_bindings(true_expr, names, macros);
+            note_bindings(false_expr, names, macros);
+        },
+        AstNode::Guard(_, ref clauses, ref body) => {
+            clauses.iter().flat_map(|clause| clause.iter()).chain(body.iter())
+                .for_each(|item| note_bindings(item, names, macros));
+        },
+        _ => {}
+    }
+}
+
+fn size(ast: &AstNode) -> usize {
+    return 1 + match *ast {
+        AstNode::Expression(ref items) => items.iter().map(|item| size(item)).sum(),
+        AstNode::Define(_, ref value) | AstNode::Located(_, ref value) => size(value),
+        AstNode::Lambda(_, ref body) => size(body),
+        AstNode::If(ref pred, ref true_expr, ref false_expr) => size(pred) + size(true_expr) + size(false_expr),
+        AstNode::Guard(_, ref clauses, ref body) =>
+            clauses.iter().flat_map(|clause| clause.iter()).chain(body.iter()).map(|item| size(item)).sum(),
+        _ => 0
+    };
+}
+
+/* Whether the identifier appears anywhere in the AST, even quoted */
+fn mentions(ast: &AstNode, name: &Symbol) -> bool {
+    match *ast {
+        AstNode::Identifier(ref ident) => ident == name,
+        AstNode::Expression(ref items) => items.iter().any(|item| mentions(item, name)),
+        AstNode::Define(ref ident, ref value) => ident == name || mentions(value, name),
+        AstNode::Defmacro(ref ident, ref params, ref body) =>
+            ident == name || params.contains(name) || mentions(body, name),
+        AstNode::Lambda(ref params, ref body) => params.contains(name) || mentions(body, name),
+        AstNode::If(ref pred, ref true_expr, ref false_expr) =>
+            mentions(pred, name) || mentions(true_expr, name) || mentions(false_expr, name),
+        AstNode::Quote(ref datum) | AstNode::Quasiquote(ref datum) | AstNode::Located(_, ref datum) =>
+            mentions(datum, name),
+        AstNode::Guard(ref variable, ref clauses, ref body) => variable == name ||
+            clauses.iter().flat_map(|clause| clause.iter()).chain(body.iter()).any(|item| mentions(item, name)),
+        _ => false
+    }
+}
+
+/* One pass over an AST, rewriting children before their parents */
+struct Rewriter<'a> {
+    optimizer: &'a Optimizer,
+    context: &'a Context,
+    scratch: Option<Context>, // where constants are folded, so folding leaves no trace in the real context
+    pass: Pass,
+    changed: bool,
+}
+
+impl<'a> Rewriter<'a> {
+    /* scope holds the variables bound around the AST, by lambdas and guards */
+    fn rewrite(&mut self, ast: &AstNode, scope: &mut Vec<Symbol>) -> AstNode {
+        let node = match *ast {
+            AstNode::Expression(ref items) if (*self).is_macro_call(items) => return ast.clone(),
+            AstNode::Expression(ref items) =>
+                AstNode::Expression(items.iter().map(|item| Box::new((*self).rewrite(item, scope))).collect()),
+            AstNode::Define(ref name, ref value) => AstNode::Define(name.clone(), Box::new((*self).rewrite(value, scope))),
+            AstNode::Lambda(ref params, ref body) => {
+                let outer = scope.len();
+                scope.extend(params.iter().cloned());
+                let body = (*self).rewrite(body, scope);
+                scope.truncate(outer);
+                AstNode::Lambda(params.clone(), Rc::new(body))
+            },
+            // The check of an inlined call must keep its lambda, and the call it falls back to isn't inlined again
+            AstNode::If(ref pred, ref true_expr, ref false_expr) if is_inline_check(pred) => {
+                AstNode::If(pred.clone(), Box::new((*self).rewrite(true_expr, scope)), false_expr.clone())
+            },
+            AstNode::If(ref pred, ref true_expr, ref false_expr) => {
+                AstNode::If(Box::new((*self).rewrite(pred, scope)), Box::new((*self).rewrite(true_expr, scope)),
+                            Box::new((*self).rewrite(false_expr, scope)))
+            },
+            AstNode::Guard(ref variable, ref clauses, ref body) => {
+                let body = body.iter().map(|item| Box::new((*self).rewrite(item, scope))).collect();
+                scope.push(variable.clone());
+                let clauses = clauses.iter().map(|clause| {
+                    clause.iter().map(|item| Box::new((*self).rewrite(item, scope))).collect()
+                }).collect();
+                scope.pop();
+                AstNode::Guard(variable.clone(), clauses, body)
+            },
+            AstNode::Located(ref span, ref expr) => AstNode::Located(span.clone(), Box::new((*self).rewrite(expr, scope))),
+            // Quoted data, macros and literals are left as they are
+            ref other => return other.clone()
+        };
+        let rewritten = match (*self).pass {
+            Pass::Inlining => (*self).inline(&node, scope),
+            Pass::BetaReduction => (*self).reduce(&node, scope),
+            Pass::ConstantFolding => (*self).fold(&node),
+            Pass::DeadBranches => prune(&node),
+        };
+        match rewritten {
+            Some(rewritten) => {
+                (*self).changed = true;
+                return rewritten;
+            },
+            None => return node
+        }
+    }
+
+    /* The arguments of macro calls are data to the macro, so aren't rewritten */
+    fn is_macro_call(&self, items: &[Box<AstNode>]) -> bool {
+        match items.first().map(|x| &**x) {
+            Some(&AstNode::Identifier(ref name)) => (*self).optimizer.macros.contains(name) || (*self).context.is_macro(name.clone()),
+            _ => false
+        }
+    }
+
+    /* (sq n) becomes (if (eq? sq <lambda>) (<lambda> n) (sq n)), which gives the same value
+     * however sq is bound when it runs.  The call is written twice, so must be small
+     */
+    fn inline(&mut self, node: &AstNode, scope: &[Symbol]) -> Option<AstNode> {
+        if let AstNode::Expression(ref items) = *node {
+            if let Some(&AstNode::Identifier(ref name)) = items.first().map(|x| &**x) {
+                if !scope.contains(name) && !(*self).context.is_builtin(name.clone()) && size(node) <= INLINE_LIMIT &&
+                    (*self).context.is_standard(Symbol::keyword("eq?")) {
+                    let lambda = (*self).optimizer.inlinable(name.clone())?;
+                    let check = vec![Box::new(AstNode::Identifier(Symbol::keyword("eq?"))),
+                                     Box::new(AstNode::Identifier(name.clone())), Box::new(lambda.clone())];
+                    let mut inlined = items.clone();
+                    *inlined[0] = lambda.clone();
+                    return Some(AstNode::If(Box::new(AstNode::Expression(check)), Box::new(AstNode::Expression(inlined)),
+                                            Box::new(node.clone())));
+                }
+            }
+        }
+        return None;
+    }
+
+    /* ((lambda (x) (* x x)) n) becomes (* n n).  The body may only call inert builtins, so
+     * nothing can look up the parameters by name, and the arguments must be literals or
+     * bound variables, which can be evaluated any number of times without failing
+     */
+    fn reduce(&self, node: &AstNode, scope: &[Symbol]) -> Option<AstNode> {
+        let items = match *node {
+            AstNode::Expression(ref items) => items,
+            _ => return None
+        };
+        let (params, body) = match items.first().map(|x| &**x) {
+            Some(&AstNode::Lambda(ref params, ref body)) => (params, body),
+            _ => return None
+        };
+        let args = &items[1..];
+        let trivial = |arg: &AstNode| match *arg {
+            AstNode::Identifier(ref name) => scope.contains(name),
+            AstNode::Quote(_) => true,
+            ref other => is_literal(other)
+        };
+        if params.len() != args.len() || !(*self).is_inert(body) || !args.iter().map(|x| &**x).all(trivial) {
+            return None;
+        }
+        let bindings: Vec<(Symbol, &AstNode)> = params.iter().cloned().zip(args.iter().map(|x| &**x)).collect();
+        return Some((*self).substitute(body, &bindings));
+    }
+
+    fn is_inert(&self, ast: &AstNode) -> bool {
+        match *ast {
+            AstNode::Identifier(_) | AstNode::Quote(_) => true,
+            AstNode::Located(_, ref expr) => (*self).is_inert(expr),
+            AstNode::If(ref pred, ref true_expr, ref false_expr) =>
+                (*self).is_inert(pred) && (*self).is_inert(true_expr) && (*self).is_inert(false_expr),
+            AstNode::Expression(ref items) => match items.split_first() {
+                Some((head, args)) => match **head {
+                    AstNode::Identifier(ref name) => is_inert(name.clone(), (*self).context) && args.iter().all(|x| (*self).is_inert(x)),
+                    _ => false
+                },
+                None => true
+            },
+            ref other => is_literal(other)
+        }
+    }
+
+    /* Builtins evaluate to themselves even where a parameter has their name, so aren't replaced */
+    fn substitute(&self, ast: &AstNode, bindings: &[(Symbol, &AstNode)]) -> AstNode {
+        match *ast {
+            AstNode::Identifier(ref name) if !(*self).context.is_builtin(name.clone()) => {
+                match bindings.iter().find(|&&(ref param, _)| param == name) {
+                    Some(&(_, arg)) => arg.clone(),
+                    None => ast.clone()
+                }
+            },
+            AstNode::Located(ref span, ref expr) => AstNode::Located(span.clone(), Box::new((*self).substitute(expr, bindings))),
+            AstNode::If(ref pred, ref true_expr, ref false_expr) => {
+                AstNode::If(Box::new((*self).substitute(pred, bindings)), Box::new((*self).substitute(true_expr, bindings)),
+                            Box::new((*self).substitute(false_expr, bindings)))
+            },
+            AstNode::Expression(ref items) =>
+                AstNode::Expression(items.iter().map(|item| Box::new((*self).substitute(item, bindings))).collect()),
+            ref other => other.clone()
+        }
+    }
+
+    /* Calls that fail are left to fail when evaluated, with their span and backtrace */
+    fn fold(&mut self, node: &AstNode) -> Option<AstNode> {
+        match *node {
+            AstNode::Located(_, ref expr) if is_literal(expr) => return Some((**expr).clone()),
+            AstNode::Expression(ref items) => {
+                let (head, args) = items.split_first()?;
+                match **head {
+                    AstNode::Identifier(ref name) if is_pure(name) && (*self).context.is_standard(name.clone()) => {},
+                    _ => return None
+                }
+                if !args.iter().all(|arg| is_literal(arg)) {
+                    return None;
+                }
+                let scratch = (*self).scratch.get_or_insert_with(Context::new);
+                match eval::apply(head, args, scratch) {
+                    Ok(ref value) if is_literal(value) => return Some(value.clone()),
+                    _ => return None
+                }
+            },
+            _ => return None
+        }
+    }
+}
+
+/* Whether the predicate is (eq? name <lambda>), as inlining writes */
+fn is_inline_check(pred: &AstNode) -> bool {
+    match *pred {
+        AstNode::Expression(ref items) if items.len() == 3 => match (&*items[0], &*items[1], &*items[2]) {
+            (&AstNode::Identifier(ref eq), &AstNode::Identifier(_), &AstNode::Lambda(..)) => eq.as_str() == "eq?",
+            _ => false
+        },
+        _ => false
+    }
+}
+
+fn prune(node: &AstNode) -> Option<AstNode> {
+    match *node {
+        AstNode::If(ref pred, ref true_expr, ref false_expr) => match **pred {
+            AstNode::Bool(true) => Some((**true_expr).clone()),
+            AstNode::Bool(false) => Some((**false_expr).clone()),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use interpreter::Interpreter;
+    use optimizer::Pass;
+    use parser::{unparse, AstNode};
+    use std::rc::Rc;
+    use symbols::Symbol;
+    use vm::Backend;
+
+    /* The source of each expression after the given passes */
+    fn optimized(source: &str, passes: &[Pass]) -> Vec<String> {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_passes(passes);
+        let program = interpreter.parse_str(source).unwrap();
+        return program.iter().map(|ast| {
+            let steps = interpreter.trace_passes(ast).unwrap();
+            unparse(steps.last().map(|&(_, ref ast)| ast).unwrap_or(ast))
+        }).collect();
+    }
+
+    #[test]
+    fn fold_constants() {
+        assert_eq!(optimized("(define f (lambda (x) (* x (* 60 60 24))))", &[Pass::ConstantFolding]),
+                   vec!["(define f (lambda (x) (* x 86400)))"]);
+        assert_eq!(optimized("(string-append \"a\" (number->string (+ 1 2)))", &[Pass::ConstantFolding]),
+                   vec!["\"a3\""]);
+        // Calls that fail, or whose arguments aren't literals, are kept
+        assert_eq!(optimized("(define g (lambda (x) (list (/ 1 0) (+ x 1) (car '(1)))))", &[Pass::ConstantFolding]),
+                   vec!["(define g (lambda (x) (list (/ 1 0) (+ x 1) (car (quote (1))))))"]);
+    }
+
+    #[test]
+    fn prune_dead_branches() {
+        let passes = [Pass::ConstantFolding, Pass::DeadBranches];
+        assert_eq!(optimized("(lambda (x) (if (< 1 2) x (car x)))", &passes), vec!["(lambda (x) x)"]);
+        assert_eq!(optimized("(lambda (x) (if 1 x 2))", &passes), vec!["(lambda (x) (if 1 x 2))"]);
+        assert_eq!(optimized("(lambda (x) (if (< 1 2) x 2))", &[Pass::DeadBranches]),
+                   vec!["(lambda (x) (if (< 1 2) x 2))"]);
+    }
+
+    #[test]
+    fn inline_and_reduce() {
+        let passes = Pass::all();
+        let source = "(define square (lambda (x) (* x x)))
+(define area (lambda (r) (* 3 (square r))))
+(define fact (lambda (n) (if (= n 0) 1 (* n (fact (- n 1))))))
+(define g (lambda (x) (fact (square 2))))
+((lambda (y) (list y y)) (car '(1)))";
+        assert_eq!(optimized(source, passes), vec![
+            "(define square (lambda (x) (* x x)))",
+            "(define area (lambda (r) (* 3 (if (eq? square (lambda (x) (* x x))) (* r r) (square r)))))",
+            "(define fact (lambda (n) (if (= n 0) 1 (* n (fact (- n 1))))))",
+            "(define g (lambda (x) (fact (if (eq? square (lambda (x) (* x x))) 4 (square 2)))))",
+            // Arguments that could fail or are costly aren't substituted
+            "((lambda (y) (list y y)) (car (quote (1))))"]);
+    }
+
+    #[test]
+    fn rebound_functions_are_not_inlined() {
+        let source = "(define square (lambda (x) (* x x)))
+(define f (lambda (square) (square 2)))
+(define g (lambda () (square 2)))";
+        assert_eq!(optimized(source, Pass::all())[2], "(define g (lambda () (square 2)))");
+        // Nor are the arguments of macros
+        let source = "(defmacro q (x) (list 'quote x)) (q (+ 1 2))";
+        assert_eq!(optimized(source, Pass::all())[1], "(q (+ 1 2))");
+    }
+
+    #[test]
+    fn redefined_functions_are_not_inlined() {
+        let source = "(define sq (lambda (x) (* x x)))
+(define g (lambda () (sq 3)))
+(define sq (lambda (x) 0))
+(g)";
+        let mut interpreter = Interpreter::new();
+        interpreter.set_passes(Pass::all());
+        assert_eq!(interpreter.eval_str(source), Ok(AstNode::Number(0.0)));
+        // Once inlined, a function bound again by later code, a macro or the host is called as bound
+        let mut interpreter = Interpreter::new();
+        interpreter.set_passes(Pass::all());
+        interpreter.eval_str("(define sq (lambda (x) (* x x))) (define g (lambda () (sq 3)))").unwrap();
+        assert_eq!(interpreter.eval_str("(g)"), Ok(AstNode::Number(9.0)));
+        assert_eq!(interpreter.eval_str("((lambda (sq) (g)) (lambda (x) 1))"), Ok(AstNode::Number(1.0)));
+        interpreter.eval_str("(defmacro redef () '(define sq (lambda (x) 0))) (redef)").unwrap();
+        assert_eq!(interpreter.eval_str("(g)"), Ok(AstNode::Number(0.0)));
+        interpreter.set_global("sq", AstNode::Lambda(vec![Symbol::intern("x").unwrap()], Rc::new(AstNode::Number(2.0)))).unwrap();
+        assert_eq!(interpreter.eval_str("(g)"), Ok(AstNode::Number(2.0)));
+        let source = "(define sq (lambda (x) (* x x)))
+(define g (lambda () (sq 3)))
+(defmacro redef () '(define sq (lambda (x) 0)))
+(redef)
+(g)";
+        for &backend in Backend::all().iter() {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_backend(backend);
+            interpreter.set_passes(Pass::all());
+            assert_eq!(interpreter.eval_str(source), Ok(AstNode::Number(0.0)));
+        }
+    }
+
+    #[test]
+    fn optimized_programs_agree() {
+        let source = "(define square (lambda (x) (* x x)))
+(define sum-squares (lambda (a b) (+ (square a) (square b))))
+(sum-squares 3 (* 2 2))
+(guard (e (else (error-object-message e))) (square (/ 1 0)))
+(if (< (square 2) 5) 'small 'large)";
+        let mut results = vec![];
+        for passes in [&[][..], Pass::all()].iter() {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_passes(passes);
+            results.push(interpreter.eval_str(source));
+        }
+        assert_eq!(results[0], results[1]);
+        assert_eq!(results[1], Ok(AstNode::Identifier(Symbol::intern("small").unwrap())));
+    }
+}
@@ -1,160 +1,783 @@
 /* parser.rs
  *
  * Takes a Vec of Tokens and returns an AST
- *
+ * try_parse returns an error for invalid syntax or expressions nested
+ * deeper than the depth limit, parse panics
  */
+// The parse functions declare what they parse before assigning it
+#![allow(clippy::needless_late_init)]
 
 
 /* exp := ( (exp|IDENT) (exp|Number|Identifier)*
  */
-use lexer::Token;
+use error::Error;
+use eval;
+use conditions::ErrorObject;
+use continuations::Continuation;
+use generators::Generator;
+use hashtables::{new_hash_table, HashTable};
+use hosts::HostObject;
+use promises;
+use promises::Promise;
+use lexer::{Span, Token};
+use limits;
+use limits::Limits;
+use symbols::Symbol;
+use vectors::new_vector;
+use std::cell::RefCell;
 use std::iter::Peekable;
+use std::mem;
+use std::rc::Rc;
 
 /* TODO: Add lambdas */
 #[derive(Debug, PartialEq, PartialOrd, Clone)]
 pub enum AstNode {
     Expression(Vec<Box<AstNode>>), // Expression(list of arguments)
-    Define(String, Box<AstNode>), // Define(name, value)
-    Lambda(Vec<String>, Box<AstNode>), // lambda(list of parameter identifiers, expr)
+    Define(Symbol, Box<AstNode>), // Define(name, value)
+    Defmacro(Symbol, Vec<Symbol>, Box<AstNode>), // Defmacro(name, parameter identifiers, expr)
+    Lambda(Vec<Symbol>, Rc<AstNode>), // lambda(list of parameter identifiers, shared expr)
+    Macro(Vec<Symbol>, Box<AstNode>), // Macro(list of parameter identifiers, expr)
     If(Box<AstNode>, Box<AstNode>, Box<AstNode>), // If (pred, true expr, false expr)
+    Quote(Box<AstNode>), // Quote(datum)
+    Quasiquote(Box<AstNode>), // Quasiquote(datum template)
+    Vector(Rc<RefCell<Vec<AstNode>>>), // Vector(shared mutable elements)
+    HashTable(Rc<RefCell<HashTable>>), // HashTable(shared mutable table)
+    Host(HostObject), // Host(opaque value owned by the embedding program)
+    Guard(Symbol, Vec<Vec<Box<AstNode>>>, Vec<Box<AstNode>>), // Guard(condition variable, cond clauses, body)
+    ErrorObject(Rc<ErrorObject>), // ErrorObject(raised condition)
+    Continuation(Rc<Continuation>), // Continuation(escape back to a call/cc)
+    Generator(Rc<Generator>), // Generator(coroutine producing values for next)
+    Promise(Rc<Promise>), // Promise(delayed value, computed once when forced)
+    Located(Span, Box<AstNode>), // Located(where the expression starts in the source, expr)
+    Local(Symbol, usize, usize), // Local(name, depth, index), a variable the resolver found a slot for, in a resolved lambda body
     Bool(bool),
+    Char(char),
     Number(f64),
     String(String),
-    Identifier(String)
+    Identifier(Symbol)
+}
+
+/* Values can nest far deeper than the stack would allow dropping them one level
+ * at a time, such as a list built up in a loop or a walked stream, which is a chain
+ * of promises.  Whatever a value alone holds is taken out and dropped in a loop
+ */
+impl Drop for AstNode {
+    fn drop(&mut self) -> () {
+        let mut values = Vec::new();
+        take_contents(self, &mut values);
+        while let Some(mut value) = values.pop() {
+            take_contents(&mut value, &mut values);
+        }
+    }
+}
+
+fn taken(node: &mut AstNode) -> AstNode {
+    return mem::replace(node, AstNode::Bool(false));
+}
+
+/* Move what the value holds, and nothing else shares, into values */
+fn take_contents(value: &mut AstNode, values: &mut Vec<AstNode>) -> () {
+    match *value {
+        AstNode::Expression(ref mut items) => values.extend(mem::take(items).into_iter().map(|item| *item)),
+        AstNode::Define(_, ref mut value) | AstNode::Defmacro(_, _, ref mut value) | AstNode::Macro(_, ref mut value) |
+        AstNode::Quote(ref mut value) | AstNode::Quasiquote(ref mut value) | AstNode::Located(_, ref mut value) => {
+            values.push(taken(value))
+        },
+        AstNode::If(ref mut pred, ref mut true_expr, ref mut false_expr) => {
+            values.extend(vec![taken(pred), taken(true_expr), taken(false_expr)])
+        },
+        AstNode::Guard(_, ref mut clauses, ref mut body) => {
+            values.extend(mem::take(clauses).into_iter().flatten().chain(mem::take(body)).map(|item| *item))
+        },
+        AstNode::Lambda(_, ref mut body) => values.extend(Rc::get_mut(body).map(taken)),
+        // The heap keeps weak references to vectors and tables, so only the strong count says whether they are shared
+        AstNode::Vector(ref elements) if Rc::strong_count(elements) == 1 => {
+            if let Ok(mut elements) = elements.try_borrow_mut() {
+                values.extend(mem::take(&mut *elements));
+            }
+        },
+        AstNode::HashTable(ref table) if Rc::strong_count(table) == 1 => {
+            if let Ok(mut table) = table.try_borrow_mut() {
+                values.extend(mem::take(&mut *table).into_entries().into_iter().flat_map(|(key, value)| vec![key, value]));
+            }
+        },
+        AstNode::Promise(ref mut promise) => values.extend(Rc::get_mut(promise).and_then(promises::take_value)),
+        _ => {}
+    }
+}
+
+/* Tokens, optionally paired with where they start in the source */
+pub trait TokenItem {
+    fn token(&self) -> &Token;
+    fn into_token(self) -> Token;
+    fn span(&self) -> Option<Span>;
+}
+
+impl TokenItem for Token {
+    fn token(&self) -> &Token {
+        return self;
+    }
+    fn into_token(self) -> Token {
+        return self;
+    }
+    fn span(&self) -> Option<Span> {
+        return None;
+    }
+}
+
+impl TokenItem for (Token, Span) {
+    fn token(&self) -> &Token {
+        return &self.0;
+    }
+    fn into_token(self) -> Token {
+        return self.0;
+    }
+    fn span(&self) -> Option<Span> {
+        return Some(self.1.clone());
+    }
+}
+
+/* How deeply the expression being parsed is nested, so source can't nest deeper than the stack allows */
+#[derive(Debug, Clone, Copy)]
+pub struct Nesting {
+    depth: usize,
+    limit: Option<usize>,
+}
+
+impl Nesting {
+    /* The nesting one level further in */
+    fn enter(self) -> Result<Nesting, Error> {
+        limits::check_nesting_depth(self.depth + 1, self.limit)?;
+        return Ok(Nesting{depth: self.depth + 1, limit: self.limit});
+    }
 }
 
-pub fn parse_lambda<I>(tokens: &mut Peekable<I>) -> AstNode
-    where I: Iterator<Item=Token>
+fn next_token<I>(tokens: &mut Peekable<I>) -> Option<Token>
+    where I: Iterator, I::Item: TokenItem
 {
-    let mut args: Vec<String> = Vec::new();
-    let expr: Box<AstNode>;
+    return tokens.next().map(TokenItem::into_token);
+}
+
+fn peek_token<I>(tokens: &mut Peekable<I>) -> Option<&Token>
+    where I: Iterator, I::Item: TokenItem
+{
+    return tokens.peek().map(TokenItem::token);
+}
+
+fn syntax_error<T>(message: String) -> Result<T, Error> {
+    return Err(Error::Syntax(message));
+}
+
+fn parse_parameters<I>(tokens: &mut Peekable<I>) -> Result<Vec<Symbol>, Error>
+    where I: Iterator, I::Item: TokenItem
+{
+    let mut args: Vec<Symbol> = Vec::new();
 
-    if let Some(Token::OpenParen) = (*tokens).next() {
-        while let Some(token) = (*tokens).next() {
+    if let Some(Token::OpenParen) = next_token(tokens) {
+        while let Some(token) = next_token(tokens) {
             match token  {
                 Token::CloseParen => break,
-                Token::Identifier(ident) => args.push(ident.clone()),
-                other => panic!("Lambda arguments must be identifiers: {:?}", other)
+                Token::Identifier(ident) => args.push(ident),
+                other => return syntax_error(format!("Lambda arguments must be identifiers: {:?}", other))
             }
         }
     }
     else {
-        panic!("Invalid syntax for lambda");
+        return syntax_error(String::from("Invalid syntax for lambda"));
     }
-    expr = Box::new(parse(tokens));
+
+    return Ok(args);
+}
+
+pub fn parse_lambda<I>(tokens: &mut Peekable<I>, nesting: Nesting) -> Result<AstNode, Error>
+    where I: Iterator, I::Item: TokenItem
+{
+    let args: Vec<Symbol>;
+    let expr: Rc<AstNode>;
+
+    args = parse_parameters(tokens)?;
+    expr = Rc::new(parse_nested(tokens, nesting)?);
 
     // Consume CloseParen
-    match (*tokens).next().unwrap() {
-        Token::CloseParen => AstNode::Lambda(args, expr),
-        _ => panic!("too many arguments for lambda! Expected CloseParen")
+    match next_token(tokens) {
+        Some(Token::CloseParen) => Ok(AstNode::Lambda(args, expr)),
+        _ => syntax_error(String::from("too many arguments for lambda! Expected CloseParen"))
     }
 }
 
-pub fn parse_if<I>(tokens: &mut Peekable<I>) -> AstNode
-    where I: Iterator<Item=Token>
+pub fn parse_if<I>(tokens: &mut Peekable<I>, nesting: Nesting) -> Result<AstNode, Error>
+    where I: Iterator, I::Item: TokenItem
 {
     let pred: Box<AstNode>;
     let if_path: Box<AstNode>;
     let else_path: Box<AstNode>;
 
-    pred = Box::new(parse(tokens));
-    if_path = Box::new(parse(tokens));
-    else_path = Box::new(parse(tokens));
+    pred = Box::new(parse_nested(tokens, nesting)?);
+    if_path = Box::new(parse_nested(tokens, nesting)?);
+    else_path = Box::new(parse_nested(tokens, nesting)?);
     // Consume CloseParen
-    match (*tokens).next().unwrap() {
-        Token::CloseParen => {},
-        token => panic!("Expected close paren in if statement but found: {:?}", token)
+    match next_token(tokens) {
+        Some(Token::CloseParen) => {},
+        token => return syntax_error(format!("Expected close paren in if statement but found: {:?}", token))
     }
 
-    return AstNode::If(pred, if_path, else_path);
+    return Ok(AstNode::If(pred, if_path, else_path));
 }
 
-pub fn parse_define<I>(tokens: &mut Peekable<I>) -> AstNode
-    where I: Iterator<Item=Token>
+pub fn parse_define<I>(tokens: &mut Peekable<I>, nesting: Nesting) -> Result<AstNode, Error>
+    where I: Iterator, I::Item: TokenItem
 {
-    let identifier: String;
+    let identifier: Symbol;
     let value: Box<AstNode>;
 
-    if let Some(token) = (*tokens).next() {
+    if let Some(token) = next_token(tokens) {
         if let Token::Identifier(ident) = token {
            identifier = ident;
         }
         else {
-            panic!("Define arg 1 expected to be an identifier: {:?}", token);
+            return syntax_error(format!("Define arg 1 expected to be an identifier: {:?}", token));
         }
     }
     else {
-        panic!("Unexpected end of token stream");
+        return syntax_error(String::from("Unexpected end of token stream"));
+    }
+    value = Box::new(parse_nested(tokens, nesting)?);
+    // Consume CloseParen
+    next_token(tokens);
+
+    return Ok(AstNode::Define(identifier, value));
+}
+
+pub fn parse_defmacro<I>(tokens: &mut Peekable<I>, nesting: Nesting) -> Result<AstNode, Error>
+    where I: Iterator, I::Item: TokenItem
+{
+    let identifier: Symbol;
+    let args: Vec<Symbol>;
+    let expr: Box<AstNode>;
+
+    match next_token(tokens) {
+        Some(Token::Identifier(ident)) => identifier = ident,
+        Some(token) => return syntax_error(format!("Defmacro arg 1 expected to be an identifier: {:?}", token)),
+        None => return syntax_error(String::from("Unexpected end of token stream"))
+    }
+    args = parse_parameters(tokens)?;
+    expr = Box::new(parse_nested(tokens, nesting)?);
+
+    // Consume CloseParen
+    match next_token(tokens) {
+        Some(Token::CloseParen) => Ok(AstNode::Defmacro(identifier, args, expr)),
+        token => syntax_error(format!("Expected close paren in defmacro but found: {:?}", token))
+    }
+}
+
+/* (guard (var clause...) body...), where each clause is a cond clause: (test expr...),
+ * (test => receiver) or (else expr...)
+ */
+pub fn parse_guard<I>(tokens: &mut Peekable<I>, nesting: Nesting) -> Result<AstNode, Error>
+    where I: Iterator, I::Item: TokenItem
+{
+    let variable: Symbol;
+    let mut clauses: Vec<Vec<Box<AstNode>>> = Vec::new();
+    let mut body: Vec<Box<AstNode>> = Vec::new();
+
+    if let Some(Token::OpenParen) = next_token(tokens) {
+        match next_token(tokens) {
+            Some(Token::Identifier(ident)) => variable = ident,
+            token => return syntax_error(format!("Guard expected a condition variable but found: {:?}", token))
+        }
+    }
+    else {
+        return syntax_error(String::from("Invalid syntax for guard"));
+    }
+    loop {
+        match next_token(tokens) {
+            Some(Token::CloseParen) => break,
+            Some(Token::OpenParen) => match parse_exp(tokens, nesting)? {
+                AstNode::Expression(ref clause) if !clause.is_empty() => clauses.push(clause.clone()),
+                _ => return syntax_error(String::from("Guard clauses can't be empty"))
+            },
+            token => return syntax_error(format!("Guard clauses must be lists: {:?}", token))
+        }
+    }
+    loop {
+        match peek_token(tokens) {
+            Some(&Token::CloseParen) => break,
+            None => return syntax_error(String::from("Unexpected end of token stream")),
+            _ => body.push(Box::new(parse_nested(tokens, nesting)?))
+        }
+    }
+    // Consume CloseParen
+    next_token(tokens);
+    if body.is_empty() {
+        return syntax_error(String::from("Guard expects a body"));
+    }
+
+    return Ok(AstNode::Guard(variable, clauses, body));
+}
+
+/* (define-generator (name params...) body) defines name as a function making a
+ * generator, whose body is (lambda (params...) body) applied to the function's arguments
+ */
+fn define_generator(name: Symbol, params: Vec<Symbol>, body: AstNode) -> AstNode {
+    let mut call = vec![Box::new(symbol("make-generator")), Box::new(AstNode::Lambda(params.clone(), Rc::new(body)))];
+    call.extend(params.iter().map(|param| Box::new(AstNode::Identifier(param.clone()))));
+    return AstNode::Define(name, Box::new(AstNode::Lambda(params, Rc::new(AstNode::Expression(call)))));
+}
+
+pub fn parse_define_generator<I>(tokens: &mut Peekable<I>, nesting: Nesting) -> Result<AstNode, Error>
+    where I: Iterator, I::Item: TokenItem
+{
+    let mut header = parse_parameters(tokens)?;
+    if header.is_empty() {
+        return syntax_error(String::from("define-generator expects a name"));
+    }
+    let name = header.remove(0);
+    let body = parse_nested(tokens, nesting)?;
+
+    // Consume CloseParen
+    match next_token(tokens) {
+        Some(Token::CloseParen) => Ok(define_generator(name, header, body)),
+        token => syntax_error(format!("Expected close paren in define-generator but found: {:?}", token))
+    }
+}
+
+/* The names an expression may look up, other than the standard builtins and quoted data, in the order they first appear */
+fn variables(ast: &AstNode, names: &mut Vec<Symbol>) -> () {
+    match *ast {
+        AstNode::Identifier(ref name) if !name.is_standard_builtin() && !names.contains(name) => {
+            names.push(name.clone())
+        },
+        AstNode::Expression(ref items) => items.iter().for_each(|item| variables(item, names)),
+        AstNode::Define(_, ref value) | AstNode::Located(_, ref value) | AstNode::Quasiquote(ref value) => {
+            variables(value, names)
+        },
+        AstNode::Lambda(_, ref body) => variables(body, names),
+        AstNode::If(ref pred, ref true_expr, ref false_expr) => {
+            variables(pred, names);
+            variables(true_expr, names);
+            variables(false_expr, names);
+        },
+        AstNode::Guard(_, ref clauses, ref body) => {
+            clauses.iter().flat_map(|clause| clause.iter()).chain(body.iter()).for_each(|item| variables(item, names))
+        },
+        _ => {}
+    }
+}
+
+/* (delay expr) becomes (#:delay (lambda () expr) 'names...), naming the variables expr uses.
+ * Scoping is dynamic, so the promise keeps the values they have in the calls it is made
+ * in, for when it is forced.  delay-force is the same but for #:delay-force
+ */
+fn delay(builtin: &'static str, expr: AstNode) -> AstNode {
+    let mut names = Vec::new();
+    variables(&expr, &mut names);
+    let mut call = vec![Box::new(symbol(builtin)), Box::new(AstNode::Lambda(vec![], Rc::new(expr)))];
+    call.extend(names.into_iter().map(|name| Box::new(AstNode::Quote(Box::new(AstNode::Identifier(name))))));
+    return AstNode::Expression(call);
+}
+
+/* The AST of (delay expr), (delay-force expr) or (stream-cons head tail), which is (list head (delay tail)) */
+fn lazy_form(form: &str, mut exprs: Vec<AstNode>) -> Result<AstNode, Error> {
+    match (form, exprs.len()) {
+        ("delay", 1) => Ok(delay("#:delay", exprs.remove(0))),
+        ("delay-force", 1) => Ok(delay("#:delay-force", exprs.remove(0))),
+        ("stream-cons", 2) => {
+            let tail = delay("#:delay", exprs.remove(1));
+            Ok(AstNode::Expression(vec![Box::new(symbol("list")), Box::new(exprs.remove(0)), Box::new(tail)]))
+        },
+        (_, count) => syntax_error(format!("{} expects {} expression(s) but got {}", form,
+                                           if form == "stream-cons" { 2 } else { 1 }, count))
+    }
+}
+
+pub fn parse_lazy<I>(tokens: &mut Peekable<I>, form: &str, nesting: Nesting) -> Result<AstNode, Error>
+    where I: Iterator, I::Item: TokenItem
+{
+    let mut exprs: Vec<AstNode> = Vec::new();
+    loop {
+        match peek_token(tokens) {
+            Some(&Token::CloseParen) => break,
+            None => return syntax_error(String::from("Unexpected end of token stream")),
+            _ => exprs.push(parse_nested(tokens, nesting)?)
+        }
+    }
+    // Consume CloseParen
+    next_token(tokens);
+
+    return lazy_form(form, exprs);
+}
+
+/* Parse the elements of a vector literal, which are data rather than expressions */
+pub fn parse_vector<I>(tokens: &mut Peekable<I>, nesting: Nesting) -> Result<AstNode, Error>
+    where I: Iterator, I::Item: TokenItem
+{
+    let mut elements: Vec<AstNode> = Vec::new();
+    loop {
+        match peek_token(tokens) {
+            Some(&Token::CloseParen) => break,
+            None => return syntax_error(String::from("Unexpected end of token stream")),
+            _ => elements.push(parse_datum(tokens, nesting)?)
+        }
     }
-    value = Box::new(parse(tokens));
     // Consume CloseParen
-    (*tokens).next();
+    next_token(tokens);
+
+    return Ok(new_vector(elements));
+}
+
+/* Parse a hash table literal of alternating key and value data */
+pub fn parse_hash_table<I>(tokens: &mut Peekable<I>, nesting: Nesting) -> Result<AstNode, Error>
+    where I: Iterator, I::Item: TokenItem
+{
+    let mut table = HashTable::new();
+    loop {
+        match peek_token(tokens) {
+            Some(&Token::CloseBrace) => break,
+            None => return syntax_error(String::from("Unexpected end of token stream")),
+            _ => {}
+        }
+        let key = parse_datum(tokens, nesting)?;
+        if let Some(&Token::CloseBrace) = peek_token(tokens) {
+            return syntax_error(format!("Hash table literal is missing a value for key: {:?}", key));
+        }
+        table.insert(key, parse_datum(tokens, nesting)?);
+    }
+    // Consume CloseBrace
+    next_token(tokens);
+
+    return Ok(new_hash_table(table));
+}
+
+/* Parse the tokens as plain data: lists and atoms, with keywords read as identifiers */
+pub fn parse_datum<I>(tokens: &mut Peekable<I>, nesting: Nesting) -> Result<AstNode, Error>
+    where I: Iterator, I::Item: TokenItem
+{
+    let nesting = nesting.enter()?;
+    return eval::with_stack(|| parse_datum_in(tokens, nesting))?;
+}
+
+fn parse_datum_in<I>(tokens: &mut Peekable<I>, nesting: Nesting) -> Result<AstNode, Error>
+    where I: Iterator, I::Item: TokenItem
+{
+    if let Some(token) = next_token(tokens) {
+        match token {
+            Token::OpenParen => {
+                let mut list: Vec<Box<AstNode>> = Vec::new();
+                loop {
+                    match peek_token(tokens) {
+                        Some(&Token::CloseParen) => break,
+                        None => return syntax_error(String::from("Unexpected end of token stream")),
+                        _ => list.push(Box::new(parse_datum(tokens, nesting)?))
+                    }
+                }
+                // Consume CloseParen
+                next_token(tokens);
+                Ok(AstNode::Expression(list))
+            },
+            Token::VectorOpen => parse_vector(tokens, nesting),
+            Token::OpenBrace => parse_hash_table(tokens, nesting),
+            Token::CloseBrace => syntax_error(String::from("Unexpected }!")),
+            Token::Quote => quoted_datum("quote", tokens, nesting),
+            Token::Quasiquote => quoted_datum("quasiquote", tokens, nesting),
+            Token::Unquote => quoted_datum("unquote", tokens, nesting),
+            Token::UnquoteSplicing => quoted_datum("unquote-splicing", tokens, nesting),
+            Token::Define => Ok(symbol("define")),
+            Token::Defmacro => Ok(symbol("defmacro")),
+            Token::Lambda => Ok(symbol("lambda")),
+            Token::If => Ok(symbol("if")),
+            Token::Guard => Ok(symbol("guard")),
+            Token::DefineGenerator => Ok(symbol("define-generator")),
+            Token::Delay => Ok(symbol("delay")),
+            Token::DelayForce => Ok(symbol("delay-force")),
+            Token::StreamCons => Ok(symbol("stream-cons")),
+            Token::Bool(x) => Ok(AstNode::Bool(x)),
+            Token::Char(x) => Ok(AstNode::Char(x)),
+            Token::Number(x) => Ok(AstNode::Number(x)),
+            Token::String(x) => Ok(AstNode::String(x)),
+            Token::Identifier(x) => Ok(AstNode::Identifier(x)),
+            Token::CloseParen => syntax_error(String::from("Unexpected )!")),
+        }
+    }
+    else {
+        return syntax_error(String::from("Unexpected end of token stream"));
+    }
+}
 
-    return AstNode::Define(identifier, value);
+fn symbol(name: &'static str) -> AstNode {
+    return AstNode::Identifier(Symbol::keyword(name));
 }
 
-pub fn parse_exp<I>(tokens: &mut Peekable<I>) -> AstNode
-    where I: Iterator<Item=Token>
+fn quoted_datum<I>(name: &'static str, tokens: &mut Peekable<I>, nesting: Nesting) -> Result<AstNode, Error>
+    where I: Iterator, I::Item: TokenItem
+{
+    return Ok(AstNode::Expression(vec![Box::new(symbol(name)), Box::new(parse_datum(tokens, nesting)?)]));
+}
+
+fn datum_parameters(datum: &AstNode) -> Result<Vec<Symbol>, Error> {
+    match *datum {
+        AstNode::Expression(ref params) => params.iter().map(|param| match **param {
+            AstNode::Identifier(ref ident) => Ok(ident.clone()),
+            ref other => syntax_error(format!("Lambda arguments must be identifiers: {:?}", other))
+        }).collect(),
+        ref other => syntax_error(format!("Invalid syntax for lambda: {:?}", other))
+    }
+}
+
+fn from_guard_datum(spec: &AstNode, body: &[Box<AstNode>]) -> Result<AstNode, Error> {
+    let convert = |items: &[Box<AstNode>]| items.iter().map(|item| from_datum(item).map(Box::new))
+        .collect::<Result<Vec<_>, _>>();
+    if let AstNode::Expression(ref items) = *spec {
+        if let Some(&AstNode::Identifier(ref variable)) = items.first().map(|x| &**x) {
+            let clauses = items[1..].iter().map(|clause| match **clause {
+                AstNode::Expression(ref clause) if !clause.is_empty() => convert(clause),
+                ref other => syntax_error(format!("Guard clauses must be lists: {:?}", other))
+            }).collect::<Result<_, _>>()?;
+            return Ok(AstNode::Guard(variable.clone(), clauses, convert(body)?));
+        }
+    }
+
+    return syntax_error(format!("Invalid syntax for guard: {:?}", spec));
+}
+
+/* Convert a datum (such as the result of a macro) into an AST that can be evaluated */
+pub fn from_datum(datum: &AstNode) -> Result<AstNode, Error> {
+    if let AstNode::Expression(ref items) = *datum {
+        if let Some(&AstNode::Identifier(ref head)) = items.first().map(|x| &**x) {
+            let args = &items[1..];
+            match (head.as_str(), args.len()) {
+                ("define", 2) => {
+                    if let AstNode::Identifier(ref name) = *args[0] {
+                        return Ok(AstNode::Define(name.clone(), Box::new(from_datum(&args[1])?)));
+                    }
+                    return syntax_error(format!("Define arg 1 expected to be an identifier: {:?}", args[0]));
+                },
+                ("defmacro", 3) => {
+                    if let AstNode::Identifier(ref name) = *args[0] {
+                        return Ok(AstNode::Defmacro(name.clone(), datum_parameters(&args[1])?,
+                                                    Box::new(from_datum(&args[2])?)));
+                    }
+                    return syntax_error(format!("Defmacro arg 1 expected to be an identifier: {:?}", args[0]));
+                },
+                ("lambda", 2) => return Ok(AstNode::Lambda(datum_parameters(&args[0])?,
+                                                           Rc::new(from_datum(&args[1])?))),
+                ("if", 3) => return Ok(AstNode::If(Box::new(from_datum(&args[0])?), Box::new(from_datum(&args[1])?),
+                                                   Box::new(from_datum(&args[2])?))),
+                ("guard", n) if n >= 2 => return from_guard_datum(&args[0], &args[1..]),
+                ("define-generator", 2) => {
+                    let mut header = datum_parameters(&args[0])?;
+                    if header.is_empty() {
+                        return syntax_error(String::from("define-generator expects a name"));
+                    }
+                    let name = header.remove(0);
+                    return Ok(define_generator(name, header, from_datum(&args[1])?));
+                },
+                ("delay", _) | ("delay-force", _) | ("stream-cons", _) => {
+                    return lazy_form(head.as_str(), args.iter().map(|arg| from_datum(arg)).collect::<Result<_, _>>()?);
+                },
+                ("quote", 1) => return Ok(AstNode::Quote(args[0].clone())),
+                ("quasiquote", 1) => return Ok(AstNode::Quasiquote(args[0].clone())),
+                ("define", _) | ("defmacro", _) | ("lambda", _) | ("if", _) | ("guard", _) | ("define-generator", _) |
+                ("quote", _) | ("quasiquote", _) =>
+                    return syntax_error(format!("Invalid syntax for {}: {:?}", head, datum)),
+                _ => {}
+            }
+        }
+        let items = items.iter().map(|item| from_datum(item).map(Box::new)).collect::<Result<_, _>>()?;
+        return Ok(AstNode::Expression(items));
+    }
+
+    return Ok(datum.clone());
+}
+
+/* Convert an AST back into a datum, the representation macros operate on */
+pub fn to_datum(ast: &AstNode) -> AstNode {
+    let list = |items: Vec<AstNode>| AstNode::Expression(items.into_iter().map(Box::new).collect());
+    let params = |params: &Vec<Symbol>| list(params.iter().map(|p| AstNode::Identifier(p.clone())).collect());
+    match *ast {
+        AstNode::Expression(ref items) => list(items.iter().map(|item| to_datum(item)).collect()),
+        AstNode::Define(ref name, ref value) => list(vec![symbol("define"), AstNode::Identifier(name.clone()), to_datum(value)]),
+        AstNode::Defmacro(ref name, ref args, ref expr) =>
+            list(vec![symbol("defmacro"), AstNode::Identifier(name.clone()), params(args), to_datum(expr)]),
+        AstNode::Lambda(ref args, ref expr) => list(vec![symbol("lambda"), params(args), to_datum(expr)]),
+        AstNode::If(ref pred, ref true_expr, ref false_expr) =>
+            list(vec![symbol("if"), to_datum(pred), to_datum(true_expr), to_datum(false_expr)]),
+        AstNode::Guard(ref variable, ref clauses, ref body) => {
+            let mut spec = vec![AstNode::Identifier(variable.clone())];
+            spec.extend(clauses.iter().map(|clause| list(clause.iter().map(|item| to_datum(item)).collect())));
+            let mut items = vec![symbol("guard"), list(spec)];
+            items.extend(body.iter().map(|expr| to_datum(expr)));
+            list(items)
+        },
+        AstNode::Located(_, ref expr) => to_datum(expr),
+        AstNode::Local(ref name, _, _) => AstNode::Identifier(name.clone()),
+        AstNode::Quote(ref datum) => list(vec![symbol("quote"), (**datum).clone()]),
+        AstNode::Quasiquote(ref datum) => list(vec![symbol("quasiquote"), (**datum).clone()]),
+        ref other => other.clone()
+    }
+}
+
+/* Source text for an AST, in the syntax the lexer reads.  Values with no
+ * syntax of their own, such as host objects, are shown as debug output
+ */
+pub fn unparse(ast: &AstNode) -> String {
+    let join = |items: &mut dyn Iterator<Item = &AstNode>| items.map(unparse).collect::<Vec<_>>().join(" ");
+    match to_datum(ast) {
+        AstNode::Expression(ref items) => format!("({})", join(&mut items.iter().map(|x| &**x))),
+        AstNode::Vector(ref elements) => format!("#({})", join(&mut elements.borrow().iter())),
+        AstNode::HashTable(ref table) => {
+            let table = table.borrow();
+            format!("{{{}}}", join(&mut table.entries().iter().flat_map(|&(ref k, ref v)| vec![k, v])))
+        },
+        AstNode::Bool(b) => format!("{}", b),
+        AstNode::Number(x) => format!("{}", x),
+        AstNode::String(ref s) => format!("{:?}", s),
+        AstNode::Char(' ') => String::from("#\\space"),
+        AstNode::Char('\n') => String::from("#\\newline"),
+        AstNode::Char('\t') => String::from("#\\tab"),
+        AstNode::Char(c) => format!("#\\{}", c),
+        AstNode::Identifier(ref name) => name.to_string(),
+        ref other => format!("{:?}", other)
+    }
+}
+
+pub fn parse_exp<I>(tokens: &mut Peekable<I>, nesting: Nesting) -> Result<AstNode, Error>
+    where I: Iterator, I::Item: TokenItem
 {
     let mut expr: Vec<Box<AstNode>> = Vec::new();
     loop {
-        if let Some(&Token::CloseParen) = (*tokens).peek() {
+        if let Some(&Token::CloseParen) = peek_token(tokens) {
             break;
         }
-        expr.push(Box::new(parse(tokens)));
+        expr.push(Box::new(parse_nested(tokens, nesting)?));
     }
     // Consume CloseParen
-    (*tokens).next();
+    next_token(tokens);
 
-    return AstNode::Expression(expr);
+    return Ok(AstNode::Expression(expr));
 }
 
+/* Panics if the tokens aren't a valid expression */
 pub fn parse<I>(tokens: &mut Peekable<I>) -> AstNode
-    where I: Iterator<Item=Token>
+    where I: Iterator, I::Item: TokenItem
 {
-    if let Some(token) = (*tokens).next() {
-        match token {
-            Token::OpenParen => match *((*tokens).peek().unwrap()) {
-                Token::Define => {
-                    (*tokens).next();
-                    return parse_define(tokens);
+    return try_parse(tokens).unwrap_or_else(|e| panic!("{}", e));
+}
+
+/* Expressions may nest as deeply as calls by default */
+pub fn try_parse<I>(tokens: &mut Peekable<I>) -> Result<AstNode, Error>
+    where I: Iterator, I::Item: TokenItem
+{
+    return try_parse_limited(tokens, Limits::default().max_depth);
+}
+
+/* Fails with a ResourceExhausted error if the expression nests deeper than the limit.  None means unlimited */
+pub fn try_parse_limited<I>(tokens: &mut Peekable<I>, max_depth: Option<usize>) -> Result<AstNode, Error>
+    where I: Iterator, I::Item: TokenItem
+{
+    return parse_nested(tokens, Nesting{depth: 0, limit: max_depth});
+}
+
+fn parse_nested<I>(tokens: &mut Peekable<I>, nesting: Nesting) -> Result<AstNode, Error>
+    where I: Iterator, I::Item: TokenItem
+{
+    let nesting = nesting.enter()?;
+    return eval::with_stack(|| parse_in(tokens, nesting))?;
+}
+
+fn parse_in<I>(tokens: &mut Peekable<I>, nesting: Nesting) -> Result<AstNode, Error>
+    where I: Iterator, I::Item: TokenItem
+{
+    if let Some(item) = tokens.next() {
+        let span = item.span();
+        match item.into_token() {
+            Token::OpenParen => match peek_token(tokens) {
+                Some(&Token::Define) => {
+                    next_token(tokens);
+                    return parse_define(tokens, nesting);
+                },
+                Some(&Token::Lambda) => {
+                    next_token(tokens);
+                    return parse_lambda(tokens, nesting);
+                },
+                Some(&Token::If) => {
+                    next_token(tokens);
+                    return parse_if(tokens, nesting);
+                },
+                Some(&Token::Defmacro) => {
+                    next_token(tokens);
+                    return parse_defmacro(tokens, nesting);
+                },
+                Some(&Token::Guard) => {
+                    next_token(tokens);
+                    return parse_guard(tokens, nesting);
+                },
+                Some(&Token::DefineGenerator) => {
+                    next_token(tokens);
+                    return parse_define_generator(tokens, nesting);
                 },
-                Token::Lambda => {
-                    (*tokens).next();
-                    return parse_lambda(tokens);
+                Some(&Token::Delay) => {
+                    next_token(tokens);
+                    return parse_lazy(tokens, "delay", nesting);
                 },
-                Token::If => {
-                    (*tokens).next();
-                    return parse_if(tokens);
+                Some(&Token::DelayForce) => {
+                    next_token(tokens);
+                    return parse_lazy(tokens, "delay-force", nesting);
                 },
-                _ => parse_exp(tokens)
+                Some(&Token::StreamCons) => {
+                    next_token(tokens);
+                    return parse_lazy(tokens, "stream-cons", nesting);
+                },
+                // Calls remember where they are, so errors raised by them can report it
+                Some(_) => parse_exp(tokens, nesting).map(|expr| match span {
+                    Some(span) => AstNode::Located(span, Box::new(expr)),
+                    None => expr
+                }),
+                None => syntax_error(String::from("Unexpected end of token stream"))
             },
-            Token::Bool(x) => AstNode::Bool(x),
-            Token::Number(x) => AstNode::Number(x),
-            Token::String(x) => AstNode::String(x),
-            Token::Identifier(x) => AstNode::Identifier(x),
-            Token::Define => panic!("Unexpected define!"),
-            Token::Lambda => panic!("Unexpected lambda!"),
-            Token::If => panic!("Unexpected if!"),
-            Token::CloseParen => panic!("Unexpected )!"),
+            Token::VectorOpen => parse_vector(tokens, nesting),
+            Token::OpenBrace => parse_hash_table(tokens, nesting),
+            Token::CloseBrace => syntax_error(String::from("Unexpected }!")),
+            Token::Quote => Ok(AstNode::Quote(Box::new(parse_datum(tokens, nesting)?))),
+            Token::Quasiquote => Ok(AstNode::Quasiquote(Box::new(parse_datum(tokens, nesting)?))),
+            Token::Bool(x) => Ok(AstNode::Bool(x)),
+            Token::Char(x) => Ok(AstNode::Char(x)),
+            Token::Number(x) => Ok(AstNode::Number(x)),
+            Token::String(x) => Ok(AstNode::String(x)),
+            Token::Identifier(x) => Ok(AstNode::Identifier(x)),
+            Token::Define => syntax_error(String::from("Unexpected define!")),
+            Token::Lambda => syntax_error(String::from("Unexpected lambda!")),
+            Token::If => syntax_error(String::from("Unexpected if!")),
+            Token::Defmacro => syntax_error(String::from("Unexpected defmacro!")),
+            Token::Guard => syntax_error(String::from("Unexpected guard!")),
+            Token::DefineGenerator => syntax_error(String::from("Unexpected define-generator!")),
+            Token::Delay => syntax_error(String::from("Unexpected delay!")),
+            Token::DelayForce => syntax_error(String::from("Unexpected delay-force!")),
+            Token::StreamCons => syntax_error(String::from("Unexpected stream-cons!")),
+            Token::Unquote | Token::UnquoteSplicing => syntax_error(String::from("Unquote outside of quasiquote!")),
+            Token::CloseParen => syntax_error(String::from("Unexpected )!")),
         }
     }
     else {
-        panic!("Unexpected end of token stream");
+        return syntax_error(String::from("Unexpected end of token stream"));
     }
 }
 
 #[cfg(test)]
 mod test {
     use parser::parse;
-    use parser::AstNode;
+    use parser::{try_parse, try_parse_limited};
+    use error::Error;
+    use parser::{from_datum, to_datum, unparse, AstNode};
+    use lexer;
     use lexer::Token;
+    use symbols::Symbol;
+    use std::rc::Rc;
 
     #[test]
     fn simple_parse() {
-        let tokens = vec![Token::OpenParen, Token::Identifier(String::from("+")),
+        let tokens = vec![Token::OpenParen, Token::Identifier(Symbol::intern("+").unwrap()),
                           Token::Number(3.0), Token::Number(4.0), Token::CloseParen];
-        let expected_ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(String::from("+"))),
+        let expected_ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(Symbol::intern("+").unwrap())),
                                                  Box::new(AstNode::Number(3.0)),
                                                  Box::new(AstNode::Number(4.0))]);
         let ast = parse(&mut tokens.into_iter().peekable());
@@ -163,13 +786,13 @@ mod test {
 
     #[test]
     fn nested_parse() {
-        let tokens = vec![Token::OpenParen, Token::Identifier(String::from("+")),
-                          Token::Number(3.0), Token::OpenParen, Token::Identifier(String::from("*")),
+        let tokens = vec![Token::OpenParen, Token::Identifier(Symbol::intern("+").unwrap()),
+                          Token::Number(3.0), Token::OpenParen, Token::Identifier(Symbol::intern("*").unwrap()),
                           Token::Number(3.0), Token::Number(4.0), Token::CloseParen, Token::CloseParen];
-        let expected_ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(String::from("+"))),
+        let expected_ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(Symbol::intern("+").unwrap())),
                                                  Box::new(AstNode::Number(3.0)),
                                                  Box::new(AstNode::Expression(vec![
-                                                    Box::new(AstNode::Identifier(String::from("*"))),
+                                                    Box::new(AstNode::Identifier(Symbol::intern("*").unwrap())),
                                                     Box::new(AstNode::Number(3.0)),
                                                     Box::new(AstNode::Number(4.0))]))]);
         let ast = parse(&mut tokens.into_iter().peekable());
@@ -178,13 +801,13 @@ mod test {
 
     #[test]
     fn first_arg_nested_parse() {
-        let tokens = vec![Token::OpenParen, Token::Identifier(String::from("+")),
-                          Token::OpenParen, Token::Identifier(String::from("*")),
+        let tokens = vec![Token::OpenParen, Token::Identifier(Symbol::intern("+").unwrap()),
+                          Token::OpenParen, Token::Identifier(Symbol::intern("*").unwrap()),
                           Token::Number(3.0), Token::Number(4.0), Token::CloseParen,
                           Token::Number(6.0), Token::CloseParen];
-        let expected_ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(String::from("+"))),
+        let expected_ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(Symbol::intern("+").unwrap())),
                                                  Box::new(AstNode::Expression(vec![
-                                                    Box::new(AstNode::Identifier(String::from("*"))),
+                                                    Box::new(AstNode::Identifier(Symbol::intern("*").unwrap())),
                                                     Box::new(AstNode::Number(3.0)),
                                                     Box::new(AstNode::Number(4.0))])),
                                                     Box::new(AstNode::Number(6.0))]);
@@ -194,9 +817,9 @@ mod test {
     // TODO: Add failure cases and string tests
     #[test]
     fn string_parse() {
-        let tokens = vec![Token::OpenParen, Token::Identifier(String::from("+")),
+        let tokens = vec![Token::OpenParen, Token::Identifier(Symbol::intern("+").unwrap()),
                           Token::String(String::from("cat")), Token::String(String::from("wow")), Token::CloseParen];
-        let expected_ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(String::from("+"))),
+        let expected_ast = AstNode::Expression(vec![Box::new(AstNode::Identifier(Symbol::intern("+").unwrap())),
                                                  Box::new(AstNode::String(String::from("cat"))),
                                                  Box::new(AstNode::String(String::from("wow")))]);
         let ast = parse(&mut tokens.into_iter().peekable());
@@ -206,8 +829,8 @@ mod test {
     #[test]
     fn define_parse() {
         let tokens = vec![Token::OpenParen, Token::Define,
-                          Token::Identifier(String::from("LENGTH")), Token::Number(10.0), Token::CloseParen];
-        let expected_ast = AstNode::Define(String::from("LENGTH"),
+                          Token::Identifier(Symbol::intern("LENGTH").unwrap()), Token::Number(10.0), Token::CloseParen];
+        let expected_ast = AstNode::Define(Symbol::intern("LENGTH").unwrap(),
                                                  Box::new(AstNode::Number(10.0)));
         let ast = parse(&mut tokens.into_iter().peekable());
         assert_eq!(ast, expected_ast);
@@ -216,15 +839,15 @@ mod test {
     #[test]
     fn define_lambda_parse() {
         //(define my_func (lambda (x y z) (* x y z)))
-        let tokens = vec![Token::OpenParen, Token::Define, Token::Identifier(String::from("my_func")), 
-            Token::OpenParen, Token::Lambda, Token::OpenParen, Token::Identifier(String::from("x")), Token::CloseParen,
-            Token::OpenParen, Token::Identifier(String::from("*")), Token::Identifier(String::from("x")),
-            Token::Identifier(String::from("x")), Token::CloseParen, Token::CloseParen];
-
-        let expected_ast = AstNode::Define(String::from("my_func"), Box::new(AstNode::Lambda(vec![String::from("x")],
-                                           Box::new(AstNode::Expression(vec![Box::new(AstNode::Identifier(String::from("*"))),
-                                                 Box::new(AstNode::Identifier(String::from("x"))),
-                                                 Box::new(AstNode::Identifier(String::from("x")))])))));
+        let tokens = vec![Token::OpenParen, Token::Define, Token::Identifier(Symbol::intern("my_func").unwrap()), 
+            Token::OpenParen, Token::Lambda, Token::OpenParen, Token::Identifier(Symbol::intern("x").unwrap()), Token::CloseParen,
+            Token::OpenParen, Token::Identifier(Symbol::intern("*").unwrap()), Token::Identifier(Symbol::intern("x").unwrap()),
+            Token::Identifier(Symbol::intern("x").unwrap()), Token::CloseParen, Token::CloseParen];
+
+        let expected_ast = AstNode::Define(Symbol::intern("my_func").unwrap(), Box::new(AstNode::Lambda(vec![Symbol::intern("x").unwrap()],
+                                           Rc::new(AstNode::Expression(vec![Box::new(AstNode::Identifier(Symbol::intern("*").unwrap())),
+                                                 Box::new(AstNode::Identifier(Symbol::intern("x").unwrap())),
+                                                 Box::new(AstNode::Identifier(Symbol::intern("x").unwrap()))])))));
         let ast = parse(&mut tokens.into_iter().peekable());
         assert_eq!(ast, expected_ast);
     }
@@ -233,7 +856,7 @@ mod test {
     #[should_panic]
     fn mismatched_paren_beginning() {
         let tokens = vec![Token::CloseParen, Token::Define,
-                          Token::Identifier(String::from("LENGTH")), Token::Number(10.0), Token::CloseParen];
+                          Token::Identifier(Symbol::intern("LENGTH").unwrap()), Token::Number(10.0), Token::CloseParen];
         parse(&mut tokens.into_iter().peekable());
     }
 
@@ -241,7 +864,7 @@ mod test {
     #[should_panic]
     fn mismatched_paren_end() {
         let tokens = vec![Token::Define,
-                          Token::Identifier(String::from("LENGTH")), Token::Number(10.0), Token::CloseParen, Token::CloseParen];
+                          Token::Identifier(Symbol::intern("LENGTH").unwrap()), Token::Number(10.0), Token::CloseParen, Token::CloseParen];
         parse(&mut tokens.into_iter().peekable());
     }
 
@@ -249,14 +872,14 @@ mod test {
     fn lambda_parse() {
         // (lambda (x) (* x x))
         let tokens = vec![Token::OpenParen, Token::Lambda,
-            Token::OpenParen, Token::Identifier(String::from("x")), Token::CloseParen,
-            Token::OpenParen, Token::Identifier(String::from("*")), Token::Identifier(String::from("x")),
-            Token::Identifier(String::from("x")), Token::CloseParen, Token::CloseParen];
-
-        let expected_ast = AstNode::Lambda(vec![String::from("x")],
-                                           Box::new(AstNode::Expression(vec![Box::new(AstNode::Identifier(String::from("*"))),
-                                                 Box::new(AstNode::Identifier(String::from("x"))),
-                                                 Box::new(AstNode::Identifier(String::from("x")))])));
+            Token::OpenParen, Token::Identifier(Symbol::intern("x").unwrap()), Token::CloseParen,
+            Token::OpenParen, Token::Identifier(Symbol::intern("*").unwrap()), Token::Identifier(Symbol::intern("x").unwrap()),
+            Token::Identifier(Symbol::intern("x").unwrap()), Token::CloseParen, Token::CloseParen];
+
+        let expected_ast = AstNode::Lambda(vec![Symbol::intern("x").unwrap()],
+                                           Rc::new(AstNode::Expression(vec![Box::new(AstNode::Identifier(Symbol::intern("*").unwrap())),
+                                                 Box::new(AstNode::Identifier(Symbol::intern("x").unwrap())),
+                                                 Box::new(AstNode::Identifier(Symbol::intern("x").unwrap()))])));
         let ast = parse(&mut tokens.into_iter().peekable());
         assert_eq!(ast, expected_ast);
     }
@@ -265,15 +888,15 @@ mod test {
     fn lambda_exp_parse() {
         // ((lambda (x) (* x x)) 4)
         let tokens = vec![Token::OpenParen, Token::OpenParen, Token::Lambda,
-            Token::OpenParen, Token::Identifier(String::from("x")), Token::CloseParen,
-            Token::OpenParen, Token::Identifier(String::from("*")), Token::Identifier(String::from("x")),
-            Token::Identifier(String::from("x")), Token::CloseParen, Token::CloseParen, Token::Number(4.0), Token::CloseParen];
+            Token::OpenParen, Token::Identifier(Symbol::intern("x").unwrap()), Token::CloseParen,
+            Token::OpenParen, Token::Identifier(Symbol::intern("*").unwrap()), Token::Identifier(Symbol::intern("x").unwrap()),
+            Token::Identifier(Symbol::intern("x").unwrap()), Token::CloseParen, Token::CloseParen, Token::Number(4.0), Token::CloseParen];
 
         let expected_ast = AstNode::Expression(vec![
-                            Box::new(AstNode::Lambda(vec![String::from("x")],
-                                        Box::new(AstNode::Expression(vec![Box::new(AstNode::Identifier(String::from("*"))),
-                                            Box::new(AstNode::Identifier(String::from("x"))),
-                                            Box::new(AstNode::Identifier(String::from("x")))])))),
+                            Box::new(AstNode::Lambda(vec![Symbol::intern("x").unwrap()],
+                                        Rc::new(AstNode::Expression(vec![Box::new(AstNode::Identifier(Symbol::intern("*").unwrap())),
+                                            Box::new(AstNode::Identifier(Symbol::intern("x").unwrap())),
+                                            Box::new(AstNode::Identifier(Symbol::intern("x").unwrap()))])))),
                             Box::new(AstNode::Number(4.0))]);
         let ast = parse(&mut tokens.into_iter().peekable());
         assert_eq!(ast, expected_ast);
@@ -282,11 +905,11 @@ mod test {
     #[test]
     fn lambda_parse_const_expr() {
         // (lambda (x) 1)
-        let tokens = vec![Token::OpenParen, Token::Lambda, Token::OpenParen, Token::Identifier(String::from("x")), Token::CloseParen,
+        let tokens = vec![Token::OpenParen, Token::Lambda, Token::OpenParen, Token::Identifier(Symbol::intern("x").unwrap()), Token::CloseParen,
             Token::Number(1.0), Token::CloseParen];
 
-        let expected_ast = AstNode::Lambda(vec![String::from("x")],
-                                           Box::new(AstNode::Number(1.0)));
+        let expected_ast = AstNode::Lambda(vec![Symbol::intern("x").unwrap()],
+                                           Rc::new(AstNode::Number(1.0)));
         let ast = parse(&mut tokens.into_iter().peekable());
         assert_eq!(ast, expected_ast);
     }
@@ -296,8 +919,8 @@ mod test {
     fn lambda_parse_malformed_args() {
         let tokens = vec![Token::OpenParen, Token::Lambda,
             Token::OpenParen, Token::Number(10.0), Token::CloseParen,
-            Token::OpenParen, Token::Identifier(String::from("*")), Token::Identifier(String::from("x")),
-            Token::Identifier(String::from("x")), Token::CloseParen, Token::CloseParen];
+            Token::OpenParen, Token::Identifier(Symbol::intern("*").unwrap()), Token::Identifier(Symbol::intern("x").unwrap()),
+            Token::Identifier(Symbol::intern("x").unwrap()), Token::CloseParen, Token::CloseParen];
 
         parse(&mut tokens.into_iter().peekable());
     }
@@ -307,8 +930,8 @@ mod test {
     fn lambda_unexpected() {
         let tokens = vec![Token::Lambda,
             Token::OpenParen, Token::Number(10.0), Token::CloseParen,
-            Token::OpenParen, Token::Identifier(String::from("*")), Token::Identifier(String::from("x")),
-            Token::Identifier(String::from("x")), Token::CloseParen, Token::CloseParen];
+            Token::OpenParen, Token::Identifier(Symbol::intern("*").unwrap()), Token::Identifier(Symbol::intern("x").unwrap()),
+            Token::Identifier(Symbol::intern("x").unwrap()), Token::CloseParen, Token::CloseParen];
 
         parse(&mut tokens.into_iter().peekable());
     }
@@ -335,6 +958,70 @@ mod test {
         parse(&mut tokens.into_iter().peekable());
     }
 
+    #[test]
+    fn guard_parse() {
+        let tokens = lexer::parse("(guard (e ((car e) => f) (else 1)) (g))");
+        let ident = |x: &str| Box::new(AstNode::Identifier(Symbol::intern(x).unwrap()));
+        let call = |x: &str| Box::new(AstNode::Expression(vec![ident(x)]));
+        let clauses = vec![vec![Box::new(AstNode::Expression(vec![ident("car"), ident("e")])), ident("=>"), ident("f")],
+                           vec![ident("else"), Box::new(AstNode::Number(1.0))]];
+        let expected_ast = AstNode::Guard(Symbol::intern("e").unwrap(), clauses, vec![call("g")]);
+        let ast = parse(&mut tokens.into_iter().peekable());
+        assert_eq!(ast, expected_ast);
+        assert_eq!(from_datum(&to_datum(&ast)), Ok(expected_ast));
+        assert!(try_parse(&mut lexer::parse("(guard e (g))").into_iter().peekable()).is_err());
+    }
+
+    #[test]
+    fn define_generator_parse() {
+        let tokens = lexer::parse("(define-generator (g a) (yield a))");
+        let ident = |x: &str| Box::new(AstNode::Identifier(Symbol::intern(x).unwrap()));
+        let body = AstNode::Expression(vec![ident("yield"), ident("a")]);
+        let make = AstNode::Expression(vec![ident("make-generator"),
+                                            Box::new(AstNode::Lambda(vec![Symbol::intern("a").unwrap()], Rc::new(body))),
+                                            ident("a")]);
+        let expected_ast = AstNode::Define(Symbol::intern("g").unwrap(),
+                                           Box::new(AstNode::Lambda(vec![Symbol::intern("a").unwrap()], Rc::new(make))));
+        let ast = parse(&mut tokens.into_iter().peekable());
+        assert_eq!(ast, expected_ast);
+        assert!(try_parse(&mut lexer::parse("(define-generator () 1)").into_iter().peekable()).is_err());
+    }
+
+    #[test]
+    fn delay_parse() {
+        let ident = |x: &str| Box::new(AstNode::Identifier(Symbol::intern(x).unwrap()));
+        let quoted = |x: &str| Box::new(AstNode::Quote(ident(x)));
+        let body = AstNode::Expression(vec![ident("f"), ident("x"), Box::new(AstNode::Quote(ident("y"))), ident("x")]);
+        let expected_ast = AstNode::Expression(vec![ident("#:delay"), Box::new(AstNode::Lambda(vec![], Rc::new(body))),
+                                                    quoted("f"), quoted("x")]);
+        let ast = parse(&mut lexer::parse("(delay (f x 'y x))").into_iter().peekable());
+        assert_eq!(ast, expected_ast);
+        assert_eq!(from_datum(&to_datum(&ast)), Ok(expected_ast));
+        match parse(&mut lexer::parse("(stream-cons 1 s)").into_iter().peekable()) {
+            AstNode::Expression(ref items) => assert_eq!((&*items[0], &*items[1]), (&*ident("list"), &AstNode::Number(1.0))),
+            other => panic!("Expected a list call: {:?}", other)
+        }
+        assert!(try_parse(&mut lexer::parse("(delay-force 1 2)").into_iter().peekable()).is_err());
+        assert!(from_datum(&to_datum(&parse(&mut lexer::parse("'(stream-cons 1)").into_iter().peekable()))).is_ok());
+    }
+
+    #[test]
+    fn calls_are_located() {
+        let tokens = lexer::try_parse_spanned(" (f 1)", None).unwrap();
+        match try_parse(&mut tokens.into_iter().peekable()) {
+            Ok(AstNode::Located(ref span, _)) => assert_eq!((span.line, span.column), (1, 2)),
+            other => panic!("Expected located call: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn unterminated_expression_error() {
+        let tokens = vec![Token::OpenParen, Token::Identifier(Symbol::intern("+").unwrap()), Token::Number(1.0)];
+        assert!(try_parse(&mut tokens.into_iter().peekable()).is_err());
+        let tokens = vec![Token::OpenParen];
+        assert!(try_parse(&mut tokens.into_iter().peekable()).is_err());
+    }
+
     #[test]
     #[should_panic]
     fn if_expected_test() {
@@ -344,5 +1031,24 @@ mod test {
 
         parse(&mut tokens.into_iter().peekable());
     }
+    #[test]
+    fn unparse_round_trip() {
+        let source = "(define f (lambda (x) (if (< x 1) '(a \"b\") (list #\\space #(1 2.5) {k false}))))";
+        let tokens = lexer::try_parse_spanned(source, None).unwrap();
+        let ast = try_parse(&mut tokens.into_iter().peekable()).unwrap();
+        assert_eq!(unparse(&ast), "(define f (lambda (x) (if (< x 1) (quote (a \"b\")) (list #\\space #(1 2.5) {k false}))))");
+    }
 
+    #[test]
+    fn nesting_limit() {
+        for source in ["(".repeat(200000), "'(".repeat(200000), "#(".repeat(200000)].iter() {
+            let source = format!("{}{}", source, ")".repeat(200000));
+            match try_parse(&mut lexer::parse(&source).into_iter().peekable()) {
+                Err(Error::ResourceExhausted(message)) => assert_eq!(message, "nesting depth limit of 1000 exceeded"),
+                other => panic!("Expected resource exhausted error: {:?}", other.map(|_| ()))
+            }
+        }
+        let source = format!("{}{}", "(".repeat(2000), ")".repeat(2000));
+        assert!(try_parse_limited(&mut lexer::parse(&source).into_iter().peekable(), None).is_ok());
+    }
 }
@@ -0,0 +1,274 @@
+/* promises.rs
+ *
+ * Promises hold an expression whose value is only worked out when it is
+ * first forced, and then kept, so forcing again gives the same value
+ * without evaluating it again.  (delay expr) makes a promise of expr,
+ * and (make-promise value) one that is already forced.
+ *
+ * (delay-force expr) is for a promise whose expr gives another promise,
+ * as a function returning (delay-force (f ...)) does when it loops.
+ * Forcing it forces that promise in turn, in a loop rather than a
+ * recursive call, so a long chain of them doesn't use up the stack.
+ *
+ * Scoping is dynamic, so a promise keeps the values of the variables its
+ * expression uses from the calls it was made in.  Other names are looked
+ * up when it is forced
+ */
+use error::Error;
+use eval;
+use eval::Context;
+use parser::AstNode;
+use symbols::Symbol;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::mem;
+use std::ptr;
+use std::rc::Rc;
+
+/* Computes a value for a promise made by a builtin, such as the rest of a stream */
+pub type Compute = Rc<dyn Fn(&mut Context) -> Result<AstNode, Error>>;
+
+#[derive(Clone)]
+enum Thunk {
+    Delayed(AstNode, Vec<Symbol>, Vec<AstNode>), // Delayed(lambda, names, values), the expression and the variables it kept
+    Builtin(Compute),
+}
+
+#[derive(Clone)]
+enum State {
+    Delayed(Thunk, bool), // Delayed(thunk, chained), chained if the thunk gives a promise to force in turn
+    Forced(AstNode),
+}
+
+pub struct Promise {
+    state: RefCell<Rc<RefCell<State>>>, // shared with the promises delay-force has chained to this one
+}
+
+impl fmt::Debug for Promise {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#<promise>")
+    }
+}
+
+/* Each promise is only equal to itself */
+impl PartialEq for Promise {
+    fn eq(&self, other: &Promise) -> bool {
+        return ptr::eq(self, other);
+    }
+}
+
+/* Promises have no natural order, they are only comparable when equal */
+impl PartialOrd for Promise {
+    fn partial_cmp(&self, other: &Promise) -> Option<Ordering> {
+        if *self == *other {
+            return Some(Ordering::Equal);
+        }
+
+        return None;
+    }
+}
+
+/* The value of a forced promise nothing else shares, leaving it empty.  A stream that
+ * has been walked is a chain of forced promises, each holding the rest of it, which
+ * dropping values takes apart in a loop with this rather than recursing down the chain
+ */
+pub(crate) fn take_value(promise: &mut Promise) -> Option<AstNode> {
+    let state = Rc::get_mut(promise.state.get_mut())?;
+    match mem::replace(state.get_mut(), State::Forced(AstNode::Bool(false))) {
+        State::Forced(value) => Some(value),
+        State::Delayed(..) => None
+    }
+}
+
+fn new_promise(state: State) -> AstNode {
+    return AstNode::Promise(Rc::new(Promise{state: RefCell::new(Rc::new(RefCell::new(state)))}));
+}
+
+/* A promise of the value compute gives when it is forced */
+pub fn lazy(compute: Compute) -> AstNode {
+    return new_promise(State::Delayed(Thunk::Builtin(compute), false));
+}
+
+/* A promise already forced to the value */
+pub fn eager(value: AstNode) -> AstNode {
+    return new_promise(State::Forced(value));
+}
+
+impl Thunk {
+    fn call(&self, context: &mut Context) -> Result<AstNode, Error> {
+        match *self {
+            Thunk::Delayed(ref lambda, ref names, ref values) => {
+                // The kept variables are bound around the call, as they were when the promise was made
+                context.add_parameters(names, values.iter().cloned().map(Box::new));
+                let result = eval::apply(lambda, &[], context);
+                (*context).remove_namespace();
+                result
+            },
+            Thunk::Builtin(ref compute) => compute(context)
+        }
+    }
+}
+
+/* The value of the promise, computing it if it hasn't been.  If computing it
+ * fails, the promise is left as it was, to be computed again when next forced
+ */
+pub fn force(promise: &Promise, context: &mut Context) -> Result<AstNode, Error> {
+    let state = promise.state.borrow().clone();
+    loop {
+        let (thunk, chained) = match *state.borrow() {
+            State::Forced(ref value) => return Ok(value.clone()),
+            State::Delayed(ref thunk, chained) => (thunk.clone(), chained)
+        };
+        let value = thunk.call(context)?;
+        // The thunk may have forced this promise itself, and the first value stands
+        if let State::Forced(ref value) = *state.borrow() {
+            return Ok(value.clone());
+        }
+        if !chained {
+            *state.borrow_mut() = State::Forced(value.clone());
+            return Ok(value);
+        }
+        let inner = match value {
+            AstNode::Promise(ref inner) => inner.clone(),
+            ref other => return Err(Error::Type(format!("delay-force expects a promise: {:?}", other)))
+        };
+        // This promise takes over the inner one's state, and the inner one shares this one's from now on
+        let inner_state = mem::replace(&mut *inner.state.borrow_mut(), state.clone());
+        let next = inner_state.borrow().clone();
+        *state.borrow_mut() = next;
+    }
+}
+
+pub fn is_builtin(ident: &str) -> bool {
+    lazy_static! {
+        static ref BUILTINS: BTreeSet<&'static str> = ["#:delay", "#:delay-force", "make-promise", "force", "promise?"]
+            .iter().cloned().collect();
+    }
+    return BUILTINS.contains(ident);
+}
+
+fn check_arity(name: &str, args: &[Box<AstNode>], count: usize) -> Result<(), Error> {
+    if args.len() != count {
+        return Err(Error::Arity(format!("{} expects {} argument(s) but got {}", name, count, args.len())));
+    }
+
+    return Ok(());
+}
+
+/* (#:delay thunk 'names...), as delay is parsed, keeping the names bound by the calls being evaluated */
+fn delay(args: &[Box<AstNode>], chained: bool, context: &Context) -> Result<AstNode, Error> {
+    let (lambda, quoted) = match args.split_first() {
+        Some((lambda, quoted)) => (lambda, quoted),
+        None => return Err(Error::Arity(String::from("delay expects an expression")))
+    };
+    let mut names = Vec::new();
+    let mut values = Vec::new();
+    for name in quoted.iter() {
+        let name = match **name {
+            AstNode::Identifier(ref name) => name.clone(),
+            ref other => return Err(Error::Type(format!("delay expects variable names: {:?}", other)))
+        };
+        if let Some(value) = context.lookup_in_calls(name.clone()) {
+            names.push(name);
+            values.push(value.clone());
+        }
+    }
+
+    return Ok(new_promise(State::Delayed(Thunk::Delayed((**lambda).clone(), names, values), chained)));
+}
+
+pub fn apply(ident: &str, args: &[Box<AstNode>], context: &mut Context) -> Result<AstNode, Error> {
+    match ident {
+        "#:delay" => delay(args, false, context),
+        "#:delay-force" => delay(args, true, context),
+        // A promise is given back as it is
+        "make-promise" => {
+            check_arity(ident, args, 1)?;
+            match *args[0] {
+                AstNode::Promise(_) => Ok((*args[0]).clone()),
+                ref value => Ok(eager(value.clone()))
+            }
+        },
+        // Anything other than a promise is its own value
+        "force" => {
+            check_arity(ident, args, 1)?;
+            match *args[0] {
+                AstNode::Promise(ref promise) => force(promise, context),
+                ref value => Ok(value.clone())
+            }
+        },
+        "promise?" => {
+            check_arity(ident, args, 1)?;
+            Ok(AstNode::Bool(matches!(*args[0], AstNode::Promise(_))))
+        },
+        _ => Err(Error::Type(format!("Invalid operator: {:?}", ident)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use error::Error;
+    use interpreter::Interpreter;
+    use parser::AstNode;
+    use symbols::Symbol;
+    use vm::Backend;
+
+    fn eval(source: &str) -> Result<AstNode, Error> {
+        let results: Vec<_> = Backend::all().iter().map(|&backend| {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_backend(backend);
+            interpreter.eval_str(source)
+        }).collect();
+        assert_eq!(results[0], results[1], "backends disagree on {}", source);
+        return results[0].clone();
+    }
+
+    fn numbers(xs: &[f64]) -> AstNode {
+        return AstNode::Expression(xs.iter().map(|&x| Box::new(AstNode::Number(x))).collect());
+    }
+
+    #[test]
+    fn forced_once() {
+        assert_eq!(eval("(force (delay (+ 1 2)))"), Ok(AstNode::Number(3.0)));
+        // The count is only bumped the first time
+        assert_eq!(eval("(define count (vector 0))
+          (define p (delay (car (list (vector-ref count 0) (vector-set! count 0 (+ 1 (vector-ref count 0)))))))
+          (list (force p) (force p) (vector-ref count 0))"), Ok(numbers(&[0.0, 0.0, 1.0])));
+        assert_eq!(eval("(list (force (make-promise 4)) (force 5) (promise? (delay 1)) (promise? 1))"),
+                   Ok(AstNode::Expression(vec![Box::new(AstNode::Number(4.0)), Box::new(AstNode::Number(5.0)),
+                                               Box::new(AstNode::Bool(true)), Box::new(AstNode::Bool(false))])));
+        assert_eq!(eval("(define p (delay 1)) (eq? p (make-promise p))"), Ok(AstNode::Bool(true)));
+    }
+
+    #[test]
+    fn variables_are_kept() {
+        // n is only bound while make is being evaluated
+        assert_eq!(eval("(define make (lambda (n) (delay (* n 10)))) (force (make 4))"), Ok(AstNode::Number(40.0)));
+        // Globals are looked up when forced
+        assert_eq!(eval("(define p (delay later)) (define later 7) (force p)"), Ok(AstNode::Number(7.0)));
+        assert_eq!(eval("(define p (delay missing)) (force p)"), Err(Error::Undefined(String::from("missing"))));
+    }
+
+    #[test]
+    fn failures_are_retried() {
+        assert_eq!(eval("(define fail (vector true))
+          (define p (delay (if (vector-ref fail 0) (car '()) 'done)))
+          (define first (guard (e (else 'failed)) (force p)))
+          (vector-set! fail 0 false)
+          (list first (force p))"),
+                   Ok(AstNode::Expression(vec![Box::new(AstNode::Identifier(Symbol::intern("failed").unwrap())),
+                                               Box::new(AstNode::Identifier(Symbol::intern("done").unwrap()))])));
+    }
+
+    #[test]
+    fn chains_are_forced_in_a_loop() {
+        let chain = "(define chain (lambda (n) (delay-force (if (= n 0) (delay 'bottom) (chain (- n 1))))))";
+        // Far more links than the call depth limit allows calls
+        assert_eq!(eval(&format!("{} (force (chain 5000))", chain)), Ok(AstNode::Identifier(Symbol::intern("bottom").unwrap())));
+        assert_eq!(eval(&format!("{} (define p (chain 3)) (force p) (force p)", chain)),
+                   Ok(AstNode::Identifier(Symbol::intern("bottom").unwrap())));
+        assert!(eval("(force (delay-force 1))").is_err());
+    }
+}
@@ -0,0 +1,378 @@
+/* resolver.rs
+ *
+ * Works out before evaluation where each variable will be found.  Scoping
+ * is dynamic: a lambda sees the variables of whichever calls are being
+ * evaluated, so only the lambda's own parameters, and the variables of
+ * guards within it, have fixed addresses.  Each is a slot of a namespace
+ * a known number of levels out from the innermost one.  Any other name is
+ * looked up dynamically, by name.  Addresses are worked out once for each
+ * lambda body, by the compiler as it compiles the body and for the tree
+ * walker as a copy of the body with each addressed variable in place of
+ * its name.
+ *
+ * A name bound nowhere in the program, neither defined nor a parameter,
+ * can't be found whatever calls are being evaluated, so is reported as
+ * unbound before the program runs
+ */
+use eval::Context;
+use lexer::Span;
+use parser::AstNode;
+use symbols::Symbol;
+use std::collections::HashSet;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Address {
+    Builtin, // Builtin, evaluates to itself
+    Local(usize, usize), // Local(depth, index), the slot of the namespace depth levels out from the innermost
+    Dynamic, // Dynamic, found by name in the namespaces of the calls being evaluated, then the globals
+}
+
+/* The namespaces of a lambda call or a guard clause, as seen from the code in them */
+#[derive(Debug, Clone)]
+pub struct Scope {
+    names: Vec<Symbol>,
+    defines: HashSet<Symbol>, // names defined in the scope, which may take the place of an outer scope's
+    opaque: bool, // whether code in the scope could define names the resolver can't see, such as macro calls
+    call: bool, // whether the scope is a lambda call, outside of which nothing is known
+}
+
+impl Scope {
+    /* The scope of a lambda body, with the parameters in their slots */
+    pub fn lambda(parameters: &[Symbol], body: &AstNode, context: &Context) -> Scope {
+        let mut scope = Scope{names: parameters.to_vec(), defines: HashSet::new(), opaque: false, call: true};
+        scope.note(body, context);
+        return scope;
+    }
+    /* The scope of guard clauses, with the condition variable in the first slot */
+    pub fn guard(variable: Symbol, clauses: &[Vec<Box<AstNode>>], context: &Context) -> Scope {
+        let mut scope = Scope{names: vec![variable], defines: HashSet::new(), opaque: false, call: false};
+        for item in clauses.iter().flat_map(|clause| clause.iter()) {
+            scope.note(item, context);
+        }
+        return scope;
+    }
+
+    /* Note the defines evaluated directly in the scope, rather than in the scopes of nested lambdas or guards */
+    fn note(&mut self, ast: &AstNode, context: &Context) -> () {
+        match *ast {
+            AstNode::Define(ref name, ref value) => {
+                (*self).defines.insert(name.clone());
+                (*self).note(value, context);
+            },
+            AstNode::Defmacro(ref name, _, _) => {
+                (*self).defines.insert(name.clone());
+            },
+            AstNode::Expression(ref items) => {
+                if let Some(&AstNode::Identifier(ref name)) = items.first().map(|x| &**x) {
+                    // A call of anything but a builtin may be a macro call, expanding to defines
+                    if !context.is_builtin(name.clone()) {
+                        (*self).opaque = true;
+                    }
+                }
+                for item in items.iter() {
+                    (*self).note(item, context);
+                }
+            },
+            AstNode::If(ref pred, ref true_expr, ref false_expr) => {
+                (*self).note(pred, context);
+                (*self).note(true_expr, context);
+                (*self).note(false_expr, context);
+            },
+            AstNode::Located(_, ref expr) => (*self).note(expr, context),
+            // The body of a guard is evaluated in this scope, its clauses in their own
+            AstNode::Guard(_, _, ref body) => {
+                for item in body.iter() {
+                    (*self).note(item, context);
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+/* Scopes are ordered outermost first */
+pub fn resolve(name: Symbol, scopes: &[Scope], context: &Context) -> Address {
+    if context.is_builtin(name.clone()) {
+        return Address::Builtin;
+    }
+    for (depth, scope) in scopes.iter().rev().enumerate() {
+        if let Some(index) = scope.names.iter().rposition(|x| *x == name) {
+            return Address::Local(depth, index);
+        }
+        if scope.call || scope.opaque || scope.defines.contains(&name) {
+            return Address::Dynamic;
+        }
+    }
+    return Address::Dynamic;
+}
+
+/* A copy of a lambda body for the tree walker, with each variable that has an address
+ * replaced by an AstNode::Local.  Nested lambdas are resolved when they are called,
+ * and quoted data is left as it is
+ */
+pub(crate) fn resolve_lambda(parameters: &[Symbol], body: &AstNode, context: &Context) -> AstNode {
+    return resolved(body, &mut vec![Scope::lambda(parameters, body, context)], context);
+}
+
+fn resolved(ast: &AstNode, scopes: &mut Vec<Scope>, context: &Context) -> AstNode {
+    match *ast {
+        AstNode::Identifier(ref name) => match resolve(name.clone(), scopes, context) {
+            Address::Local(depth, index) => AstNode::Local(name.clone(), depth, index),
+            Address::Builtin | Address::Dynamic => ast.clone()
+        },
+        AstNode::Located(ref span, ref expr) => AstNode::Located(span.clone(), Box::new(resolved(expr, scopes, context))),
+        AstNode::Expression(ref items) => {
+            AstNode::Expression(items.iter().map(|item| Box::new(resolved(item, scopes, context))).collect())
+        },
+        AstNode::Define(ref name, ref value) => AstNode::Define(name.clone(), Box::new(resolved(value, scopes, context))),
+        AstNode::If(ref pred, ref true_expr, ref false_expr) => {
+            AstNode::If(Box::new(resolved(pred, scopes, context)), Box::new(resolved(true_expr, scopes, context)),
+                        Box::new(resolved(false_expr, scopes, context)))
+        },
+        // The body of a guard is evaluated in the enclosing scope, its clauses in their own
+        AstNode::Guard(ref variable, ref clauses, ref body) => {
+            let body = body.iter().map(|item| Box::new(resolved(item, scopes, context))).collect();
+            scopes.push(Scope::guard(variable.clone(), clauses, context));
+            let clauses = clauses.iter().map(|clause| clause.iter().map(|item| match **item {
+                // else and => are part of the clause's syntax rather than variables
+                AstNode::Identifier(ref name) if name == "else" || name == "=>" => item.clone(),
+                ref other => Box::new(resolved(other, scopes, context))
+            }).collect()).collect();
+            scopes.pop();
+            AstNode::Guard(variable.clone(), clauses, body)
+        },
+        ref other => other.clone()
+    }
+}
+
+/* The code a resolved lambda body was made from */
+pub(crate) fn unresolved(ast: &AstNode) -> AstNode {
+    let each = |items: &[Box<AstNode>]| items.iter().map(|item| Box::new(unresolved(item))).collect();
+    match *ast {
+        AstNode::Local(ref name, _, _) => AstNode::Identifier(name.clone()),
+        AstNode::Located(ref span, ref expr) => AstNode::Located(span.clone(), Box::new(unresolved(expr))),
+        AstNode::Expression(ref items) => AstNode::Expression(each(items)),
+        AstNode::Define(ref name, ref value) => AstNode::Define(name.clone(), Box::new(unresolved(value))),
+        AstNode::If(ref pred, ref true_expr, ref false_expr) => {
+            AstNode::If(Box::new(unresolved(pred)), Box::new(unresolved(true_expr)), Box::new(unresolved(false_expr)))
+        },
+        AstNode::Guard(ref variable, ref clauses, ref body) => {
+            AstNode::Guard(variable.clone(), clauses.iter().map(|clause| each(clause)).collect(), each(body))
+        },
+        ref other => other.clone()
+    }
+}
+
+/* A variable no definition or parameter binds, and where it is used */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unbound {
+    pub name: Symbol,
+    pub span: Option<Span>,
+}
+
+impl fmt::Display for Unbound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (*self).span {
+            Some(ref span) => write!(f, "unbound variable {} ({})", (*self).name, span),
+            None => write!(f, "unbound variable {}", (*self).name)
+        }
+    }
+}
+
+/* The variables the program uses that nothing binds, in the order they are used.
+ * Names defined anywhere in the program, even later, or bound as parameters count
+ * as bound, since with dynamic scoping any of them may be found when it runs
+ */
+pub fn unbound(program: &[AstNode], context: &Context) -> Vec<Unbound> {
+    let mut bound: HashSet<Symbol> = HashSet::new();
+    let mut macros: HashSet<Symbol> = HashSet::new();
+    for ast in program.iter() {
+        bindings(ast, &mut bound, &mut macros);
+    }
+    let mut found = Vec::new();
+    for ast in program.iter() {
+        uses(ast, None, &macros, context, &mut |name, span| {
+            if !bound.contains(&name) && context.lookup(name.clone()).is_none() {
+                found.push(Unbound{name, span: span.cloned()});
+            }
+        });
+    }
+    return found;
+}
+
+fn bindings(ast: &AstNode, bound: &mut HashSet<Symbol>, macros: &mut HashSet<Symbol>) -> () {
+    match *ast {
+        AstNode::Define(ref name, ref value) => {
+            bound.insert(name.clone());
+            bindings(value, bound, macros);
+        },
+        AstNode::Defmacro(ref name, ref params, ref body) => {
+            bound.insert(name.clone());
+            macros.insert(name.clone());
+            bound.extend(params.iter().cloned());
+            bindings(body, bound, macros);
+        },
+        AstNode::Lambda(ref params, ref body) => {
+            bound.extend(params.iter().cloned());
+            bindings(body, bound, macros);
+        },
+        AstNode::Guard(ref variable, ref clauses, ref body) => {
+            bound.insert(variable.clone());
+            for item in clauses.iter().flat_map(|clause| clause.iter()).chain(body.iter()) {
+                bindings(item, bound, macros);
+            }
+        },
+        AstNode::Expression(ref items) => items.iter().for_each(|item| bindings(item, bound, macros)),
+        AstNode::If(ref pred, ref true_expr, ref false_expr) => {
+            bindings(pred, bound, macros);
+            bindings(true_expr, bound, macros);
+            bindings(false_expr, bound, macros);
+        },
+        AstNode::Located(_, ref expr) => bindings(expr, bound, macros),
+        _ => {}
+    }
+}
+
+/* Calls use on every identifier that is evaluated, with the span of the innermost call around it.
+ * Quoted data and the arguments of macros aren't evaluated
+ */
+fn uses<F: FnMut(Symbol, Option<&Span>)>(ast: &AstNode, span: Option<&Span>, macros: &HashSet<Symbol>,
+                                         context: &Context, found: &mut F) -> () {
+    match *ast {
+        AstNode::Identifier(ref name) if !context.is_builtin(name.clone()) => found(name.clone(), span),
+        AstNode::Located(ref span, ref expr) => uses(expr, Some(span), macros, context, found),
+        AstNode::Expression(ref items) => {
+            if let Some(&AstNode::Identifier(ref name)) = items.first().map(|x| &**x) {
+                if macros.contains(name) || context.is_macro(name.clone()) {
+                    return;
+                }
+            }
+            items.iter().for_each(|item| uses(item, span, macros, context, found));
+        },
+        AstNode::Define(_, ref value) => uses(value, span, macros, context, found),
+        AstNode::Lambda(_, ref body) => uses(body, span, macros, context, found),
+        AstNode::Defmacro(_, _, ref body) => uses(body, span, macros, context, found),
+        AstNode::If(ref pred, ref true_expr, ref false_expr) => {
+            uses(pred, span, macros, context, found);
+            uses(true_expr, span, macros, context, found);
+            uses(false_expr, span, macros, context, found);
+        },
+        AstNode::Guard(_, ref clauses, ref body) => {
+            for clause in clauses.iter() {
+                // else and => are part of the clause's syntax rather than variables
+                for item in clause.iter() {
+                    match **item {
+                        AstNode::Identifier(ref name) if name == "else" || name == "=>" => {},
+                        ref other => uses(other, span, macros, context, found)
+                    }
+                }
+            }
+            body.iter().for_each(|item| uses(item, span, macros, context, found));
+        },
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use eval::Context;
+    use interpreter::Interpreter;
+    use parser::{unparse, AstNode};
+    use resolver::{resolve, resolve_lambda, unresolved, Address, Scope};
+    use symbols::Symbol;
+    use vm::Backend;
+
+    fn parse(source: &str) -> Vec<AstNode> {
+        return Interpreter::new().parse_source(source, "test.wsp").unwrap();
+    }
+
+    fn body(lambda: &AstNode) -> (Vec<Symbol>, AstNode) {
+        match *lambda {
+            AstNode::Lambda(ref params, ref body) => (params.clone(), (**body).clone()),
+            ref other => panic!("Expected a lambda: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn addresses_of_parameters() {
+        let context = Context::new();
+        let (params, lambda_body) = body(&parse("(lambda (a b a) (list a b c))")[0]);
+        let scopes = vec![Scope::lambda(&params, &lambda_body, &context)];
+        assert_eq!(resolve(Symbol::intern("a").unwrap(), &scopes, &context), Address::Local(0, 2));
+        assert_eq!(resolve(Symbol::intern("b").unwrap(), &scopes, &context), Address::Local(0, 1));
+        assert_eq!(resolve(Symbol::intern("c").unwrap(), &scopes, &context), Address::Dynamic);
+        assert_eq!(resolve(Symbol::intern("car").unwrap(), &scopes, &context), Address::Builtin);
+    }
+
+    #[test]
+    fn addresses_through_guards() {
+        let context = Context::new();
+        let (params, lambda_body) = body(&parse("(lambda (x y) (guard (e (else (list e x))) (car y)))")[0]);
+        let guard = match lambda_body {
+            AstNode::Guard(ref variable, ref clauses, _) => Scope::guard(variable.clone(), clauses, &context),
+            ref other => panic!("Expected a guard: {:?}", other)
+        };
+        let scopes = vec![Scope::lambda(&params, &lambda_body, &context), guard];
+        assert_eq!(resolve(Symbol::intern("e").unwrap(), &scopes, &context), Address::Local(0, 0));
+        assert_eq!(resolve(Symbol::intern("x").unwrap(), &scopes, &context), Address::Local(1, 0));
+
+        // Defines and possible macro calls in a guard clause may take the place of outer variables
+        for clauses in ["(e (else (define x 1) x))", "(e (else (my-macro) x))"].iter() {
+            let source = format!("(guard {} 1)", clauses);
+            let guard = match parse(&source)[0] {
+                AstNode::Guard(ref variable, ref clauses, _) => Scope::guard(variable.clone(), clauses, &context),
+                ref other => panic!("Expected a guard: {:?}", other)
+            };
+            let scopes = vec![Scope::lambda(&params, &lambda_body, &context), guard];
+            assert_eq!(resolve(Symbol::intern("x").unwrap(), &scopes, &context), Address::Dynamic);
+        }
+    }
+
+    #[test]
+    fn resolved_lambda_bodies() {
+        let context = Context::new();
+        let (params, lambda_body) = body(&parse("(lambda (x y) (guard (e (else (list e x z))) (f y (lambda () x))))")[0]);
+        let resolved = format!("{:?}", resolve_lambda(&params, &lambda_body, &context));
+        for local in ["Local(\"e\", 0, 0)", "Local(\"x\", 1, 0)", "Local(\"y\", 0, 1)"].iter() {
+            assert!(resolved.contains(local), "{} not in {}", local, resolved);
+        }
+        // Other names are found by name, and nested lambdas are resolved when called
+        for name in ["Identifier(\"z\")", "Identifier(\"f\")", "Identifier(\"list\")", "Identifier(\"x\")"].iter() {
+            assert!(resolved.contains(name), "{} not in {}", name, resolved);
+        }
+        assert_eq!(unresolved(&resolve_lambda(&params, &lambda_body, &context)), lambda_body);
+    }
+
+    #[test]
+    fn tree_walker_finds_addressed_variables() {
+        let source = "(define f (lambda (x y) (guard (e (else (list e x (g)))) (raise y))))
+(define g (lambda () x))
+(define h (lambda (x) (guard (e (else (define x (+ x e)) (list x e))) (raise 5))))
+(list (f 1 'oops) (h 1) ((lambda (a) a)))";
+        for &backend in Backend::all().iter() {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_backend(backend);
+            interpreter.eval_str("(define a 'global)").unwrap();
+            assert_eq!(interpreter.eval_str(source).map(|value| unparse(&value)),
+                       Ok(String::from("((oops 1 1) (6 5) global)")));
+        }
+    }
+
+    #[test]
+    fn report_unbound_variables() {
+        let mut interpreter = Interpreter::new();
+        interpreter.eval_str("(define known 1)").unwrap();
+        let program = parse("(define f (lambda (x) (g x known)))
+(define g (lambda (a b) (+ a b y)))
+(define h (lambda () x))
+(defmacro m (form) form)
+(m (anything goes))
+'(quoted names)
+(guard (e (else e)) (list z))
+(f missing)");
+        let found: Vec<String> = interpreter.unbound_variables(&program).iter().map(|u| u.to_string()).collect();
+        assert_eq!(found, vec!["unbound variable y (test.wsp:2:25)", "unbound variable z (test.wsp:7:21)",
+                               "unbound variable missing (test.wsp:8:1)"]);
+    }
+}
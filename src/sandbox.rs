@@ -0,0 +1,124 @@
+/* sandbox.rs
+ *
+ * Policies restricting which groups of privileged builtins a script may use.
+ * Context checks the policy whenever a builtin is looked up, so a denied
+ * builtin can be neither called nor passed around as a value.
+ *
+ * Embedders get the default policy unless they choose another, so it only
+ * permits the clock: files, processes and the environment must be allowed
+ */
+use std::collections::BTreeSet;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Group {
+    Filesystem,
+    Process,
+    Environment,
+    Time,
+}
+
+impl fmt::Display for Group {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Group::Filesystem => "filesystem",
+            Group::Process => "process",
+            Group::Environment => "environment",
+            Group::Time => "time",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/* A group is permitted if it isn't denied and, when there is an allow list, is on it */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    allowed: Option<BTreeSet<Group>>,
+    denied: BTreeSet<Group>,
+}
+
+impl Policy {
+    pub fn allow_all() -> Policy {
+        return Policy{allowed: None, denied: BTreeSet::new()};
+    }
+    /* Only groups allowed afterwards are permitted */
+    pub fn deny_all() -> Policy {
+        return Policy{allowed: Some(BTreeSet::new()), denied: BTreeSet::new()};
+    }
+    pub fn allow(mut self, group: Group) -> Policy {
+        if let Some(ref mut allowed) = self.allowed {
+            allowed.insert(group);
+        }
+        self.denied.remove(&group);
+        return self;
+    }
+    pub fn deny(mut self, group: Group) -> Policy {
+        self.denied.insert(group);
+        return self;
+    }
+    pub fn permits(&self, group: Group) -> bool {
+        return !(*self).denied.contains(&group) &&
+            (*self).allowed.as_ref().is_none_or(|allowed| allowed.contains(&group));
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        return Policy::allow_all().deny(Group::Filesystem).deny(Group::Process).deny(Group::Environment);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use error::Error;
+    use interpreter::Interpreter;
+    use parser::AstNode;
+    use sandbox::{Group, Policy};
+
+    #[test]
+    fn allow_and_deny_lists() {
+        assert!(Policy::allow_all().permits(Group::Filesystem));
+        let policy = Policy::allow_all().deny(Group::Process);
+        assert!(!policy.permits(Group::Process) && policy.permits(Group::Time));
+        let policy = Policy::deny_all().allow(Group::Time);
+        assert!(policy.permits(Group::Time) && !policy.permits(Group::Environment));
+        assert!(!Policy::deny_all().allow(Group::Time).deny(Group::Time).permits(Group::Time));
+    }
+
+    #[test]
+    fn default_denies_host_access() {
+        let policy = Policy::default();
+        assert!(!policy.permits(Group::Filesystem) && !policy.permits(Group::Process));
+        assert!(!policy.permits(Group::Environment) && policy.permits(Group::Time));
+        let mut interpreter = Interpreter::new();
+        match interpreter.eval_str("(run-command \"echo\" \"hi\")") {
+            Err(Error::NotPermitted(message)) => assert_eq!(message, "run-command is in the process group"),
+            other => panic!("Expected not permitted error: {:?}", other)
+        }
+        assert!(interpreter.eval_str("(write-file \"wisp_sandbox_test.txt\" \"x\")").is_err());
+        assert!(interpreter.eval_str("(exit 0)").is_err());
+    }
+
+    #[test]
+    fn exit_is_an_error() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_policy(Policy::allow_all());
+        // The host decides what exiting means, rather than the script ending its process
+        assert_eq!(interpreter.eval_str("(guard (e (else 'caught)) (exit 3))"), Err(Error::Exit(3)));
+    }
+
+    #[test]
+    fn denied_builtins_are_not_permitted() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_policy(Policy::deny_all().allow(Group::Time));
+        match interpreter.eval_str("(read-file \"/etc/passwd\")") {
+            Err(Error::NotPermitted(message)) => assert_eq!(message, "read-file is in the filesystem group"),
+            other => panic!("Expected not permitted error: {:?}", other)
+        }
+        // Denied builtins can't be smuggled in as values either
+        assert!(interpreter.eval_str("(define f getenv)").is_err());
+        assert!(interpreter.eval_str("(apply 'getenv '(\"HOME\"))").is_err());
+        assert!(interpreter.call_function("exit", vec![AstNode::Number(1.0)]).is_err());
+        assert_eq!(interpreter.eval_str("(> (current-time) 0)"), Ok(AstNode::Bool(true)));
+    }
+}
@@ -0,0 +1,214 @@
+/* streams.rs
+ *
+ * Streams are lists whose rest is only worked out when it is asked for,
+ * so they can go on forever.  A stream is either the empty list or
+ * (stream-cons head tail), which is (list head (delay tail)): stream-car
+ * is its head and stream-cdr forces its tail.  Each tail is a promise, so
+ * is only computed once however many times it is asked for.
+ *
+ * stream-map and stream-filter give streams of their own, computing each
+ * element as their tails are forced.  stream-take gives the first elements
+ * of a stream as a list
+ */
+use error::Error;
+use eval;
+use eval::Context;
+use parser::AstNode;
+use promises;
+use std::collections::BTreeSet;
+use std::rc::Rc;
+
+pub fn is_builtin(ident: &str) -> bool {
+    lazy_static! {
+        static ref BUILTINS: BTreeSet<&'static str> = ["stream-car", "stream-cdr", "stream-take", "stream-map",
+            "stream-filter"].iter().cloned().collect();
+    }
+    return BUILTINS.contains(ident);
+}
+
+fn check_arity(name: &str, args: &[Box<AstNode>], count: usize) -> Result<(), Error> {
+    if args.len() != count {
+        return Err(Error::Arity(format!("{} expects {} argument(s) but got {}", name, count, args.len())));
+    }
+
+    return Ok(());
+}
+
+/* The head and unforced tail of a stream, or None if it is empty */
+fn stream_arg<'a>(name: &str, arg: &'a AstNode) -> Result<Option<(&'a AstNode, &'a AstNode)>, Error> {
+    match *arg {
+        AstNode::Expression(ref items) if items.is_empty() => Ok(None),
+        AstNode::Expression(ref items) if items.len() == 2 && matches!(*items[1], AstNode::Promise(_)) => {
+            Ok(Some((&items[0], &items[1])))
+        },
+        ref other => Err(Error::Type(format!("{} expects a stream: {:?}", name, other)))
+    }
+}
+
+fn force(tail: &AstNode, context: &mut Context) -> Result<AstNode, Error> {
+    match *tail {
+        AstNode::Promise(ref promise) => promises::force(promise, context),
+        ref other => Ok(other.clone())
+    }
+}
+
+/* A stream of the head and a tail computed when it is forced */
+fn stream_cons(head: AstNode, tail: promises::Compute) -> AstNode {
+    return AstNode::Expression(vec![Box::new(head), Box::new(promises::lazy(tail))]);
+}
+
+/* The function applied to the heads of the streams, followed by its stream-map over their tails */
+fn map_streams(function: AstNode, streams: &[AstNode], context: &mut Context) -> Result<AstNode, Error> {
+    let mut heads = Vec::new();
+    let mut tails = Vec::new();
+    for stream in streams.iter() {
+        match stream_arg("stream-map", stream)? {
+            Some((head, tail)) => {
+                heads.push(Box::new(head.clone()));
+                tails.push(tail.clone());
+            },
+            // The stream ends with the shortest
+            None => return Ok(AstNode::Expression(vec![]))
+        }
+    }
+    let value = eval::apply(&function, &heads, context)?;
+    return Ok(stream_cons(value, Rc::new(move |context| {
+        let rests = tails.iter().map(|tail| force(tail, context)).collect::<Result<Vec<_>, _>>()?;
+        map_streams(function.clone(), &rests, context)
+    })));
+}
+
+/* The stream from the first element the predicate accepts.  Those it passes over are
+ * skipped in a loop, so a long run of them doesn't use up the stack
+ */
+fn filter_stream(predicate: AstNode, stream: AstNode, context: &mut Context) -> Result<AstNode, Error> {
+    let mut stream = stream;
+    loop {
+        let (head, tail) = match stream_arg("stream-filter", &stream)? {
+            Some((head, tail)) => (head.clone(), tail.clone()),
+            None => return Ok(stream)
+        };
+        if eval::apply(&predicate, &[Box::new(head.clone())], context)? != AstNode::Bool(false) {
+            return Ok(stream_cons(head, Rc::new(move |context| {
+                let rest = force(&tail, context)?;
+                filter_stream(predicate.clone(), rest, context)
+            })));
+        }
+        stream = force(&tail, context)?;
+    }
+}
+
+pub fn apply(ident: &str, args: &[Box<AstNode>], context: &mut Context) -> Result<AstNode, Error> {
+    match ident {
+        "stream-car" => {
+            check_arity(ident, args, 1)?;
+            match stream_arg(ident, &args[0])? {
+                Some((head, _)) => Ok(head.clone()),
+                None => Err(Error::OutOfBounds(String::from("stream-car of empty stream")))
+            }
+        },
+        "stream-cdr" => {
+            check_arity(ident, args, 1)?;
+            match stream_arg(ident, &args[0])? {
+                Some((_, tail)) => force(tail, context),
+                None => Err(Error::OutOfBounds(String::from("stream-cdr of empty stream")))
+            }
+        },
+        // (stream-take n s) is a list of the first n elements of s, or all of them if it has fewer
+        "stream-take" => {
+            check_arity(ident, args, 2)?;
+            let count = match *args[0] {
+                AstNode::Number(n) if n >= 0.0 && n.fract() == 0.0 => n as usize,
+                ref other => return Err(Error::Type(format!("{} expects a count: {:?}", ident, other)))
+            };
+            let mut items = Vec::new();
+            let mut stream = (*args[1]).clone();
+            while items.len() < count {
+                let (head, tail) = match stream_arg(ident, &stream)? {
+                    Some((head, tail)) => (head.clone(), tail.clone()),
+                    None => break
+                };
+                items.push(Box::new(head));
+                // The tail past the last element taken is left unforced
+                if items.len() < count {
+                    stream = force(&tail, context)?;
+                }
+            }
+            Ok(AstNode::Expression(items))
+        },
+        // (stream-map f s1 ...) calls (f x1 ...) with the elements of each stream in turn
+        "stream-map" => {
+            if args.len() < 2 {
+                return Err(Error::Arity(format!("{} expects a function and at least 1 stream", ident)));
+            }
+            let streams: Vec<AstNode> = args[1..].iter().map(|stream| (**stream).clone()).collect();
+            map_streams((*args[0]).clone(), &streams, context)
+        },
+        "stream-filter" => {
+            check_arity(ident, args, 2)?;
+            filter_stream((*args[0]).clone(), (*args[1]).clone(), context)
+        },
+        _ => Err(Error::Type(format!("Invalid operator: {:?}", ident)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use error::Error;
+    use interpreter::Interpreter;
+    use parser::AstNode;
+    use vm::Backend;
+
+    const INTEGERS: &str = "(define integers-from (lambda (n) (stream-cons n (integers-from (+ n 1)))))
+(define naturals (integers-from 0))";
+
+    fn numbers(xs: &[f64]) -> AstNode {
+        return AstNode::Expression(xs.iter().map(|&x| Box::new(AstNode::Number(x))).collect());
+    }
+
+    fn eval(source: &str) -> Result<AstNode, Error> {
+        let results: Vec<_> = Backend::all().iter().map(|&backend| {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_backend(backend);
+            interpreter.eval_str(INTEGERS).unwrap();
+            interpreter.eval_str(source)
+        }).collect();
+        assert_eq!(results[0], results[1], "backends disagree on {}", source);
+        return results[0].clone();
+    }
+
+    #[test]
+    fn infinite_streams() {
+        assert_eq!(eval("(stream-take 4 naturals)"), Ok(numbers(&[0.0, 1.0, 2.0, 3.0])));
+        assert_eq!(eval("(stream-car (stream-cdr (stream-cdr naturals)))"), Ok(AstNode::Number(2.0)));
+        assert_eq!(eval("(stream-take 3 (stream-map * naturals (stream-cdr naturals)))"), Ok(numbers(&[0.0, 2.0, 6.0])));
+        assert_eq!(eval("(stream-take 3 (stream-filter (lambda (x) (> x 4)) naturals))"), Ok(numbers(&[5.0, 6.0, 7.0])));
+        // Each tail is only computed once, however often the stream is walked
+        assert_eq!(eval("(define count (vector 0))
+          (define counted (lambda (n)
+            (stream-cons n (car (list (counted (+ n 1)) (vector-set! count 0 (+ 1 (vector-ref count 0))))))))
+          (define s (counted 0))
+          (list (stream-take 3 s) (stream-take 3 s) (vector-ref count 0))"),
+                   Ok(AstNode::Expression(vec![Box::new(numbers(&[0.0, 1.0, 2.0])), Box::new(numbers(&[0.0, 1.0, 2.0])),
+                                               Box::new(AstNode::Number(2.0))])));
+    }
+
+    #[test]
+    fn finite_streams() {
+        let stream = "(stream-cons 1 (stream-cons 2 '()))";
+        assert_eq!(eval(&format!("(stream-take 5 {})", stream)), Ok(numbers(&[1.0, 2.0])));
+        assert_eq!(eval(&format!("(stream-take 5 (stream-map (lambda (x) (* x 3)) {}))", stream)),
+                   Ok(numbers(&[3.0, 6.0])));
+        assert_eq!(eval(&format!("(stream-filter (lambda (x) (> x 5)) {})", stream)), Ok(AstNode::Expression(vec![])));
+        assert!(eval("(stream-car '())").is_err());
+        assert!(eval("(stream-cdr '(1 2))").is_err());
+        assert!(eval("(stream-take -1 naturals)").is_err());
+    }
+
+    #[test]
+    fn long_walks() {
+        // Far deeper than the call depth limit, which forcing each tail in turn never nears
+        assert_eq!(eval("(stream-take 1 (stream-filter (lambda (x) (> x 20000)) naturals))"), Ok(numbers(&[20001.0])));
+        assert_eq!(eval("(fold-left + 0 (stream-take 20000 naturals))"), Ok(AstNode::Number(199990000.0)));
+    }
+}
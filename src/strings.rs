@@ -0,0 +1,323 @@
+/* strings.rs
+ *
+ * Builtin string and character library.
+ * Indexes and lengths count characters rather than UTF-8 bytes
+ */
+use error::Error;
+use eval::Context;
+use parser::AstNode;
+use symbols::Symbol;
+use std::collections::BTreeSet;
+
+pub fn is_builtin(ident: &str) -> bool {
+    lazy_static! {
+        static ref BUILTINS: BTreeSet<&'static str> = ["string-length", "string-append", "substring",
+            "string-ref", "string-upcase", "string-downcase", "string-split", "string-join", "string-contains",
+            "string-replace", "string->number", "number->string", "string->symbol", "char->integer",
+            "integer->char", "char-alphabetic?", "char-numeric?", "char-whitespace?", "string->list",
+            "list->string"].iter().cloned().collect();
+    }
+    return BUILTINS.contains(ident);
+}
+
+fn check_arity(name: &str, args: &[Box<AstNode>], min: usize, max: usize) -> Result<(), Error> {
+    if args.len() < min || args.len() > max {
+        if min == max {
+            return Err(Error::Arity(format!("{} expects {} argument(s) but got {}", name, min, args.len())));
+        }
+        return Err(Error::Arity(format!("{} expects {} to {} arguments but got {}", name, min, max, args.len())));
+    }
+
+    return Ok(());
+}
+
+fn string_arg<'a>(name: &str, arg: &'a AstNode) -> Result<&'a str, Error> {
+    match *arg {
+        AstNode::String(ref s) => Ok(s.as_str()),
+        ref other => Err(Error::Type(format!("{} expects a string: {:?}", name, other)))
+    }
+}
+
+fn char_arg(name: &str, arg: &AstNode) -> Result<char, Error> {
+    match *arg {
+        AstNode::Char(c) => Ok(c),
+        ref other => Err(Error::Type(format!("{} expects a character: {:?}", name, other)))
+    }
+}
+
+pub fn index_arg(name: &str, arg: &AstNode) -> Result<usize, Error> {
+    match *arg {
+        AstNode::Number(x) if x >= 0.0 && x.fract() == 0.0 => Ok(x as usize),
+        ref other => Err(Error::Type(format!("{} expects a non-negative integer index: {:?}", name, other)))
+    }
+}
+
+/* Returns the characters of s in the range [start, end) */
+fn char_range(name: &str, s: &str, start: usize, end: usize) -> Result<String, Error> {
+    let length = s.chars().count();
+    if start > end || end > length {
+        return Err(Error::OutOfBounds(format!("{} range {}..{} out of bounds for string of length {}",
+                                              name, start, end, length)));
+    }
+    return Ok(s.chars().skip(start).take(end - start).collect());
+}
+
+/* Apply the string builtin with the given name to the evaluated arguments */
+pub fn apply(ident: &str, args: &[Box<AstNode>], context: &mut Context) -> Result<AstNode, Error> {
+    match ident {
+        "string-length" => {
+            check_arity(ident, args, 1, 1)?;
+            Ok(AstNode::Number(string_arg(ident, &args[0])?.chars().count() as f64))
+        },
+        "string-append" => {
+            let parts = args.iter().map(|arg| string_arg(ident, arg)).collect::<Result<Vec<_>, _>>()?;
+            context.reserve(1 + parts.iter().map(|part| part.len() as u64).sum::<u64>())?;
+            let mut result = String::new();
+            for part in parts.iter() {
+                result.push_str(part);
+            }
+            Ok(AstNode::String(result))
+        },
+        "substring" => {
+            check_arity(ident, args, 2, 3)?;
+            let s = string_arg(ident, &args[0])?;
+            let start = index_arg(ident, &args[1])?;
+            let end = match args.get(2) {
+                Some(end) => index_arg(ident, end)?,
+                None => s.chars().count()
+            };
+            Ok(AstNode::String(char_range(ident, s, start, end)?))
+        },
+        "string-ref" => {
+            check_arity(ident, args, 2, 2)?;
+            let s = string_arg(ident, &args[0])?;
+            let index = index_arg(ident, &args[1])?;
+            match s.chars().nth(index) {
+                Some(c) => Ok(AstNode::Char(c)),
+                None => Err(Error::OutOfBounds(format!("{} index {} out of bounds for string of length {}",
+                                                       ident, index, s.chars().count())))
+            }
+        },
+        "string-upcase" => {
+            check_arity(ident, args, 1, 1)?;
+            Ok(AstNode::String(string_arg(ident, &args[0])?.to_uppercase()))
+        },
+        "string-downcase" => {
+            check_arity(ident, args, 1, 1)?;
+            Ok(AstNode::String(string_arg(ident, &args[0])?.to_lowercase()))
+        },
+        "string-split" => {
+            // Splits on whitespace unless a separator is given
+            check_arity(ident, args, 1, 2)?;
+            let s = string_arg(ident, &args[0])?;
+            let parts: Vec<&str> = match args.get(1) {
+                Some(separator) => s.split(string_arg(ident, separator)?).collect(),
+                None => s.split_whitespace().collect()
+            };
+            Ok(AstNode::Expression(parts.into_iter().map(|part| Box::new(AstNode::String(String::from(part)))).collect()))
+        },
+        "string-join" => {
+            check_arity(ident, args, 1, 2)?;
+            let separator = match args.get(1) {
+                Some(separator) => string_arg(ident, separator)?,
+                None => ""
+            };
+            let parts: Vec<&str> = match *args[0] {
+                AstNode::Expression(ref list) => list.iter().map(|part| string_arg(ident, part))
+                                                            .collect::<Result<_, _>>()?,
+                ref other => return Err(Error::Type(format!("{} expects a list of strings: {:?}", ident, other)))
+            };
+            let separators = parts.len().saturating_sub(1) as u64 * separator.len() as u64;
+            context.reserve(1 + separators + parts.iter().map(|part| part.len() as u64).sum::<u64>())?;
+            Ok(AstNode::String(parts.join(separator)))
+        },
+        "string-contains" => {
+            check_arity(ident, args, 2, 2)?;
+            Ok(AstNode::Bool(string_arg(ident, &args[0])?.contains(string_arg(ident, &args[1])?)))
+        },
+        "string-replace" => {
+            check_arity(ident, args, 3, 3)?;
+            let s = string_arg(ident, &args[0])?;
+            let (from, to) = (string_arg(ident, &args[1])?, string_arg(ident, &args[2])?);
+            // An empty pattern matches between every character
+            let matches = s.matches(from).count() as u64;
+            context.reserve(1 + s.len() as u64 + matches.saturating_mul(to.len() as u64))?;
+            Ok(AstNode::String(s.replace(from, to)))
+        },
+        "string->number" => {
+            // Returns false if the string isn't a number
+            check_arity(ident, args, 1, 1)?;
+            match string_arg(ident, &args[0])?.trim().parse::<f64>() {
+                Ok(x) => Ok(AstNode::Number(x)),
+                Err(_) => Ok(AstNode::Bool(false))
+            }
+        },
+        "number->string" => {
+            check_arity(ident, args, 1, 1)?;
+            match *args[0] {
+                AstNode::Number(x) => Ok(AstNode::String(x.to_string())),
+                ref other => Err(Error::Type(format!("{} expects a number: {:?}", ident, other)))
+            }
+        },
+        "string->symbol" => {
+            check_arity(ident, args, 1, 1)?;
+            let name = string_arg(ident, &args[0])?;
+            if Symbol::find(name).is_none() {
+                context.new_symbol()?;
+            }
+            Ok(AstNode::Identifier(Symbol::intern(name)?))
+        },
+        "char->integer" => {
+            check_arity(ident, args, 1, 1)?;
+            Ok(AstNode::Number(char_arg(ident, &args[0])? as u32 as f64))
+        },
+        "integer->char" => {
+            check_arity(ident, args, 1, 1)?;
+            let code = index_arg(ident, &args[0])?;
+            match ::std::char::from_u32(code as u32) {
+                Some(c) if code <= u32::MAX as usize => Ok(AstNode::Char(c)),
+                _ => Err(Error::OutOfBounds(format!("{} invalid code point: {}", ident, code)))
+            }
+        },
+        "char-alphabetic?" => {
+            check_arity(ident, args, 1, 1)?;
+            Ok(AstNode::Bool(char_arg(ident, &args[0])?.is_alphabetic()))
+        },
+        "char-numeric?" => {
+            check_arity(ident, args, 1, 1)?;
+            Ok(AstNode::Bool(char_arg(ident, &args[0])?.is_numeric()))
+        },
+        "char-whitespace?" => {
+            check_arity(ident, args, 1, 1)?;
+            Ok(AstNode::Bool(char_arg(ident, &args[0])?.is_whitespace()))
+        },
+        "string->list" => {
+            check_arity(ident, args, 1, 1)?;
+            context.reserve(1 + string_arg(ident, &args[0])?.chars().count() as u64)?;
+            Ok(AstNode::Expression(string_arg(ident, &args[0])?.chars().map(|c| Box::new(AstNode::Char(c))).collect()))
+        },
+        "list->string" => {
+            check_arity(ident, args, 1, 1)?;
+            match *args[0] {
+                AstNode::Expression(ref list) => Ok(AstNode::String(list.iter().map(|c| char_arg(ident, c))
+                                                                        .collect::<Result<_, _>>()?)),
+                ref other => Err(Error::Type(format!("{} expects a list of characters: {:?}", ident, other)))
+            }
+        },
+        _ => Err(Error::Undefined(String::from(ident)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use error::Error;
+    use eval::Context;
+    use parser::AstNode;
+    use strings;
+    use symbols::Symbol;
+
+    fn apply(ident: &str, args: &[Box<AstNode>]) -> Result<AstNode, Error> {
+        return strings::apply(ident, args, &mut Context::new());
+    }
+
+    fn string(s: &str) -> Box<AstNode> {
+        Box::new(AstNode::String(String::from(s)))
+    }
+
+    fn number(x: f64) -> Box<AstNode> {
+        Box::new(AstNode::Number(x))
+    }
+
+    #[test]
+    fn length_counts_chars() {
+        assert_eq!(apply("string-length", &[string("héllo")]).unwrap(), AstNode::Number(5.0));
+        assert_eq!(apply("string-length", &[string("")]).unwrap(), AstNode::Number(0.0));
+    }
+
+    #[test]
+    fn append() {
+        assert_eq!(apply("string-append", &[string("ab"), string("c"), string("")]).unwrap(), *string("abc"));
+        assert_eq!(apply("string-append", &[]).unwrap(), *string(""));
+    }
+
+    #[test]
+    fn substring_utf8() {
+        assert_eq!(apply("substring", &[string("añbc"), number(1.0), number(3.0)]).unwrap(), *string("ñb"));
+        assert_eq!(apply("substring", &[string("añbc"), number(2.0)]).unwrap(), *string("bc"));
+        assert_eq!(apply("string-ref", &[string("日本語"), number(1.0)]).unwrap(), AstNode::Char('本'));
+    }
+
+    #[test]
+    fn substring_out_of_range() {
+        assert!(apply("substring", &[string("abc"), number(2.0), number(4.0)]).is_err());
+    }
+
+    #[test]
+    fn string_ref_fractional_index() {
+        assert!(apply("string-ref", &[string("abc"), number(0.5)]).is_err());
+    }
+
+    #[test]
+    fn case() {
+        assert_eq!(apply("string-upcase", &[string("Straße")]).unwrap(), *string("STRASSE"));
+        assert_eq!(apply("string-downcase", &[string("ABC")]).unwrap(), *string("abc"));
+    }
+
+    #[test]
+    fn split_and_join() {
+        let words = AstNode::Expression(vec![string("a"), string("b"), string("c")]);
+        assert_eq!(apply("string-split", &[string(" a  b\tc ")]).unwrap(), words);
+        assert_eq!(apply("string-split", &[string("a,b,c"), string(",")]).unwrap(), words);
+        assert_eq!(apply("string-join", &[Box::new(words.clone()), string("-")]).unwrap(), *string("a-b-c"));
+        assert_eq!(apply("string-join", &[Box::new(words)]).unwrap(), *string("abc"));
+    }
+
+    #[test]
+    fn contains_and_replace() {
+        assert_eq!(apply("string-contains", &[string("hello"), string("ell")]).unwrap(), AstNode::Bool(true));
+        assert_eq!(apply("string-contains", &[string("hello"), string("xyz")]).unwrap(), AstNode::Bool(false));
+        assert_eq!(apply("string-replace", &[string("a-b-c"), string("-"), string("+")]).unwrap(), *string("a+b+c"));
+    }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(apply("string->number", &[string("-2.5")]).unwrap(), AstNode::Number(-2.5));
+        assert_eq!(apply("string->number", &[string("abc")]).unwrap(), AstNode::Bool(false));
+        assert_eq!(apply("number->string", &[number(3.0)]).unwrap(), *string("3"));
+        assert_eq!(apply("number->string", &[number(0.75)]).unwrap(), *string("0.75"));
+        assert_eq!(apply("string->symbol", &[string("abc")]).unwrap(), AstNode::Identifier(Symbol::intern("abc").unwrap()));
+    }
+
+    #[test]
+    fn string_ref_out_of_range() {
+        assert!(apply("string-ref", &[string("abc"), number(3.0)]).is_err());
+    }
+
+    #[test]
+    fn char_conversions() {
+        assert_eq!(apply("char->integer", &[Box::new(AstNode::Char('A'))]).unwrap(), AstNode::Number(65.0));
+        assert_eq!(apply("integer->char", &[number(955.0)]).unwrap(), AstNode::Char('λ'));
+    }
+
+    #[test]
+    fn integer_to_char_surrogate() {
+        assert!(apply("integer->char", &[number(55296.0)]).is_err());
+    }
+
+    #[test]
+    fn char_predicates() {
+        let c = |x| Box::new(AstNode::Char(x));
+        assert_eq!(apply("char-alphabetic?", &[c('é')]).unwrap(), AstNode::Bool(true));
+        assert_eq!(apply("char-alphabetic?", &[c('1')]).unwrap(), AstNode::Bool(false));
+        assert_eq!(apply("char-numeric?", &[c('7')]).unwrap(), AstNode::Bool(true));
+        assert_eq!(apply("char-whitespace?", &[c('\n')]).unwrap(), AstNode::Bool(true));
+        assert_eq!(apply("char-whitespace?", &[c('x')]).unwrap(), AstNode::Bool(false));
+    }
+
+    #[test]
+    fn string_list_round_trip() {
+        let chars = AstNode::Expression(vec![Box::new(AstNode::Char('h')), Box::new(AstNode::Char('ï'))]);
+        assert_eq!(apply("string->list", &[string("hï")]).unwrap(), chars);
+        assert_eq!(apply("list->string", &[Box::new(chars)]).unwrap(), *string("hï"));
+    }
+}
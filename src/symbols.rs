@@ -0,0 +1,263 @@
+/* symbols.rs
+ *
+ * Identifiers are interned: each distinct name is stored once, for the
+ * life of the program, and a Symbol is a handle to it.  Symbols are
+ * cloned without copying the name and compare by identity rather than by
+ * name, so looking up, defining and comparing names costs no allocation.
+ * Whether a name is one of the standard builtins is worked out once, when
+ * it is first interned.
+ *
+ * Interned symbols are never freed.  gensym makes uninterned symbols, which
+ * aren't added to the table, so no name in the source can refer to them.
+ * They are reference counted, and freed with the last value that holds
+ * them, and the symbol limit bounds how many a script may make.
+ *
+ * Ids can run out.  Making a symbol then fails, except for the names the
+ * interpreter itself uses, which have ids kept back for them
+ */
+use error::Error;
+use eval;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::ptr;
+use std::rc::Rc;
+use std::sync::{Mutex, MutexGuard};
+
+/* The name a symbol refers to */
+struct Entry {
+    id: u32,
+    name: String,
+    builtin: bool, // whether the name is a standard builtin, before any natives are registered
+}
+
+#[derive(Clone)]
+enum Handle {
+    Interned(&'static Entry),
+    Uninterned(Rc<Entry>),
+}
+
+#[derive(Clone)]
+pub struct Symbol {
+    handle: Handle,
+}
+
+struct Table {
+    interned: HashMap<&'static str, &'static Entry>,
+    made: u32, // how many symbols have been made, interned or not
+}
+
+lazy_static! {
+    static ref SYMBOLS: Mutex<Table> = Mutex::new(Table{interned: HashMap::new(), made: 0});
+}
+
+fn table() -> MutexGuard<'static, Table> {
+    return SYMBOLS.lock().unwrap_or_else(|e| e.into_inner());
+}
+
+/* The ids past this are kept for the interpreter's own names */
+const LAST_ID: u32 = u32::MAX - (1 << 16);
+const LAST_KEYWORD_ID: u32 = u32::MAX - 1; // so the count of symbols made fits
+
+/* Symbols are numbered in the order they were made, up to the last id they may have */
+fn make(table: &mut Table, name: &str, builtin: bool, last: u32) -> Option<Entry> {
+    let id = table.made;
+    if id > last {
+        return None;
+    }
+    table.made = id + 1;
+    return Some(Entry{id, name: String::from(name), builtin});
+}
+
+fn intern_in(table: &mut Table, name: &str, last: u32) -> Option<&'static Entry> {
+    if let Some(&entry) = table.interned.get(name) {
+        return Some(entry);
+    }
+    let entry: &'static Entry = Box::leak(Box::new(make(table, name, eval::is_builtin(name), last)?));
+    table.interned.insert(&entry.name, entry);
+    return Some(entry);
+}
+
+fn exhausted() -> Error {
+    return Error::ResourceExhausted(String::from("no symbol ids are left"));
+}
+
+impl Symbol {
+    /* The symbol for the name, interning it the first time it is seen */
+    pub fn intern(name: &str) -> Result<Symbol, Error> {
+        return match intern_in(&mut table(), name, LAST_ID) {
+            Some(entry) => Ok(Symbol{handle: Handle::Interned(entry)}),
+            None => Err(exhausted())
+        };
+    }
+    /* The symbol for a name the interpreter itself uses, such as define, a builtin
+     * or a field of a derived host type.  They are few, and have ids kept back
+     * for them, so are interned even once scripts have used up the rest
+     */
+    pub(crate) fn keyword(name: &'static str) -> Symbol {
+        let entry = intern_in(&mut table(), name, LAST_KEYWORD_ID).expect("Ran out of ids for the interpreter's own names");
+        return Symbol{handle: Handle::Interned(entry)};
+    }
+    /* A symbol distinct from every other, even those with the same name.  It is
+     * never a builtin, and intern and find never give it
+     */
+    pub fn uninterned(name: &str) -> Result<Symbol, Error> {
+        return match make(&mut table(), name, false, LAST_ID) {
+            Some(entry) => Ok(Symbol{handle: Handle::Uninterned(Rc::new(entry))}),
+            None => Err(exhausted())
+        };
+    }
+    /* The symbol for the name if it has been interned.  A name never
+     * interned can't have been defined or used
+     */
+    pub fn find(name: &str) -> Option<Symbol> {
+        return table().interned.get(name).map(|&entry| Symbol{handle: Handle::Interned(entry)});
+    }
+    /* How many symbols have been made, interned or not, by every interpreter */
+    pub fn count() -> usize {
+        return table().made as usize;
+    }
+    /* Symbols are numbered in the order they were made */
+    pub fn id(&self) -> u32 {
+        return (*self).entry().id;
+    }
+    pub fn as_str(&self) -> &str {
+        return &(*self).entry().name;
+    }
+    pub(crate) fn is_standard_builtin(&self) -> bool {
+        return (*self).entry().builtin;
+    }
+    fn entry(&self) -> &Entry {
+        match (*self).handle {
+            Handle::Interned(entry) => entry,
+            Handle::Uninterned(ref entry) => entry
+        }
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Symbol) -> bool {
+        return ptr::eq((*self).entry(), other.entry());
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) -> () {
+        (*self).entry().id.hash(state);
+    }
+}
+
+/* Ordered by name, so that sorting doesn't depend on the order names were interned.
+ * Uninterned symbols with the same name are ordered by when they were made
+ */
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Symbol) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl Ord for Symbol {
+    fn cmp(&self, other: &Symbol) -> Ordering {
+        if (*self) == *other {
+            return Ordering::Equal;
+        }
+        return (*self).as_str().cmp(other.as_str()).then_with(|| (*self).id().cmp(&other.id()));
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        return (*self).as_str() == other;
+    }
+}
+
+impl<'a> PartialEq<&'a str> for Symbol {
+    fn eq(&self, other: &&'a str) -> bool {
+        return (*self).as_str() == *other;
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+    fn deref(&self) -> &str {
+        return (*self).as_str();
+    }
+}
+
+/* Shown as the name would be, so that symbols read like the strings they replace */
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return fmt::Debug::fmt((*self).as_str(), f);
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return f.write_str((*self).as_str());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use symbols::{intern_in, Handle, Symbol, Table, LAST_ID, LAST_KEYWORD_ID};
+
+    #[test]
+    fn interned_once() {
+        let a = Symbol::intern("interned-once").unwrap();
+        let b = Symbol::intern(&String::from("interned-once")).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a.id(), b.id());
+        assert_eq!(Symbol::find("interned-once"), Some(a.clone()));
+        assert_eq!(Symbol::find("never-interned-anywhere"), None);
+        assert!(Symbol::intern("interned-twice").unwrap() != a);
+        assert!(a < Symbol::intern("interned-twice").unwrap());
+        assert_eq!(a, "interned-once");
+        assert_eq!(format!("{:?} {}", a, a), "\"interned-once\" interned-once");
+        assert!(Symbol::intern("car").unwrap().is_standard_builtin());
+        assert!(!a.is_standard_builtin());
+    }
+
+    #[test]
+    fn uninterned_symbols() {
+        let before = Symbol::count();
+        let a = Symbol::uninterned("uninterned-name").unwrap();
+        let b = Symbol::uninterned("uninterned-name").unwrap();
+        assert!(a != b && a.id() != b.id());
+        assert!(a < b && a.clone() == a);
+        assert_eq!(a.as_str(), "uninterned-name");
+        assert_eq!(Symbol::find("uninterned-name"), None);
+        assert!(Symbol::intern("uninterned-name").unwrap() != a);
+        assert!(!Symbol::uninterned("car").unwrap().is_standard_builtin());
+        assert!(Symbol::count() >= before + 4);
+        // Freed with the last copy
+        let name = match a.handle {
+            Handle::Uninterned(ref entry) => Rc::downgrade(entry),
+            Handle::Interned(_) => panic!("Expected an uninterned symbol")
+        };
+        let copy = a.clone();
+        drop(a);
+        assert!(name.upgrade().is_some());
+        drop(copy);
+        assert!(name.upgrade().is_none());
+    }
+
+    #[test]
+    fn running_out_of_ids() {
+        let mut table = Table{interned: HashMap::new(), made: LAST_ID};
+        let last = intern_in(&mut table, "last-script-name", LAST_ID).unwrap();
+        assert_eq!(last.id, LAST_ID);
+        assert!(intern_in(&mut table, "one-name-too-many", LAST_ID).is_none());
+        // Names already interned are still found, and the interpreter's own names still made
+        assert_eq!(intern_in(&mut table, "last-script-name", LAST_ID).unwrap().id, LAST_ID);
+        assert_eq!(intern_in(&mut table, "lambda", LAST_KEYWORD_ID).unwrap().id, LAST_ID + 1);
+        table.made = LAST_KEYWORD_ID;
+        assert_eq!(intern_in(&mut table, "define", LAST_KEYWORD_ID).unwrap().id, LAST_KEYWORD_ID);
+        assert!(intern_in(&mut table, "if", LAST_KEYWORD_ID).is_none());
+    }
+}
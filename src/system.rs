@@ -0,0 +1,200 @@
+/* system.rs
+ *
+ * Builtins with access to the host system: files, processes,
+ * environment variables and the clock.  Each belongs to a sandbox
+ * group so a policy can withhold it
+ */
+use error::Error;
+use eval::Context;
+use parser::AstNode;
+use sandbox::Group;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/* How long sleep waits between checks for cancellation or the deadline */
+const SLEEP_SLICE: Duration = Duration::from_millis(10);
+
+fn builtins() -> &'static BTreeMap<&'static str, Group> {
+    lazy_static! {
+        static ref BUILTINS: BTreeMap<&'static str, Group> = [
+            ("read-file", Group::Filesystem), ("write-file", Group::Filesystem), ("file-exists?", Group::Filesystem),
+            ("run-command", Group::Process), ("exit", Group::Process),
+            ("getenv", Group::Environment), ("command-line", Group::Environment),
+            ("current-time", Group::Time), ("sleep", Group::Time)].iter().cloned().collect();
+    }
+    return &BUILTINS;
+}
+
+pub fn is_builtin(ident: &str) -> bool {
+    return builtins().contains_key(ident);
+}
+
+/* The sandbox group of a system builtin */
+pub fn group(ident: &str) -> Option<Group> {
+    return builtins().get(ident).cloned();
+}
+
+fn check_arity(name: &str, args: &[Box<AstNode>], count: usize) -> Result<(), Error> {
+    if args.len() != count {
+        return Err(Error::Arity(format!("{} expects {} argument(s) but got {}", name, count, args.len())));
+    }
+
+    return Ok(());
+}
+
+fn string_arg<'a>(name: &str, arg: &'a AstNode) -> Result<&'a str, Error> {
+    match *arg {
+        AstNode::String(ref s) => Ok(s.as_str()),
+        ref other => Err(Error::Type(format!("{} expects a string: {:?}", name, other)))
+    }
+}
+
+fn number_arg(name: &str, arg: &AstNode) -> Result<f64, Error> {
+    match *arg {
+        AstNode::Number(x) => Ok(x),
+        ref other => Err(Error::Type(format!("{} expects a number: {:?}", name, other)))
+    }
+}
+
+fn io_error(name: &str, subject: &str, error: ::std::io::Error) -> Error {
+    return Error::Io(format!("{} {}: {}", name, subject, error));
+}
+
+/* Apply the system builtin with the given name to the evaluated arguments */
+pub fn apply(ident: &str, args: &[Box<AstNode>], context: &Context) -> Result<AstNode, Error> {
+    match ident {
+        "read-file" => {
+            check_arity(ident, args, 1)?;
+            let path = string_arg(ident, &args[0])?;
+            fs::read_to_string(path).map(AstNode::String).map_err(|e| io_error(ident, path, e))
+        },
+        "write-file" => {
+            check_arity(ident, args, 2)?;
+            let path = string_arg(ident, &args[0])?;
+            fs::write(path, string_arg(ident, &args[1])?).map_err(|e| io_error(ident, path, e))?;
+            Ok(AstNode::Expression(vec![]))
+        },
+        "file-exists?" => {
+            check_arity(ident, args, 1)?;
+            Ok(AstNode::Bool(Path::new(string_arg(ident, &args[0])?).exists()))
+        },
+        "run-command" => {
+            // (run-command program arg ...) returns the program's standard output
+            if args.is_empty() {
+                return Err(Error::Arity(format!("{} expects a program", ident)));
+            }
+            let program = string_arg(ident, &args[0])?;
+            let arguments = args[1..].iter().map(|arg| string_arg(ident, arg)).collect::<Result<Vec<_>, _>>()?;
+            let output = process::Command::new(program).args(arguments).output().map_err(|e| io_error(ident, program, e))?;
+            Ok(AstNode::String(String::from_utf8_lossy(&output.stdout).into_owned()))
+        },
+        // The evaluation stops with an Exit error, and the host decides whether its process ends
+        "exit" => {
+            check_arity(ident, args, 1)?;
+            Err(Error::Exit(number_arg(ident, &args[0])? as i32))
+        },
+        "getenv" => {
+            // Returns false if the variable isn't set
+            check_arity(ident, args, 1)?;
+            match env::var(string_arg(ident, &args[0])?) {
+                Ok(value) => Ok(AstNode::String(value)),
+                Err(_) => Ok(AstNode::Bool(false))
+            }
+        },
+        "command-line" => {
+            check_arity(ident, args, 0)?;
+            Ok(AstNode::Expression(env::args().map(|arg| Box::new(AstNode::String(arg))).collect()))
+        },
+        "current-time" => {
+            // Seconds since the Unix epoch
+            check_arity(ident, args, 0)?;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            Ok(AstNode::Number(now.as_secs_f64()))
+        },
+        // Sleeps a slice at a time, so a cancelled evaluation or one past its deadline stops soon after
+        "sleep" => {
+            check_arity(ident, args, 1)?;
+            let mut remaining = Duration::try_from_secs_f64(number_arg(ident, &args[0])?).map_err(|_| {
+                Error::Type(format!("{} expects a non-negative number of seconds: {:?}", ident, args[0]))
+            })?;
+            while !remaining.is_zero() {
+                context.poll_interrupts()?;
+                let slice = remaining.min(SLEEP_SLICE);
+                thread::sleep(slice);
+                remaining -= slice;
+            }
+            Ok(AstNode::Expression(vec![]))
+        },
+        _ => Err(Error::Undefined(String::from(ident)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use error::Error;
+    use eval::Context;
+    use interpreter::Interpreter;
+    use parser::AstNode;
+    use sandbox::Group;
+    use std::env;
+    use std::time::{Duration, Instant};
+    use system::{apply, group};
+
+    fn string(s: &str) -> Box<AstNode> {
+        Box::new(AstNode::String(String::from(s)))
+    }
+
+    #[test]
+    fn groups() {
+        assert_eq!(group("read-file"), Some(Group::Filesystem));
+        assert_eq!(group("getenv"), Some(Group::Environment));
+        assert_eq!(group("car"), None);
+    }
+
+    #[test]
+    fn files() {
+        let c = Context::new();
+        let path = env::temp_dir().join(format!("wisp_system_{}.txt", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        apply("write-file", &[string(path), string("contents")], &c).unwrap();
+        assert_eq!(apply("file-exists?", &[string(path)], &c), Ok(AstNode::Bool(true)));
+        assert_eq!(apply("read-file", &[string(path)], &c), Ok(AstNode::String(String::from("contents"))));
+        ::std::fs::remove_file(path).unwrap();
+        match apply("read-file", &[string(path)], &c) {
+            Err(Error::Io(_)) => {},
+            other => panic!("Expected IO error: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn environment_and_time() {
+        let c = Context::new();
+        assert_eq!(apply("getenv", &[string("WISP_SURELY_UNSET_VARIABLE")], &c), Ok(AstNode::Bool(false)));
+        match apply("current-time", &[], &c) {
+            Ok(AstNode::Number(t)) => assert!(t > 1.0e9),
+            other => panic!("Expected a time: {:?}", other)
+        }
+        assert!(apply("sleep", &[Box::new(AstNode::Number(-1.0))], &c).is_err());
+    }
+
+    #[test]
+    fn sleep_is_interrupted() {
+        let mut interpreter = Interpreter::new();
+        match interpreter.eval_str("(sleep (* 100000000000 100000000000))") {
+            Err(Error::Type(_)) => {},
+            other => panic!("Expected type error: {:?}", other)
+        }
+        let start = Instant::now();
+        interpreter.set_deadline(Some(start + Duration::from_millis(50)));
+        match interpreter.eval_str("(sleep 60)") {
+            Err(Error::Timeout(_)) => {},
+            other => panic!("Expected timeout: {:?}", other)
+        }
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,213 @@
+/* vectors.rs
+ *
+ * Builtin vector library.
+ * Vectors are mutable and shared: every copy of a vector value refers to the same elements
+ */
+use error::Error;
+use eval;
+use eval::Context;
+use heap;
+use parser::AstNode;
+use strings::index_arg;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::rc::Rc;
+
+pub fn is_builtin(ident: &str) -> bool {
+    lazy_static! {
+        static ref BUILTINS: BTreeSet<&'static str> = ["make-vector", "vector", "vector-ref", "vector-set!",
+            "vector-length", "vector->list", "list->vector", "vector-map", "vector-fill!"].iter().cloned().collect();
+    }
+    return BUILTINS.contains(ident);
+}
+
+pub fn new_vector(elements: Vec<AstNode>) -> AstNode {
+    let vector = Rc::new(RefCell::new(elements));
+    heap::track_vector(&vector);
+    return AstNode::Vector(vector);
+}
+
+fn check_arity(name: &str, args: &[Box<AstNode>], min: usize, max: usize) -> Result<(), Error> {
+    if args.len() < min || args.len() > max {
+        return Err(Error::Arity(format!("{} expects {} to {} arguments but got {}", name, min, max, args.len())));
+    }
+
+    return Ok(());
+}
+
+fn vector_arg<'a>(name: &str, arg: &'a AstNode) -> Result<&'a Rc<RefCell<Vec<AstNode>>>, Error> {
+    match *arg {
+        AstNode::Vector(ref elements) => Ok(elements),
+        ref other => Err(Error::Type(format!("{} expects a vector: {:?}", name, other)))
+    }
+}
+
+fn bounds_check(name: &str, elements: &[AstNode], index: usize) -> Result<(), Error> {
+    if index >= elements.len() {
+        return Err(Error::OutOfBounds(format!("{} index {} out of bounds for vector of length {}",
+                                              name, index, elements.len())));
+    }
+
+    return Ok(());
+}
+
+/* Apply the vector builtin with the given name to the evaluated arguments */
+pub fn apply(ident: &str, args: &[Box<AstNode>], context: &mut Context) -> Result<AstNode, Error> {
+    match ident {
+        "make-vector" => {
+            check_arity(ident, args, 1, 2)?;
+            let length = index_arg(ident, &args[0])?;
+            context.reserve((length as u64).saturating_add(1))?;
+            let fill = match args.get(1) {
+                Some(fill) => (**fill).clone(),
+                None => AstNode::Number(0.0)
+            };
+            // Without an allocation limit the length is only bounded by memory
+            let mut elements = Vec::new();
+            elements.try_reserve_exact(length)
+                .map_err(|error| Error::ResourceExhausted(format!("{} of length {}: {}", ident, length, error)))?;
+            elements.resize(length, fill);
+            Ok(new_vector(elements))
+        },
+        "vector" => Ok(new_vector(args.iter().map(|arg| (**arg).clone()).collect())),
+        "vector-ref" => {
+            check_arity(ident, args, 2, 2)?;
+            let elements = vector_arg(ident, &args[0])?.borrow();
+            let index = index_arg(ident, &args[1])?;
+            bounds_check(ident, &elements, index)?;
+            Ok(elements[index].clone())
+        },
+        "vector-set!" => {
+            check_arity(ident, args, 3, 3)?;
+            context.check_element(&args[2])?;
+            {
+                let mut elements = vector_arg(ident, &args[0])?.borrow_mut();
+                let index = index_arg(ident, &args[1])?;
+                bounds_check(ident, &elements, index)?;
+                elements[index] = (*args[2]).clone();
+            }
+            Ok((*args[0]).clone())
+        },
+        "vector-length" => {
+            check_arity(ident, args, 1, 1)?;
+            Ok(AstNode::Number(vector_arg(ident, &args[0])?.borrow().len() as f64))
+        },
+        "vector->list" => {
+            check_arity(ident, args, 1, 1)?;
+            let elements = vector_arg(ident, &args[0])?.borrow();
+            Ok(AstNode::Expression(elements.iter().map(|x| Box::new(x.clone())).collect()))
+        },
+        "list->vector" => {
+            check_arity(ident, args, 1, 1)?;
+            match *args[0] {
+                AstNode::Expression(ref list) => {
+                    context.reserve(1 + list.len() as u64)?;
+                    Ok(new_vector(list.iter().map(|x| (**x).clone()).collect()))
+                },
+                ref other => Err(Error::Type(format!("{} expects a list: {:?}", ident, other)))
+            }
+        },
+        "vector-map" => {
+            // (vector-map f v1 v2 ...) stops at the end of the shortest vector
+            if args.len() < 2 {
+                return Err(Error::Arity(format!("{} expects a function and at least 1 vector", ident)));
+            }
+            let vectors = args[1..].iter().map(|arg| vector_arg(ident, arg).map(|v| v.borrow().clone()))
+                                          .collect::<Result<Vec<_>, _>>()?;
+            let length = vectors.iter().map(|v| v.len()).min().unwrap_or(0);
+            let mut result = Vec::with_capacity(length);
+            for i in 0..length {
+                let call_args: Vec<Box<AstNode>> = vectors.iter().map(|v| Box::new(v[i].clone())).collect();
+                result.push(eval::apply(&args[0], &call_args, context)?);
+            }
+            Ok(new_vector(result))
+        },
+        "vector-fill!" => {
+            check_arity(ident, args, 2, 2)?;
+            context.check_element(&args[1])?;
+            for element in vector_arg(ident, &args[0])?.borrow_mut().iter_mut() {
+                *element = (*args[1]).clone();
+            }
+            Ok((*args[0]).clone())
+        },
+        _ => Err(Error::Undefined(String::from(ident)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use error::Error;
+    use eval::Context;
+    use parser::AstNode;
+    use symbols::Symbol;
+    use vectors::{apply, new_vector};
+
+    fn number(x: f64) -> Box<AstNode> {
+        Box::new(AstNode::Number(x))
+    }
+
+    fn numbers(xs: &[f64]) -> AstNode {
+        new_vector(xs.iter().map(|x| AstNode::Number(*x)).collect())
+    }
+
+    #[test]
+    fn make_and_ref() {
+        let mut c = Context::new();
+        let v = apply("make-vector", &[number(3.0), number(7.0)], &mut c).unwrap();
+        assert_eq!(v, numbers(&[7.0, 7.0, 7.0]));
+        let v = apply("vector", &[number(1.0), number(2.0)], &mut c).unwrap();
+        assert_eq!(apply("vector-ref", &[Box::new(v.clone()), number(1.0)], &mut c).unwrap(), AstNode::Number(2.0));
+        assert_eq!(apply("vector-length", &[Box::new(v)], &mut c).unwrap(), AstNode::Number(2.0));
+    }
+
+    #[test]
+    fn set_is_shared() {
+        let mut c = Context::new();
+        let v = numbers(&[1.0, 2.0]);
+        let alias = v.clone();
+        apply("vector-set!", &[Box::new(v), number(0.0), number(9.0)], &mut c).unwrap();
+        assert_eq!(alias, numbers(&[9.0, 2.0]));
+        apply("vector-fill!", &[Box::new(alias.clone()), number(4.0)], &mut c).unwrap();
+        assert_eq!(alias, numbers(&[4.0, 4.0]));
+    }
+
+    #[test]
+    fn bounds_checked() {
+        let mut c = Context::new();
+        let v = Box::new(numbers(&[1.0, 2.0]));
+        match apply("vector-ref", &[v.clone(), number(2.0)], &mut c) {
+            Err(Error::OutOfBounds(_)) => {},
+            other => panic!("Expected out of bounds error: {:?}", other)
+        }
+        assert!(apply("vector-set!", &[v.clone(), number(5.0), number(0.0)], &mut c).is_err());
+        assert!(apply("vector-ref", &[v, number(-1.0)], &mut c).is_err());
+        assert!(apply("vector-ref", &[number(1.0), number(0.0)], &mut c).is_err());
+    }
+
+    #[test]
+    fn huge_lengths_are_refused() {
+        let mut c = Context::new();
+        match apply("make-vector", &[number(1e20), number(0.0)], &mut c) {
+            Err(Error::ResourceExhausted(_)) => {},
+            other => panic!("Expected resource exhausted error: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn list_conversion() {
+        let mut c = Context::new();
+        let list = AstNode::Expression(vec![number(1.0), number(2.0)]);
+        let v = apply("list->vector", &[Box::new(list.clone())], &mut c).unwrap();
+        assert_eq!(v, numbers(&[1.0, 2.0]));
+        assert_eq!(apply("vector->list", &[Box::new(v)], &mut c).unwrap(), list);
+    }
+
+    #[test]
+    fn map() {
+        let mut c = Context::new();
+        let plus = Box::new(AstNode::Identifier(Symbol::intern("+").unwrap()));
+        let result = apply("vector-map", &[plus, Box::new(numbers(&[1.0, 2.0, 3.0])), Box::new(numbers(&[10.0, 20.0]))],
+                           &mut c).unwrap();
+        assert_eq!(result, numbers(&[11.0, 22.0]));
+    }
+}
@@ -0,0 +1,457 @@
+/* vm.rs
+ *
+ * A stack machine running the bytecode from compiler.  Values are ASTs,
+ * and scopes, limits, spans and call frames are kept in the Context as
+ * the tree walker keeps them, so both give the same results.  Calls from
+ * one lambda to another push a vm frame rather than recursing natively
+ */
+use compiler::{compile, compile_lambda, Chunk, Op};
+use error::Error;
+use eval;
+use eval::Context;
+use frames::Frame;
+use lexer::Span;
+use parser::AstNode;
+use symbols::Symbol;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::{Rc, Weak};
+use wspc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    #[default]
+    TreeWalker,
+    Bytecode,
+}
+
+impl Backend {
+    /* Every backend, for comparing them */
+    pub fn all() -> &'static [Backend] {
+        return &[Backend::TreeWalker, Backend::Bytecode];
+    }
+}
+
+/* What is made of lambda bodies, such as their compiled code, keyed by the address of
+ * the shared body.  A weak reference to the body keeps its address from being reused
+ * by another while it is cached, without keeping the body alive.  Bodies since freed
+ * are forgotten rather than let the cache grow
+ */
+#[derive(Clone)]
+pub struct BodyCache<T> {
+    entries: HashMap<usize, (Weak<AstNode>, T)>,
+}
+
+/* Compiled lambda bodies */
+pub type CodeCache = BodyCache<Rc<Chunk>>;
+
+impl<T: Clone> BodyCache<T> {
+    pub fn new() -> BodyCache<T> {
+        return BodyCache{entries: HashMap::new()};
+    }
+    pub(crate) fn get(&self, body: &Rc<AstNode>) -> Option<T> {
+        return (*self).entries.get(&(Rc::as_ptr(body) as usize)).map(|&(_, ref value)| value.clone());
+    }
+    pub(crate) fn insert(&mut self, body: &Rc<AstNode>, value: T) -> () {
+        if (*self).entries.len() == (*self).entries.capacity() {
+            (*self).entries.retain(|_, &mut (ref body, _)| body.strong_count() > 0);
+        }
+        (*self).entries.insert(Rc::as_ptr(body) as usize, (Rc::downgrade(body), value));
+    }
+    /* How many bodies are cached, some of which may have been freed since */
+    pub fn len(&self) -> usize {
+        return (*self).entries.len();
+    }
+    pub fn is_empty(&self) -> bool {
+        return (*self).entries.is_empty();
+    }
+}
+
+impl<T: Clone> Default for BodyCache<T> {
+    fn default() -> BodyCache<T> {
+        return BodyCache::new();
+    }
+}
+
+impl<T> fmt::Debug for BodyCache<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BodyCache({} entries)", (*self).entries.len())
+    }
+}
+
+/* A body is only ever shared by copies of the same lambda, so its parameters are the same */
+fn lambda_chunk(parameters: &[Symbol], body: &Rc<AstNode>, context: &mut Context) -> Rc<Chunk> {
+    if let Some(chunk) = context.code_cache().get(body) {
+        return chunk;
+    }
+    let chunk = Rc::new(compile_lambda(parameters, body, context));
+    context.code_cache().insert(body, chunk.clone());
+    return chunk;
+}
+
+/* What returning from a vm frame has to undo */
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Top, // Top, a top level evaluation
+    Body, // Body, a lambda body applied by eval::apply, which handles the call frame
+    Call, // Call, a lambda called by the vm
+}
+
+struct CallFrame {
+    chunk: Rc<Chunk>,
+    pc: usize,
+    kind: Kind,
+    spans: Vec<Option<Span>>, // the spans that were current before each span entered by the frame
+}
+
+impl CallFrame {
+    fn new(chunk: Rc<Chunk>, kind: Kind) -> CallFrame {
+        return CallFrame{chunk, pc: 0, kind, spans: vec![]};
+    }
+}
+
+struct Vm {
+    stack: Vec<AstNode>,
+    frames: Vec<CallFrame>,
+}
+
+/* Compile and evaluate an AST */
+pub fn eval(ast: &AstNode, context: &mut Context) -> Result<AstNode, Error> {
+    return run(CallFrame::new(Rc::new(compile(ast, context)), Kind::Top), context);
+}
+
+/* Evaluate a chunk compiled earlier, such as one loaded from a compiled file */
+pub fn eval_chunk(chunk: Chunk, context: &mut Context) -> Result<AstNode, Error> {
+    wspc::check(&chunk)?;
+    return run(CallFrame::new(Rc::new(chunk), Kind::Top), context);
+}
+
+/* Bind the arguments in a new namespace, as a lambda call does */
+fn enter_lambda(parameters: &[Symbol], args: Vec<AstNode>, context: &mut Context) -> Result<(), Error> {
+    context.enter_call()?;
+    context.add_parameters(parameters, args.into_iter().map(Box::new));
+    return Ok(());
+}
+
+fn exit_lambda(context: &mut Context) -> () {
+    (*context).remove_namespace();
+    context.exit_call();
+}
+
+/* Apply a lambda to evaluated arguments, for eval::apply */
+pub fn call_lambda(parameters: &[Symbol], body: &Rc<AstNode>, args: &[Box<AstNode>], context: &mut Context)
+    -> Result<AstNode, Error>
+{
+    enter_lambda(parameters, args.iter().map(|x| (**x).clone()).collect(), context)?;
+    let chunk = lambda_chunk(parameters, body, context);
+    return run(CallFrame::new(chunk, Kind::Body), context);
+}
+
+/* The compiler never makes code that underflows the stack or refers to slots that don't
+ * exist, but a chunk loaded from a file may have been made by anything
+ */
+pub(crate) fn malformed() -> Error {
+    return Error::Io(String::from("malformed compiled code"));
+}
+
+fn run(frame: CallFrame, context: &mut Context) -> Result<AstNode, Error> {
+    let mut vm = Vm{stack: Vec::new(), frames: vec![frame]};
+    let result = vm.execute(context);
+    if result.is_err() {
+        vm.unwind(context);
+    }
+    return result;
+}
+
+impl Vm {
+    fn pop(&mut self) -> Result<AstNode, Error> {
+        return (*self).stack.pop().ok_or_else(malformed);
+    }
+
+    fn execute(&mut self, context: &mut Context) -> Result<AstNode, Error> {
+        loop {
+            let (chunk, op) = {
+                let frame = (*self).frames.last_mut().unwrap();
+                frame.pc += 1;
+                (frame.chunk.clone(), frame.chunk.code[frame.pc - 1].clone())
+            };
+            match op {
+                Op::Constant(i) => {
+                    context.step()?;
+                    (*self).stack.push(chunk.constants[i].clone());
+                },
+                Op::Load(i) => {
+                    context.step()?;
+                    let value = load(chunk.names[i].clone(), context)?;
+                    (*self).stack.push(value);
+                },
+                Op::LoadLocal(depth, index, i) => {
+                    context.step()?;
+                    let value = match context.lookup_local(depth, index)? {
+                        Some(value) => value.clone(),
+                        None => load(chunk.names[i].clone(), context)?
+                    };
+                    (*self).stack.push(value);
+                },
+                Op::Define(i) => {
+                    context.step()?;
+                    if context.is_builtin(chunk.names[i].clone()) {
+                        return Err(Error::Syntax(format!("Can't override buildin: {:?}", chunk.names[i].clone())));
+                    }
+                },
+                Op::Bind(i, define) => {
+                    let value = (*self).pop()?;
+                    context.add_define(chunk.names[i].clone(), Box::new(value));
+                    (*self).stack.push(chunk.constants[define].clone());
+                },
+                Op::JumpUnless(address) => {
+                    context.step()?;
+                    match (*self).pop()? {
+                        AstNode::Bool(true) => {},
+                        AstNode::Bool(false) => (*self).frames.last_mut().unwrap().pc = address,
+                        pred => return Err(Error::Type(format!("Unexpected predicate for if statement: {:?}", pred)))
+                    }
+                },
+                Op::Jump(address) => {
+                    (*self).frames.last_mut().unwrap().pc = address;
+                },
+                Op::Expand(name, call, address) => {
+                    if context.is_macro(chunk.names[name].clone()) {
+                        let mut expansion = chunk.constants[call].clone();
+                        eval::eval(&mut expansion, context)?;
+                        (*self).stack.push(expansion);
+                        (*self).frames.last_mut().unwrap().pc = address;
+                    }
+                },
+                Op::Call(count, name) => {
+                    context.step()?;
+                    if count > (*self).stack.len() {
+                        return Err(malformed());
+                    }
+                    let args = (*self).stack.split_off((*self).stack.len() - count);
+                    let op = (*self).pop()?;
+                    context.push_frame(Frame{function: chunk.names[name].clone(), span: context.current_span(),
+                                             args: args.clone()});
+                    (*self).call(op, args, context)?;
+                },
+                Op::Eval(i) => {
+                    let mut value = chunk.constants[i].clone();
+                    eval::eval(&mut value, context)?;
+                    (*self).stack.push(value);
+                },
+                Op::EnterSpan(i) => {
+                    let outer = context.enter_span(chunk.spans[i].clone());
+                    (*self).frames.last_mut().unwrap().spans.push(outer);
+                },
+                Op::ExitSpan => {
+                    let outer = (*self).frames.last_mut().unwrap().spans.pop().ok_or_else(malformed)?;
+                    context.exit_span(outer);
+                },
+                Op::Return => {
+                    let value = (*self).pop()?;
+                    let frame = (*self).frames.pop().unwrap();
+                    match frame.kind {
+                        Kind::Top => return Ok(value),
+                        Kind::Body => {
+                            exit_lambda(context);
+                            return Ok(value);
+                        },
+                        Kind::Call => {
+                            exit_lambda(context);
+                            context.pop_frame();
+                            context.allocate(&value)?;
+                            (*self).stack.push(value);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /* Lambdas get a vm frame, anything else is applied as the tree walker would */
+    fn call(&mut self, op: AstNode, args: Vec<AstNode>, context: &mut Context) -> Result<(), Error> {
+        if let AstNode::Lambda(ref parameters, ref body) = op {
+            if let Err(error) = enter_lambda(parameters, args, context) {
+                context.note_backtrace();
+                context.pop_frame();
+                return Err(error);
+            }
+            let chunk = lambda_chunk(parameters, body, context);
+            (*self).frames.push(CallFrame::new(chunk, Kind::Call));
+            return Ok(());
+        }
+        let args: Vec<Box<AstNode>> = args.into_iter().map(Box::new).collect();
+        let value = eval::apply(&op, &args, context);
+        if value.is_err() {
+            context.note_backtrace();
+        }
+        context.pop_frame();
+        let value = value?;
+        context.allocate(&value)?;
+        (*self).stack.push(value);
+        return Ok(());
+    }
+
+    /* Undo what the frames left unfinished by an error have done to the context */
+    fn unwind(&mut self, context: &mut Context) -> () {
+        if (*self).frames.iter().any(|frame| !frame.spans.is_empty()) {
+            if let Some(span) = context.current_span() {
+                context.note_error_span(&span);
+            }
+        }
+        context.note_backtrace();
+        while let Some(frame) = (*self).frames.pop() {
+            for outer in frame.spans.into_iter().rev() {
+                context.exit_span(outer);
+            }
+            match frame.kind {
+                Kind::Top => {},
+                Kind::Body => exit_lambda(context),
+                Kind::Call => {
+                    exit_lambda(context);
+                    context.pop_frame();
+                }
+            }
+        }
+    }
+}
+
+/* Builtins evaluate to themselves, other identifiers to their definition */
+fn load(name: Symbol, context: &mut Context) -> Result<AstNode, Error> {
+    if context.is_builtin(name.clone()) {
+        context.check_permitted(&name)?;
+        return Ok(AstNode::Identifier(name));
+    }
+    match context.lookup(name.clone()) {
+        Some(value) => Ok(value.clone()),
+        None => Err(Error::Undefined(name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use compiler::{Chunk, Op};
+    use error::Error;
+    use eval::Context;
+    use interpreter::Interpreter;
+    use lexer;
+    use limits::Limits;
+    use parser;
+    use parser::{unparse, AstNode};
+    use symbols::Symbol;
+    use vm::{eval, Backend};
+
+    /* Evaluate with each backend, returning the result of the last expression and its backtrace */
+    fn both(source: &str) -> Vec<(Result<AstNode, Error>, Option<String>)> {
+        return Backend::all().iter().map(|&backend| {
+            let mut interpreter = Interpreter::new();
+            interpreter.set_backend(backend);
+            let result = interpreter.eval_source(source, "test.wsp");
+            (result, interpreter.backtrace().map(|b| format!("{}", b)))
+        }).collect();
+    }
+
+    fn same(source: &str) -> Result<AstNode, Error> {
+        let results = both(source);
+        assert_eq!(results[0], results[1], "backends differ on {}", source);
+        return results[0].0.clone();
+    }
+
+    #[test]
+    fn backends_agree() {
+        assert_eq!(same("(define fib (lambda (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))) (fib 15)"),
+                   Ok(AstNode::Number(610.0)));
+        same("(define twice (lambda (f x) (f (f x)))) (twice (lambda (x) (* x 3)) 2)").unwrap();
+        same("(map (lambda (x) (list x (* x x))) '(1 2 3))").unwrap();
+        same("(defmacro unless (c body) `(if ,c false ,body)) (define f (lambda (x) (unless (= x 0) (/ 1 x)))) (f 4)").unwrap();
+        same("(define x 1) (define g (lambda () x)) (define h (lambda (x) (g))) (h 5)").unwrap();
+        same("(define v (vector 1 2)) (vector-set! v 0 'a) v").unwrap();
+        same("(define r (define s 2))").unwrap();
+        same("(guard (e ((equal? e 'oops) (list 'caught e))) (car (list (raise 'oops))))").unwrap();
+        same("((lambda (n) (if n 1 2)) 3)").unwrap_err();
+        same("(define car 5)").unwrap_err();
+        // Parameters are loaded by slot, falling back to their name when they have no argument
+        assert_eq!(same("(define x 7) ((lambda (a x) x) 1)"), Ok(AstNode::Number(7.0)));
+        assert_eq!(same("((lambda (x) (list (define x 5) x)) 1)").map(|v| format!("{:?}", v)),
+                   Ok(String::from("Expression([Define(\"x\", Number(5.0)), Number(5.0)])")));
+        assert_eq!(same("((lambda (a a) a) 1 2)"), Ok(AstNode::Number(2.0)));
+        assert_eq!(same("((lambda (x) (list (define y x) y)) 1)").map(|v| format!("{:?}", v)),
+                   Ok(String::from("Expression([Define(\"y\", Identifier(\"x\")), Number(1.0)])")));
+        assert_eq!(same("(defmacro twice (x) `(list ,x ,x)) ((lambda (m) (m (+ 1 2))) twice)").map(|v| unparse(&v)),
+                   Ok(String::from("(3 3)")));
+        same("(undefined-function 1 2)").unwrap_err();
+    }
+
+    #[test]
+    fn errors_agree() {
+        let source = "(define fact (lambda (n)
+  (if (= n 0)
+    (car n)
+    (* n (fact (- n 1))))))
+(guard (e (else (error-object-span e))) (fact 3))
+(fact 3)";
+        let results = both(source);
+        assert_eq!(results[0], results[1]);
+        assert_eq!(results[1].1.as_ref().map(|b| b.lines().count()), Some(5));
+        assert_eq!(same("(guard (e (else (error-object-span e))) (define f (lambda (n) (car n)))\n (f 3))"),
+                   same("'(1 63)"));
+    }
+
+    #[test]
+    fn limits_apply() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_backend(Backend::Bytecode);
+        interpreter.set_limits(Limits{max_steps: Some(100), ..Limits::default()});
+        interpreter.eval_str("(define count (lambda (n) (if (= n 0) 0 (count (- n 1)))))").unwrap();
+        assert_eq!(interpreter.eval_str("(count 5)"), Ok(AstNode::Number(0.0)));
+        assert!(interpreter.eval_str("(count 50)").is_err());
+        interpreter.set_limits(Limits{max_depth: Some(3000), ..Limits::default()});
+        interpreter.eval_str("(define sum (lambda (n) (if (= n 0) 0 (+ n (sum (- n 1))))))").unwrap();
+        assert_eq!(interpreter.eval_str("(sum 2500)"), Ok(AstNode::Number(3126250.0)));
+        assert!(interpreter.eval_str("(sum 3500)").is_err());
+        // The namespaces and calls of abandoned lambdas are cleaned up
+        assert_eq!(interpreter.usage().depth, 0);
+        assert_eq!(interpreter.eval_str("(sum 10)"), Ok(AstNode::Number(55.0)));
+    }
+
+    #[test]
+    fn malformed_chunks_are_errors() {
+        let malformed = Err(Error::Io(String::from("malformed compiled code")));
+        let names = vec![Symbol::intern("f").unwrap()];
+        let mut interpreter = Interpreter::new();
+        // More arguments than the stack holds
+        let chunk = Chunk{code: vec![Op::Call(5, 0), Op::Return], names: names.clone(), ..Chunk::default()};
+        assert_eq!(interpreter.eval_chunk(chunk), malformed);
+        // A variable of a call that isn't being made
+        let chunk = Chunk{code: vec![Op::LoadLocal(3, 7, 0), Op::Return], names: names.clone(), ..Chunk::default()};
+        assert_eq!(interpreter.eval_chunk(chunk), malformed);
+        let chunk = Chunk{code: vec![Op::ExitSpan, Op::Return], names: names.clone(), ..Chunk::default()};
+        assert_eq!(interpreter.eval_chunk(chunk), malformed);
+        // Indices out of range and code that doesn't return are refused before it runs
+        let chunk = Chunk{code: vec![Op::Constant(3), Op::Return], ..Chunk::default()};
+        assert_eq!(interpreter.eval_chunk(chunk), malformed);
+        let chunk = Chunk{code: vec![Op::Load(1), Op::Return], names, ..Chunk::default()};
+        assert_eq!(interpreter.eval_chunk(chunk), malformed);
+        let chunk = Chunk{code: vec![Op::Jump(9), Op::Return], ..Chunk::default()};
+        assert_eq!(interpreter.eval_chunk(chunk), malformed);
+        let chunk = Chunk{code: vec![Op::Constant(0)], constants: vec![AstNode::Number(1.0)], ..Chunk::default()};
+        assert_eq!(interpreter.eval_chunk(chunk), malformed);
+        assert_eq!(interpreter.eval_str("(+ 1 2)"), Ok(AstNode::Number(3.0)));
+    }
+
+    #[test]
+    fn code_of_freed_lambdas_is_forgotten() {
+        let mut context = Context::new();
+        context.set_backend(Backend::Bytecode);
+        let mut run = |source: &str| {
+            let ast = parser::parse(&mut lexer::parse(source).into_iter().peekable());
+            return eval(&ast, &mut context);
+        };
+        // Each expansion is a new lambda, freed once it has been called
+        run("(defmacro fresh () '((lambda (x) x) 1))").unwrap();
+        for _ in 0..1000 {
+            assert_eq!(run("(fresh)"), Ok(AstNode::Number(1.0)));
+        }
+        assert!(context.code_cache().len() < 100);
+    }
+}
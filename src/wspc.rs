@@ -0,0 +1,570 @@
+/* wspc.rs
+ *
+ * The compiled file format: the chunks of a program's top level
+ * expressions, so the vm can run it without lexing or parsing it.
+ * A file is the magic bytes WSPC, the format version, the length and
+ * checksum of the payload, then the payload.  Lambda bodies are kept
+ * as AST, and compiled when first called as they are from source
+ */
+use compiler::{Chunk, Op};
+use conditions::ErrorObject;
+use error::Error;
+use eval;
+use hashtables::{new_hash_table, HashTable};
+use lexer::Span;
+use limits::DEFAULT_MAX_DEPTH;
+use parser::AstNode;
+use symbols::Symbol;
+use vectors::new_vector;
+use vm;
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+
+pub const MAGIC: &[u8; 4] = b"WSPC";
+/* Bump whenever the encoding of chunks, ops or values changes */
+pub const VERSION: u32 = 2;
+const HEADER_LEN: usize = 24;
+
+/* 64 bit FNV-1a */
+pub fn checksum(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes.iter() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    return hash;
+}
+
+pub fn encode(chunks: &[Chunk]) -> Result<Vec<u8>, Error> {
+    let mut payload = Writer{bytes: Vec::new()};
+    payload.len(chunks.len());
+    for chunk in chunks.iter() {
+        payload.chunk(chunk)?;
+    }
+    let payload = payload.bytes;
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&checksum(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    return Ok(bytes);
+}
+
+/* Checks the header before decoding anything, so files from other versions or
+ * damaged files are refused rather than misread
+ */
+pub fn decode(bytes: &[u8]) -> Result<Vec<Chunk>, Error> {
+    if bytes.len() < HEADER_LEN || bytes[0..4] != MAGIC[..] {
+        return Err(Error::Io(String::from("not a compiled wisp file")));
+    }
+    let mut header = Reader{bytes: &bytes[4..HEADER_LEN], position: 0, depth: 0};
+    let version = header.u32()?;
+    if version != VERSION {
+        return Err(Error::Io(format!("compiled with format version {}, expected version {}", version, VERSION)));
+    }
+    let len = header.u64()? as usize;
+    let expected = header.u64()?;
+    let payload = &bytes[HEADER_LEN..];
+    if payload.len() != len {
+        return Err(Error::Io(format!("expected {} bytes of code, found {}", len, payload.len())));
+    }
+    if checksum(payload) != expected {
+        return Err(Error::Io(String::from("checksum mismatch, the file is corrupt")));
+    }
+
+    let mut reader = Reader{bytes: payload, position: 0, depth: 0};
+    let count = reader.len()?;
+    let mut chunks = Vec::new();
+    for _ in 0..count {
+        chunks.push(reader.chunk()?);
+    }
+    if reader.position != payload.len() {
+        return Err(Error::Io(String::from("unexpected bytes after the last chunk")));
+    }
+    return Ok(chunks);
+}
+
+pub fn write_file<P: AsRef<Path>>(path: P, chunks: &[Chunk]) -> Result<(), Error> {
+    let path = path.as_ref();
+    let bytes = encode(chunks)?;
+    return fs::write(path, bytes).map_err(|e| Error::Io(format!("{}: {}", path.display(), e)));
+}
+
+pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<Chunk>, Error> {
+    let path = path.as_ref();
+    let bytes = fs::read(path).map_err(|e| Error::Io(format!("{}: {}", path.display(), e)))?;
+    return decode(&bytes).map_err(|e| match e {
+        Error::Io(message) => Error::Io(format!("{}: {}", path.display(), message)),
+        other => other
+    });
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, x: u8) -> () {
+        (*self).bytes.push(x);
+    }
+    fn u32(&mut self, x: u32) -> () {
+        (*self).bytes.extend_from_slice(&x.to_le_bytes());
+    }
+    fn u64(&mut self, x: u64) -> () {
+        (*self).bytes.extend_from_slice(&x.to_le_bytes());
+    }
+    fn len(&mut self, x: usize) -> () {
+        (*self).u64(x as u64);
+    }
+    fn str(&mut self, s: &str) -> () {
+        (*self).len(s.len());
+        (*self).bytes.extend_from_slice(s.as_bytes());
+    }
+    fn symbols(&mut self, symbols: &[Symbol]) -> () {
+        (*self).len(symbols.len());
+        for symbol in symbols.iter() {
+            (*self).str(symbol);
+        }
+    }
+
+    fn chunk(&mut self, chunk: &Chunk) -> Result<(), Error> {
+        (*self).len(chunk.code.len());
+        for op in chunk.code.iter() {
+            (*self).op(op);
+        }
+        (*self).len(chunk.constants.len());
+        for constant in chunk.constants.iter() {
+            (*self).node(constant)?;
+        }
+        (*self).symbols(&chunk.names);
+        (*self).len(chunk.spans.len());
+        for span in chunk.spans.iter() {
+            (*self).span(span);
+        }
+        return Ok(());
+    }
+
+    fn op(&mut self, op: &Op) -> () {
+        let (tag, operands) = match *op {
+            Op::Constant(i) => (0, vec![i]),
+            Op::Load(i) => (1, vec![i]),
+            Op::Define(i) => (2, vec![i]),
+            Op::Bind(i, define) => (3, vec![i, define]),
+            Op::JumpUnless(address) => (4, vec![address]),
+            Op::Jump(address) => (5, vec![address]),
+            Op::Expand(name, call, address) => (6, vec![name, call, address]),
+            Op::Call(count, name) => (7, vec![count, name]),
+            Op::Eval(i) => (8, vec![i]),
+            Op::EnterSpan(i) => (9, vec![i]),
+            Op::ExitSpan => (10, vec![]),
+            Op::Return => (11, vec![]),
+            Op::LoadLocal(depth, index, name) => (12, vec![depth, index, name])
+        };
+        (*self).u8(tag);
+        for operand in operands {
+            (*self).len(operand);
+        }
+    }
+
+    fn span(&mut self, span: &Span) -> () {
+        match span.source {
+            Some(ref source) => {
+                (*self).u8(1);
+                (*self).str(source);
+            },
+            None => (*self).u8(0)
+        }
+        (*self).len(span.line);
+        (*self).len(span.column);
+    }
+
+    fn nodes<'a, I: ExactSizeIterator<Item = &'a AstNode>>(&mut self, nodes: I) -> Result<(), Error> {
+        (*self).len(nodes.len());
+        for node in nodes {
+            (*self).node(node)?;
+        }
+        return Ok(());
+    }
+
+    fn node(&mut self, node: &AstNode) -> Result<(), Error> {
+        return eval::with_stack(|| (*self).node_in(node))?;
+    }
+
+    fn node_in(&mut self, node: &AstNode) -> Result<(), Error> {
+        match *node {
+            AstNode::Expression(ref items) => {
+                (*self).u8(0);
+                (*self).nodes(items.iter().map(|x| &**x))?;
+            },
+            AstNode::Define(ref name, ref value) => {
+                (*self).u8(1);
+                (*self).str(name);
+                (*self).node(value)?;
+            },
+            AstNode::Defmacro(ref name, ref parameters, ref body) => {
+                (*self).u8(2);
+                (*self).str(name);
+                (*self).symbols(parameters);
+                (*self).node(body)?;
+            },
+            AstNode::Lambda(ref parameters, ref body) => {
+                (*self).u8(3);
+                (*self).symbols(parameters);
+                (*self).node(body)?;
+            },
+            AstNode::Macro(ref parameters, ref body) => {
+                (*self).u8(4);
+                (*self).symbols(parameters);
+                (*self).node(body)?;
+            },
+            AstNode::If(ref pred, ref true_expr, ref false_expr) => {
+                (*self).u8(5);
+                (*self).node(pred)?;
+                (*self).node(true_expr)?;
+                (*self).node(false_expr)?;
+            },
+            AstNode::Quote(ref datum) => {
+                (*self).u8(6);
+                (*self).node(datum)?;
+            },
+            AstNode::Quasiquote(ref datum) => {
+                (*self).u8(7);
+                (*self).node(datum)?;
+            },
+            AstNode::Vector(ref elements) => {
+                (*self).u8(8);
+                (*self).nodes(elements.borrow().iter())?;
+            },
+            AstNode::HashTable(ref table) => {
+                (*self).u8(9);
+                let table = table.borrow();
+                (*self).len(table.len());
+                for &(ref key, ref value) in table.entries().iter() {
+                    (*self).node(key)?;
+                    (*self).node(value)?;
+                }
+            },
+            AstNode::Guard(ref variable, ref clauses, ref body) => {
+                (*self).u8(10);
+                (*self).str(variable);
+                (*self).len(clauses.len());
+                for clause in clauses.iter() {
+                    (*self).nodes(clause.iter().map(|x| &**x))?;
+                }
+                (*self).nodes(body.iter().map(|x| &**x))?;
+            },
+            AstNode::ErrorObject(ref object) => {
+                (*self).u8(11);
+                (*self).str(&object.kind);
+                (*self).str(&object.message);
+                (*self).nodes(object.irritants.iter())?;
+                match object.span {
+                    Some(ref span) => {
+                        (*self).u8(1);
+                        (*self).span(span);
+                    },
+                    None => (*self).u8(0)
+                }
+            },
+            AstNode::Located(ref span, ref expr) => {
+                (*self).u8(12);
+                (*self).span(span);
+                (*self).node(expr)?;
+            },
+            AstNode::Bool(b) => {
+                (*self).u8(13);
+                (*self).u8(b as u8);
+            },
+            AstNode::Char(c) => {
+                (*self).u8(14);
+                (*self).u32(c as u32);
+            },
+            AstNode::Number(x) => {
+                (*self).u8(15);
+                (*self).u64(x.to_bits());
+            },
+            AstNode::String(ref s) => {
+                (*self).u8(16);
+                (*self).str(s);
+            },
+            // Resolved variables are written as the names they were resolved from
+            AstNode::Identifier(ref s) | AstNode::Local(ref s, _, _) => {
+                (*self).u8(17);
+                (*self).str(s);
+            },
+            AstNode::Host(ref object) => {
+                return Err(Error::Type(format!("Can't write a host object to a compiled file: {:?}", object)));
+            },
+            AstNode::Continuation(ref continuation) => {
+                return Err(Error::Type(format!("Can't write a continuation to a compiled file: {:?}", continuation)));
+            },
+            AstNode::Generator(ref generator) => {
+                return Err(Error::Type(format!("Can't write a generator to a compiled file: {:?}", generator)));
+            },
+            AstNode::Promise(ref promise) => {
+                return Err(Error::Type(format!("Can't write a promise to a compiled file: {:?}", promise)));
+            }
+        }
+        return Ok(());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    depth: usize, // how deeply the value being read is nested
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        if n > (*self).bytes.len() - (*self).position {
+            return Err(Error::Io(String::from("unexpected end of compiled code")));
+        }
+        let bytes = &(*self).bytes[(*self).position..(*self).position + n];
+        (*self).position += n;
+        return Ok(bytes);
+    }
+    fn u8(&mut self) -> Result<u8, Error> {
+        return Ok((*self).take(1)?[0]);
+    }
+    fn u32(&mut self) -> Result<u32, Error> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice((*self).take(4)?);
+        return Ok(u32::from_le_bytes(bytes));
+    }
+    fn u64(&mut self) -> Result<u64, Error> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice((*self).take(8)?);
+        return Ok(u64::from_le_bytes(bytes));
+    }
+    fn len(&mut self) -> Result<usize, Error> {
+        return Ok((*self).u64()? as usize);
+    }
+    fn string(&mut self) -> Result<String, Error> {
+        let len = (*self).len()?;
+        let bytes = (*self).take(len)?;
+        return String::from_utf8(bytes.to_vec()).map_err(|_| Error::Io(String::from("invalid UTF-8 in compiled code")));
+    }
+    /* Names are interned as they are read, as the lexer does */
+    fn symbol(&mut self) -> Result<Symbol, Error> {
+        return Symbol::intern(&(*self).string()?);
+    }
+    fn symbols(&mut self) -> Result<Vec<Symbol>, Error> {
+        let count = (*self).len()?;
+        return (0..count).map(|_| (*self).symbol()).collect();
+    }
+
+    fn chunk(&mut self) -> Result<Chunk, Error> {
+        let count = (*self).len()?;
+        let code = (0..count).map(|_| (*self).op()).collect::<Result<_, _>>()?;
+        let constants = (*self).nodes()?;
+        let names = (*self).symbols()?;
+        let count = (*self).len()?;
+        let spans = (0..count).map(|_| (*self).span()).collect::<Result<_, _>>()?;
+        let chunk = Chunk{code, constants, names, spans};
+        check(&chunk)?;
+        return Ok(chunk);
+    }
+
+    fn op(&mut self) -> Result<Op, Error> {
+        let op = match (*self).u8()? {
+            0 => Op::Constant((*self).len()?),
+            1 => Op::Load((*self).len()?),
+            2 => Op::Define((*self).len()?),
+            3 => Op::Bind((*self).len()?, (*self).len()?),
+            4 => Op::JumpUnless((*self).len()?),
+            5 => Op::Jump((*self).len()?),
+            6 => Op::Expand((*self).len()?, (*self).len()?, (*self).len()?),
+            7 => Op::Call((*self).len()?, (*self).len()?),
+            8 => Op::Eval((*self).len()?),
+            9 => Op::EnterSpan((*self).len()?),
+            10 => Op::ExitSpan,
+            11 => Op::Return,
+            12 => Op::LoadLocal((*self).len()?, (*self).len()?, (*self).len()?),
+            tag => return Err(Error::Io(format!("unknown instruction {}", tag)))
+        };
+        return Ok(op);
+    }
+
+    fn span(&mut self) -> Result<Span, Error> {
+        let source = match (*self).u8()? {
+            0 => None,
+            _ => Some(Rc::from((*self).string()?))
+        };
+        return Ok(Span{source, line: (*self).len()?, column: (*self).len()?});
+    }
+
+    fn nodes(&mut self) -> Result<Vec<AstNode>, Error> {
+        let count = (*self).len()?;
+        return (0..count).map(|_| (*self).node()).collect();
+    }
+
+    /* The elements of an expression or a guard's clauses, boxed as the parser boxes them */
+    #[allow(clippy::vec_box)]
+    fn boxed(&mut self) -> Result<Vec<Box<AstNode>>, Error> {
+        return Ok((*self).nodes()?.into_iter().map(Box::new).collect());
+    }
+
+    /* Values nest no deeper than the default depth limit lets source nest */
+    fn node(&mut self) -> Result<AstNode, Error> {
+        if (*self).depth >= DEFAULT_MAX_DEPTH {
+            return Err(Error::Io(format!("values nested more than {} deep", DEFAULT_MAX_DEPTH)));
+        }
+        (*self).depth += 1;
+        let node = eval::with_stack(|| (*self).node_in())?;
+        (*self).depth -= 1;
+        return node;
+    }
+
+    fn node_in(&mut self) -> Result<AstNode, Error> {
+        let node = match (*self).u8()? {
+            0 => AstNode::Expression((*self).boxed()?),
+            1 => AstNode::Define((*self).symbol()?, Box::new((*self).node()?)),
+            2 => AstNode::Defmacro((*self).symbol()?, (*self).symbols()?, Box::new((*self).node()?)),
+            3 => AstNode::Lambda((*self).symbols()?, Rc::new((*self).node()?)),
+            4 => AstNode::Macro((*self).symbols()?, Box::new((*self).node()?)),
+            5 => AstNode::If(Box::new((*self).node()?), Box::new((*self).node()?), Box::new((*self).node()?)),
+            6 => AstNode::Quote(Box::new((*self).node()?)),
+            7 => AstNode::Quasiquote(Box::new((*self).node()?)),
+            8 => new_vector((*self).nodes()?),
+            9 => {
+                let count = (*self).len()?;
+                let mut table = HashTable::new();
+                for _ in 0..count {
+                    let key = (*self).node()?;
+                    table.insert(key, (*self).node()?);
+                }
+                new_hash_table(table)
+            },
+            10 => {
+                let variable = (*self).symbol()?;
+                let count = (*self).len()?;
+                let clauses = (0..count).map(|_| (*self).boxed()).collect::<Result<_, _>>()?;
+                AstNode::Guard(variable, clauses, (*self).boxed()?)
+            },
+            11 => {
+                let kind = (*self).string()?;
+                let message = (*self).string()?;
+                let irritants = (*self).nodes()?;
+                let span = match (*self).u8()? {
+                    0 => None,
+                    _ => Some((*self).span()?)
+                };
+                AstNode::ErrorObject(Rc::new(ErrorObject{kind, message, irritants, span}))
+            },
+            12 => {
+                let span = (*self).span()?;
+                AstNode::Located(span, Box::new((*self).node()?))
+            },
+            13 => AstNode::Bool((*self).u8()? != 0),
+            14 => {
+                let c = (*self).u32()?;
+                AstNode::Char(::std::char::from_u32(c).ok_or_else(|| Error::Io(format!("invalid character {}", c)))?)
+            },
+            15 => AstNode::Number(f64::from_bits((*self).u64()?)),
+            16 => AstNode::String((*self).string()?),
+            17 => AstNode::Identifier((*self).symbol()?),
+            tag => return Err(Error::Io(format!("unknown value {}", tag)))
+        };
+        return Ok(node);
+    }
+}
+
+/* Every index in the code must be in range, so a loaded or hand built chunk can't make the vm panic.
+ * The vm checks the stack and the slots of local variables as it runs
+ */
+pub fn check(chunk: &Chunk) -> Result<(), Error> {
+    let (code, constants, names, spans) = (chunk.code.len(), chunk.constants.len(), chunk.names.len(), chunk.spans.len());
+    let valid = chunk.code.iter().all(|op| match *op {
+        Op::Constant(i) | Op::Eval(i) => i < constants,
+        Op::Load(i) | Op::Define(i) => i < names,
+        Op::LoadLocal(_, _, name) => name < names,
+        Op::Bind(name, define) => name < names && define < constants,
+        Op::JumpUnless(address) | Op::Jump(address) => address < code,
+        Op::Expand(name, call, address) => name < names && call < constants && address < code,
+        Op::Call(_, name) => name < names,
+        Op::EnterSpan(i) => i < spans,
+        Op::ExitSpan | Op::Return => true
+    });
+    if !valid || chunk.code.last() != Some(&Op::Return) {
+        return Err(vm::malformed());
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod test {
+    use compiler::{Chunk, Op};
+    use error::Error;
+    use interpreter::Interpreter;
+    use parser::AstNode;
+    use wspc::{decode, encode, HEADER_LEN, VERSION};
+
+    const PROGRAM: &str = "(define square (lambda (x) (* x x)))
+(defmacro twice (x) (list '+ x x))
+(define v (vector 1 #\\a \"s\"))
+(guard (e (else (list 'caught (error-object-message e)))) (car (square 3)))
+(twice (square 4))
+(vector-ref v 1)";
+
+    fn results(interpreter: &mut Interpreter, program: Vec<AstNode>) -> Vec<Result<AstNode, Error>> {
+        return program.into_iter().map(|ast| interpreter.eval_ast(ast)).collect();
+    }
+
+    #[test]
+    fn compiled_programs_run_as_source() {
+        let mut interpreter = Interpreter::new();
+        let chunks = interpreter.compile_source(PROGRAM, "program.wsp").unwrap();
+        let loaded = decode(&encode(&chunks).unwrap()).unwrap();
+        assert_eq!(loaded, chunks);
+
+        let mut compiled = Interpreter::new();
+        let actual: Vec<_> = loaded.into_iter().map(|chunk| compiled.eval_chunk(chunk)).collect();
+        let mut source = Interpreter::new();
+        let program = source.parse_source(PROGRAM, "program.wsp").unwrap();
+        assert_eq!(actual, results(&mut source, program));
+        assert_eq!(actual[5], Ok(AstNode::Char('a')));
+    }
+
+    #[test]
+    fn spans_survive_compilation() {
+        let mut interpreter = Interpreter::new();
+        let chunks = interpreter.compile_source("(define f (lambda (n) (car n)))\n(f 1)", "spans.wsp").unwrap();
+        for chunk in decode(&encode(&chunks).unwrap()).unwrap() {
+            let _ = interpreter.eval_chunk(chunk);
+        }
+        assert_eq!(format!("{}", interpreter.backtrace().unwrap()), "at car (spans.wsp:1:23)\nat f (spans.wsp:2:1)");
+    }
+
+    #[test]
+    fn damaged_files_are_refused() {
+        let chunks = Interpreter::new().compile_source("(+ 1 2)", "small.wsp").unwrap();
+        let bytes = encode(&chunks).unwrap();
+
+        let mut corrupt = bytes.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert_eq!(decode(&corrupt), Err(Error::Io(String::from("checksum mismatch, the file is corrupt"))));
+
+        let mut version = bytes.clone();
+        version[4] += 1;
+        assert_eq!(decode(&version), Err(Error::Io(format!("compiled with format version {}, expected version {}",
+                                                          VERSION + 1, VERSION))));
+
+        assert!(decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(decode(&bytes[..HEADER_LEN - 1]).is_err());
+        assert_eq!(decode(b"(+ 1 2)"), Err(Error::Io(String::from("not a compiled wisp file"))));
+    }
+
+    #[test]
+    fn deeply_nested_values_are_refused() {
+        let nest = |depth: usize| (0..depth).fold(AstNode::Number(1.0), |datum, _| AstNode::Quote(Box::new(datum)));
+        let chunk = |constant| Chunk{code: vec![Op::Constant(0), Op::Return], constants: vec![constant], ..Chunk::default()};
+        let bytes = encode(&[chunk(nest(999))]).unwrap();
+        assert_eq!(decode(&bytes).map(|chunks| chunks.len()), Ok(1));
+        let bytes = encode(&[chunk(nest(1000))]).unwrap();
+        assert_eq!(decode(&bytes), Err(Error::Io(String::from("values nested more than 1000 deep"))));
+    }
+}
@@ -0,0 +1,263 @@
+/* backends.rs
+ *
+ * Differential tests: each backend must give the same values and errors,
+ * with the same backtraces, for every program in tests/programs and for
+ * randomly generated programs.  Optimized programs must give the same
+ * values and errors too
+ */
+// Explicit returns and derefs, as in the wisp crate
+#![allow(clippy::needless_return, clippy::explicit_auto_deref)]
+extern crate wisp;
+
+use std::fs;
+use std::path::Path;
+use std::rc::Rc;
+use wisp::lexer::Span;
+use wisp::{AstNode, Backend, Interpreter, Limits, Pass, Symbol};
+
+const MAX_STEPS: u64 = 20000;
+const RANDOM_PROGRAMS: u64 = 500;
+
+/* The outcome of each top level expression, as text so NaNs compare equal */
+fn run(program: &[AstNode], backend: Backend) -> Vec<String> {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_backend(backend);
+    interpreter.set_limits(Limits{max_steps: Some(MAX_STEPS), ..Limits::default()});
+    return program.iter().map(|ast| match interpreter.eval_ast(ast.clone()) {
+        Ok(value) => format!("{:?}", value),
+        Err(error) => format!("{:?} {:?}", error, interpreter.backtrace().map(|b| b.to_string()))
+    }).collect();
+}
+
+/* As run, with every optimization pass.  Optimizing changes step counts and backtraces,
+ * so they aren't compared, and running out of steps is taken to agree with anything.
+ * Defines and lambdas evaluate to their optimized code, so only their names and
+ * parameters are compared
+ */
+fn run_optimized(program: &[AstNode], backend: Backend, passes: &[Pass]) -> Vec<Option<String>> {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_backend(backend);
+    interpreter.set_passes(passes);
+    interpreter.set_limits(Limits{max_steps: Some(MAX_STEPS), ..Limits::default()});
+    interpreter.note_program(program);
+    return program.iter().map(|ast| match interpreter.eval_ast(ast.clone()) {
+        Ok(AstNode::Define(ref name, _)) => Some(format!("define {}", name)),
+        Ok(AstNode::Lambda(ref parameters, _)) => Some(format!("lambda {:?}", parameters)),
+        Ok(value) => Some(format!("{:?}", value)),
+        Err(wisp::Error::ResourceExhausted(_)) => None,
+        Err(error) => Some(format!("{:?}", error))
+    }).collect();
+}
+
+fn compare_optimized(name: &str, program: &[AstNode]) -> Result<(), String> {
+    for &backend in Backend::all().iter() {
+        let expected = run_optimized(program, backend, &[]);
+        let actual = run_optimized(program, backend, Pass::all());
+        for (i, (a, b)) in expected.iter().zip(actual.iter()).enumerate() {
+            if let (Some(a), Some(b)) = (a, b) {
+                if a != b {
+                    return Err(format!("{} expression {} on {:?}: {:?}\n  unoptimized gave {}\n  optimized gave {}",
+                                       name, i + 1, backend, program[i], a, b));
+                }
+            }
+        }
+    }
+
+    return Ok(());
+}
+
+/* Describes the first expression the backends disagree on */
+fn compare(name: &str, program: &[AstNode]) -> Result<(), String> {
+    let backends = Backend::all();
+    let expected = run(program, backends[0]);
+    for &backend in backends[1..].iter() {
+        let actual = run(program, backend);
+        for (i, (a, b)) in expected.iter().zip(actual.iter()).enumerate() {
+            if a != b {
+                return Err(format!("{} expression {}: {:?}\n  {:?} gave {}\n  {:?} gave {}",
+                                   name, i + 1, program[i], backends[0], a, backend, b));
+            }
+        }
+    }
+
+    return Ok(());
+}
+
+#[test]
+fn programs_agree() {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("programs");
+    let mut paths: Vec<_> = fs::read_dir(&directory).unwrap().map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "wsp")).collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no programs in {}", directory.display());
+    for path in paths {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let source = fs::read_to_string(&path).unwrap();
+        let program = Interpreter::new().parse_source(&source, &name).unwrap();
+        if let Err(difference) = compare(&name, &program).and_then(|_| compare_optimized(&name, &program)) {
+            panic!("{}", difference);
+        }
+    }
+}
+
+#[test]
+fn redefined_functions_agree() {
+    let source = "(define sq (lambda (x) (* x x)))
+(define g (lambda () (sq 3)))
+(define sq (lambda (x) 0))
+(g)";
+    let program = Interpreter::new().parse_str(source).unwrap();
+    if let Err(difference) = compare_optimized("redefinition", &program) {
+        panic!("{}", difference);
+    }
+    let source = "(define sq (lambda (x) (* x x)))
+(define g (lambda () (sq 3)))
+(defmacro redef () '(define sq (lambda (x) 0)))
+(g)
+(redef)
+(g)";
+    let program = Interpreter::new().parse_str(source).unwrap();
+    if let Err(difference) = compare_optimized("redefinition by a macro", &program) {
+        panic!("{}", difference);
+    }
+}
+
+/* Builds random programs out of the forms the evaluator knows.  The same
+ * seed always gives the same program, so failures can be reproduced
+ */
+struct Generator {
+    state: u64,
+    functions: usize,
+}
+
+impl Generator {
+    fn new(seed: u64) -> Generator {
+        return Generator{state: seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1, functions: 0};
+    }
+    // xorshift64
+    fn next(&mut self) -> u64 {
+        let mut x = (*self).state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        (*self).state = x;
+        return x;
+    }
+    fn below(&mut self, n: usize) -> usize {
+        return ((*self).next() % n as u64) as usize;
+    }
+    fn pick(&mut self, choices: &[&str]) -> AstNode {
+        let i = (*self).below(choices.len());
+        return ident(choices[i]);
+    }
+
+    fn leaf(&mut self, vars: &[&str]) -> AstNode {
+        match (*self).below(10) {
+            0 => AstNode::Bool((*self).below(2) == 0),
+            1 => AstNode::String(String::from("s")),
+            2 => ident("undefined"),
+            3..=5 if !vars.is_empty() => (*self).pick(vars),
+            _ => AstNode::Number((*self).below(7) as f64 - 2.0)
+        }
+    }
+
+    fn exprs(&mut self, count: usize, depth: usize, vars: &[&str]) -> Vec<AstNode> {
+        return (0..count).map(|_| (*self).expr(depth, vars)).collect();
+    }
+
+    fn expr(&mut self, depth: usize, vars: &[&str]) -> AstNode {
+        if depth == 0 || (*self).below(5) == 0 {
+            return (*self).leaf(vars);
+        }
+        let depth = depth - 1;
+        match (*self).below(12) {
+            0..=2 => {
+                let mut items = vec![(*self).pick(&["+", "-", "*", "/"])];
+                let count = 1 + (*self).below(3);
+                items.extend((*self).exprs(count, depth, vars));
+                call(items)
+            },
+            3 => {
+                let mut items = vec![(*self).pick(&["<", "=", ">=", "equal?"])];
+                items.extend((*self).exprs(2, depth, vars));
+                call(items)
+            },
+            4 => {
+                let pred = if (*self).below(4) == 0 { (*self).expr(depth, vars) } else {
+                    let op = (*self).pick(&["<", "="]);
+                    let args = (*self).exprs(2, depth, vars);
+                    call(vec![op, args[0].clone(), args[1].clone()])
+                };
+                AstNode::If(Box::new(pred), Box::new((*self).expr(depth, vars)), Box::new((*self).expr(depth, vars)))
+            },
+            5 => {
+                let op = (*self).pick(&["list", "cons", "car", "cdr"]);
+                let count = 1 + (*self).below(2);
+                let mut items = vec![op];
+                items.extend((*self).exprs(count, depth, vars));
+                call(items)
+            },
+            6 => {
+                let datum = call((*self).exprs(2, 0, &[]));
+                AstNode::Quote(Box::new(datum))
+            },
+            7 if (*self).functions > 0 => {
+                let f = (*self).below((*self).functions);
+                let mut items = vec![ident(&format!("f{}", f))];
+                items.extend((*self).exprs(2, depth, vars));
+                call(items)
+            },
+            8 => {
+                let body = (*self).expr(depth, &["y"]);
+                let lambda = AstNode::Lambda(vec![Symbol::intern("y").unwrap()], Rc::new(body));
+                call(vec![lambda, (*self).expr(depth, vars)])
+            },
+            9 => {
+                let caught = AstNode::Quote(Box::new(ident("caught")));
+                let clauses = vec![vec![Box::new(ident("else")), Box::new(caught)]];
+                AstNode::Guard(Symbol::intern("e").unwrap(), clauses, vec![Box::new((*self).expr(depth, vars))])
+            },
+            10 => call(vec![ident("twice"), (*self).expr(depth, vars)]),
+            _ => {
+                let span = Span{source: None, line: 1 + (*self).below(50), column: 1 + (*self).below(80)};
+                let op = (*self).pick(&["+", "car", "list"]);
+                let arg = (*self).expr(depth, vars);
+                AstNode::Located(span, Box::new(call(vec![op, arg])))
+            }
+        }
+    }
+
+    /* A few functions, each only calling those before it so the program ends, then calls of them */
+    fn program(&mut self) -> Vec<AstNode> {
+        let twice = call(vec![ident("list"), AstNode::Quote(Box::new(ident("+"))), ident("x"), ident("x")]);
+        let mut program = vec![AstNode::Defmacro(Symbol::intern("twice").unwrap(), vec![Symbol::intern("x").unwrap()], Box::new(twice))];
+        for i in 0..(1 + (*self).below(4)) {
+            let body = (*self).expr(4, &["a", "b"]);
+            let lambda = AstNode::Lambda(vec![Symbol::intern("a").unwrap(), Symbol::intern("b").unwrap()], Rc::new(body));
+            program.push(AstNode::Define(Symbol::intern(&format!("f{}", i)).unwrap(), Box::new(lambda)));
+            (*self).functions += 1;
+        }
+        let count = 1 + (*self).below(4);
+        program.extend((*self).exprs(count, 5, &[]));
+        return program;
+    }
+}
+
+fn ident(name: &str) -> AstNode {
+    return AstNode::Identifier(Symbol::intern(name).unwrap());
+}
+
+fn call(items: Vec<AstNode>) -> AstNode {
+    return AstNode::Expression(items.into_iter().map(Box::new).collect());
+}
+
+#[test]
+fn random_programs_agree() {
+    for seed in 0..RANDOM_PROGRAMS {
+        let program = Generator::new(seed).program();
+        let name = format!("seed {}", seed);
+        if let Err(difference) = compare(&name, &program).and_then(|_| compare_optimized(&name, &program)) {
+            panic!("{}", difference);
+        }
+    }
+}
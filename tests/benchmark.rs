@@ -0,0 +1,72 @@
+/* benchmark.rs
+ *
+ * A variable heavy benchmark: recursive functions with several
+ * parameters, each looked up many times per call.  Ignored by default,
+ * run it with cargo test --release -- --ignored --nocapture
+ *
+ * Each backend's time is reported, then the time to look the program's
+ * names up in a table keyed by strings, as every lookup did before
+ * identifiers were interned, next to the time keyed by symbols.  Both are
+ * measured in the same run, so they compare on any machine.  Timings
+ * depend on the machine, so nothing is asserted of them
+ */
+// Explicit returns, as in the wisp crate
+#![allow(clippy::needless_return)]
+extern crate wisp;
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use wisp::{AstNode, Backend, Interpreter, Symbol};
+
+const PROGRAM: &str = "(define vars (lambda (alpha beta gamma delta)
+  (+ (* alpha beta) (- gamma delta) alpha beta gamma delta)))
+(define loop (lambda (i total) (if (= i 0) total (loop (- i 1) (+ total (vars i total i 1))))))
+(define fib (lambda (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2))))))";
+const RUNS: usize = 20;
+/* The names PROGRAM looks up, and how many lookups of them to time */
+const NAMES: &[&str] = &["alpha", "beta", "gamma", "delta", "i", "total", "n", "vars", "loop", "fib"];
+const LOOKUPS: usize = 2_000_000;
+
+fn time(backend: Backend) -> Duration {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_backend(backend);
+    interpreter.eval_source(PROGRAM, "benchmark.wsp").unwrap();
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        assert_eq!(interpreter.eval_str("(fib 18)").unwrap(), AstNode::Number(2584.0));
+        interpreter.eval_str("(loop 800 0)").unwrap();
+    }
+    return start.elapsed();
+}
+
+/* Looks each key up in turn, LOOKUPS times in all */
+fn time_lookups<K: Hash + Eq + Clone>(keys: Vec<K>) -> Duration {
+    let table: HashMap<K, f64> = keys.iter().cloned().zip(0..).map(|(key, i)| (key, i as f64)).collect();
+    let start = Instant::now();
+    let mut total = 0.0;
+    for i in 0..LOOKUPS {
+        total += table[black_box(&keys[i % keys.len()])];
+    }
+    black_box(total);
+    return start.elapsed();
+}
+
+#[test]
+#[ignore]
+fn variable_lookups() {
+    let mut times = Vec::new();
+    for &backend in Backend::all().iter() {
+        let elapsed = time(backend);
+        println!("{:?}: {:?} for {} runs", backend, elapsed, RUNS);
+        times.push(elapsed);
+    }
+    println!("The bytecode vm took {:.2} times as long as the tree walker",
+        times[1].as_secs_f64() / times[0].as_secs_f64());
+
+    let strings = time_lookups(NAMES.iter().map(|&name| String::from(name)).collect());
+    let symbols = time_lookups(NAMES.iter().map(|&name| Symbol::intern(name).unwrap()).collect());
+    println!("{} lookups: {:?} keyed by strings, {:?} keyed by symbols, {:.2} times as long with strings",
+        LOOKUPS, strings, symbols, strings.as_secs_f64() / symbols.as_secs_f64());
+}
@@ -0,0 +1,39 @@
+/* lexer.rs
+ *
+ * The lexer as a crate depending on wisp sees it, with whichever regex
+ * version that crate resolves.  Words made of letters that name character
+ * classes, such as space, must lex as words, and any white space must
+ * separate them
+ */
+// Explicit returns, as in the wisp crate
+#![allow(clippy::needless_return)]
+extern crate wisp;
+
+use wisp::lexer::{self, Token};
+use wisp::{AstNode, Error, Interpreter, Symbol};
+
+#[test]
+fn white_space_separates_tokens() {
+    let tokens = lexer::try_parse("(space\tpace\r\ncase\u{a0}escape 12.5 -3)").unwrap();
+    assert_eq!(tokens, vec![Token::OpenParen, Token::Identifier(Symbol::intern("space").unwrap()),
+                            Token::Identifier(Symbol::intern("pace").unwrap()), Token::Identifier(Symbol::intern("case").unwrap()),
+                            Token::Identifier(Symbol::intern("escape").unwrap()), Token::Number(12.5),
+                            Token::Number(-3.0), Token::CloseParen]);
+}
+
+#[test]
+fn only_ascii_digits_are_numbers() {
+    match lexer::try_parse("\u{661}\u{662}") {
+        Err(Error::Syntax(_)) => {},
+        other => panic!("Expected a syntax error, got {:?}", other)
+    }
+}
+
+#[test]
+fn macros_lex_through_the_interpreter() {
+    let mut interpreter = Interpreter::new();
+    interpreter.eval_str("(defmacro sq (x) `(* ,x ,x))").unwrap();
+    assert_eq!(interpreter.eval_str("(sq\n\t(+ 1 2))").unwrap(), AstNode::Number(9.0));
+    assert_eq!(interpreter.eval_str("(define escape 'space) escape").unwrap(),
+               AstNode::Identifier(Symbol::intern("space").unwrap()));
+}
@@ -0,0 +1,186 @@
+/* lib.rs
+ *
+ * #[derive(ToWisp, FromWisp)] for structs and enums.
+ * The generated code calls the record helpers in wisp::convert,
+ * which define how each shape of type is represented.  It names
+ * everything by its full path, so it compiles whatever the deriving
+ * module has in scope
+ */
+// Explicit returns, as in the wisp crate
+#![allow(clippy::needless_return)]
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use] extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, Generics, Ident};
+
+/* Require every type parameter to implement the derived trait */
+fn add_bounds(mut generics: Generics, bound: TokenStream2) -> Generics {
+    for param in generics.type_params_mut() {
+        param.bounds.push(parse_quote!(#bound));
+    }
+    return generics;
+}
+
+/* Builds the value of the given fields, which are bound to variables of the same name */
+fn fields_to_wisp(fields: &Fields, bindings: &[Ident]) -> TokenStream2 {
+    match *fields {
+        Fields::Named(_) => {
+            let names = fields.iter().map(|f| f.ident.as_ref().unwrap().to_string());
+            quote! {
+                ::wisp::convert::record(::std::vec![#((#names, ::wisp::convert::ToWisp::to_wisp(#bindings))),*])
+            }
+        },
+        Fields::Unnamed(_) => quote! {
+            ::wisp::AstNode::Expression(::std::vec![#(::std::boxed::Box::new(::wisp::convert::ToWisp::to_wisp(#bindings))),*])
+        },
+        Fields::Unit => quote! { ::wisp::AstNode::Expression(::std::vec![]) }
+    }
+}
+
+/* Constructs path (a struct or variant) from the fields in value */
+fn fields_from_wisp(fields: &Fields, path: TokenStream2, owner: &str) -> TokenStream2 {
+    match *fields {
+        Fields::Named(_) => {
+            let idents = fields.iter().map(|f| f.ident.clone().unwrap());
+            let names = fields.iter().map(|f| f.ident.as_ref().unwrap().to_string());
+            quote! {
+                ::std::result::Result::Ok(#path { #(#idents: ::wisp::convert::record_field(value, #owner, #names)?),* })
+            }
+        },
+        Fields::Unnamed(_) => {
+            let count = fields.len();
+            let indexes = 0..count;
+            quote! {{
+                let items = ::wisp::convert::tuple_items(value, #owner, #count)?;
+                ::std::result::Result::Ok(#path(#(::wisp::convert::tuple_item(&items, #owner, #indexes)?),*))
+            }}
+        },
+        Fields::Unit => quote! { ::std::result::Result::Ok(#path) }
+    }
+}
+
+fn bindings(fields: &Fields) -> Vec<Ident> {
+    return fields.iter().enumerate().map(|(i, f)| match f.ident {
+        Some(ref ident) => ident.clone(),
+        None => format_ident!("field{}", i)
+    }).collect();
+}
+
+/* The pattern that binds each field to its name in bindings */
+fn pattern(fields: &Fields, path: TokenStream2, bindings: &[Ident]) -> TokenStream2 {
+    match *fields {
+        Fields::Named(_) => quote! { #path { #(#bindings),* } },
+        Fields::Unnamed(_) => quote! { #path(#(#bindings),*) },
+        Fields::Unit => quote! { #path }
+    }
+}
+
+#[proc_macro_derive(ToWisp)]
+pub fn derive_to_wisp(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let body = match input.data {
+        Data::Struct(ref data) => {
+            let bindings = bindings(&data.fields);
+            let pattern = pattern(&data.fields, quote!(#name), &bindings);
+            let value = match data.fields {
+                Fields::Unit => {
+                    let tag = name.to_string();
+                    quote! { ::wisp::convert::tag(#tag) }
+                },
+                ref fields => fields_to_wisp(fields, &bindings)
+            };
+            quote! {
+                let #pattern = self;
+                #value
+            }
+        },
+        Data::Enum(ref data) => {
+            let arms = data.variants.iter().map(|variant| {
+                let ident = &variant.ident;
+                let tag = ident.to_string();
+                let bindings = bindings(&variant.fields);
+                let pattern = pattern(&variant.fields, quote!(#name::#ident), &bindings);
+                let value = match variant.fields {
+                    Fields::Unit => quote! { ::wisp::convert::tag(#tag) },
+                    ref fields => {
+                        let fields = fields_to_wisp(fields, &bindings);
+                        quote! { ::wisp::convert::tagged(#tag, #fields) }
+                    }
+                };
+                quote! { #pattern => #value }
+            });
+            quote! {
+                match self {
+                    #(#arms),*
+                }
+            }
+        },
+        Data::Union(_) => {
+            return syn::Error::new_spanned(name, "ToWisp can't be derived for unions").to_compile_error().into();
+        }
+    };
+    let generics = add_bounds(input.generics.clone(), quote!(::wisp::convert::ToWisp));
+    let (impl_generics, type_generics, where_clause) = generics.split_for_impl();
+    let expanded = quote! {
+        impl #impl_generics ::wisp::convert::ToWisp for #name #type_generics #where_clause {
+            #[allow(unused_variables)]
+            fn to_wisp(self) -> ::wisp::AstNode {
+                #body
+            }
+        }
+    };
+    return expanded.into();
+}
+
+#[proc_macro_derive(FromWisp)]
+pub fn derive_from_wisp(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let name = &input.ident;
+    let owner = name.to_string();
+    let body = match input.data {
+        Data::Struct(ref data) => {
+            match data.fields {
+                Fields::Unit => quote! {
+                    ::wisp::convert::unit(value, #owner, #owner)?;
+                    ::std::result::Result::Ok(#name)
+                },
+                ref fields => fields_from_wisp(fields, quote!(#name), &owner)
+            }
+        },
+        Data::Enum(ref data) => {
+            let arms = data.variants.iter().map(|variant| {
+                let ident = &variant.ident;
+                let tag = ident.to_string();
+                let variant_owner = format!("{}::{}", owner, tag);
+                let construct = fields_from_wisp(&variant.fields, quote!(#name::#ident), &variant_owner);
+                quote! { #tag => #construct }
+            });
+            quote! {
+                let (tag, fields) = ::wisp::convert::variant(value, #owner)?;
+                let value = &fields;
+                match tag.as_str() {
+                    #(#arms,)*
+                    other => ::wisp::convert::unknown_variant(other, #owner)
+                }
+            }
+        },
+        Data::Union(_) => {
+            return syn::Error::new_spanned(name, "FromWisp can't be derived for unions").to_compile_error().into();
+        }
+    };
+    let generics = add_bounds(input.generics.clone(), quote!(::wisp::convert::FromWisp));
+    let (impl_generics, type_generics, where_clause) = generics.split_for_impl();
+    let expanded = quote! {
+        impl #impl_generics ::wisp::convert::FromWisp for #name #type_generics #where_clause {
+            fn from_wisp(value: &::wisp::AstNode) -> ::std::result::Result<Self, ::wisp::Error> {
+                #body
+            }
+        }
+    };
+    return expanded.into();
+}